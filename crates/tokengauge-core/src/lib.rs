@@ -1,39 +1,134 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
-
-use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, Local, Utc};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Datelike, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
 // ============================================================================
 // Codexbar Payload Types
 // ============================================================================
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A provider's usage windows. Fields serialize as camelCase but also accept
+/// snake_case, for codexbar forks and hand-written fixtures.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UsageSnapshot {
     pub primary: Option<UsageWindow>,
     pub secondary: Option<UsageWindow>,
+    #[serde(alias = "updated_at")]
     pub updated_at: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl UsageSnapshot {
+    /// The window shown in the session slot: the shorter of the two.
+    pub fn session_window(&self) -> Option<&UsageWindow> {
+        order_windows(self.primary.as_ref(), self.secondary.as_ref()).0
+    }
+
+    /// The window shown in the weekly slot: the longer of the two.
+    pub fn weekly_window(&self) -> Option<&UsageWindow> {
+        order_windows(self.primary.as_ref(), self.secondary.as_ref()).1
+    }
+}
+
+/// Order two windows as (session, weekly) by `window_minutes`, shortest
+/// first, since codexbar does not order them the same way for every
+/// provider. Unless both lengths are known, primary stays the session.
+fn order_windows<W: std::borrow::Borrow<UsageWindow>>(
+    primary: Option<W>,
+    secondary: Option<W>,
+) -> (Option<W>, Option<W>) {
+    let minutes = |window: &Option<W>| window.as_ref().and_then(|w| w.borrow().window_minutes);
+    match (minutes(&primary), minutes(&secondary)) {
+        (Some(first), Some(second)) if second < first => (secondary, primary),
+        _ => (primary, secondary),
+    }
+}
+
+/// One usage window, accepting the same snake_case aliases as
+/// [`UsageSnapshot`].
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UsageWindow {
-    pub used_percent: Option<u8>,
+    /// Percent of the window used. Some providers report fractions, and
+    /// values above 100 are clamped when formatted.
+    #[serde(alias = "used_percent")]
+    pub used_percent: Option<f64>,
+    #[serde(alias = "reset_description")]
     pub reset_description: Option<String>,
+    /// RFC3339 reset time, when codexbar reports one
+    #[serde(alias = "resetAt", alias = "resets_at", alias = "reset_at")]
     pub resets_at: Option<String>,
+    #[serde(alias = "window_minutes")]
     pub window_minutes: Option<u32>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Credits {
+    /// Accepts numbers, numeric strings such as "1234", or null
+    #[serde(default, deserialize_with = "deserialize_lenient_f64")]
     pub remaining: Option<f64>,
+    /// Credit limit, when the provider reports one
+    #[serde(default, deserialize_with = "deserialize_lenient_f64")]
+    pub total: Option<f64>,
+    /// Credits spent, used when `remaining` is missing
+    #[serde(default, deserialize_with = "deserialize_lenient_f64")]
+    pub used: Option<f64>,
+}
+
+impl Credits {
+    /// Remaining credits, derived from `total - used` when not reported.
+    pub fn remaining_amount(&self) -> Option<f64> {
+        self.remaining.or_else(|| Some(self.total? - self.used?))
+    }
+
+    /// Percent of the credit limit spent; `None` without a positive total.
+    pub fn used_percent(&self) -> Option<f64> {
+        credits_used_percent(self.remaining_amount(), self.total)
+    }
+}
+
+fn credits_used_percent(remaining: Option<f64>, total: Option<f64>) -> Option<f64> {
+    let total = total.filter(|total| *total > 0.0)?;
+    Some(clamp_percent((total - remaining?) * 100.0 / total))
+}
+
+/// Credits text for rows: "42.00", or "42.00/100.00" when the limit is known.
+fn format_credits(remaining: Option<f64>, total: Option<f64>) -> String {
+    match (remaining, total) {
+        (Some(remaining), Some(total)) => format!("{remaining:.2}/{total:.2}"),
+        (Some(remaining), None) => format!("{remaining:.2}"),
+        (None, _) => "—".to_string(),
+    }
+}
+
+/// Deserialize a number that some providers send as an integer, a float or a
+/// numeric string. `null` becomes `None`; anything else is an error.
+fn deserialize_lenient_f64<'de, D>(deserializer: D) -> std::result::Result<Option<f64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::Number(number) => Ok(number.as_f64()),
+        serde_json::Value::String(text) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| D::Error::custom(format!("expected a number, got {text:?}"))),
+        other => Err(D::Error::custom(format!("expected a number, got {other}"))),
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -47,6 +142,8 @@ pub struct ProviderError {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProviderPayload {
+    /// Empty when codexbar omits it; fetches fill in the requested provider
+    #[serde(default)]
     pub provider: String,
     pub version: Option<String>,
     pub source: Option<String>,
@@ -83,6 +180,8 @@ pub struct ProviderInfo {
     /// Environment variable name for API key (only for Api type)
     pub env_var: Option<&'static str>,
     pub label: &'static str,
+    /// Compact label for space-constrained outputs such as waybar
+    pub short_label: Option<&'static str>,
 }
 
 /// Registry of all supported providers.
@@ -93,12 +192,14 @@ pub const PROVIDERS: &[ProviderInfo] = &[
         provider_type: ProviderType::OAuth,
         env_var: None,
         label: "Codex",
+        short_label: Some("CDX"),
     },
     ProviderInfo {
         name: "claude",
         provider_type: ProviderType::OAuth,
         env_var: None,
         label: "Claude",
+        short_label: Some("CLD"),
     },
     // API providers
     ProviderInfo {
@@ -106,30 +207,35 @@ pub const PROVIDERS: &[ProviderInfo] = &[
         provider_type: ProviderType::Api,
         env_var: Some("ZAI_API_TOKEN"),
         label: "z.ai",
+        short_label: Some("ZAI"),
     },
     ProviderInfo {
         name: "kimik2",
         provider_type: ProviderType::Api,
         env_var: Some("KIMI_K2_API_KEY"),
         label: "Kimi K2",
+        short_label: Some("KK2"),
     },
     ProviderInfo {
         name: "copilot",
         provider_type: ProviderType::Api,
         env_var: Some("COPILOT_API_TOKEN"),
         label: "Copilot",
+        short_label: Some("CPL"),
     },
     ProviderInfo {
         name: "minimax",
         provider_type: ProviderType::Api,
         env_var: Some("MINIMAX_API_TOKEN"),
         label: "MiniMax",
+        short_label: Some("MMX"),
     },
     ProviderInfo {
         name: "kimi",
         provider_type: ProviderType::Api,
         env_var: Some("KIMI_AUTH_TOKEN"),
         label: "Kimi",
+        short_label: Some("KMI"),
     },
 ];
 
@@ -143,6 +249,17 @@ pub fn provider_label(name: &str) -> &str {
     get_provider_info(name).map(|p| p.label).unwrap_or(name)
 }
 
+/// Get the short label for a provider: the `[short_labels]` config entry,
+/// then the registry short label, then the full label, then the name.
+pub fn provider_short_label<'a>(name: &'a str, config: &'a TokenGaugeConfig) -> &'a str {
+    if let Some(label) = config.short_labels.get(name) {
+        return label;
+    }
+    get_provider_info(name)
+        .map(|p| p.short_label.unwrap_or(p.label))
+        .unwrap_or(name)
+}
+
 // ============================================================================
 // Configuration Types
 // ============================================================================
@@ -166,6 +283,60 @@ pub struct ProvidersConfig {
     pub copilot: Option<ApiProviderConfig>,
     pub minimax: Option<ApiProviderConfig>,
     pub kimi: Option<ApiProviderConfig>,
+    /// `--source` to pass codexbar per provider, replacing the one implied
+    /// by the provider type
+    pub source: BTreeMap<String, ProviderSource>,
+}
+
+/// A provider's field in [`ProvidersConfig`]: an OAuth on/off flag or an API
+/// provider's key section.
+enum ProviderSlot<'a> {
+    OAuth(&'a mut Option<bool>),
+    Api(&'a mut Option<ApiProviderConfig>),
+}
+
+/// How codexbar should query a provider.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderSource {
+    /// Let codexbar pick
+    Auto,
+    #[serde(rename = "oauth")]
+    OAuth,
+    Api,
+}
+
+impl ProviderSource {
+    /// The value passed to codexbar's `--source`.
+    pub fn as_arg(self) -> &'static str {
+        match self {
+            ProviderSource::Auto => "auto",
+            ProviderSource::OAuth => "oauth",
+            ProviderSource::Api => "api",
+        }
+    }
+}
+
+/// A suspicious but non-fatal problem in the provider configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// `[providers.<name>]` is present but its `api_key` is blank
+    EmptyApiKey { provider: String },
+    /// The same API key is configured for several providers
+    SharedApiKey { providers: Vec<String> },
+}
+
+impl std::fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigWarning::EmptyApiKey { provider } => {
+                write!(f, "[providers.{provider}] has an empty api_key")
+            }
+            ConfigWarning::SharedApiKey { providers } => {
+                write!(f, "the same api_key is set for {}", providers.join(", "))
+            }
+        }
+    }
 }
 
 /// An enabled provider with its configuration.
@@ -175,74 +346,146 @@ pub struct EnabledProvider {
     pub provider_type: ProviderType,
     pub api_key: Option<String>,
     pub env_var: Option<&'static str>,
+    /// `[providers.source]` override for codexbar's `--source`
+    pub source: Option<ProviderSource>,
+}
+
+impl EnabledProvider {
+    /// Build an enabled provider from its registry entry, so the provider
+    /// type and env var always match `PROVIDERS`.
+    pub fn from_info(info: &ProviderInfo, api_key: Option<String>) -> Self {
+        Self {
+            name: info.name.to_string(),
+            provider_type: info.provider_type,
+            api_key,
+            env_var: info.env_var,
+            source: None,
+        }
+    }
 }
 
 impl ProvidersConfig {
     /// Get list of all enabled providers with their configuration.
+    ///
+    /// OAuth providers are enabled with `true`; API providers are enabled when
+    /// their section (with an `api_key`) is present. Order follows `PROVIDERS`.
     pub fn enabled_providers(&self) -> Vec<EnabledProvider> {
-        let mut enabled = Vec::new();
-
-        // OAuth providers
-        if self.codex.unwrap_or(false) {
-            enabled.push(EnabledProvider {
-                name: "codex".to_string(),
-                provider_type: ProviderType::OAuth,
-                api_key: None,
-                env_var: None,
-            });
-        }
-        if self.claude.unwrap_or(false) {
-            enabled.push(EnabledProvider {
-                name: "claude".to_string(),
-                provider_type: ProviderType::OAuth,
-                api_key: None,
-                env_var: None,
-            });
+        PROVIDERS
+            .iter()
+            .filter(|info| self.is_enabled(info.name))
+            .map(|info| self.configured(info))
+            .collect()
+    }
+
+    /// `info` as this config would fetch it, with its API key and
+    /// `[providers.source]` override, whether or not it is enabled.
+    pub fn configured(&self, info: &ProviderInfo) -> EnabledProvider {
+        let api_key = self
+            .api_config(info.name)
+            .map(|config| config.api_key.clone());
+        EnabledProvider {
+            source: self.source.get(info.name).copied(),
+            ..EnabledProvider::from_info(info, api_key)
         }
+    }
 
-        // API providers - enabled if api_key is present
-        if let Some(ref config) = self.zai {
-            enabled.push(EnabledProvider {
-                name: "zai".to_string(),
-                provider_type: ProviderType::Api,
-                api_key: Some(config.api_key.clone()),
-                env_var: Some("ZAI_API_TOKEN"),
-            });
+    /// Reject `[providers.source]` entries for providers that don't exist.
+    pub fn validate_sources(&self) -> Result<()> {
+        match self
+            .source
+            .keys()
+            .find(|name| get_provider_info(name).is_none())
+        {
+            Some(name) => Err(anyhow!("providers.source has unknown provider {name:?}")),
+            None => Ok(()),
         }
-        if let Some(ref config) = self.kimik2 {
-            enabled.push(EnabledProvider {
-                name: "kimik2".to_string(),
-                provider_type: ProviderType::Api,
-                api_key: Some(config.api_key.clone()),
-                env_var: Some("KIMI_K2_API_KEY"),
-            });
+    }
+
+    /// Config that enables exactly `providers`, with their API keys.
+    pub fn from_enabled(providers: &[EnabledProvider]) -> Self {
+        let mut config = Self::default();
+        for info in PROVIDERS {
+            let enabled = providers.iter().find(|provider| provider.name == info.name);
+            match config.slot_mut(info.name) {
+                Some(ProviderSlot::OAuth(flag)) => *flag = Some(enabled.is_some()),
+                Some(ProviderSlot::Api(section)) => {
+                    *section = enabled
+                        .and_then(|provider| provider.api_key.clone())
+                        .map(|api_key| ApiProviderConfig { api_key });
+                }
+                None => {}
+            }
         }
-        if let Some(ref config) = self.copilot {
-            enabled.push(EnabledProvider {
-                name: "copilot".to_string(),
-                provider_type: ProviderType::Api,
-                api_key: Some(config.api_key.clone()),
-                env_var: Some("COPILOT_API_TOKEN"),
-            });
+        config
+    }
+
+    /// Turn off every enabled provider not in `names`.
+    fn retain(&mut self, names: &[String]) {
+        for info in PROVIDERS {
+            if names.iter().any(|name| name == info.name) {
+                continue;
+            }
+            match self.slot_mut(info.name) {
+                Some(ProviderSlot::OAuth(flag)) => *flag = flag.map(|_| false),
+                Some(ProviderSlot::Api(section)) => *section = None,
+                None => {}
+            }
         }
-        if let Some(ref config) = self.minimax {
-            enabled.push(EnabledProvider {
-                name: "minimax".to_string(),
-                provider_type: ProviderType::Api,
-                api_key: Some(config.api_key.clone()),
-                env_var: Some("MINIMAX_API_TOKEN"),
-            });
+    }
+
+    /// The field that enables `provider`, by registry name.
+    fn slot_mut(&mut self, provider: &str) -> Option<ProviderSlot<'_>> {
+        Some(match provider {
+            "codex" => ProviderSlot::OAuth(&mut self.codex),
+            "claude" => ProviderSlot::OAuth(&mut self.claude),
+            "zai" => ProviderSlot::Api(&mut self.zai),
+            "kimik2" => ProviderSlot::Api(&mut self.kimik2),
+            "copilot" => ProviderSlot::Api(&mut self.copilot),
+            "minimax" => ProviderSlot::Api(&mut self.minimax),
+            "kimi" => ProviderSlot::Api(&mut self.kimi),
+            _ => return None,
+        })
+    }
+
+    /// API key section for an API provider, if configured.
+    pub fn api_config(&self, provider: &str) -> Option<&ApiProviderConfig> {
+        match provider {
+            "zai" => self.zai.as_ref(),
+            "kimik2" => self.kimik2.as_ref(),
+            "copilot" => self.copilot.as_ref(),
+            "minimax" => self.minimax.as_ref(),
+            "kimi" => self.kimi.as_ref(),
+            _ => None,
         }
-        if let Some(ref config) = self.kimi {
-            enabled.push(EnabledProvider {
-                name: "kimi".to_string(),
-                provider_type: ProviderType::Api,
-                api_key: Some(config.api_key.clone()),
-                env_var: Some("KIMI_AUTH_TOKEN"),
-            });
+    }
+
+    /// Look for likely mistakes in the provider sections: empty API keys and
+    /// the same key pasted into more than one provider.
+    pub fn validate(&self) -> Vec<ConfigWarning> {
+        let mut warnings = Vec::new();
+        let mut by_key: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+
+        for info in PROVIDERS {
+            let Some(config) = self.api_config(info.name) else {
+                continue;
+            };
+            let key = config.api_key.trim();
+            if key.is_empty() {
+                warnings.push(ConfigWarning::EmptyApiKey {
+                    provider: info.name.to_string(),
+                });
+            } else {
+                by_key.entry(key).or_default().push(info.name.to_string());
+            }
         }
 
-        enabled
+        warnings.extend(
+            by_key
+                .into_values()
+                .filter(|providers| providers.len() > 1)
+                .map(|providers| ConfigWarning::SharedApiKey { providers }),
+        );
+        warnings
     }
 
     /// Check if a provider is enabled (used for filtering payloads).
@@ -250,12 +493,7 @@ impl ProvidersConfig {
         match provider {
             "codex" => self.codex.unwrap_or(false),
             "claude" => self.claude.unwrap_or(false),
-            "zai" => self.zai.is_some(),
-            "kimik2" => self.kimik2.is_some(),
-            "copilot" => self.copilot.is_some(),
-            "minimax" => self.minimax.is_some(),
-            "kimi" => self.kimi.is_some(),
-            _ => false,
+            _ => self.api_config(provider).is_some(),
         }
     }
 }
@@ -264,16 +502,65 @@ impl ProvidersConfig {
 #[serde(default)]
 pub struct WaybarConfig {
     pub window: WaybarWindow,
+    /// Emit empty text when no providers have data, so waybar hides the module
+    pub hide_when_empty: bool,
+    /// Text shown when no providers have data (unless `hide_when_empty`)
+    pub empty_text: String,
+    /// Show both the session and weekly windows in the text, ignoring `window`
+    pub show_both: bool,
+    /// Include the other window's text as `alt`, for waybar's `format-alt`
+    pub emit_alt: bool,
+    /// Text placed between providers
+    pub separator: String,
+    /// Use short provider labels (e.g. "CLD") in the text
+    pub short_labels: bool,
+    /// How much of each provider's usage to draw
+    pub style: WaybarStyle,
+    /// Append the remaining credits summed over API providers, e.g. "Σ 128.40 cr"
+    pub show_total_credits: bool,
+    /// Emit the tooltip as pango markup with percentages colored by severity
+    pub tooltip_markup: bool,
 }
 
 impl Default for WaybarConfig {
     fn default() -> Self {
         Self {
             window: WaybarWindow::Daily,
+            hide_when_empty: false,
+            empty_text: "—".to_string(),
+            show_both: false,
+            emit_alt: false,
+            separator: "  ".to_string(),
+            short_labels: false,
+            style: WaybarStyle::Full,
+            show_total_credits: false,
+            tooltip_markup: false,
+        }
+    }
+}
+
+impl WaybarConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.separator.is_empty() {
+            return Err(anyhow!("waybar.separator must not be empty"));
         }
+        Ok(())
     }
 }
 
+/// Waybar text style for each provider.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WaybarStyle {
+    /// Label, bar glyphs and percentage
+    #[default]
+    Full,
+    /// Label and bar glyphs only
+    Bars,
+    /// Label and percentages only, for very small bars
+    Numbers,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum WaybarWindow {
@@ -282,1170 +569,6963 @@ pub enum WaybarWindow {
     Weekly,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(default)]
-pub struct TokenGaugeConfig {
-    pub codexbar_bin: String,
-    pub refresh_secs: u64,
-    pub cache_file: PathBuf,
-    /// Timeout in seconds for each provider request
-    pub timeout_secs: u64,
-    pub providers: ProvidersConfig,
-    pub waybar: WaybarConfig,
+/// Parses `--window` and `TOKENGAUGE_WINDOW`, ignoring case.
+impl std::str::FromStr for WaybarWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "daily" => Ok(WaybarWindow::Daily),
+            "weekly" => Ok(WaybarWindow::Weekly),
+            other => Err(format!(
+                "unknown window {other:?} (expected daily or weekly)"
+            )),
+        }
+    }
 }
 
-impl Default for TokenGaugeConfig {
-    fn default() -> Self {
-        Self {
-            codexbar_bin: "codexbar".to_string(),
-            refresh_secs: 600,
-            cache_file: PathBuf::from("/tmp/tokengauge-usage.json"),
-            timeout_secs: 2,
-            providers: ProvidersConfig {
-                codex: Some(true),
-                claude: Some(true),
-                ..Default::default()
-            },
-            waybar: WaybarConfig::default(),
+impl WaybarWindow {
+    /// The other window.
+    pub fn opposite(&self) -> WaybarWindow {
+        match self {
+            WaybarWindow::Daily => WaybarWindow::Weekly,
+            WaybarWindow::Weekly => WaybarWindow::Daily,
         }
     }
 }
 
-// ============================================================================
-// Fetch Results
-// ============================================================================
+/// Default codexbar arguments. `{provider}` and `{source}` are substituted
+/// per request.
+pub const DEFAULT_ARGS_TEMPLATE: &[&str] = &[
+    "usage",
+    "--provider",
+    "{provider}",
+    "--source",
+    "{source}",
+    "--format",
+    "json",
+    "--json-only",
+];
 
-/// Error from fetching a single provider.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ProviderFetchError {
-    pub provider: String,
-    /// Short, cleaned-up error message for display
-    pub message: String,
-    /// Full raw error message for debugging
-    pub raw: String,
+/// Codexbar invocation settings.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CodexbarConfig {
+    /// Arguments passed to codexbar, with `{provider}`/`{source}` placeholders.
+    /// The resulting command must print JSON (and only JSON) on stdout.
+    pub args_template: Vec<String>,
+    /// Renames for JSON keys from codexbar forks, e.g. `percent = "usedPercent"`.
+    /// Applied at every depth before parsing; empty keeps the standard keys.
+    pub fields: BTreeMap<String, String>,
+    /// Query every provider in one codexbar call (`{provider}` becomes a
+    /// comma-separated list) when codexbar supports it, falling back to one
+    /// call each if that fails
+    pub batch: bool,
 }
 
-impl ProviderFetchError {
-    /// Create a new error with both cleaned and raw messages.
-    pub fn new(provider: String, raw_message: &str) -> Self {
+impl Default for CodexbarConfig {
+    fn default() -> Self {
         Self {
-            provider,
-            message: clean_error_message(raw_message),
-            raw: raw_message.to_string(),
+            args_template: DEFAULT_ARGS_TEMPLATE
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect(),
+            fields: BTreeMap::new(),
+            batch: false,
         }
     }
 }
 
-/// Clean up error messages to extract the meaningful part.
-/// Removes JSON log prefixes and extracts key error info.
-fn clean_error_message(raw: &str) -> String {
-    // If it's a codexbar failure with JSON in stderr, try to extract the actual error
-    if raw.contains("codexbar failed") {
-        // Try to find API error messages like "401: {\"error\":\"Unauthorized\"}"
-        if let Some(api_error) = extract_api_error(raw) {
-            return api_error;
-        }
-        // Try to find "No available fetch strategy" errors
-        if raw.contains("No available fetch strategy") {
-            return "No available fetch strategy".to_string();
-        }
-        // Try to extract message from JSON payload error
-        if let Some(msg) = extract_json_message(raw) {
-            return msg;
+impl CodexbarConfig {
+    /// Check that the template can identify the provider being requested.
+    pub fn validate(&self) -> Result<()> {
+        if !self
+            .args_template
+            .iter()
+            .any(|arg| arg.contains("{provider}"))
+        {
+            return Err(anyhow!(
+                "codexbar.args_template must contain a {{provider}} placeholder"
+            ));
         }
-        // Default: just say it failed
-        return "API request failed".to_string();
+        Ok(())
     }
 
-    // If it's a timeout
-    if raw.contains("timeout") {
-        return "Request timed out".to_string();
+    /// Substitute placeholders to produce the final argument list.
+    pub fn render_args(&self, provider: &str, source: &str) -> Vec<String> {
+        self.args_template
+            .iter()
+            .map(|arg| {
+                arg.replace("{provider}", provider)
+                    .replace("{source}", source)
+            })
+            .collect()
     }
+}
 
-    // Clean up codexbar API error messages like "Kimi K2 API returned 401: {\"error\":..."
-    if raw.contains("API returned") || raw.contains("API error") {
-        if let Some(api_error) = extract_api_error(raw) {
-            return api_error;
-        }
-        // Extract just the status part
-        if let Some(status) = extract_http_status(raw) {
-            return format!("API error ({})", status);
-        }
-    }
+/// Proxy settings passed to the codexbar subprocess. Unset fields leave the
+/// inherited environment untouched.
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub https_proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+}
 
-    // If message is reasonably short, use it as-is
-    if raw.len() <= 60 {
-        return raw.to_string();
+impl NetworkConfig {
+    /// Environment variables to inject, in a stable order.
+    pub fn env_vars(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("HTTPS_PROXY", &self.https_proxy),
+            ("HTTP_PROXY", &self.http_proxy),
+            ("NO_PROXY", &self.no_proxy),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+        .collect()
     }
+}
 
-    // Truncate long messages
-    format!("{}...", &raw[..57])
+/// Circuit breaker settings for providers that keep failing.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BreakerConfig {
+    /// Consecutive failures before a provider is paused (0 disables)
+    pub failure_threshold: u32,
+    /// First pause length in seconds; doubles with every further failure
+    pub base_cooldown_secs: u64,
+    /// Upper bound for the pause length in seconds
+    pub max_cooldown_secs: u64,
+    /// What to do when codexbar can't fetch an enabled provider at all
+    pub unsupported: UnsupportedPolicy,
 }
 
-/// Try to extract API error like "Unauthorized" or "Invalid API key"
-fn extract_api_error(raw: &str) -> Option<String> {
-    // Look for patterns like: API returned 401: {"error":"Unauthorized"}
-    // Or: Kimi K2 API error: {"error":"Unauthorized"}
-    if let Some(idx) = raw.find("\"error\":\"") {
-        let start = idx + 9;
-        if let Some(end) = raw[start..].find('"') {
-            let error = &raw[start..start + end];
-            // Look for HTTP status code
-            if let Some(status) = extract_http_status(raw) {
-                return Some(format!("{} (HTTP {})", error, status));
-            }
-            return Some(error.to_string());
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 0,
+            base_cooldown_secs: 600,
+            max_cooldown_secs: 6 * 3600,
+            unsupported: UnsupportedPolicy::Retry,
         }
     }
-    None
 }
 
-/// Extract HTTP status code from error message
-fn extract_http_status(raw: &str) -> Option<&'static str> {
-    // Look for patterns like "returned 401:" or "status: 401)"
-    ["401", "403", "404", "500", "502", "503"]
-        .iter()
-        .find(|&pattern| raw.contains(pattern))
-        .copied()
+/// Handling of providers codexbar reports it has no fetch strategy for.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsupportedPolicy {
+    /// Keep trying (and failing) every refresh
+    #[default]
+    Retry,
+    /// Stop fetching the provider until a forced (manual) refresh
+    Disable,
 }
 
-/// Try to extract "message" field from JSON in error
-fn extract_json_message(raw: &str) -> Option<String> {
-    // Look for "message":"..." pattern
-    if let Some(idx) = raw.find("\"message\":\"") {
-        let start = idx + 11;
-        if let Some(end) = raw[start..].find('"') {
-            let msg = &raw[start..start + end];
-            if !msg.is_empty() && msg.len() <= 80 {
-                return Some(msg.to_string());
-            }
+/// Usage history recorded after each live fetch.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Append a sample per provider to `file` after every live fetch
+    pub enabled: bool,
+    /// JSON-lines file holding the samples
+    pub file: PathBuf,
+    /// Samples older than this many days are dropped (0 keeps them all)
+    pub max_age_days: u64,
+    /// Most samples kept, oldest dropped first (0 means no limit)
+    pub max_points: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            file: PathBuf::from("/tmp/tokengauge-history.jsonl"),
+            max_age_days: 30,
+            max_points: 10_000,
         }
     }
-    None
 }
 
-/// Result of fetching all providers.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct FetchResult {
-    pub payloads: Vec<ProviderPayload>,
-    pub errors: Vec<ProviderFetchError>,
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct FetchConfig {
+    /// Run codexbar for all providers at once, or one at a time
+    pub mode: FetchMode,
 }
 
-/// Cached data format - stores both payloads and errors.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum CachedData {
-    /// New format with payloads and errors
-    Full {
-        payloads: Vec<ProviderPayload>,
-        errors: Vec<ProviderFetchError>,
-    },
-    /// Legacy format - just an array of payloads (for backwards compatibility)
-    Legacy(Vec<ProviderPayload>),
+/// How provider fetches are scheduled.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FetchMode {
+    /// One codexbar process per provider, all at once
+    #[default]
+    Parallel,
+    /// One codexbar process at a time, for memory-constrained machines
+    Sequential,
 }
 
-impl CachedData {
-    pub fn payloads(&self) -> &[ProviderPayload] {
-        match self {
-            CachedData::Full { payloads, .. } => payloads,
-            CachedData::Legacy(payloads) => payloads,
-        }
-    }
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct RefreshConfig {
+    /// Up to this many seconds are added to `refresh_secs` each cycle so
+    /// refreshes spread out instead of landing on the same boundary
+    pub jitter_secs: u64,
+}
 
-    pub fn errors(&self) -> &[ProviderFetchError] {
-        match self {
-            CachedData::Full { errors, .. } => errors,
-            CachedData::Legacy(_) => &[],
-        }
-    }
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Write the cache as indented JSON for easier manual inspection
+    pub pretty: bool,
+    /// Maximum bytes of raw error text kept per provider error
+    pub max_raw_error_bytes: usize,
+    /// Seconds after a cache write during which unforced writes are skipped
+    pub min_write_interval_secs: u64,
+}
 
-    pub fn into_parts(self) -> (Vec<ProviderPayload>, Vec<ProviderFetchError>) {
-        match self {
-            CachedData::Full { payloads, errors } => (payloads, errors),
-            CachedData::Legacy(payloads) => (payloads, Vec::new()),
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            pretty: false,
+            max_raw_error_bytes: DEFAULT_MAX_RAW_ERROR_BYTES,
+            min_write_interval_secs: 0,
         }
     }
 }
 
-// ============================================================================
-// Provider Row (for display)
-// ============================================================================
-
-#[derive(Debug, Clone)]
-pub struct ProviderRow {
-    pub provider: String,
-    pub session_used: Option<u8>,
-    pub session_window_minutes: Option<u32>,
-    pub session_reset: String,
-    pub weekly_used: Option<u8>,
-    pub weekly_window_minutes: Option<u32>,
-    pub weekly_reset: String,
-    pub credits: String,
-    pub source: String,
-    pub updated: String,
+/// A column of the TUI usage table.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TuiColumn {
+    /// A check or cross for whether the provider's last fetch succeeded
+    Status,
+    Provider,
+    Session,
+    SessionReset,
+    Weekly,
+    WeeklyReset,
+    Credits,
+    Source,
+    Updated,
 }
 
-// ============================================================================
-// Config Loading
-// ============================================================================
+impl TuiColumn {
+    pub const ALL: [TuiColumn; 9] = [
+        TuiColumn::Status,
+        TuiColumn::Provider,
+        TuiColumn::Session,
+        TuiColumn::SessionReset,
+        TuiColumn::Weekly,
+        TuiColumn::WeeklyReset,
+        TuiColumn::Credits,
+        TuiColumn::Source,
+        TuiColumn::Updated,
+    ];
+}
 
-pub fn load_config(path: Option<PathBuf>) -> Result<TokenGaugeConfig> {
-    let path = path.unwrap_or_else(default_config_path);
+/// Refresh indicator animation in the TUI.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpinnerStyle {
+    #[default]
+    Braille,
+    Ascii,
+    /// No animation, just a static "refreshing" label
+    None,
+}
 
-    let contents = fs::read_to_string(&path)
-        .with_context(|| format!("failed to read config at {}", path.display()))?;
-    let mut config: TokenGaugeConfig = toml::from_str(&contents)
-        .with_context(|| format!("failed to parse config at {}", path.display()))?;
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TuiConfig {
+    /// Render the provider name reversed when usage is above
+    /// `display.critical_above`
+    pub highlight_critical: bool,
+    /// Table columns to show, in order
+    pub columns: Vec<TuiColumn>,
+    /// Refresh spinner: "braille", "ascii" or "none"
+    pub spinner: SpinnerStyle,
+    /// Milliseconds each spinner frame is shown, independent of the poll rate
+    pub spinner_interval_ms: u64,
+    /// Seconds without a keypress before input polling slows down (0 disables)
+    pub idle_after_secs: u64,
+    /// Show the Source column; `false` hides it even if listed in `columns`
+    pub show_source: bool,
+    /// Draw the session and weekly bars stacked in one two-line cell
+    pub stacked_windows: bool,
+}
 
-    // Apply defaults for empty values
-    if config.codexbar_bin.is_empty() {
-        config.codexbar_bin = "codexbar".to_string();
+impl Default for TuiConfig {
+    fn default() -> Self {
+        Self {
+            highlight_critical: true,
+            columns: TuiColumn::ALL.to_vec(),
+            spinner: SpinnerStyle::Braille,
+            spinner_interval_ms: 100,
+            idle_after_secs: 30,
+            show_source: true,
+            stacked_windows: false,
+        }
     }
-    if config.cache_file.as_os_str().is_empty() {
-        config.cache_file = PathBuf::from("/tmp/tokengauge-usage.json");
+}
+
+impl TuiConfig {
+    /// Columns to draw: `columns` minus any hidden by a toggle. With
+    /// `stacked_windows` the Session cell also carries the weekly bar, so
+    /// Weekly is dropped when both are listed.
+    pub fn visible_columns(&self) -> Vec<TuiColumn> {
+        let stacked = self.stacks_windows();
+        self.columns
+            .iter()
+            .copied()
+            .filter(|&column| self.show_source || column != TuiColumn::Source)
+            .filter(|&column| !stacked || column != TuiColumn::Weekly)
+            .collect()
     }
-    if config.refresh_secs == 0 {
-        config.refresh_secs = 600;
+
+    /// Whether the session and weekly bars share one cell.
+    pub fn stacks_windows(&self) -> bool {
+        self.stacked_windows
+            && self.columns.contains(&TuiColumn::Session)
+            && self.columns.contains(&TuiColumn::Weekly)
     }
+}
 
-    Ok(config)
+/// Whether percentages are shown as used or remaining.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PercentDisplay {
+    #[default]
+    Used,
+    Remaining,
 }
 
-pub fn default_config_path() -> PathBuf {
-    let config_dir = std::env::var("XDG_CONFIG_HOME")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| {
-            let mut home = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
-            home.push(".config");
-            home
-        });
-    config_dir.join("tokengauge").join("config.toml")
+/// How rows for several accounts of one provider are combined when
+/// `display.aggregate_accounts` is on. Credits are always summed.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountAggregation {
+    /// Show the busiest account's usage
+    #[default]
+    Max,
+    /// Show the mean usage across accounts
+    Avg,
 }
 
-// ============================================================================
-// Fetching Logic
-// ============================================================================
+impl PercentDisplay {
+    /// Convert a used percentage into the percentage to display.
+    pub fn value(self, used: u8) -> u8 {
+        match self {
+            PercentDisplay::Used => used.min(100),
+            PercentDisplay::Remaining => 100 - used.min(100),
+        }
+    }
 
-/// Fetch a single provider using codexbar.
-pub fn fetch_single_provider(
-    codexbar_bin: &str,
-    provider: &EnabledProvider,
-    timeout: Duration,
-) -> Result<Vec<ProviderPayload>> {
-    let source = match provider.provider_type {
-        ProviderType::OAuth => "oauth",
-        ProviderType::Api => "api",
-    };
+    pub fn label(self) -> &'static str {
+        match self {
+            PercentDisplay::Used => "used",
+            PercentDisplay::Remaining => "remaining",
+        }
+    }
+}
 
-    let mut command = Command::new(codexbar_bin);
-    command
-        .arg("usage")
-        .arg("--provider")
-        .arg(&provider.name)
-        .arg("--source")
-        .arg(source)
-        .arg("--format")
-        .arg("json")
-        .arg("--json-only");
+/// How close a usage window is to its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    High,
+    Critical,
+}
 
-    // Set API key environment variable if needed
-    if let (Some(api_key), Some(env_var)) = (&provider.api_key, provider.env_var) {
-        command.env(env_var, api_key);
+impl Severity {
+    /// Lowercase name, as used in waybar CSS classes.
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        }
     }
 
-    // Run with timeout using a separate thread
-    let (tx, rx) = mpsc::channel();
-    let child = command
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()
-        .with_context(|| format!("failed to spawn codexbar for {}", provider.name))?;
-
-    let provider_name = provider.name.clone();
-    thread::spawn(move || {
-        let result = child.wait_with_output();
-        let _ = tx.send(result);
-    });
+    /// Hex color for markup such as waybar's pango tooltip, matching the
+    /// green/yellow/light red/red the TUI draws with.
+    pub fn hex(self) -> &'static str {
+        match self {
+            Severity::Ok => "#50fa7b",
+            Severity::Warning => "#f1fa8c",
+            Severity::High => "#ffb86c",
+            Severity::Critical => "#ff5555",
+        }
+    }
+}
 
-    let output = rx
-        .recv_timeout(timeout)
-        .map_err(|_| anyhow!("timeout after {:?}", timeout))?
-        .with_context(|| format!("failed to run codexbar for {}", provider_name))?;
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    /// Show percentages as "used" or "remaining"
+    pub show: PercentDisplay,
+    /// Used percentage above which usage is a warning
+    pub warning_above: u8,
+    /// Used percentage above which usage is high
+    pub high_above: u8,
+    /// Used percentage above which usage is critical
+    pub critical_above: u8,
+    /// Decimal places shown in percentage text; bars always use whole percents
+    pub precision: u8,
+    /// Collapse rows for several accounts of the same provider into one
+    pub aggregate_accounts: bool,
+    /// How collapsed accounts combine their usage
+    pub aggregate_strategy: AccountAggregation,
+    /// Show the weekly window in the TUI table and waybar tooltip
+    pub show_weekly: bool,
+}
 
-    if !output.status.success() {
-        // Try to parse JSON error from stdout first
-        if let Ok(payloads) = parse_payload_bytes(&output.stdout) {
-            // Codexbar returns non-zero but still outputs JSON with error info
-            return Ok(payloads);
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            show: PercentDisplay::Used,
+            warning_above: 30,
+            high_above: 60,
+            critical_above: 80,
+            precision: 0,
+            aggregate_accounts: false,
+            aggregate_strategy: AccountAggregation::Max,
+            show_weekly: true,
         }
+    }
+}
 
-        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        let detail = if !stderr.is_empty() {
-            stderr
-        } else if !stdout.is_empty() {
-            stdout
-        } else {
-            "no error output".to_string()
+impl DisplayConfig {
+    /// Format an exact used percentage as "used" or "remaining" text with
+    /// `precision` decimal places, e.g. "42.5".
+    pub fn format_percent(&self, used_percent: f64) -> String {
+        let used = clamp_percent(used_percent);
+        let value = match self.show {
+            PercentDisplay::Used => used,
+            PercentDisplay::Remaining => 100.0 - used,
         };
-        return Err(anyhow!("codexbar failed ({}) - {}", output.status, detail));
+        // Round explicitly so text agrees with the rounded bar (42.5 -> 43)
+        let precision = usize::from(self.precision);
+        let scale = 10f64.powi(i32::from(self.precision));
+        format!("{:.precision$}", (value * scale).round() / scale)
     }
 
-    parse_payload_bytes(&output.stdout)
+    /// Classify a used percentage. Always based on usage, so "used" and
+    /// "remaining" displays agree on what is bad.
+    pub fn severity(&self, used_percent: u8) -> Severity {
+        let used = used_percent.min(100);
+        if used > self.critical_above {
+            Severity::Critical
+        } else if used > self.high_above {
+            Severity::High
+        } else if used > self.warning_above {
+            Severity::Warning
+        } else {
+            Severity::Ok
+        }
+    }
 }
 
-/// Fetch all enabled providers in parallel.
-pub fn fetch_all_providers(config: &TokenGaugeConfig) -> FetchResult {
-    let enabled = config.providers.enabled_providers();
-    let timeout = Duration::from_secs(config.timeout_secs);
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TokenGaugeConfig {
+    pub codexbar_bin: String,
+    pub refresh_secs: u64,
+    /// Age in seconds after which usage is shown as unknown instead of stale;
+    /// 0 never expires it
+    pub max_age_secs: u64,
+    pub cache_file: PathBuf,
+    /// Timeout in seconds for each provider request
+    pub timeout_secs: u64,
+    pub providers: ProvidersConfig,
+    pub waybar: WaybarConfig,
+    pub tui: TuiConfig,
+    pub display: DisplayConfig,
+    pub cache: CacheConfig,
+    pub network: NetworkConfig,
+    pub codexbar: CodexbarConfig,
+    pub refresh: RefreshConfig,
+    pub fetch: FetchConfig,
+    pub breaker: BreakerConfig,
+    pub history: HistoryConfig,
+    /// Per-provider short label overrides, keyed by provider name
+    pub short_labels: BTreeMap<String, String>,
+    /// Profile overlaid on the base config, from `--profile` or
+    /// `TOKENGAUGE_PROFILE`
+    #[serde(skip)]
+    pub profile: Option<String>,
+}
 
-    if enabled.is_empty() {
-        return FetchResult {
-            payloads: Vec::new(),
-            errors: Vec::new(),
-        };
+impl Default for TokenGaugeConfig {
+    fn default() -> Self {
+        Self {
+            codexbar_bin: "codexbar".to_string(),
+            refresh_secs: 600,
+            max_age_secs: 0,
+            cache_file: PathBuf::from("/tmp/tokengauge-usage.json"),
+            timeout_secs: 2,
+            providers: ProvidersConfig {
+                codex: Some(true),
+                claude: Some(true),
+                ..Default::default()
+            },
+            waybar: WaybarConfig::default(),
+            tui: TuiConfig::default(),
+            display: DisplayConfig::default(),
+            cache: CacheConfig::default(),
+            network: NetworkConfig::default(),
+            codexbar: CodexbarConfig::default(),
+            refresh: RefreshConfig::default(),
+            fetch: FetchConfig::default(),
+            breaker: BreakerConfig::default(),
+            history: HistoryConfig::default(),
+            short_labels: BTreeMap::new(),
+            profile: None,
+        }
     }
+}
 
-    // Spawn threads for each provider
-    let handles: Vec<_> = enabled
-        .into_iter()
-        .map(|provider| {
-            let bin = config.codexbar_bin.clone();
-            let provider_name = provider.name.clone();
-            thread::spawn(move || {
-                let result = fetch_single_provider(&bin, &provider, timeout);
-                (provider_name, result)
-            })
-        })
-        .collect();
+impl TokenGaugeConfig {
+    /// Cache age at which data counts as stale, including this process's jitter.
+    pub fn refresh_threshold(&self) -> Duration {
+        Duration::from_secs(self.refresh_secs) + jitter(self.refresh.jitter_secs, process_seed())
+    }
 
-    // Collect results
-    let mut payloads = Vec::new();
-    let mut errors = Vec::new();
+    /// Staleness of the cache file, judged by its modification time. This is
+    /// the check both binaries use before deciding to refetch; a missing file
+    /// is stale, and `refresh_secs = 0` means never trust the cache.
+    pub fn cache_file_is_stale(&self) -> bool {
+        self.refresh_secs == 0 || cache_is_stale(&self.cache_file, self.refresh_threshold())
+    }
 
-    for handle in handles {
-        match handle.join() {
-            Ok((provider_name, Ok(provider_payloads))) => {
-                // Filter out payloads with errors and add successful ones
-                for payload in provider_payloads {
-                    if payload.has_error() {
-                        let msg = payload
-                            .error
-                            .as_ref()
-                            .and_then(|e| e.message.clone())
-                            .unwrap_or_else(|| "Unknown error".to_string());
-                        errors.push(ProviderFetchError::new(provider_name.clone(), &msg));
-                    } else {
-                        payloads.push(payload);
-                    }
-                }
-            }
-            Ok((provider_name, Err(e))) => {
-                errors.push(ProviderFetchError::new(provider_name, &e.to_string()));
-            }
-            Err(_) => {
-                // Thread panicked - shouldn't happen normally
-                errors.push(ProviderFetchError {
-                    provider: "unknown".to_string(),
-                    message: "thread panicked".to_string(),
-                    raw: "thread panicked".to_string(),
-                });
-            }
+    /// Whether a refresh should write the cache now. Unforced writes are
+    /// skipped while the file is younger than `cache.min_write_interval_secs`,
+    /// so refreshes that land close together only write once. The interval is
+    /// capped at `refresh_secs`: a skipped write also drops the new breaker
+    /// state, and a cache left stale would have every run refetch.
+    pub fn cache_write_due(&self, force: bool) -> bool {
+        let min_interval = self.cache.min_write_interval_secs.min(self.refresh_secs);
+        if force || min_interval == 0 {
+            return true;
         }
+        cache_age(&self.cache_file)
+            .is_none_or(|cache| cache.age >= Duration::from_secs(min_interval))
     }
 
-    FetchResult { payloads, errors }
+    /// Warning to show when the cache file is dated in the future, in which
+    /// case it is treated as fresh (see `cache_age`).
+    pub fn cache_clock_skew_warning(&self) -> Option<String> {
+        let skew = cache_age(&self.cache_file)?.clock_skew?;
+        Some(format!(
+            "cache {} is dated {}s in the future (clock skew?), treating it as fresh",
+            self.cache_file.display(),
+            skew.as_secs()
+        ))
+    }
 }
 
-// ============================================================================
-// Payload Processing
-// ============================================================================
+/// Whether the cache at `path` is at least `refresh` old. A missing file or
+/// unreadable mtime is stale; a future mtime is fresh (see `cache_age`).
+fn cache_is_stale(path: &Path, refresh: Duration) -> bool {
+    cache_age(path).is_none_or(|cache| cache.age >= refresh)
+}
 
-pub fn parse_payload(value: serde_json::Value) -> Result<Vec<ProviderPayload>> {
-    if value.is_array() {
-        serde_json::from_value(value).context("failed to parse provider payload list")
-    } else {
-        let payload: ProviderPayload =
-            serde_json::from_value(value).context("failed to parse provider payload")?;
-        Ok(vec![payload])
+/// Directories that are emptied on reboot on most Linux systems.
+const VOLATILE_DIRS: &[&str] = &["/tmp", "/dev/shm", "/run"];
+
+/// Whether `path` is likely wiped on reboot: under `/tmp` and friends, or on
+/// a tmpfs/ramfs mount listed in `/proc/mounts`.
+pub fn is_volatile_path(path: &Path) -> bool {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    is_volatile_with_mounts(path, &mounts)
+}
+
+/// `is_volatile_path` against `mounts`, in the `/proc/mounts` format.
+fn is_volatile_with_mounts(path: &Path, mounts: &str) -> bool {
+    if VOLATILE_DIRS.iter().any(|dir| path.starts_with(dir)) {
+        return true;
     }
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?;
+            let fs_type = fields.next()?;
+            Some((mount_point, fs_type))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        // The deepest mount containing the path is the one it lives on
+        .max_by_key(|(mount_point, _)| mount_point.len())
+        .is_some_and(|(_, fs_type)| matches!(fs_type, "tmpfs" | "ramfs"))
 }
 
-pub fn parse_payload_bytes(bytes: &[u8]) -> Result<Vec<ProviderPayload>> {
-    let value: serde_json::Value =
-        serde_json::from_slice(bytes).context("codexbar output was not JSON")?;
-    parse_payload(value)
+/// Advice to print at startup when the cache is on a volatile path, since
+/// each boot then starts with an empty cache and a full refetch.
+pub fn volatile_cache_warning(config: &TokenGaugeConfig) -> Option<String> {
+    if !is_volatile_path(&config.cache_file) {
+        return None;
+    }
+    let suggestion = default_cache_path();
+    Some(format!(
+        "cache_file {} is cleared on reboot; set cache_file = \"{}\" to keep it",
+        config.cache_file.display(),
+        suggestion.display()
+    ))
 }
 
-pub fn payload_to_rows(payloads: Vec<ProviderPayload>) -> Vec<ProviderRow> {
-    payloads
-        .into_iter()
-        .filter(|payload| !payload.has_error())
-        .map(provider_to_row)
-        .collect()
+/// How old the cache file is, judged by its modification time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheAge {
+    pub age: Duration,
+    /// How far in the future the modification time is, if the clock was
+    /// moved back
+    pub clock_skew: Option<Duration>,
 }
 
-pub fn format_window(window: Option<UsageWindow>) -> (Option<u8>, Option<u32>, String) {
-    if let Some(window) = window {
-        let used = window.used_percent.map(|used| used.min(100));
-        let minutes = window.window_minutes;
-        let reset = format_reset_time(window.resets_at.as_deref(), window.reset_description);
-        (used, minutes, reset)
-    } else {
-        (None, None, "—".into())
-    }
+/// Time since the cache file was last modified, if it exists. A
+/// modification time in the future (the clock was moved back) counts as
+/// just written, with `clock_skew` set, rather than as unknown - otherwise
+/// every run would refetch until the clock caught up.
+pub fn cache_age(path: &Path) -> Option<CacheAge> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    Some(match modified.elapsed() {
+        Ok(age) => CacheAge {
+            age,
+            clock_skew: None,
+        },
+        Err(error) => CacheAge {
+            age: Duration::ZERO,
+            clock_skew: Some(error.duration()),
+        },
+    })
 }
 
-/// Format reset time as relative duration (e.g., "in 2h 30m") if possible,
-/// otherwise fall back to the description (e.g., "Jan 22 at 5:59PM").
-fn format_reset_time(resets_at: Option<&str>, description: Option<String>) -> String {
-    if let Some(resets_at) = resets_at {
-        if let Ok(reset_time) = DateTime::parse_from_rfc3339(resets_at) {
-            let now = Utc::now();
-            let reset_utc = reset_time.with_timezone(&Utc);
-            let duration = reset_utc.signed_duration_since(now);
-
-            if duration.num_seconds() > 0 {
-                let total_minutes = duration.num_minutes();
-                let hours = total_minutes / 60;
-                let mins = total_minutes % 60;
-
-                return if hours > 0 {
-                    format!("in {}h {}m", hours, mins)
-                } else {
-                    format!("in {}m", mins)
-                };
-            }
-        }
+/// Deterministic jitter in `0..=max_secs` seconds derived from `seed`.
+pub fn jitter(max_secs: u64, seed: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
     }
-    // Fall back to description if we can't compute relative time
-    description.unwrap_or_else(|| "—".to_string())
+    // splitmix64 finalizer - plenty for spreading refreshes
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    Duration::from_secs(z % (max_secs + 1))
 }
 
-pub fn format_updated(value: Option<String>) -> String {
-    let Some(value) = value else {
-        return "—".to_string();
-    };
-    if let Ok(timestamp) = DateTime::parse_from_rfc3339(&value) {
-        let local = timestamp.with_timezone(&Local);
-        return local.format("%H:%M").to_string();
-    }
-    if let Some((_, time_part)) = value.split_once('T') {
-        let time = time_part.trim_end_matches('Z');
-        let short = time.get(0..5).unwrap_or(time);
-        return short.to_string();
-    }
-    value
+/// Seed that stays the same for the lifetime of the process.
+fn process_seed() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    *SEED.get_or_init(|| {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        nanos ^ u64::from(std::process::id())
+    })
 }
 
-fn provider_to_row(payload: ProviderPayload) -> ProviderRow {
-    let usage = payload.usage;
-    let (
-        session_used,
-        session_window,
-        session_reset,
-        weekly_used,
-        weekly_window,
-        weekly_reset,
-        updated,
-    ) = if let Some(usage) = usage {
-        let primary = usage.primary;
-        let secondary = usage.secondary;
-        let updated = format_updated(usage.updated_at);
-        let (session_used, session_window, session_reset) = format_window(primary);
-        let (weekly_used, weekly_window, weekly_reset) = format_window(secondary);
-        (
-            session_used,
-            session_window,
-            session_reset,
-            weekly_used,
-            weekly_window,
-            weekly_reset,
-            updated,
-        )
-    } else {
-        (None, None, "—".into(), None, None, "—".into(), "—".into())
-    };
+// ============================================================================
+// Fetch Results
+// ============================================================================
 
-    let credits = payload
-        .credits
-        .and_then(|credits| credits.remaining)
-        .map(|remaining| format!("{remaining:.2}"))
-        .unwrap_or_else(|| "—".to_string());
-
-    let source = match (payload.version, payload.source) {
-        (Some(version), Some(source)) => format!("{version} ({source})"),
-        (Some(version), None) => version,
-        (None, Some(source)) => source,
-        (None, None) => "—".to_string(),
-    };
+/// What kind of failure a provider error is, for failures with a specific fix.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Default)]
+pub enum FetchErrorKind {
+    #[default]
+    #[serde(rename = "other")]
+    Other,
+    /// The OAuth login expired or was revoked; logging in again fixes it
+    #[serde(rename = "oauth_expired")]
+    OAuthExpired,
+    /// codexbar has no way to fetch this provider, so retrying can't help
+    #[serde(rename = "unsupported")]
+    Unsupported,
+    /// codexbar's output was cut off; the payloads it did finish were kept
+    #[serde(rename = "truncated")]
+    Truncated,
+}
 
-    ProviderRow {
-        provider: provider_label(&payload.provider).to_string(),
-        session_used,
-        session_window_minutes: session_window,
-        session_reset,
-        weekly_used,
-        weekly_window_minutes: weekly_window,
-        weekly_reset,
-        credits,
-        source,
-        updated,
-    }
+/// Error from fetching a single provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderFetchError {
+    pub provider: String,
+    /// Short, cleaned-up error message for display
+    pub message: String,
+    /// Full raw error message for debugging
+    pub raw: String,
+    #[serde(default)]
+    pub kind: FetchErrorKind,
+    /// Unix timestamp of the first refresh in the current run of this error
+    #[serde(default)]
+    pub first_seen_at: Option<i64>,
+    /// Unix timestamp of the latest refresh that hit this error
+    #[serde(default)]
+    pub last_seen_at: Option<i64>,
 }
 
-// ============================================================================
-// Cache Operations
-// ============================================================================
+/// Default cap on `ProviderFetchError::raw`, in bytes.
+pub const DEFAULT_MAX_RAW_ERROR_BYTES: usize = 4096;
 
-/// Read cache, returning both payloads and errors.
-pub fn read_cache_full(path: &Path) -> Result<CachedData> {
-    let contents = fs::read_to_string(path)
-        .with_context(|| format!("failed to read cache file {}", path.display()))?;
-    let cached: CachedData = serde_json::from_str(&contents).context("cached JSON was invalid")?;
-    Ok(cached)
+impl ProviderFetchError {
+    /// Create a new error with both cleaned and raw messages.
+    pub fn new(provider: String, raw_message: &str) -> Self {
+        Self::with_raw_limit(provider, raw_message, DEFAULT_MAX_RAW_ERROR_BYTES)
+    }
+
+    /// Like `new`, but truncates the raw message to at most `max_raw_bytes`
+    /// (plus a short marker) so noisy stderr cannot bloat the cache.
+    pub fn with_raw_limit(provider: String, raw_message: &str, max_raw_bytes: usize) -> Self {
+        Self {
+            kind: classify_error(&provider, raw_message),
+            message: clean_error_message(raw_message),
+            raw: truncate_raw(raw_message, max_raw_bytes),
+            provider,
+            first_seen_at: None,
+            last_seen_at: None,
+        }
+    }
+
+    /// How long the provider has been failing this way as of `now`, e.g.
+    /// "2h" or "3d 4h"; `None` under a minute or when never stamped.
+    pub fn failing_for(&self, now: i64) -> Option<String> {
+        let minutes = (now - self.first_seen_at?) / 60;
+        let (days, hours, mins) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+        let text = if minutes < 1 {
+            return None;
+        } else if minutes < 60 {
+            format!("{mins}m")
+        } else if days == 0 && mins == 0 {
+            format!("{hours}h")
+        } else if days == 0 {
+            format!("{hours}h {mins}m")
+        } else if hours == 0 {
+            format!("{days}d")
+        } else {
+            format!("{days}d {hours}h")
+        };
+        Some(text)
+    }
+
+    /// Whether the fix is logging in to the provider again.
+    pub fn needs_reauth(&self) -> bool {
+        self.kind == FetchErrorKind::OAuthExpired
+    }
 }
 
-/// Read cache, returning only successful payloads (for backwards compatibility).
-pub fn read_cache(path: &Path) -> Result<Vec<ProviderPayload>> {
-    let cached = read_cache_full(path)?;
-    Ok(cached.payloads().to_vec())
+/// The current time as a unix timestamp, the clock both binaries pass as
+/// `now` to [`merge_error_times`], [`prometheus_metrics`] and friends.
+pub fn unix_now() -> i64 {
+    Utc::now().timestamp()
 }
 
-/// Write cache with both payloads and errors.
-pub fn write_cache_full(
-    path: &Path,
-    payloads: &[ProviderPayload],
-    errors: &[ProviderFetchError],
-) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).ok();
+/// Stamp this refresh's `errors` as seen at `now`. An error that was also
+/// in `previous` (the cached errors) for the same provider and kind keeps
+/// its `first_seen_at`; anything else starts a new run. Errors that cleared
+/// drop out of the cache, so one that comes back later starts over.
+pub fn merge_error_times(
+    errors: &mut [ProviderFetchError],
+    previous: &[ProviderFetchError],
+    now: i64,
+) {
+    for error in errors {
+        let first_seen = previous
+            .iter()
+            .find(|old| old.provider == error.provider && old.kind == error.kind)
+            .and_then(|old| old.first_seen_at);
+        error.first_seen_at = Some(first_seen.unwrap_or(now));
+        error.last_seen_at = Some(now);
     }
-    let data = CachedData::Full {
-        payloads: payloads.to_vec(),
-        errors: errors.to_vec(),
-    };
-    let contents = serde_json::to_string(&data)?;
-    fs::write(path, contents)
-        .with_context(|| format!("failed to write cache {}", path.display()))?;
-    Ok(())
 }
 
-/// Write cache with only payloads (legacy, for backwards compatibility).
-pub fn write_cache(path: &Path, payloads: &[ProviderPayload]) -> Result<()> {
-    write_cache_full(path, payloads, &[])
+/// Lowercase words and phrases in codexbar errors caused by an expired or
+/// revoked OAuth login. Matched as whole words, so "401" doesn't match
+/// "4012ms".
+const OAUTH_EXPIRED_PATTERNS: &[&str] = &[
+    "token expired",
+    "token has expired",
+    "token is expired",
+    "expired token",
+    "invalid_grant",
+    "invalid_token",
+    "invalid token",
+    "session expired",
+    "not logged in",
+    "log in again",
+    "login again",
+    "please log in",
+    "please login",
+    "re-authenticate",
+    "reauthenticate",
+    "unauthorized",
+    "401",
+];
+
+/// codexbar's error for a provider it cannot fetch, lowercased.
+const UNSUPPORTED_PATTERN: &str = "no available fetch strategy";
+
+/// Classify a raw error. Only OAuth providers can have an expired login: for
+/// API-key providers an unauthorized response means a wrong key instead.
+fn classify_error(provider: &str, raw: &str) -> FetchErrorKind {
+    let oauth =
+        get_provider_info(provider).is_some_and(|info| info.provider_type == ProviderType::OAuth);
+    let raw = raw.to_lowercase();
+    if raw.contains(UNSUPPORTED_PATTERN) {
+        FetchErrorKind::Unsupported
+    } else if oauth
+        && OAUTH_EXPIRED_PATTERNS
+            .iter()
+            .any(|pattern| contains_word(&raw, pattern))
+    {
+        FetchErrorKind::OAuthExpired
+    } else {
+        FetchErrorKind::Other
+    }
 }
 
-// ============================================================================
-// Config File Operations
-// ============================================================================
+/// Whether `word` occurs in `text` with no letter, digit or underscore
+/// directly before or after it.
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + word.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
 
-pub fn ensure_config_dir(path: &Path) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("failed to create config directory {}", parent.display()))?;
+/// The longest prefix of `text` that fits in `max_bytes` and ends on a char
+/// boundary.
+fn prefix_within(text: &str, max_bytes: usize) -> &str {
+    if text.len() <= max_bytes {
+        return text;
     }
-    Ok(())
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
 }
 
-pub fn ensure_cache_dir(path: &Path) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("failed to create cache directory {}", parent.display()))?;
+/// Truncate `raw` to at most `max_bytes` on a char boundary, noting how much
+/// was dropped.
+fn truncate_raw(raw: &str, max_bytes: usize) -> String {
+    if raw.len() <= max_bytes {
+        return raw.to_string();
     }
-    Ok(())
+    let kept = prefix_within(raw, max_bytes);
+    format!("{kept}… [truncated {} bytes]", raw.len() - kept.len())
 }
 
-pub fn write_default_config(path: &Path) -> Result<()> {
-    ensure_config_dir(path)?;
-    let contents = r#"# TokenGauge Configuration
+/// Clean up error messages to extract the meaningful part.
+/// Removes JSON log prefixes and extracts key error info.
+fn clean_error_message(raw: &str) -> String {
+    // If it's a codexbar failure with JSON in stderr, try to extract the actual error
+    if raw.contains("codexbar failed") {
+        // Binary output is already summarized by the fetch path
+        if let Some(start) = raw.find(NON_TEXT_OUTPUT) {
+            return raw[start..].to_string();
+        }
+        // Try to find API error messages like "401: {\"error\":\"Unauthorized\"}"
+        if let Some(api_error) = extract_api_error(raw) {
+            return api_error;
+        }
+        // Try to find "No available fetch strategy" errors
+        if raw.contains("No available fetch strategy") {
+            return "No available fetch strategy".to_string();
+        }
+        // Try to extract message from JSON payload error
+        if let Some(msg) = extract_json_message(raw) {
+            return msg;
+        }
+        // Default: just say it failed
+        return "API request failed".to_string();
+    }
 
-# Path to codexbar binary
-codexbar_bin = "codexbar"
+    // If it's a timeout
+    if raw.contains("timeout") {
+        return "Request timed out".to_string();
+    }
 
-# Refresh interval in seconds
-refresh_secs = 600
+    // Clean up codexbar API error messages like "Kimi K2 API returned 401: {\"error\":..."
+    if raw.contains("API returned") || raw.contains("API error") {
+        if let Some(api_error) = extract_api_error(raw) {
+            return api_error;
+        }
+        // Extract just the status part
+        if let Some(status) = extract_http_status(raw) {
+            return format!("API error ({})", status);
+        }
+    }
 
-# Cache file location
-cache_file = "/tmp/tokengauge-usage.json"
+    // If message is reasonably short, use it as-is
+    if raw.len() <= 60 {
+        return raw.to_string();
+    }
 
-[waybar]
-# Which window to show in waybar: "daily" or "weekly"
-window = "daily"
+    // Truncate long messages
+    format!("{}...", prefix_within(raw, 57))
+}
 
-[providers]
-# OAuth providers - set to true/false to enable/disable
-codex = true
-claude = true
+/// Try to extract API error like "Unauthorized" or "Invalid API key"
+fn extract_api_error(raw: &str) -> Option<String> {
+    // Look for patterns like: API returned 401: {"error":"Unauthorized"}
+    // Or: Kimi K2 API error: {"error":"Unauthorized"}
+    if let Some(idx) = raw.find("\"error\":\"") {
+        let start = idx + 9;
+        if let Some(end) = raw[start..].find('"') {
+            let error = &raw[start..start + end];
+            // Look for HTTP status code
+            if let Some(status) = extract_http_status(raw) {
+                return Some(format!("{} (HTTP {})", error, status));
+            }
+            return Some(error.to_string());
+        }
+    }
+    None
+}
 
-# API providers - uncomment and add your API key to enable
-# [providers.zai]
-# api_key = "your-zai-api-key"
+/// Extract HTTP status code from error message
+fn extract_http_status(raw: &str) -> Option<&'static str> {
+    // Look for patterns like "returned 401:" or "status: 401)"
+    ["401", "403", "404", "500", "502", "503"]
+        .iter()
+        .find(|&pattern| raw.contains(pattern))
+        .copied()
+}
 
-# [providers.kimik2]
-# api_key = "your-kimi-k2-api-key"
+/// Try to extract "message" field from JSON in error
+fn extract_json_message(raw: &str) -> Option<String> {
+    // Look for "message":"..." pattern
+    if let Some(idx) = raw.find("\"message\":\"") {
+        let start = idx + 11;
+        if let Some(end) = raw[start..].find('"') {
+            let msg = &raw[start..start + end];
+            if !msg.is_empty() && msg.len() <= 80 {
+                return Some(msg.to_string());
+            }
+        }
+    }
+    None
+}
 
-# [providers.copilot]
-# api_key = "your-copilot-api-key"
+/// Result of fetching all providers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchResult {
+    pub payloads: Vec<ProviderPayload>,
+    pub errors: Vec<ProviderFetchError>,
+}
 
-# [providers.minimax]
-# api_key = "your-minimax-api-key"
+/// Everything fetched for one provider: its payload, its error, or both.
+#[derive(Debug, Clone)]
+pub struct ProviderResult {
+    pub provider: String,
+    pub payload: Option<ProviderPayload>,
+    pub error: Option<ProviderFetchError>,
+}
 
-# [providers.kimi]
-# api_key = "your-kimi-api-key"
-"#;
-    fs::write(path, contents)
-        .with_context(|| format!("failed to write config {}", path.display()))?;
-    Ok(())
+impl FetchResult {
+    /// Payloads codexbar returned without an in-band error; see
+    /// [`ProviderPayload::has_error`].
+    pub fn iter_ok(&self) -> impl Iterator<Item = &ProviderPayload> {
+        self.payloads.iter().filter(|payload| !payload.has_error())
+    }
+
+    /// Providers whose fetch failed outright.
+    pub fn iter_err(&self) -> impl Iterator<Item = &ProviderFetchError> {
+        self.errors.iter()
+    }
+
+    /// Every provider mentioned by a payload or an error, without duplicates,
+    /// in the same order as [`FetchResult::results`].
+    pub fn provider_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        let all = self
+            .payloads
+            .iter()
+            .map(|payload| payload.provider.as_str())
+            .chain(self.iter_err().map(|error| error.provider.as_str()));
+        for name in all {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    pub fn successful_count(&self) -> usize {
+        self.iter_ok().count()
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// Payloads and errors merged into one entry per provider, in order of
+    /// first appearance (payloads first, then errors). If codexbar returned
+    /// several payloads for one provider, the first is kept.
+    pub fn results(&self) -> Vec<ProviderResult> {
+        let mut results: Vec<ProviderResult> = Vec::new();
+        for payload in &self.payloads {
+            if !results
+                .iter()
+                .any(|result| result.provider == payload.provider)
+            {
+                results.push(ProviderResult {
+                    provider: payload.provider.clone(),
+                    payload: Some(payload.clone()),
+                    error: None,
+                });
+            }
+        }
+        for error in &self.errors {
+            match results
+                .iter_mut()
+                .find(|result| result.provider == error.provider)
+            {
+                Some(result) if result.error.is_none() => result.error = Some(error.clone()),
+                Some(_) => {}
+                None => results.push(ProviderResult {
+                    provider: error.provider.clone(),
+                    payload: None,
+                    error: Some(error.clone()),
+                }),
+            }
+        }
+        results
+    }
 }
 
-// ============================================================================
-// Tests
-// ============================================================================
+/// Cached data format - stores both payloads and errors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CachedData {
+    /// New format with payloads and errors
+    Full {
+        payloads: Vec<ProviderPayload>,
+        errors: Vec<ProviderFetchError>,
+        #[serde(default, skip_serializing_if = "Breakers::is_empty")]
+        breakers: Breakers,
+    },
+    /// Legacy format - just an array of payloads (for backwards compatibility)
+    Legacy(Vec<ProviderPayload>),
+}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl CachedData {
+    pub fn payloads(&self) -> &[ProviderPayload] {
+        match self {
+            CachedData::Full { payloads, .. } => payloads,
+            CachedData::Legacy(payloads) => payloads,
+        }
+    }
 
-    // ------------------------------------------------------------------------
-    // format_window tests
-    // ------------------------------------------------------------------------
+    pub fn errors(&self) -> &[ProviderFetchError] {
+        match self {
+            CachedData::Full { errors, .. } => errors,
+            CachedData::Legacy(_) => &[],
+        }
+    }
+
+    pub fn breakers(&self) -> Breakers {
+        match self {
+            CachedData::Full { breakers, .. } => breakers.clone(),
+            CachedData::Legacy(_) => Breakers::new(),
+        }
+    }
+
+    pub fn into_parts(self) -> (Vec<ProviderPayload>, Vec<ProviderFetchError>) {
+        match self {
+            CachedData::Full {
+                payloads, errors, ..
+            } => (payloads, errors),
+            CachedData::Legacy(payloads) => (payloads, Vec::new()),
+        }
+    }
+}
+
+// ============================================================================
+// Circuit Breaker
+// ============================================================================
+
+/// Circuit breakers keyed by provider name.
+pub type Breakers = BTreeMap<String, CircuitBreaker>;
+
+/// Where a provider's circuit breaker currently stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Fetch normally
+    Closed,
+    /// Paused after repeated failures; skip until the cooldown ends
+    Open,
+    /// Cooldown over; allow one trial fetch
+    HalfOpen,
+}
+
+/// Failure tracking for one provider, persisted in the cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    pub consecutive_failures: u32,
+    /// Unix timestamp until which the provider is paused
+    pub open_until: Option<i64>,
+    /// Switched off by `UnsupportedPolicy::Disable`; only a forced refresh
+    /// fetches the provider again
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self, now: DateTime<Utc>, config: &BreakerConfig) -> BreakerState {
+        if config.failure_threshold == 0 || self.consecutive_failures < config.failure_threshold {
+            return BreakerState::Closed;
+        }
+        match self.open_until {
+            Some(until) if now.timestamp() < until => BreakerState::Open,
+            _ => BreakerState::HalfOpen,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Count a failure, (re)opening the breaker once the threshold is reached.
+    pub fn record_failure(&mut self, now: DateTime<Utc>, config: &BreakerConfig) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if config.failure_threshold == 0 || self.consecutive_failures < config.failure_threshold {
+            return;
+        }
+        let doublings = (self.consecutive_failures - config.failure_threshold).min(16);
+        let cooldown = config
+            .base_cooldown_secs
+            .saturating_mul(1 << doublings)
+            .min(config.max_cooldown_secs);
+        self.open_until = Some(now.timestamp().saturating_add(cooldown as i64));
+    }
+}
+
+/// Fetch enabled providers, skipping any whose breaker is open or disabled
+/// and updating the breakers from the outcome. A forced fetch (manual
+/// refresh) gives open and disabled breakers a trial run, e.g. right after
+/// re-authenticating or upgrading codexbar. `batch` holds
+/// what is known about codexbar's batch support, for `codexbar.batch`.
+pub fn fetch_with_breakers(
+    config: &TokenGaugeConfig,
+    breakers: &mut Breakers,
+    batch: &BatchSupport,
+    force: bool,
+) -> FetchResult {
+    let now = Utc::now();
+    let (paused, allowed): (Vec<_>, Vec<_>) = config
+        .providers
+        .enabled_providers()
+        .into_iter()
+        .filter(|provider| {
+            force
+                || !breakers
+                    .get(&provider.name)
+                    .is_some_and(|breaker| breaker.disabled)
+        })
+        .partition(|provider| {
+            !force
+                && breakers.get(&provider.name).is_some_and(|breaker| {
+                    breaker.state(now, &config.breaker) == BreakerState::Open
+                })
+        });
+
+    let allowed_names: Vec<String> = allowed.iter().map(|p| p.name.clone()).collect();
+    let deadline = fetch_deadline(config, allowed.len());
+    let mut result = fetch_providers(config, allowed, deadline, batch);
+
+    for name in allowed_names {
+        let breaker = breakers.entry(name.clone()).or_default();
+        // Truncated output still produced data, so it isn't a failure
+        if result
+            .errors
+            .iter()
+            .any(|error| error.provider == name && error.kind != FetchErrorKind::Truncated)
+        {
+            breaker.record_failure(now, &config.breaker);
+        } else {
+            breaker.record_success();
+        }
+    }
+
+    for error in &mut result.errors {
+        if should_auto_disable(error.kind, config.breaker.unsupported) {
+            breakers.entry(error.provider.clone()).or_default().disabled = true;
+            error.message =
+                "not supported by this codexbar - skipped until a manual refresh".to_string();
+        }
+    }
+    breakers.retain(|_, breaker| breaker.consecutive_failures > 0 || breaker.disabled);
+
+    for provider in paused {
+        let failures = breakers
+            .get(&provider.name)
+            .map(|breaker| breaker.consecutive_failures)
+            .unwrap_or_default();
+        let message = format!("paused after {failures} failures - re-auth and refresh");
+        result.errors.push(ProviderFetchError {
+            provider: provider.name,
+            message: message.clone(),
+            raw: message,
+            kind: FetchErrorKind::Other,
+            first_seen_at: None,
+            last_seen_at: None,
+        });
+    }
+
+    result
+}
+
+/// Whether an error of `kind` should switch its provider off for the session.
+pub fn should_auto_disable(kind: FetchErrorKind, policy: UnsupportedPolicy) -> bool {
+    kind == FetchErrorKind::Unsupported && policy == UnsupportedPolicy::Disable
+}
+
+// ============================================================================
+// Provider Row (for display)
+// ============================================================================
+
+/// A provider's usage formatted for display.
+///
+/// Serializes with camelCase keys (`sessionUsed`, `weeklyReset`, ...) to match
+/// the codexbar payloads it is built from.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderRow {
+    /// Display label, e.g. "Claude" or "Claude (2 accounts)"
+    pub provider: String,
+    /// Provider name as codexbar reports it, e.g. "claude"
+    pub name: String,
+    /// Used percentage rounded to a whole number, as drawn in bars
+    pub session_used: Option<u8>,
+    /// Exact used percentage, for text shown with `display.precision`
+    pub session_percent: Option<f64>,
+    pub session_window_minutes: Option<u32>,
+    pub session_reset: String,
+    /// RFC3339 (UTC) time the session window resets, from codexbar or parsed
+    /// from its description
+    pub session_reset_at: Option<String>,
+    pub weekly_used: Option<u8>,
+    pub weekly_percent: Option<f64>,
+    pub weekly_window_minutes: Option<u32>,
+    pub weekly_reset: String,
+    pub weekly_reset_at: Option<String>,
+    pub credits: String,
+    pub credits_remaining: Option<f64>,
+    pub credits_total: Option<f64>,
+    /// Percent of the credit limit spent, when the limit is known
+    pub credits_used_percent: Option<f64>,
+    /// Provider CLI version reported by codexbar
+    pub version: Option<String>,
+    /// How usage was fetched, e.g. "oauth" or "api"
+    pub source_kind: Option<String>,
+    pub updated: String,
+    /// Seconds since codexbar's `updatedAt`, as of when the row was built
+    pub updated_age_secs: Option<u64>,
+    /// True when this row was carried forward from a previous refresh
+    pub is_stale: bool,
+    /// Display time of the last successful update for a stale row
+    pub stale_since: Option<String>,
+    /// True when this row was read from the cache rather than fetched by
+    /// this refresh; see [`mark_from_cache`]
+    pub from_cache: bool,
+    /// Per-account rows folded into this one by [`aggregate_accounts`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<ProviderRow>,
+}
+
+impl ProviderRow {
+    /// Placeholder row for a provider whose fetch failed and that has no
+    /// earlier numbers to show: em-dash usage and a short reason in
+    /// `updated`.
+    pub fn failed(error: &ProviderFetchError) -> Self {
+        let reason = if error.needs_reauth() {
+            "re-login"
+        } else {
+            "failed"
+        };
+        Self {
+            provider: provider_label(&error.provider).to_string(),
+            name: error.provider.clone(),
+            session_used: None,
+            session_percent: None,
+            session_window_minutes: None,
+            session_reset: "—".to_string(),
+            session_reset_at: None,
+            weekly_used: None,
+            weekly_percent: None,
+            weekly_window_minutes: None,
+            weekly_reset: "—".to_string(),
+            weekly_reset_at: None,
+            credits: "—".to_string(),
+            credits_remaining: None,
+            credits_total: None,
+            credits_used_percent: None,
+            version: None,
+            source_kind: None,
+            updated: reason.to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
+        }
+    }
+
+    /// Version and source combined for display, e.g. "2.1.12 (oauth)".
+    pub fn source_text(&self) -> String {
+        match (&self.version, &self.source_kind) {
+            (Some(version), Some(source)) => format!("{version} ({source})"),
+            (Some(version), None) => version.clone(),
+            (None, Some(source)) => source.clone(),
+            (None, None) => "—".to_string(),
+        }
+    }
+
+    /// Convert the row to a JSON value for custom templates and scripts.
+    /// Includes the combined `source` string alongside the split fields.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(object) = value.as_object_mut() {
+            object.insert("source".to_string(), self.source_text().into());
+        }
+        value
+    }
+}
+
+/// Rows in the Prometheus text format: `tokengauge_used_percent` and
+/// `tokengauge_reset_seconds` (seconds from `now`, a unix timestamp, until
+/// the window resets) for each provider and window that reports them.
+pub fn prometheus_metrics(rows: &[ProviderRow], now: i64) -> String {
+    let mut used = String::new();
+    let mut reset = String::new();
+    for row in rows {
+        let provider = row
+            .name
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let windows = [
+            ("session", row.session_percent, &row.session_reset_at),
+            ("weekly", row.weekly_percent, &row.weekly_reset_at),
+        ];
+        for (window, percent, reset_at) in windows {
+            let labels = format!("provider=\"{provider}\",window=\"{window}\"");
+            if let Some(percent) = percent {
+                used.push_str(&format!("tokengauge_used_percent{{{labels}}} {percent}\n"));
+            }
+            let reset_at = reset_at
+                .as_deref()
+                .and_then(|value| DateTime::parse_from_rfc3339(value).ok());
+            if let Some(reset_at) = reset_at {
+                let seconds = (reset_at.timestamp() - now).max(0);
+                reset.push_str(&format!("tokengauge_reset_seconds{{{labels}}} {seconds}\n"));
+            }
+        }
+    }
+    format!(
+        "# HELP tokengauge_used_percent Percent of the usage window used.\n\
+         # TYPE tokengauge_used_percent gauge\n\
+         {used}\
+         # HELP tokengauge_reset_seconds Seconds until the usage window resets.\n\
+         # TYPE tokengauge_reset_seconds gauge\n\
+         {reset}"
+    )
+}
+
+/// Providers whose reported version differs between two fetches, as
+/// `(provider, old_version, new_version)`. Providers missing a version on
+/// either side are ignored.
+pub fn version_changes(
+    old: &[ProviderPayload],
+    new: &[ProviderPayload],
+) -> Vec<(String, String, String)> {
+    new.iter()
+        .filter_map(|payload| {
+            let new_version = payload.version.as_ref()?;
+            let old_version = old
+                .iter()
+                .find(|previous| previous.provider == payload.provider)?
+                .version
+                .as_ref()?;
+            (old_version != new_version).then(|| {
+                (
+                    payload.provider.clone(),
+                    old_version.clone(),
+                    new_version.clone(),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Flag `rows`, and the accounts folded into them, as read from the cache.
+pub fn mark_from_cache(rows: &mut [ProviderRow]) {
+    for row in rows {
+        row.from_cache = true;
+        mark_from_cache(&mut row.accounts);
+    }
+}
+
+/// Carry forward rows from the previous refresh for providers that failed
+/// this cycle, marking them stale so the last known numbers stay visible.
+/// Failed providers with no previous row get a `ProviderRow::failed`
+/// placeholder, so the row set doesn't change when a fetch starts failing.
+pub fn carry_forward_stale_rows(
+    rows: &mut Vec<ProviderRow>,
+    previous: &[ProviderRow],
+    errors: &[ProviderFetchError],
+) {
+    for error in errors {
+        if rows.iter().any(|row| row.name == error.provider) {
+            continue;
+        }
+        // Placeholders from an earlier failure have nothing worth carrying
+        let old = previous.iter().find(|row| {
+            row.name == error.provider
+                && (row.session_used.is_some()
+                    || row.weekly_used.is_some()
+                    || row.credits_remaining.is_some())
+        });
+        if let Some(old) = old {
+            let mut stale = old.clone();
+            if !stale.is_stale {
+                stale.is_stale = true;
+                stale.stale_since = Some(old.updated.clone());
+            }
+            rows.push(stale);
+        } else {
+            rows.push(ProviderRow::failed(error));
+        }
+    }
+}
+
+// ============================================================================
+// Config Loading
+// ============================================================================
+
+/// Load the config, applying the profile named by `TOKENGAUGE_PROFILE`, if any.
+pub fn load_config(path: Option<PathBuf>) -> Result<TokenGaugeConfig> {
+    load_config_with_profile(path, env_profile().as_deref())
+}
+
+/// Settings given on the command line, which win over the config file.
+/// `None` or an empty list leaves the config's value alone.
+#[derive(Debug, Clone, Default)]
+pub struct CliOverrides {
+    /// Fetch exactly these providers, replacing `[providers]`; see
+    /// `providers_from_names`
+    pub enable: Option<Vec<EnabledProvider>>,
+    pub refresh_secs: Option<u64>,
+    pub timeout_secs: Option<u64>,
+    pub window: Option<WaybarWindow>,
+    /// Only fetch these providers, out of the ones the config enables
+    pub providers: Vec<String>,
+}
+
+/// The effective config: `config` with every set field of `overrides`
+/// applied. Both binaries go through this so precedence is the same.
+pub fn apply_overrides(mut config: TokenGaugeConfig, overrides: &CliOverrides) -> TokenGaugeConfig {
+    if let Some(refresh_secs) = overrides.refresh_secs {
+        config.refresh_secs = refresh_secs;
+    }
+    if let Some(timeout_secs) = overrides.timeout_secs {
+        config.timeout_secs = timeout_secs;
+    }
+    if let Some(window) = &overrides.window {
+        config.waybar.window = window.clone();
+    }
+    if let Some(enable) = &overrides.enable {
+        config.providers = ProvidersConfig {
+            source: std::mem::take(&mut config.providers.source),
+            ..ProvidersConfig::from_enabled(enable)
+        };
+        // A one-off provider set gets its own cache, so it neither shows nor
+        // overwrites the usage cached for the configured providers
+        config.cache_file = scoped_cache_file(&config.cache_file, enable);
+    }
+    if !overrides.providers.is_empty() {
+        config.providers.retain(&overrides.providers);
+    }
+    config
+}
+
+/// `cache_file` with the provider names added to its stem, e.g.
+/// `usage-claude+zai.json`.
+fn scoped_cache_file(cache_file: &Path, providers: &[EnabledProvider]) -> PathBuf {
+    let names: Vec<&str> = providers
+        .iter()
+        .map(|provider| provider.name.as_str())
+        .collect();
+    let stem = cache_file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let name = match cache_file.extension() {
+        Some(extension) => format!("{stem}-{}.{}", names.join("+"), extension.to_string_lossy()),
+        None => format!("{stem}-{}", names.join("+")),
+    };
+    cache_file.with_file_name(name)
+}
+
+/// Providers named on the command line, built from the registry without
+/// looking at `[providers]`. API providers take their key from their env
+/// var via `lookup`; an unknown name or a missing key is an error.
+pub fn providers_from_names(
+    names: &[String],
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<EnabledProvider>> {
+    let mut providers: Vec<EnabledProvider> = Vec::new();
+    for name in names {
+        let info = get_provider_info(name).ok_or_else(|| {
+            let known: Vec<&str> = PROVIDERS.iter().map(|info| info.name).collect();
+            anyhow!(
+                "unknown provider: {name} (expected one of {})",
+                known.join(", ")
+            )
+        })?;
+        if providers.iter().any(|provider| provider.name == info.name) {
+            continue;
+        }
+        let api_key = match (info.provider_type, info.env_var) {
+            (ProviderType::Api, Some(env_var)) => {
+                let key = lookup(env_var).filter(|key| !key.trim().is_empty());
+                Some(key.ok_or_else(|| {
+                    anyhow!("{name} is an API provider: set {env_var} to fetch it")
+                })?)
+            }
+            _ => None,
+        };
+        providers.push(EnabledProvider::from_info(info, api_key));
+    }
+    Ok(providers)
+}
+
+/// Load the config with the `[profiles.<name>]` table for `profile`
+/// overlaid on the base settings.
+pub fn load_config_with_profile(
+    path: Option<PathBuf>,
+    profile: Option<&str>,
+) -> Result<TokenGaugeConfig> {
+    let path = path.unwrap_or_else(default_config_path);
+
+    let file = fs::File::open(&path)
+        .with_context(|| format!("failed to read config at {}", path.display()))?;
+    load_config_from_reader(file, &path, profile)
+}
+
+/// Profile selected through `TOKENGAUGE_PROFILE`; empty means none.
+fn env_profile() -> Option<String> {
+    std::env::var("TOKENGAUGE_PROFILE")
+        .ok()
+        .filter(|profile| !profile.is_empty())
+}
+
+/// Config path meaning "read the config from stdin", as in `--config -`.
+pub const STDIN_CONFIG: &str = "-";
+
+/// Load a config from `reader`, such as stdin. `origin` names the source in
+/// error messages.
+pub fn load_config_from_reader(
+    mut reader: impl Read,
+    origin: &Path,
+    profile: Option<&str>,
+) -> Result<TokenGaugeConfig> {
+    let mut contents = String::new();
+    reader
+        .read_to_string(&mut contents)
+        .with_context(|| format!("failed to read config at {}", origin.display()))?;
+    let table: toml::Table = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse config at {}", origin.display()))?;
+    config_from_table(table, origin, profile)
+}
+
+/// Apply `profile`, deserialize and finish a parsed config table.
+fn config_from_table(
+    mut table: toml::Table,
+    origin: &Path,
+    profile: Option<&str>,
+) -> Result<TokenGaugeConfig> {
+    let profile_sets_cache = apply_profile(&mut table, profile)
+        .with_context(|| format!("invalid config at {}", origin.display()))?;
+    let mut config: TokenGaugeConfig = toml::Value::Table(table)
+        .try_into()
+        .with_context(|| format!("failed to parse config at {}", origin.display()))?;
+    // Keep each profile's data apart unless the profile picks its own file
+    if !profile_sets_cache {
+        config.cache_file = resolve_cache_file(&config, profile);
+    }
+    config.profile = profile.map(str::to_string);
+    finish_config(config, origin)
+}
+
+/// Cache path for `profile`: the configured file with the profile name
+/// appended to its stem, e.g. `usage.json` becomes `usage-work.json`, so
+/// switching profiles never shows another profile's data. Without a profile
+/// the configured path is returned unchanged.
+pub fn resolve_cache_file(config: &TokenGaugeConfig, profile: Option<&str>) -> PathBuf {
+    let Some(profile) = profile else {
+        return config.cache_file.clone();
+    };
+    let profile: String = profile
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let path = &config.cache_file;
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "tokengauge-usage".to_string());
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}-{profile}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{profile}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Remove the `[profiles]` table and overlay the selected profile's settings
+/// onto the base, so e.g. `[profiles.work.providers]` replaces only the
+/// providers it names. Returns whether the profile sets its own `cache_file`.
+fn apply_profile(table: &mut toml::Table, profile: Option<&str>) -> Result<bool> {
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(anyhow!("profiles must be a table of [profiles.<name>]")),
+        None => toml::Table::new(),
+    };
+    let Some(name) = profile else {
+        return Ok(false);
+    };
+    match profiles.get(name) {
+        Some(toml::Value::Table(overlay)) => {
+            merge_toml(table, overlay.clone());
+            Ok(overlay.contains_key("cache_file"))
+        }
+        Some(_) => Err(anyhow!("profile {name:?} must be a table")),
+        None if profiles.is_empty() => Err(anyhow!(
+            "unknown profile {name:?}: no [profiles.<name>] sections are defined"
+        )),
+        None => Err(anyhow!(
+            "unknown profile {name:?} (available: {})",
+            profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+        )),
+    }
+}
+
+/// Load several config files, each layered over the ones before it, e.g. a
+/// system-wide `/etc/tokengauge/config.toml` followed by the user's config.
+/// Later files win for plain values; tables such as `[providers]` are merged
+/// key by key.
+pub fn load_config_layered(paths: &[PathBuf]) -> Result<TokenGaugeConfig> {
+    let Some(last) = paths.last() else {
+        return Err(anyhow!("no config files to load"));
+    };
+    let mut merged = toml::Table::new();
+    for path in paths {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config at {}", path.display()))?;
+        let layer: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config at {}", path.display()))?;
+        merge_toml(&mut merged, layer);
+    }
+    config_from_table(merged, last, env_profile().as_deref())
+}
+
+/// Overlay `layer` onto `base`, recursing into tables present in both.
+fn merge_toml(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(layer_table)) => {
+                merge_toml(base_table, layer_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Apply `TOKENGAUGE_*` overrides on top of values read from the file, so
+/// the precedence is environment, then file, then built-in defaults.
+/// `lookup` returns the value of an environment variable, if set.
+pub fn apply_env_overrides(
+    config: &mut TokenGaugeConfig,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    let parse_secs = |name: &str, value: String| {
+        value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("invalid {name} value {value:?}: expected whole seconds"))
+    };
+    if let Some(value) = lookup("TOKENGAUGE_REFRESH_SECS") {
+        config.refresh_secs = parse_secs("TOKENGAUGE_REFRESH_SECS", value)?;
+    }
+    if let Some(value) = lookup("TOKENGAUGE_TIMEOUT_SECS") {
+        config.timeout_secs = parse_secs("TOKENGAUGE_TIMEOUT_SECS", value)?;
+    }
+    if let Some(value) = lookup("TOKENGAUGE_CODEXBAR_BIN") {
+        config.codexbar_bin = value;
+    }
+    if let Some(value) = lookup("TOKENGAUGE_CACHE_FILE") {
+        config.cache_file = PathBuf::from(value);
+    }
+    if let Some(value) = lookup("TOKENGAUGE_WINDOW") {
+        config.waybar.window = value
+            .trim()
+            .parse()
+            .map_err(|error| anyhow!("invalid TOKENGAUGE_WINDOW: {error}"))?;
+    }
+    Ok(())
+}
+
+/// Apply environment overrides, fill in defaults for empty values and
+/// validate a freshly parsed config.
+fn finish_config(mut config: TokenGaugeConfig, path: &Path) -> Result<TokenGaugeConfig> {
+    apply_env_overrides(&mut config, |name| std::env::var(name).ok())?;
+
+    // Apply defaults for empty values
+    if config.codexbar_bin.is_empty() {
+        config.codexbar_bin = "codexbar".to_string();
+    }
+    if config.cache_file.as_os_str().is_empty() {
+        config.cache_file = PathBuf::from("/tmp/tokengauge-usage.json");
+    }
+    if config.history.file.as_os_str().is_empty() {
+        config.history.file = HistoryConfig::default().file;
+    }
+    if config.tui.columns.is_empty() {
+        config.tui.columns = TuiColumn::ALL.to_vec();
+    }
+    config
+        .codexbar
+        .validate()
+        .and_then(|_| config.waybar.validate())
+        .and_then(|_| config.providers.validate_sources())
+        .with_context(|| format!("invalid config at {}", path.display()))?;
+
+    Ok(config)
+}
+
+/// `$XDG_CONFIG_HOME/tokengauge/config.toml`, falling back to
+/// `$HOME/.config` and then the current directory.
+pub fn default_config_path() -> PathBuf {
+    xdg_path(|name| std::env::var_os(name), "XDG_CONFIG_HOME", ".config").join("config.toml")
+}
+
+/// `$XDG_CACHE_HOME/tokengauge/usage.json`, falling back to `$HOME/.cache`
+/// and then the current directory. A suggestion for `cache_file`, which
+/// still defaults to `/tmp`.
+pub fn default_cache_path() -> PathBuf {
+    xdg_path(|name| std::env::var_os(name), "XDG_CACHE_HOME", ".cache").join("usage.json")
+}
+
+/// TokenGauge's directory under the XDG base directory `xdg_var`, else
+/// under `$HOME/<home_subdir>`, else `./tokengauge`. Empty or relative
+/// values are ignored, as the XDG spec requires. Reads the environment
+/// through `lookup`.
+fn xdg_path(
+    lookup: impl Fn(&str) -> Option<std::ffi::OsString>,
+    xdg_var: &str,
+    home_subdir: &str,
+) -> PathBuf {
+    let absolute = |name: &str| {
+        lookup(name)
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+    };
+    let base = absolute(xdg_var)
+        .or_else(|| absolute("HOME").map(|home| home.join(home_subdir)))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base.join("tokengauge")
+}
+
+/// The config path a binary uses: `--config` if given, else the default.
+pub fn resolve_config_path(path: Option<PathBuf>) -> PathBuf {
+    path.unwrap_or_else(default_config_path)
+}
+
+// ============================================================================
+// Fetching Logic
+// ============================================================================
+
+/// Inject configured proxy variables into the codexbar environment.
+fn apply_network_env(command: &mut Command, network: &NetworkConfig) {
+    for (name, value) in network.env_vars() {
+        command.env(name, value);
+    }
+}
+
+/// Fetch a single provider using codexbar.
+pub fn fetch_single_provider(
+    config: &TokenGaugeConfig,
+    provider: &EnabledProvider,
+    timeout: Duration,
+) -> Result<Vec<ProviderPayload>> {
+    fetch_provider_cancellable(config, provider, timeout, &AtomicBool::new(false))
+        .map(|parsed| parsed.payloads)
+}
+
+/// Fetch a single provider, killing codexbar early if `cancel` is set.
+fn fetch_provider_cancellable(
+    config: &TokenGaugeConfig,
+    provider: &EnabledProvider,
+    timeout: Duration,
+    cancel: &AtomicBool,
+) -> Result<ParsedOutput> {
+    let output = run_codexbar(config, provider, timeout, cancel)?;
+    let mut parsed = parse_output(&output, &config.codexbar)?;
+    for payload in &mut parsed.payloads {
+        if payload.provider.is_empty() {
+            payload.provider = provider.name.clone();
+        }
+    }
+    Ok(parsed)
+}
+
+/// Run codexbar for one provider and return its raw output, without
+/// interpreting it. Useful for diagnosing odd payloads.
+pub fn fetch_single_provider_raw(
+    config: &TokenGaugeConfig,
+    provider: &EnabledProvider,
+    timeout: Duration,
+) -> Result<Output> {
+    run_codexbar(config, provider, timeout, &AtomicBool::new(false))
+}
+
+/// The `--source` value codexbar expects for `provider`: the configured
+/// override, else the one implied by its type.
+fn source_arg(provider: &EnabledProvider) -> &'static str {
+    if let Some(source) = provider.source {
+        return source.as_arg();
+    }
+    match provider.provider_type {
+        ProviderType::OAuth => "oauth",
+        ProviderType::Api => "api",
+    }
+}
+
+/// Build the codexbar command for `provider`: program, arguments, API key
+/// and proxy environment. Nothing is spawned.
+pub fn build_codexbar_command(config: &TokenGaugeConfig, provider: &EnabledProvider) -> Command {
+    let mut command = Command::new(&config.codexbar_bin);
+    command.args(
+        config
+            .codexbar
+            .render_args(&provider.name, source_arg(provider)),
+    );
+
+    // Set API key environment variable if needed
+    if let (Some(api_key), Some(env_var)) = (&provider.api_key, provider.env_var) {
+        command.env(env_var, api_key);
+    }
+    apply_network_env(&mut command, &config.network);
+    command
+}
+
+/// The codexbar command line that would run for `provider`, shell-quoted,
+/// with environment variable values redacted. Nothing is spawned.
+pub fn build_command_preview(config: &TokenGaugeConfig, provider: &EnabledProvider) -> String {
+    let command = build_codexbar_command(config, provider);
+    let env = command
+        .get_envs()
+        .map(|(name, _)| format!("{}=<redacted>", name.to_string_lossy()));
+    let program = std::iter::once(command.get_program()).chain(command.get_args());
+    env.chain(program.map(|word| shell_word(&word.to_string_lossy())))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quote `word` for display if a shell would split or expand it.
+fn shell_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Build one codexbar command covering all of `providers`. `{source}` is
+/// their shared source, or "auto" when they differ; every API key is set.
+fn build_batch_command(config: &TokenGaugeConfig, providers: &[EnabledProvider]) -> Command {
+    let names: Vec<&str> = providers.iter().map(|p| p.name.as_str()).collect();
+    let source = match providers.iter().map(source_arg).collect::<BTreeSet<_>>() {
+        sources if sources.len() == 1 => sources.into_iter().next().unwrap_or("auto"),
+        _ => "auto",
+    };
+    let mut command = Command::new(&config.codexbar_bin);
+    command.args(config.codexbar.render_args(&names.join(","), source));
+    for provider in providers {
+        if let (Some(api_key), Some(env_var)) = (&provider.api_key, provider.env_var) {
+            command.env(env_var, api_key);
+        }
+    }
+    apply_network_env(&mut command, &config.network);
+    command
+}
+
+/// Oldest codexbar whose `--provider` accepts a comma-separated list.
+const BATCH_MIN_VERSION: (u32, u32, u32) = (0, 18, 0);
+
+/// Batch support per codexbar binary, learned by probing. Clones share what
+/// they learn, so a caller that keeps one across refreshes probes each
+/// binary once.
+#[derive(Debug, Clone, Default)]
+pub struct BatchSupport {
+    known: Arc<Mutex<BTreeMap<String, bool>>>,
+}
+
+impl BatchSupport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `config.codexbar_bin` is worth a batch call, probing it the
+    /// first time it is seen.
+    fn supported(&self, config: &TokenGaugeConfig) -> bool {
+        let known = self
+            .known
+            .lock()
+            .ok()
+            .and_then(|known| known.get(&config.codexbar_bin).copied());
+        known.unwrap_or_else(|| {
+            let supported = probe_batch_support(config);
+            self.record(&config.codexbar_bin, supported);
+            supported
+        })
+    }
+
+    fn record(&self, codexbar_bin: &str, supported: bool) {
+        if let Ok(mut known) = self.known.lock() {
+            known.insert(codexbar_bin.to_string(), supported);
+        }
+    }
+}
+
+/// Ask codexbar for its version: releases older than `BATCH_MIN_VERSION`
+/// can't batch. An unknown version gets the benefit of the doubt; if the
+/// batch call is then rejected, that settles it.
+fn probe_batch_support(config: &TokenGaugeConfig) -> bool {
+    codexbar_version(config)
+        .and_then(|version| parse_version(&version))
+        .is_none_or(|version| version >= BATCH_MIN_VERSION)
+}
+
+/// The first `major.minor[.patch]` number in `text`, e.g. "CodexBar 0.18.0".
+fn parse_version(text: &str) -> Option<(u32, u32, u32)> {
+    text.split_whitespace().find_map(|word| {
+        let mut parts = word.trim_start_matches('v').split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let patch = match parts.next() {
+            Some(patch) => {
+                let digits: String = patch.chars().take_while(char::is_ascii_digit).collect();
+                digits.parse().ok()?
+            }
+            None => 0,
+        };
+        Some((major, minor, patch))
+    })
+}
+
+/// Whether a failed codexbar run rejected its arguments, rather than failing
+/// along the way (timeouts, crashes, network trouble).
+fn is_usage_error(output: &Output) -> bool {
+    if output.status.success() {
+        return false;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
+    output.status.code() == Some(2)
+        || ["usage:", "unknown provider", "invalid value"]
+            .iter()
+            .any(|needle| stderr.contains(needle))
+}
+
+/// Try fetching `providers` in one codexbar call. Returns each provider's
+/// payloads by index, `None` for any the response didn't cover. Only a
+/// codexbar that rejects the provider list is marked unsupported in
+/// `support`; any other failure leaves everything to per-provider fetches
+/// for this refresh alone.
+fn fetch_batch(
+    config: &TokenGaugeConfig,
+    providers: &[EnabledProvider],
+    timeout: Duration,
+    support: &BatchSupport,
+) -> Vec<Option<Vec<ProviderPayload>>> {
+    let uncovered = || vec![None; providers.len()];
+    if providers.len() < 2 || !support.supported(config) {
+        return uncovered();
+    }
+
+    let mut command = build_batch_command(config, providers);
+    let cancel = AtomicBool::new(false);
+    let Ok(output) = run_with_timeout(&mut command, timeout, &cancel, "batch") else {
+        return uncovered();
+    };
+    if is_usage_error(&output) {
+        support.record(&config.codexbar_bin, false);
+        return uncovered();
+    }
+    match parse_output(&output, &config.codexbar) {
+        // A cut-off response may be missing providers; fetch them one by one
+        Ok(parsed) if !parsed.truncated => {
+            split_batch_payloads(parsed.payloads, providers).unwrap_or_else(uncovered)
+        }
+        _ => uncovered(),
+    }
+}
+
+/// Group a batch response by provider, in `providers` order. `None` when a
+/// payload names no provider (or one that wasn't asked for), since its
+/// owner can't be told.
+fn split_batch_payloads(
+    payloads: Vec<ProviderPayload>,
+    providers: &[EnabledProvider],
+) -> Option<Vec<Option<Vec<ProviderPayload>>>> {
+    let mut grouped: Vec<Option<Vec<ProviderPayload>>> = vec![None; providers.len()];
+    for payload in payloads {
+        let index = providers.iter().position(|p| p.name == payload.provider)?;
+        grouped[index].get_or_insert_with(Vec::new).push(payload);
+    }
+    Some(grouped)
+}
+
+fn run_codexbar(
+    config: &TokenGaugeConfig,
+    provider: &EnabledProvider,
+    timeout: Duration,
+    cancel: &AtomicBool,
+) -> Result<Output> {
+    let mut command = build_codexbar_command(config, provider);
+    run_with_timeout(&mut command, timeout, cancel, &provider.name)
+}
+
+/// Version printed by `codexbar --version`, or `None` if codexbar is missing
+/// or fails.
+pub fn codexbar_version(config: &TokenGaugeConfig) -> Option<String> {
+    let mut command = Command::new(&config.codexbar_bin);
+    command.arg("--version");
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let output =
+        run_with_timeout(&mut command, timeout, &AtomicBool::new(false), "--version").ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!version.is_empty()).then_some(version)
+}
+
+/// TokenGauge and codexbar versions plus the config path, as one JSON object
+/// for bug reports. `tokengauge` is the calling binary's version.
+pub fn version_report(
+    tokengauge: &str,
+    config_path: &Path,
+    config: &TokenGaugeConfig,
+) -> serde_json::Value {
+    serde_json::json!({
+        "tokengauge": tokengauge,
+        "codexbar": codexbar_version(config),
+        "config": config_path.display().to_string(),
+    })
+}
+
+/// Write `value` as a single line of JSON. Every JSON output of both binaries
+/// goes through here, so labels and messages are always escaped by serde
+/// rather than interpolated, and a stray newline can't split a waybar update
+/// in two.
+pub fn write_json<T: Serialize + ?Sized>(out: &mut dyn Write, value: &T) -> Result<()> {
+    let line = serde_json::to_string(value)?;
+    writeln!(out, "{line}")?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Whether `error` is a write to a pipe whose reader went away, e.g. output
+/// piped into `head`. Callers skip the output rather than fail.
+pub fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|error| error.kind() == std::io::ErrorKind::BrokenPipe)
+}
+
+/// Interpret codexbar output the same way a normal fetch does.
+pub fn parse_provider_output(
+    output: &Output,
+    codexbar: &CodexbarConfig,
+) -> Result<Vec<ProviderPayload>> {
+    parse_output(output, codexbar).map(|parsed| parsed.payloads)
+}
+
+/// Payloads parsed from codexbar output.
+#[derive(Debug)]
+struct ParsedOutput {
+    payloads: Vec<ProviderPayload>,
+    /// The output was cut off mid-write and `payloads` were salvaged from it
+    truncated: bool,
+}
+
+fn parse_output(output: &Output, codexbar: &CodexbarConfig) -> Result<ParsedOutput> {
+    let fields = &codexbar.fields;
+    if !output.status.success() {
+        // Try to parse JSON error from stdout first, then stderr
+        if let Ok(payloads) = parse_output_streams(&output.stdout, &output.stderr, fields) {
+            // Codexbar returns non-zero but still outputs JSON with error info
+            return Ok(payloads);
+        }
+
+        let detail = describe_output(&output.stderr)
+            .or_else(|| describe_output(&output.stdout))
+            .unwrap_or_else(|| "no error output".to_string());
+        return Err(anyhow!("codexbar failed ({}) - {}", output.status, detail));
+    }
+
+    parse_output_streams(&output.stdout, &output.stderr, fields)
+}
+
+const NON_TEXT_OUTPUT: &str = "codexbar produced non-text output";
+
+/// Describe a codexbar output stream for an error message. Returns `None`
+/// for empty output; binary output is summarized rather than mangled.
+fn describe_output(bytes: &[u8]) -> Option<String> {
+    let is_text = std::str::from_utf8(bytes).is_ok_and(|text| {
+        !text
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+    });
+    if !is_text {
+        return Some(format!("{NON_TEXT_OUTPUT} ({} bytes)", bytes.len()));
+    }
+    let text = String::from_utf8_lossy(bytes).trim().to_string();
+    (!text.is_empty()).then_some(text)
+}
+
+/// Parse stdout, falling back to stderr for codexbar builds that log the
+/// JSON there. The stdout error is kept when neither stream parses.
+fn parse_output_streams(
+    stdout: &[u8],
+    stderr: &[u8],
+    fields: &BTreeMap<String, String>,
+) -> Result<ParsedOutput> {
+    parse_mapped_bytes(stdout, fields)
+        .or_else(|error| parse_mapped_bytes(stderr, fields).map_err(|_| error))
+}
+
+/// Run a command to completion, killing it if it outlives `timeout` or
+/// `cancel` is set.
+fn run_with_timeout(
+    command: &mut Command,
+    timeout: Duration,
+    cancel: &AtomicBool,
+    provider_name: &str,
+) -> Result<Output> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to spawn codexbar for {}", provider_name))?;
+
+    // Drain both pipes in the background so a chatty child can't block
+    let stdout = spawn_pipe_reader(child.stdout.take());
+    let stderr = spawn_pipe_reader(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| format!("failed to run codexbar for {}", provider_name))?
+        {
+            break status;
+        }
+        let cancelled = cancel.load(Ordering::Relaxed);
+        if cancelled || started.elapsed() >= timeout {
+            // Readers are left to finish on their own; a grandchild may
+            // still hold the pipes open
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(if cancelled {
+                anyhow!("timeout: overall fetch deadline exceeded")
+            } else {
+                anyhow!("timeout after {:?}", timeout)
+            });
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn spawn_pipe_reader<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+/// Fetch all enabled providers, in parallel or one at a time per
+/// `fetch.mode`.
+///
+/// Waits at most one provider timeout per round plus a second of grace
+/// overall; see [`fetch_all_providers_with_deadline`].
+pub fn fetch_all_providers(config: &TokenGaugeConfig) -> FetchResult {
+    let deadline = fetch_deadline(config, config.providers.enabled_providers().len());
+    fetch_all_providers_with_deadline(config, deadline)
+}
+
+/// Overall time allowed for fetching `providers`: one timeout when they
+/// run together, one each in sequential mode, plus a second of grace.
+fn fetch_deadline(config: &TokenGaugeConfig, providers: usize) -> Duration {
+    let rounds = match config.fetch.mode {
+        FetchMode::Parallel => 1,
+        FetchMode::Sequential => providers.max(1) as u32,
+    };
+    Duration::from_secs(config.timeout_secs) * rounds + Duration::from_secs(1)
+}
+
+/// Fetch all enabled providers in parallel, giving up on any still running
+/// once `deadline` has passed. Outstanding codexbar processes are killed and
+/// reported as timed out, and whatever finished in time is returned.
+///
+/// With `codexbar.batch`, codexbar's batch support is probed afresh on every
+/// call; use [`fetch_with_breakers`] to keep what was learned.
+pub fn fetch_all_providers_with_deadline(
+    config: &TokenGaugeConfig,
+    deadline: Duration,
+) -> FetchResult {
+    fetch_providers(
+        config,
+        config.providers.enabled_providers(),
+        deadline,
+        &BatchSupport::new(),
+    )
+}
+
+/// Fetch `enabled`. A batch call, when configured, gets one provider
+/// timeout of its own; `deadline` covers the per-provider fetches after it.
+fn fetch_providers(
+    config: &TokenGaugeConfig,
+    enabled: Vec<EnabledProvider>,
+    deadline: Duration,
+    batch: &BatchSupport,
+) -> FetchResult {
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    if enabled.is_empty() {
+        return FetchResult {
+            payloads: Vec::new(),
+            errors: Vec::new(),
+        };
+    }
+
+    let mut results: Vec<Option<Result<ParsedOutput>>> = if config.codexbar.batch {
+        fetch_batch(config, &enabled, timeout, batch)
+            .into_iter()
+            .map(|payloads| {
+                payloads.map(|payloads| {
+                    Ok(ParsedOutput {
+                        payloads,
+                        truncated: false,
+                    })
+                })
+            })
+            .collect()
+    } else {
+        enabled.iter().map(|_| None).collect()
+    };
+
+    // Fetch each provider the batch call didn't cover
+    let started = Instant::now();
+    let config = Arc::new(config.clone());
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let names: Vec<String> = enabled.iter().map(|p| p.name.clone()).collect();
+    let pending: Vec<(usize, EnabledProvider)> = enabled
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| results[*index].is_none())
+        .collect();
+    let worker_cancel = Arc::clone(&cancel);
+    let fetch = move |config: &TokenGaugeConfig, (index, provider): (usize, EnabledProvider)| {
+        let result = fetch_provider_cancellable(config, &provider, timeout, &worker_cancel);
+        let _ = tx.send((index, result));
+    };
+    match config.fetch.mode {
+        FetchMode::Parallel => {
+            for job in pending {
+                let config = Arc::clone(&config);
+                let fetch = fetch.clone();
+                thread::spawn(move || fetch(&config, job));
+            }
+            // Threads hold the only senders now
+            drop(fetch);
+        }
+        FetchMode::Sequential => {
+            // A single worker, so at most one codexbar is alive at a time
+            let config = Arc::clone(&config);
+            thread::spawn(move || {
+                for job in pending {
+                    fetch(&config, job);
+                }
+            });
+        }
+    }
+
+    // Collect results until all arrive or the deadline passes
+    let mut deadline_hit = false;
+    while results.iter().any(Option::is_none) {
+        let remaining = deadline.saturating_sub(started.elapsed());
+        match rx.recv_timeout(remaining) {
+            Ok((index, result)) => results[index] = Some(result),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                deadline_hit = true;
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    // Stop any stragglers
+    cancel.store(true, Ordering::Relaxed);
+
+    let mut payloads = Vec::new();
+    let mut errors = Vec::new();
+    let new_error = |provider: String, raw: &str| {
+        ProviderFetchError::with_raw_limit(provider, raw, config.cache.max_raw_error_bytes)
+    };
+
+    for (provider_name, result) in names.into_iter().zip(results) {
+        match result {
+            Some(Ok(parsed)) => {
+                if parsed.truncated {
+                    let mut error = new_error(
+                        provider_name.clone(),
+                        &format!(
+                            "codexbar output was truncated, recovered {} payload(s)",
+                            parsed.payloads.len()
+                        ),
+                    );
+                    error.kind = FetchErrorKind::Truncated;
+                    errors.push(error);
+                }
+                // Filter out payloads with errors and add successful ones
+                for payload in parsed.payloads {
+                    if payload.has_error() {
+                        let msg = payload
+                            .error
+                            .as_ref()
+                            .and_then(|e| e.message.clone())
+                            .unwrap_or_else(|| "Unknown error".to_string());
+                        errors.push(new_error(provider_name.clone(), &msg));
+                    } else {
+                        payloads.push(payload);
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                errors.push(new_error(provider_name, &e.to_string()));
+            }
+            None if deadline_hit => {
+                let raw = format!("timeout: overall fetch deadline of {:?} exceeded", deadline);
+                errors.push(new_error(provider_name, &raw));
+            }
+            None => {
+                // Thread panicked - shouldn't happen normally
+                errors.push(ProviderFetchError {
+                    provider: provider_name,
+                    message: "thread panicked".to_string(),
+                    raw: "thread panicked".to_string(),
+                    kind: FetchErrorKind::Other,
+                    first_seen_at: None,
+                    last_seen_at: None,
+                });
+            }
+        }
+    }
+
+    FetchResult { payloads, errors }
+}
+
+// ============================================================================
+// Payload Processing
+// ============================================================================
+
+pub fn parse_payload(value: serde_json::Value) -> Result<Vec<ProviderPayload>> {
+    if value.is_array() {
+        serde_json::from_value(value).context("failed to parse provider payload list")
+    } else {
+        let payload: ProviderPayload =
+            serde_json::from_value(value).context("failed to parse provider payload")?;
+        Ok(vec![payload])
+    }
+}
+
+/// Rename object keys at any depth according to `fields` (incoming key to
+/// standard key), so forks with different key names parse normally.
+pub fn remap_fields(value: &mut serde_json::Value, fields: &BTreeMap<String, String>) {
+    match value {
+        serde_json::Value::Object(object) => {
+            if !fields.is_empty() {
+                let entries = std::mem::take(object);
+                for (key, value) in entries {
+                    let key = fields.get(&key).cloned().unwrap_or(key);
+                    object.insert(key, value);
+                }
+            }
+            object
+                .values_mut()
+                .for_each(|value| remap_fields(value, fields));
+        }
+        serde_json::Value::Array(items) => {
+            items.iter_mut().for_each(|item| remap_fields(item, fields));
+        }
+        _ => {}
+    }
+}
+
+pub fn parse_payload_bytes(bytes: &[u8]) -> Result<Vec<ProviderPayload>> {
+    parse_mapped_bytes(bytes, &BTreeMap::new()).map(|parsed| parsed.payloads)
+}
+
+/// Parse codexbar output after applying `[codexbar.fields]` renames.
+fn parse_mapped_bytes(bytes: &[u8], fields: &BTreeMap<String, String>) -> Result<ParsedOutput> {
+    let mut value: serde_json::Value = match serde_json::from_slice(bytes) {
+        Ok(value) => value,
+        Err(error) => {
+            // Newline-delimited JSON: one payload (or array) per line
+            if let Some(payloads) = parse_ndjson(bytes, fields) {
+                return Ok(ParsedOutput {
+                    payloads,
+                    truncated: false,
+                });
+            }
+            let payloads = parse_truncated(bytes, fields)
+                .ok_or(error)
+                .context("codexbar output was not JSON")?;
+            return Ok(ParsedOutput {
+                payloads,
+                truncated: true,
+            });
+        }
+    };
+    remap_fields(&mut value, fields);
+    Ok(ParsedOutput {
+        payloads: parse_payload(value)?,
+        truncated: false,
+    })
+}
+
+/// Best-effort parse of output cut off mid-write (e.g. codexbar killed at
+/// the timeout): keeps every complete top-level value, plus the complete
+/// elements of a top-level array that never closed.
+fn parse_truncated(
+    bytes: &[u8],
+    fields: &BTreeMap<String, String>,
+) -> Option<Vec<ProviderPayload>> {
+    let values = complete_json_values(bytes);
+    if values.is_empty() {
+        return None;
+    }
+    let mut payloads = Vec::new();
+    for mut value in values {
+        remap_fields(&mut value, fields);
+        payloads.extend(parse_payload(value).ok()?);
+    }
+    Some(payloads)
+}
+
+/// Complete JSON objects/arrays in `bytes` that a truncation left intact:
+/// every closed top-level value, then, if the last top-level value is an
+/// unclosed array, each closed element inside it. Empty unless the output
+/// really does stop inside a value.
+fn complete_json_values(bytes: &[u8]) -> Vec<serde_json::Value> {
+    // A cut can land inside a multi-byte character
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(error) => std::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or_default(),
+    };
+    let mut complete = Vec::new();
+    let mut elements = Vec::new();
+    // Opening bracket and byte offset of every unclosed value
+    let mut open: Vec<(u8, usize)> = Vec::new();
+    let (mut in_string, mut escaped) = (false, false);
+    for (index, byte) in text.bytes().enumerate() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                if open.is_empty() {
+                    elements.clear();
+                }
+                open.push((byte, index));
+            }
+            b'}' | b']' => {
+                let Some((_, start)) = open.pop() else {
+                    continue;
+                };
+                let span = &text[start..=index];
+                match open.as_slice() {
+                    [] => complete.push(span),
+                    [(b'[', _)] => elements.push(span),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    // Output that ends cleanly but doesn't parse isn't truncated, just bad
+    if open.is_empty() {
+        return Vec::new();
+    }
+    complete.extend(elements);
+    complete
+        .into_iter()
+        .filter_map(|span| serde_json::from_str(span).ok())
+        .collect()
+}
+
+/// Parse newline-delimited JSON, skipping blank lines. Returns `None` unless
+/// there are at least two lines and every one of them parses.
+fn parse_ndjson(bytes: &[u8], fields: &BTreeMap<String, String>) -> Option<Vec<ProviderPayload>> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    let lines: Vec<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    if lines.len() < 2 {
+        return None;
+    }
+
+    let mut payloads = Vec::new();
+    for line in lines {
+        let mut value: serde_json::Value = serde_json::from_str(line).ok()?;
+        remap_fields(&mut value, fields);
+        payloads.extend(parse_payload(value).ok()?);
+    }
+    Some(payloads)
+}
+
+pub fn payload_to_rows(payloads: Vec<ProviderPayload>) -> Vec<ProviderRow> {
+    payloads
+        .into_iter()
+        .filter(|payload| !payload.has_error())
+        .map(provider_to_row)
+        .collect()
+}
+
+/// Rows as the front ends show them: [`payload_to_rows`], with accounts of
+/// the same provider collapsed when `display.aggregate_accounts` is on.
+pub fn display_rows(payloads: Vec<ProviderPayload>, display: &DisplayConfig) -> Vec<ProviderRow> {
+    let rows = payload_to_rows(payloads);
+    if display.aggregate_accounts {
+        aggregate_accounts(rows, display.aggregate_strategy)
+    } else {
+        rows
+    }
+}
+
+/// Remaining credits summed over the API providers in `rows`. OAuth
+/// providers and rows without credits don't count; `None` when nothing
+/// adds up to more than zero.
+pub fn total_credits(rows: &[ProviderRow]) -> Option<f64> {
+    let total: f64 = rows
+        .iter()
+        .filter(|row| {
+            get_provider_info(&row.name).is_some_and(|info| info.provider_type == ProviderType::Api)
+        })
+        .filter_map(|row| row.credits_remaining)
+        .sum();
+    (total > 0.0).then_some(total)
+}
+
+/// Collapse rows sharing a provider into one row labelled e.g.
+/// "Claude (2 accounts)", keeping the originals in `accounts`. Usage is the
+/// max or mean of the accounts that report it, credits are summed, and the
+/// remaining fields come from the first account. Groups keep the order their
+/// provider first appears in; single-account providers are left untouched.
+pub fn aggregate_accounts(
+    rows: Vec<ProviderRow>,
+    strategy: AccountAggregation,
+) -> Vec<ProviderRow> {
+    let mut groups: Vec<Vec<ProviderRow>> = Vec::new();
+    for row in rows {
+        match groups.iter_mut().find(|group| group[0].name == row.name) {
+            Some(group) => group.push(row),
+            None => groups.push(vec![row]),
+        }
+    }
+
+    let combine = |values: Vec<f64>| -> Option<f64> {
+        if values.is_empty() {
+            return None;
+        }
+        Some(match strategy {
+            AccountAggregation::Max => values.iter().copied().fold(f64::MIN, f64::max),
+            AccountAggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+        })
+    };
+
+    groups
+        .into_iter()
+        .map(|mut group| {
+            if group.len() == 1 {
+                return group.remove(0);
+            }
+            let session_percent =
+                combine(group.iter().filter_map(|row| row.session_percent).collect());
+            let weekly_percent =
+                combine(group.iter().filter_map(|row| row.weekly_percent).collect());
+            let remaining: Vec<f64> = group
+                .iter()
+                .filter_map(|row| row.credits_remaining)
+                .collect();
+            let credits_remaining = (!remaining.is_empty()).then(|| remaining.iter().sum());
+            // A combined limit only makes sense when every account has one
+            let credits_total = group
+                .iter()
+                .map(|row| row.credits_total)
+                .sum::<Option<f64>>();
+            let first = &group[0];
+            ProviderRow {
+                provider: format!("{} ({} accounts)", first.provider, group.len()),
+                session_used: session_percent.map(|used| used.round() as u8),
+                session_percent,
+                weekly_used: weekly_percent.map(|used| used.round() as u8),
+                weekly_percent,
+                credits: format_credits(credits_remaining, credits_total),
+                credits_remaining,
+                credits_total,
+                credits_used_percent: credits_used_percent(credits_remaining, credits_total),
+                is_stale: group.iter().all(|row| row.is_stale),
+                accounts: group.clone(),
+                ..first.clone()
+            }
+        })
+        .collect()
+}
+
+/// Clamp a reported percentage to 0..=100, treating NaN as 0.
+fn clamp_percent(percent: f64) -> f64 {
+    if percent.is_nan() {
+        0.0
+    } else {
+        percent.clamp(0.0, 100.0)
+    }
+}
+
+/// A usage window's cadence, derived from its length in minutes rather than
+/// from whether codexbar reported it as the primary or secondary window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowKind {
+    /// Rolling window of whole hours below a day, e.g. 5
+    Hours(u32),
+    Daily,
+    Weekly,
+    Monthly,
+    /// Any other length, in minutes
+    Minutes(u32),
+}
+
+impl WindowKind {
+    /// Short display label, e.g. "5h", "Weekly" or "90m".
+    pub fn label(self) -> String {
+        match self {
+            WindowKind::Hours(hours) => format!("{hours}h"),
+            WindowKind::Daily => "Daily".to_string(),
+            WindowKind::Weekly => "Weekly".to_string(),
+            WindowKind::Monthly => "Monthly".to_string(),
+            WindowKind::Minutes(minutes) => format!("{minutes}m"),
+        }
+    }
+}
+
+/// Classify a window length. Months of 28 to 31 days all count as monthly.
+pub fn window_kind(minutes: u32) -> WindowKind {
+    const DAY: u32 = 24 * 60;
+    match minutes {
+        DAY => WindowKind::Daily,
+        m if m == 7 * DAY => WindowKind::Weekly,
+        m if (28 * DAY..=31 * DAY).contains(&m) && m % DAY == 0 => WindowKind::Monthly,
+        m if m > 0 && m < DAY && m % 60 == 0 => WindowKind::Hours(m / 60),
+        m => WindowKind::Minutes(m),
+    }
+}
+
+/// Label for a window of `minutes`, or `fallback` (e.g. "Session") when
+/// codexbar did not report the length.
+pub fn window_label(minutes: Option<u32>, fallback: &str) -> String {
+    minutes
+        .map(|minutes| window_kind(minutes).label())
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+/// Exact used percentage of a window, clamped to 100.
+pub fn window_percent(window: Option<&UsageWindow>) -> Option<f64> {
+    window
+        .and_then(|window| window.used_percent)
+        .map(clamp_percent)
+}
+
+pub fn format_window(window: Option<UsageWindow>) -> (Option<u8>, Option<u32>, String) {
+    if let Some(window) = window {
+        let used = window_percent(Some(&window)).map(|used| used.round() as u8);
+        let minutes = window.window_minutes;
+        let reset = format_reset_time(window.resets_at.as_deref(), window.reset_description);
+        (used, minutes, reset)
+    } else {
+        (None, None, "—".into())
+    }
+}
+
+/// When `window` resets: its RFC3339 `resets_at`, or else a time parsed
+/// from the human `reset_description` relative to `now`.
+pub fn reset_timestamp(window: &UsageWindow, now: DateTime<Local>) -> Option<DateTime<Utc>> {
+    if let Some(resets_at) = window.resets_at.as_deref()
+        && let Ok(reset) = DateTime::parse_from_rfc3339(resets_at)
+    {
+        return Some(reset.with_timezone(&Utc));
+    }
+    parse_reset_description(window.reset_description.as_deref()?, now)
+}
+
+/// Parse the reset descriptions codexbar produces: relative ones such as
+/// "in 2h 30m" or "3d 4h", and local dates such as "Jan 20 at 12:59PM",
+/// which are taken to be the next such date after `now`.
+fn parse_reset_description(description: &str, now: DateTime<Local>) -> Option<DateTime<Utc>> {
+    let text = description.trim();
+    let relative = text.strip_prefix("in ").unwrap_or(text);
+    let mut total = chrono::Duration::zero();
+    let mut parts = 0;
+    for part in relative.split_whitespace() {
+        // The unit is the last char, which need not be one byte
+        let (split, _) = part.char_indices().next_back()?;
+        let (amount, unit) = part.split_at(split);
+        let Ok(amount) = amount.parse::<i64>() else {
+            parts = 0;
+            break;
+        };
+        total += match unit {
+            "d" => chrono::Duration::days(amount),
+            "h" => chrono::Duration::hours(amount),
+            "m" => chrono::Duration::minutes(amount),
+            _ => {
+                parts = 0;
+                break;
+            }
+        };
+        parts += 1;
+    }
+    if parts > 0 {
+        return Some((now + total).with_timezone(&Utc));
+    }
+
+    let with_year = format!("{} {}", now.year(), text.replace(" at ", " "));
+    let naive = NaiveDateTime::parse_from_str(&with_year, "%Y %b %d %I:%M%p").ok()?;
+    let mut reset = Local.from_local_datetime(&naive).earliest()?;
+    if reset < now - chrono::Duration::days(1) {
+        // "Jan 2" seen in late December is next year's
+        let next_year = naive.with_year(now.year() + 1)?;
+        reset = Local.from_local_datetime(&next_year).earliest()?;
+    }
+    Some(reset.with_timezone(&Utc))
+}
+
+/// Format reset time as relative duration (e.g., "in 2h 30m") if possible,
+/// otherwise fall back to the description (e.g., "Jan 22 at 5:59PM").
+fn format_reset_time(resets_at: Option<&str>, description: Option<String>) -> String {
+    if let Some(resets_at) = resets_at
+        && let Ok(reset_time) = DateTime::parse_from_rfc3339(resets_at)
+    {
+        let now = Utc::now();
+        let reset_utc = reset_time.with_timezone(&Utc);
+        let duration = reset_utc.signed_duration_since(now);
+
+        if duration.num_seconds() > 0 {
+            let total_minutes = duration.num_minutes();
+            let hours = total_minutes / 60;
+            let mins = total_minutes % 60;
+
+            return if hours > 0 {
+                format!("in {}h {}m", hours, mins)
+            } else {
+                format!("in {}m", mins)
+            };
+        }
+    }
+    // Fall back to description if we can't compute relative time
+    description.unwrap_or_else(|| "—".to_string())
+}
+
+pub fn format_updated(value: Option<String>) -> String {
+    let Some(value) = value else {
+        return "—".to_string();
+    };
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(&value) {
+        let local = timestamp.with_timezone(&Local);
+        return local.format("%H:%M").to_string();
+    }
+    if let Some((_, time_part)) = value.split_once('T') {
+        let time = time_part.trim_end_matches('Z');
+        let short = time.get(0..5).unwrap_or(time);
+        return short.to_string();
+    }
+    value
+}
+
+/// How old a provider's usage data is relative to the refresh settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgeState {
+    /// Younger than the refresh interval
+    Fresh,
+    /// Older than the refresh interval but still worth showing
+    Stale,
+    /// Older than `max_age`, too old to trust
+    Expired,
+}
+
+/// Classify an RFC3339 `updatedAt` timestamp as of `now`. Missing or
+/// unparseable timestamps are treated as fresh since their age is unknown.
+pub fn age_state(
+    updated_at: Option<&str>,
+    now: DateTime<Utc>,
+    refresh: Duration,
+    max_age: Duration,
+) -> AgeState {
+    let Some(updated) = updated_at.and_then(|value| DateTime::parse_from_rfc3339(value).ok())
+    else {
+        return AgeState::Fresh;
+    };
+    let age = now
+        .signed_duration_since(updated.with_timezone(&Utc))
+        .to_std()
+        .unwrap_or_default();
+    classify_age(age, refresh, max_age)
+}
+
+/// Classify a known data age against the refresh settings. A zero
+/// `max_age` never expires data.
+pub fn classify_age(age: Duration, refresh: Duration, max_age: Duration) -> AgeState {
+    if !max_age.is_zero() && age >= max_age {
+        AgeState::Expired
+    } else if age >= refresh {
+        AgeState::Stale
+    } else {
+        AgeState::Fresh
+    }
+}
+
+/// Drop usage windows from payloads whose data has expired, so the display
+/// shows em-dashes rather than numbers that are too old to trust.
+pub fn expire_old_usage(payloads: &mut [ProviderPayload], config: &TokenGaugeConfig) {
+    let refresh = Duration::from_secs(config.refresh_secs);
+    let max_age = Duration::from_secs(config.max_age_secs);
+    let now = Utc::now();
+    for payload in payloads {
+        let updated_at = payload
+            .usage
+            .as_ref()
+            .and_then(|usage| usage.updated_at.as_deref());
+        if age_state(updated_at, now, refresh, max_age) == AgeState::Expired
+            && let Some(usage) = payload.usage.as_mut()
+        {
+            usage.primary = None;
+            usage.secondary = None;
+        }
+    }
+}
+
+fn provider_to_row(payload: ProviderPayload) -> ProviderRow {
+    let usage = payload.usage;
+    let session_percent = usage
+        .as_ref()
+        .and_then(|usage| window_percent(usage.session_window()));
+    let weekly_percent = usage
+        .as_ref()
+        .and_then(|usage| window_percent(usage.weekly_window()));
+    let updated_at = usage
+        .as_ref()
+        .and_then(|usage| usage.updated_at.as_deref())
+        .and_then(|value| DateTime::parse_from_rfc3339(value).ok());
+    // Relative descriptions like "in 2h" count from when codexbar took the
+    // reading, which for cached payloads can be a while ago
+    let now = updated_at.map_or_else(Local::now, |updated| updated.with_timezone(&Local));
+    let reset_at = |window: Option<&UsageWindow>| {
+        window
+            .and_then(|window| reset_timestamp(window, now))
+            .map(|reset| reset.to_rfc3339_opts(SecondsFormat::Secs, true))
+    };
+    let updated_age_secs = updated_at.map(|updated| {
+        // A timestamp in the future (clock skew) is just fresh
+        let age = Utc::now().signed_duration_since(updated.with_timezone(&Utc));
+        age.num_seconds().max(0) as u64
+    });
+    let session_reset_at = usage
+        .as_ref()
+        .and_then(|usage| reset_at(usage.session_window()));
+    let weekly_reset_at = usage
+        .as_ref()
+        .and_then(|usage| reset_at(usage.weekly_window()));
+    let (
+        session_used,
+        session_window,
+        session_reset,
+        weekly_used,
+        weekly_window,
+        weekly_reset,
+        updated,
+    ) = if let Some(usage) = usage {
+        let (session, weekly) = order_windows(usage.primary, usage.secondary);
+        let updated = format_updated(usage.updated_at);
+        let (session_used, session_window, session_reset) = format_window(session);
+        let (weekly_used, weekly_window, weekly_reset) = format_window(weekly);
+        (
+            session_used,
+            session_window,
+            session_reset,
+            weekly_used,
+            weekly_window,
+            weekly_reset,
+            updated,
+        )
+    } else {
+        (None, None, "—".into(), None, None, "—".into(), "—".into())
+    };
+
+    let credits_remaining = payload.credits.as_ref().and_then(Credits::remaining_amount);
+    let credits_total = payload.credits.as_ref().and_then(|credits| credits.total);
+
+    ProviderRow {
+        provider: provider_label(&payload.provider).to_string(),
+        name: payload.provider,
+        session_used,
+        session_percent,
+        session_window_minutes: session_window,
+        session_reset,
+        session_reset_at,
+        weekly_used,
+        weekly_percent,
+        weekly_window_minutes: weekly_window,
+        weekly_reset,
+        weekly_reset_at,
+        credits: format_credits(credits_remaining, credits_total),
+        credits_remaining,
+        credits_total,
+        credits_used_percent: payload.credits.as_ref().and_then(Credits::used_percent),
+        version: payload.version,
+        source_kind: payload.source,
+        updated,
+        updated_age_secs,
+        is_stale: false,
+        stale_since: None,
+        from_cache: false,
+        accounts: Vec::new(),
+    }
+}
+
+// ============================================================================
+// Cache Operations
+// ============================================================================
+
+/// Read cache, returning both payloads and errors.
+pub fn read_cache_full(path: &Path) -> Result<CachedData> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read cache file {}", path.display()))?;
+    let cached: CachedData = serde_json::from_str(&contents).context("cached JSON was invalid")?;
+    Ok(cached)
+}
+
+/// A corrupt cache that `read_cache_or_recover` moved aside.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredCache {
+    pub path: PathBuf,
+    pub moved_to: PathBuf,
+    /// Why the cache could not be read
+    pub reason: String,
+}
+
+impl std::fmt::Display for RecoveredCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cache {} was invalid ({}), moved to {}",
+            self.path.display(),
+            self.reason,
+            self.moved_to.display()
+        )
+    }
+}
+
+/// Read cache, quarantining it if it cannot be parsed.
+///
+/// A missing or unreadable cache returns `Ok(None)`. A corrupt cache (e.g.
+/// truncated on a full disk, or not UTF-8 at all) is moved aside with a
+/// `.corrupt` suffix and returned as `Err`, so callers can report it and
+/// fall through to a fresh fetch instead of failing.
+pub fn read_cache_or_recover(path: &Path) -> Result<Option<CachedData>, RecoveredCache> {
+    let Ok(contents) = fs::read(path) else {
+        return Ok(None);
+    };
+    let error = match serde_json::from_slice(&contents) {
+        Ok(cached) => return Ok(Some(cached)),
+        Err(error) => error,
+    };
+    let mut quarantine = path.as_os_str().to_owned();
+    quarantine.push(".corrupt");
+    let quarantine = PathBuf::from(quarantine);
+    if fs::rename(path, &quarantine).is_err() {
+        fs::remove_file(path).ok();
+    }
+    Err(RecoveredCache {
+        path: path.to_path_buf(),
+        moved_to: quarantine,
+        reason: error.to_string(),
+    })
+}
+
+/// Read cache, returning only successful payloads (for backwards compatibility).
+pub fn read_cache(path: &Path) -> Result<Vec<ProviderPayload>> {
+    let cached = read_cache_full(path)?;
+    Ok(cached.payloads().to_vec())
+}
+
+/// Write cache with payloads, errors and circuit breaker state.
+pub fn write_cache_full(
+    path: &Path,
+    payloads: &[ProviderPayload],
+    errors: &[ProviderFetchError],
+    breakers: &Breakers,
+    cache: &CacheConfig,
+) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    let data = CachedData::Full {
+        payloads: payloads.to_vec(),
+        errors: errors.to_vec(),
+        breakers: breakers.clone(),
+    };
+    let contents = if cache.pretty {
+        serde_json::to_string_pretty(&data)?
+    } else {
+        serde_json::to_string(&data)?
+    };
+    fs::write(path, contents)
+        .with_context(|| format!("failed to write cache {}", path.display()))?;
+    Ok(())
+}
+
+/// Write cache with only payloads (legacy, for backwards compatibility).
+pub fn write_cache(path: &Path, payloads: &[ProviderPayload]) -> Result<()> {
+    write_cache_full(
+        path,
+        payloads,
+        &[],
+        &Breakers::new(),
+        &CacheConfig::default(),
+    )
+}
+
+// ============================================================================
+// History
+// ============================================================================
+
+/// One provider's usage at a point in time, stored as a JSON line.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct HistorySample {
+    /// Unix timestamp in seconds
+    pub timestamp: i64,
+    pub provider: String,
+    pub session: Option<f64>,
+    pub weekly: Option<f64>,
+}
+
+/// Samples for every successful payload, all stamped with `now`.
+pub fn history_samples(payloads: &[ProviderPayload], now: DateTime<Utc>) -> Vec<HistorySample> {
+    payloads
+        .iter()
+        .filter(|payload| !payload.has_error())
+        .map(|payload| {
+            let usage = payload.usage.as_ref();
+            HistorySample {
+                timestamp: now.timestamp(),
+                provider: payload.provider.clone(),
+                session: window_percent(usage.and_then(UsageSnapshot::session_window)),
+                weekly: window_percent(usage.and_then(UsageSnapshot::weekly_window)),
+            }
+        })
+        .collect()
+}
+
+/// Grace past `max_age_days` before expired samples trigger a rewrite, so the
+/// file is not rewritten on every append once the oldest sample ages out.
+const HISTORY_AGE_SLACK_SECS: i64 = 24 * 3600;
+
+/// Drop samples older than `max_age_days` before `now`, then keep at most the
+/// newest `max_points`. Samples are assumed to be in recorded order.
+pub fn trim_history(samples: &mut Vec<HistorySample>, history: &HistoryConfig, now: DateTime<Utc>) {
+    if history.max_age_days > 0 {
+        let cutoff = now.timestamp() - history.max_age_days as i64 * 24 * 3600;
+        samples.retain(|sample| sample.timestamp >= cutoff);
+    }
+    if history.max_points > 0 && samples.len() > history.max_points {
+        samples.drain(..samples.len() - history.max_points);
+    }
+}
+
+/// Whether the history file has drifted far enough past the retention limits
+/// to be worth rewriting. Only the first line is read: the oldest sample
+/// decides the age check, and the line count is estimated from the file size
+/// and the length of the lines just appended. Rewrites happen once the file
+/// is a quarter over `max_points`, so appends stay cheap in between.
+fn history_needs_trim(
+    path: &Path,
+    history: &HistoryConfig,
+    line_len: u64,
+    now: DateTime<Utc>,
+) -> bool {
+    if history.max_points > 0 && line_len > 0 {
+        let size = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+        let limit = history.max_points as u64 + history.max_points as u64 / 4;
+        if size / line_len > limit {
+            return true;
+        }
+    }
+    if history.max_age_days > 0
+        && let Ok(file) = fs::File::open(path)
+    {
+        let mut first = String::new();
+        BufReader::new(file).read_line(&mut first).ok();
+        if let Ok(oldest) = serde_json::from_str::<HistorySample>(&first) {
+            let cutoff = now.timestamp() - history.max_age_days as i64 * 24 * 3600;
+            return oldest.timestamp < cutoff - HISTORY_AGE_SLACK_SECS;
+        }
+    }
+    false
+}
+
+/// Append samples to the history file, one JSON object per line, rewriting
+/// the file with [`trim_history`] applied once it grows past the retention
+/// limits in `history`.
+pub fn append_history(
+    path: &Path,
+    samples: &[HistorySample],
+    history: &HistoryConfig,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if samples.is_empty() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    let mut lines = String::new();
+    for sample in samples {
+        lines.push_str(&serde_json::to_string(sample)?);
+        lines.push('\n');
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open history {}", path.display()))?;
+    file.write_all(lines.as_bytes())
+        .with_context(|| format!("failed to write history {}", path.display()))?;
+    drop(file);
+
+    let line_len = lines.len() as u64 / samples.len() as u64;
+    if history_needs_trim(path, history, line_len, now) {
+        let mut kept = read_history(path)?;
+        trim_history(&mut kept, history, now);
+        let mut contents = String::new();
+        for sample in &kept {
+            contents.push_str(&serde_json::to_string(sample)?);
+            contents.push('\n');
+        }
+        // Write aside and rename so a crash never leaves a half-trimmed file
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, contents)
+            .with_context(|| format!("failed to write history {}", path.display()))?;
+        fs::rename(&temp, path)
+            .with_context(|| format!("failed to replace history {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Read the history file in recorded order. A missing file is empty history,
+/// and lines that do not parse (e.g. a torn final write) are skipped.
+pub fn read_history(path: &Path) -> Result<Vec<HistorySample>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => {
+            return Err(error)
+                .with_context(|| format!("failed to read history {}", path.display()));
+        }
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Record a sample per payload when history is enabled.
+pub fn record_history(config: &TokenGaugeConfig, payloads: &[ProviderPayload]) -> Result<()> {
+    if !config.history.enabled {
+        return Ok(());
+    }
+    let now = Utc::now();
+    append_history(
+        &config.history.file,
+        &history_samples(payloads, now),
+        &config.history,
+        now,
+    )
+}
+
+// ============================================================================
+// Config File Operations
+// ============================================================================
+
+pub fn ensure_config_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create config directory {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+pub fn ensure_cache_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create cache directory {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+pub fn write_default_config(path: &Path) -> Result<()> {
+    ensure_config_dir(path)?;
+    let contents = r#"# TokenGauge Configuration
+
+# Path to codexbar binary
+codexbar_bin = "codexbar"
+
+# Refresh interval in seconds (0 = always fetch, never use the cache)
+refresh_secs = 600
+
+# Age in seconds after which usage is shown as unknown (—) instead of stale
+# (0 = never)
+max_age_secs = 0
+
+# Cache file location
+cache_file = "/tmp/tokengauge-usage.json"
+
+[waybar]
+# Which window to show in waybar: "daily" or "weekly"
+window = "daily"
+# Hide the module entirely when no providers have data
+hide_when_empty = false
+# Text shown when no providers have data
+empty_text = "—"
+# Show both session and weekly mini-bars per provider (ignores window)
+show_both = false
+# Add the other window's text as "alt" so format-alt can toggle daily/weekly
+emit_alt = false
+# Text between providers, e.g. " │ "
+separator = "  "
+# Use short provider labels such as "CLD" and "CDX"
+short_labels = false
+# Text style: "full" (label, bar and percent), "bars" (no percent) or
+# "numbers" (no glyphs; "19/12" with show_both)
+style = "full"
+# Append the remaining credits summed over API providers ("Σ 128.40 cr")
+show_total_credits = false
+# Color tooltip percentages by severity using pango markup
+tooltip_markup = false
+
+[tui]
+# Highlight providers whose usage is above display.critical_above
+highlight_critical = true
+# Table columns, in order. Any of: status, provider, session, session_reset,
+# weekly, weekly_reset, credits, source, updated
+columns = ["status", "provider", "session", "session_reset", "weekly", "weekly_reset", "credits", "source", "updated"]
+# Refresh spinner: "braille", "ascii", or "none" for a static label
+spinner = "braille"
+# Milliseconds each spinner frame stays on screen
+spinner_interval_ms = 100
+# Seconds without a keypress before polling slows down to save power (0 disables)
+idle_after_secs = 30
+# Show the Source column (version and fetch method)
+show_source = true
+# Stack the session and weekly bars in one cell to save width
+stacked_windows = false
+
+[display]
+# Show percentages as "used" or "remaining"
+show = "used"
+# Used percentages above which a window is a warning, high, or critical
+warning_above = 30
+high_above = 60
+critical_above = 80
+# Decimal places in percentage text (tooltips, announcements)
+precision = 0
+# Collapse several accounts of one provider into a single row, combining
+# usage with "max" or "avg" (credits are summed)
+aggregate_accounts = false
+aggregate_strategy = "max"
+# Show the weekly window in the TUI table and waybar tooltip
+show_weekly = true
+
+[cache]
+# Write the cache as indented JSON (larger, but easier to read)
+pretty = false
+# Maximum bytes of raw error output stored per provider error
+max_raw_error_bytes = 4096
+# Skip cache writes within this many seconds of the last one, unless a
+# refresh was forced (0 always writes; capped at refresh_secs)
+min_write_interval_secs = 0
+
+[refresh]
+# Add a random 0..jitter_secs delay to refresh_secs to spread out refreshes
+jitter_secs = 0
+
+[fetch]
+# "parallel" runs codexbar for every provider at once; "sequential" runs one
+# at a time to keep memory use down
+mode = "parallel"
+
+[breaker]
+# Pause a provider after this many consecutive failures (0 disables)
+failure_threshold = 0
+# Pause length in seconds, doubling on each further failure up to the max
+base_cooldown_secs = 600
+max_cooldown_secs = 21600
+# When codexbar has no way to fetch an enabled provider: "retry" every
+# refresh, or "disable" it until a manual refresh in the TUI
+unsupported = "retry"
+
+[history]
+# Record a usage sample per provider after every live fetch
+enabled = false
+file = "/tmp/tokengauge-history.jsonl"
+# Drop samples older than this many days (0 keeps them all)
+max_age_days = 30
+# Keep at most this many samples, oldest first out (0 means no limit)
+max_points = 10000
+
+# Override short labels used when waybar.short_labels is on
+# [short_labels]
+# claude = "CL"
+
+# Arguments passed to codexbar; {provider} and {source} are substituted.
+# The command must print JSON only on stdout.
+# [codexbar]
+# args_template = ["usage", "--provider", "{provider}", "--source", "{source}", "--format", "json", "--json-only"]
+# Fetch all providers in one call ({provider} becomes "claude,codex") when
+# codexbar is 0.18 or newer; falls back to one call per provider otherwise
+# batch = false
+
+# Rename JSON keys from codexbar forks to the standard ones
+# [codexbar.fields]
+# percent = "usedPercent"
+
+# Proxy settings for codexbar (defaults to the inherited environment)
+# [network]
+# https_proxy = "http://proxy.example.com:3128"
+# http_proxy = "http://proxy.example.com:3128"
+# no_proxy = "localhost,127.0.0.1"
+
+[providers]
+# OAuth providers - set to true/false to enable/disable
+codex = true
+claude = true
+
+# API providers - uncomment and add your API key to enable
+# [providers.zai]
+# api_key = "your-zai-api-key"
+
+# [providers.kimik2]
+# api_key = "your-kimi-k2-api-key"
+
+# [providers.copilot]
+# api_key = "your-copilot-api-key"
+
+# [providers.minimax]
+# api_key = "your-minimax-api-key"
+
+# [providers.kimi]
+# api_key = "your-kimi-api-key"
+
+# Force codexbar's --source for a provider: "oauth", "api" or "auto"
+# [providers.source]
+# claude = "auto"
+"#;
+    fs::write(path, contents)
+        .with_context(|| format!("failed to write config {}", path.display()))?;
+    Ok(())
+}
+
+/// `$VISUAL`, else `$EDITOR`, skipping unset or blank values.
+fn pick_editor(visual: Option<OsString>, editor: Option<OsString>) -> Option<OsString> {
+    [visual, editor]
+        .into_iter()
+        .flatten()
+        .find(|value| !value.to_string_lossy().trim().is_empty())
+}
+
+/// Open the config at `path` in `$VISUAL` or `$EDITOR`, writing the default
+/// config first if there is none. Returns `false` when no editor is set. The
+/// editor value may carry arguments, e.g. "code --wait".
+pub fn edit_config(path: &Path) -> Result<bool> {
+    if path == Path::new(STDIN_CONFIG) {
+        return Err(anyhow!("cannot edit a config read from stdin"));
+    }
+    if !path.exists() {
+        write_default_config(path)?;
+    }
+    let Some(editor) = pick_editor(std::env::var_os("VISUAL"), std::env::var_os("EDITOR")) else {
+        return Ok(false);
+    };
+    let mut script = editor;
+    script.push(r#" "$1""#);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&script)
+        .arg("sh")
+        .arg(path)
+        .status()
+        .with_context(|| format!("failed to start editor {}", script.to_string_lossy()))?;
+    if !status.success() {
+        return Err(anyhow!("editor exited with {status}"));
+    }
+    Ok(true)
+}
+
+/// Load the config at `path`, first writing the default template if it is
+/// missing and `auto_create` is set. A path of `-` reads the config from stdin
+/// and never creates anything. Binaries call this once at startup so that
+/// fetching never writes config files.
+pub fn ensure_config(
+    path: &Path,
+    auto_create: bool,
+    profile: Option<&str>,
+) -> Result<TokenGaugeConfig> {
+    if path == Path::new(STDIN_CONFIG) {
+        return load_config_from_reader(std::io::stdin().lock(), Path::new("stdin"), profile);
+    }
+    if !path.exists() {
+        if !auto_create {
+            return Err(anyhow!(
+                "no config at {}; run with --init to create one",
+                path.display()
+            ));
+        }
+        write_default_config(path)?;
+    }
+    load_config_with_profile(Some(path.to_path_buf()), profile)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+#[path = "../tests/support/mod.rs"]
+mod test_support;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{FakeCodexbar, scripted, with_fake_codexbar};
+
+    // ------------------------------------------------------------------------
+    // config editing tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn pick_editor_prefers_visual_and_skips_blank() {
+        let os = |value: &str| Some(OsString::from(value));
+        assert_eq!(pick_editor(os("nvim"), os("vi")), os("nvim"));
+        assert_eq!(pick_editor(None, os("vi")), os("vi"));
+        assert_eq!(pick_editor(os("  "), os("nano")), os("nano"));
+        assert_eq!(pick_editor(os(""), None), None);
+        assert_eq!(pick_editor(None, None), None);
+    }
+
+    #[test]
+    fn xdg_path_prefers_xdg_then_home_then_cwd() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(key, _)| *key == name)
+                    .map(|(_, value)| std::ffi::OsString::from(value))
+            }
+        };
+        let config = |vars| xdg_path(env(vars), "XDG_CONFIG_HOME", ".config");
+        let cache = |vars| xdg_path(env(vars), "XDG_CACHE_HOME", ".cache");
+
+        let both = &[
+            ("XDG_CONFIG_HOME", "/xdg/config"),
+            ("XDG_CACHE_HOME", "/xdg/cache"),
+            ("HOME", "/home/u"),
+        ];
+        assert_eq!(config(both), Path::new("/xdg/config/tokengauge"));
+        assert_eq!(cache(both), Path::new("/xdg/cache/tokengauge"));
+
+        let home_only = &[("HOME", "/home/u")];
+        assert_eq!(config(home_only), Path::new("/home/u/.config/tokengauge"));
+        assert_eq!(cache(home_only), Path::new("/home/u/.cache/tokengauge"));
+
+        // Empty or relative XDG values don't count
+        let ignored = &[
+            ("XDG_CONFIG_HOME", ""),
+            ("XDG_CACHE_HOME", "rel/cache"),
+            ("HOME", "/home/u"),
+        ];
+        assert_eq!(config(ignored), Path::new("/home/u/.config/tokengauge"));
+        assert_eq!(cache(ignored), Path::new("/home/u/.cache/tokengauge"));
+
+        assert_eq!(config(&[]), Path::new("./tokengauge"));
+        assert_eq!(cache(&[("HOME", "")]), Path::new("./tokengauge"));
+
+        assert!(default_config_path().ends_with("tokengauge/config.toml"));
+        assert!(default_cache_path().ends_with("tokengauge/usage.json"));
+    }
+
+    #[test]
+    fn resolve_config_path_honors_override() {
+        assert_eq!(resolve_config_path(None), default_config_path());
+        assert_eq!(
+            resolve_config_path(Some(PathBuf::from("/etc/tg.toml"))),
+            PathBuf::from("/etc/tg.toml")
+        );
+    }
+
+    // ------------------------------------------------------------------------
+    // format_window tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn format_window_with_resets_at() {
+        // Use a time 2 hours and 30 minutes in the future
+        let future = Utc::now() + chrono::Duration::hours(2) + chrono::Duration::minutes(30);
+        let window = UsageWindow {
+            used_percent: Some(42.0),
+            reset_description: Some("Jan 20 at 12:59PM".to_string()),
+            resets_at: Some(future.to_rfc3339()),
+            window_minutes: Some(300),
+        };
+        let (used, minutes, reset) = format_window(Some(window));
+        assert_eq!(used, Some(42));
+        assert_eq!(minutes, Some(300));
+        // Allow for slight timing variations (29-30m)
+        assert!(
+            reset.starts_with("in 2h 2") || reset.starts_with("in 2h 30"),
+            "unexpected reset: {}",
+            reset
+        );
+    }
+
+    #[test]
+    fn format_window_falls_back_to_description() {
+        // When resets_at is missing, fall back to description
+        let window = UsageWindow {
+            used_percent: Some(42.0),
+            reset_description: Some("Jan 20 at 12:59PM".to_string()),
+            resets_at: None,
+            window_minutes: Some(300),
+        };
+        let (used, minutes, reset) = format_window(Some(window));
+        assert_eq!(used, Some(42));
+        assert_eq!(minutes, Some(300));
+        assert_eq!(reset, "Jan 20 at 12:59PM");
+    }
+
+    #[test]
+    fn reset_timestamp_prefers_provided_time() {
+        let now = Local.with_ymd_and_hms(2026, 1, 18, 9, 0, 0).unwrap();
+        let window: UsageWindow = serde_json::from_str(
+            r#"{"usedPercent":10,"resetDescription":"in 1h","resetAt":"2026-01-20T12:59:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            reset_timestamp(&window, now).unwrap().to_rfc3339(),
+            "2026-01-20T12:59:00+00:00"
+        );
+    }
+
+    #[test]
+    fn reset_timestamp_parses_descriptions() {
+        let now = Local.with_ymd_and_hms(2026, 12, 30, 9, 0, 0).unwrap();
+        let parse = |description: &str| {
+            let window = UsageWindow {
+                used_percent: None,
+                reset_description: Some(description.to_string()),
+                resets_at: None,
+                window_minutes: None,
+            };
+            reset_timestamp(&window, now)
+        };
+        let at = |y, mo, d, h, mi| {
+            Local
+                .with_ymd_and_hms(y, mo, d, h, mi, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+
+        assert_eq!(parse("in 2h 30m"), Some(at(2026, 12, 30, 11, 30)));
+        assert_eq!(parse("1d 3h"), Some(at(2026, 12, 31, 12, 0)));
+        assert_eq!(parse("Dec 31 at 8:59AM"), Some(at(2026, 12, 31, 8, 59)));
+        // Early January seen from late December rolls over to next year
+        assert_eq!(parse("Jan 2 at 12:59PM"), Some(at(2027, 1, 2, 12, 59)));
+        assert_eq!(parse("soon"), None);
+        assert_eq!(parse("in 2x"), None);
+        // Localized descriptions are unparsed, not a panic
+        assert_eq!(parse("明日 9:00"), None);
+        assert_eq!(parse("→ 5h"), None);
+        assert_eq!(parse("in 5時"), None);
+    }
+
+    #[test]
+    fn rows_carry_reset_timestamps() {
+        let payload: ProviderPayload = serde_json::from_str(
+            r#"{"provider":"claude","usage":{
+                "primary":{"usedPercent":10,"windowMinutes":300,"resetsAt":"2030-01-01T05:00:00Z"},
+                "secondary":{"usedPercent":20,"windowMinutes":10080,"resetDescription":"Mon"}}}"#,
+        )
+        .unwrap();
+        let row = provider_to_row(payload);
+        assert_eq!(
+            row.session_reset_at.as_deref(),
+            Some("2030-01-01T05:00:00Z")
+        );
+        assert_eq!(row.weekly_reset_at, None);
+        assert_eq!(row.to_json()["sessionResetAt"], "2030-01-01T05:00:00Z");
+    }
+
+    #[test]
+    fn rows_resolve_relative_resets_from_updated_at() {
+        let payload: ProviderPayload = serde_json::from_str(
+            r#"{"provider":"claude","usage":{"updatedAt":"2030-01-01T00:00:00Z",
+                "primary":{"usedPercent":10,"windowMinutes":300,"resetDescription":"in 2h 30m"}}}"#,
+        )
+        .unwrap();
+        let row = provider_to_row(payload);
+        assert_eq!(
+            row.session_reset_at.as_deref(),
+            Some("2030-01-01T02:30:00Z")
+        );
+    }
+
+    #[test]
+    fn prometheus_metrics_expose_usage_and_reset_seconds() {
+        let payload: ProviderPayload = serde_json::from_str(
+            r#"{"provider":"claude","usage":{
+                "primary":{"usedPercent":19.5,"windowMinutes":300,"resetsAt":"2030-01-01T05:00:00Z"},
+                "secondary":{"usedPercent":20,"windowMinutes":10080}}}"#,
+        )
+        .unwrap();
+        let row = provider_to_row(payload);
+        let now = DateTime::parse_from_rfc3339("2030-01-01T04:00:00Z")
+            .unwrap()
+            .timestamp();
+        let metrics = prometheus_metrics(&[row], now);
+
+        assert!(metrics.contains("# TYPE tokengauge_reset_seconds gauge\n"));
+        assert!(
+            metrics
+                .contains("tokengauge_used_percent{provider=\"claude\",window=\"session\"} 19.5\n")
+        );
+        assert!(
+            metrics.contains("tokengauge_used_percent{provider=\"claude\",window=\"weekly\"} 20\n")
+        );
+        assert!(
+            metrics.contains(
+                "tokengauge_reset_seconds{provider=\"claude\",window=\"session\"} 3600\n"
+            )
+        );
+        // No reset time, no reset metric
+        assert!(
+            !metrics.contains("tokengauge_reset_seconds{provider=\"claude\",window=\"weekly\"}")
+        );
+    }
+
+    #[test]
+    fn format_window_clamps_over_100() {
+        let window = UsageWindow {
+            used_percent: Some(150.0),
+            reset_description: None,
+            resets_at: None,
+            window_minutes: None,
+        };
+        let (used, _, _) = format_window(Some(window));
+        assert_eq!(used, Some(100)); // clamped to 100
+    }
+
+    #[test]
+    fn fractional_percent_parses_and_rounds_for_bars() {
+        let json = r#"{"provider":"claude","usage":{"primary":{"usedPercent":42.6},"secondary":{"usedPercent":101.5}}}"#;
+        let payload: ProviderPayload = serde_json::from_str(json).unwrap();
+        let usage = payload.usage.as_ref().unwrap();
+        assert_eq!(usage.primary.as_ref().unwrap().used_percent, Some(42.6));
+
+        let rows = payload_to_rows(vec![payload]);
+        assert_eq!(rows[0].session_used, Some(43));
+        assert_eq!(rows[0].session_percent, Some(42.6));
+        assert_eq!(rows[0].weekly_used, Some(100));
+        assert_eq!(rows[0].weekly_percent, Some(100.0));
+    }
+
+    #[test]
+    fn format_window_none() {
+        let (used, minutes, reset) = format_window(None);
+        assert_eq!(used, None);
+        assert_eq!(minutes, None);
+        assert_eq!(reset, "—");
+    }
+
+    #[test]
+    fn format_window_missing_both_resets_at_and_description() {
+        let window = UsageWindow {
+            used_percent: Some(50.0),
+            reset_description: None,
+            resets_at: None,
+            window_minutes: Some(60),
+        };
+        let (_, _, reset) = format_window(Some(window));
+        assert_eq!(reset, "—");
+    }
+
+    #[test]
+    fn window_kind_from_known_minutes() {
+        assert_eq!(window_kind(300), WindowKind::Hours(5));
+        assert_eq!(window_kind(60), WindowKind::Hours(1));
+        assert_eq!(window_kind(1440), WindowKind::Daily);
+        assert_eq!(window_kind(10080), WindowKind::Weekly);
+        assert_eq!(window_kind(43200), WindowKind::Monthly);
+        assert_eq!(window_kind(44640), WindowKind::Monthly);
+        assert_eq!(window_kind(90), WindowKind::Minutes(90));
+        assert_eq!(window_kind(2880), WindowKind::Minutes(2880));
+        assert_eq!(window_kind(0), WindowKind::Minutes(0));
+    }
+
+    #[test]
+    fn window_label_falls_back_without_minutes() {
+        assert_eq!(window_label(Some(300), "Session"), "5h");
+        assert_eq!(window_label(Some(10080), "Session"), "Weekly");
+        assert_eq!(window_label(Some(43200), "Weekly"), "Monthly");
+        assert_eq!(window_label(Some(90), "Session"), "90m");
+        assert_eq!(window_label(None, "Session"), "Session");
+    }
+
+    #[test]
+    fn format_window_minutes_only() {
+        // Use a time 45 minutes in the future
+        let future = Utc::now() + chrono::Duration::minutes(45);
+        let window = UsageWindow {
+            used_percent: Some(10.0),
+            reset_description: None,
+            resets_at: Some(future.to_rfc3339()),
+            window_minutes: Some(60),
+        };
+        let (_, _, reset) = format_window(Some(window));
+        // Allow for slight timing variations (44-45m)
+        assert!(
+            reset == "in 44m" || reset == "in 45m",
+            "unexpected reset: {}",
+            reset
+        );
+    }
+
+    // ------------------------------------------------------------------------
+    // format_updated tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn format_updated_rfc3339() {
+        // Full RFC3339 timestamp should be formatted to local time HH:MM
+        let result = format_updated(Some("2026-01-20T07:37:16Z".to_string()));
+        // We can't assert exact time due to timezone, but it should be HH:MM format
+        assert!(result.len() == 5 || result.len() <= 8); // "HH:MM" or with timezone offset
+        assert!(result.contains(':'));
+    }
+
+    #[test]
+    fn format_updated_iso_with_t() {
+        // ISO format with T separator, extracts time part
+        let result = format_updated(Some("2026-01-20T14:30:00Z".to_string()));
+        assert!(result.contains(':'));
+    }
+
+    #[test]
+    fn format_updated_none() {
+        assert_eq!(format_updated(None), "—");
+    }
+
+    #[test]
+    fn format_updated_fallback() {
+        // Unknown format returns as-is
+        let result = format_updated(Some("unknown format".to_string()));
+        assert_eq!(result, "unknown format");
+    }
+
+    // ------------------------------------------------------------------------
+    // severity tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn severity_boundaries() {
+        let display = DisplayConfig::default();
+        for (used, expected) in [
+            (0, Severity::Ok),
+            (30, Severity::Ok),
+            (31, Severity::Warning),
+            (60, Severity::Warning),
+            (61, Severity::High),
+            (80, Severity::High),
+            (81, Severity::Critical),
+            (100, Severity::Critical),
+            (150, Severity::Critical),
+        ] {
+            assert_eq!(display.severity(used), expected, "used {used}");
+        }
+    }
+
+    #[test]
+    fn severity_hex_follows_boundaries() {
+        let display = DisplayConfig::default();
+        for (used, expected) in [
+            (30, "#50fa7b"),
+            (31, "#f1fa8c"),
+            (60, "#f1fa8c"),
+            (61, "#ffb86c"),
+            (80, "#ffb86c"),
+            (81, "#ff5555"),
+        ] {
+            assert_eq!(display.severity(used).hex(), expected, "used {used}");
+        }
+    }
+
+    #[test]
+    fn severity_ignores_display_mode_and_honors_thresholds() {
+        let display = DisplayConfig {
+            show: PercentDisplay::Remaining,
+            warning_above: 50,
+            high_above: 75,
+            critical_above: 95,
+            precision: 0,
+            ..Default::default()
+        };
+        assert_eq!(display.severity(50), Severity::Ok);
+        assert_eq!(display.severity(51), Severity::Warning);
+        assert_eq!(display.severity(76), Severity::High);
+        assert_eq!(display.severity(96), Severity::Critical);
+    }
+
+    #[test]
+    fn format_percent_honors_precision_and_mode() {
+        let mut display = DisplayConfig::default();
+        assert_eq!(display.format_percent(42.46), "42");
+        assert_eq!(display.format_percent(42.5), "43");
+        assert_eq!(display.format_percent(150.0), "100");
+
+        display.precision = 1;
+        assert_eq!(display.format_percent(42.46), "42.5");
+        assert_eq!(display.format_percent(42.0), "42.0");
+
+        display.show = PercentDisplay::Remaining;
+        assert_eq!(display.format_percent(42.46), "57.5");
+    }
+
+    // ------------------------------------------------------------------------
+    // jitter tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        for seed in 0..1000 {
+            assert!(jitter(30, seed) <= Duration::from_secs(30));
+        }
+        // Different seeds should actually spread out
+        let distinct: std::collections::HashSet<_> =
+            (0..100).map(|seed| jitter(30, seed)).collect();
+        assert!(distinct.len() > 10);
+    }
+
+    #[test]
+    fn cache_file_is_stale_by_mtime() {
+        use std::time::SystemTime;
+        let path = temp_path("staleness-mtime.json");
+        fs::write(&path, "{}").unwrap();
+        let mut config = TokenGaugeConfig {
+            cache_file: path.clone(),
+            ..Default::default()
+        };
+        config.refresh.jitter_secs = 0;
+        let set_mtime = |mtime: SystemTime| {
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        let now = SystemTime::now();
+
+        set_mtime(now - Duration::from_secs(60));
+        assert!(!config.cache_file_is_stale());
+        set_mtime(now - Duration::from_secs(config.refresh_secs - 1));
+        assert!(!config.cache_file_is_stale());
+        set_mtime(now - Duration::from_secs(config.refresh_secs));
+        assert!(config.cache_file_is_stale());
+        set_mtime(now - Duration::from_secs(3600));
+        assert!(config.cache_file_is_stale());
+        assert!(config.cache_clock_skew_warning().is_none());
+        // Clock skew: a future mtime is fresh, not stale forever
+        set_mtime(now + Duration::from_secs(3600));
+        assert!(!config.cache_file_is_stale());
+        let warning = config.cache_clock_skew_warning().unwrap();
+        assert!(warning.contains("in the future"), "{warning}");
+        // refresh_secs = 0 never trusts even a fresh cache
+        let never = TokenGaugeConfig {
+            refresh_secs: 0,
+            ..config.clone()
+        };
+        assert!(never.cache_file_is_stale());
+
+        fs::remove_file(&path).unwrap();
+        assert!(config.cache_file_is_stale());
+    }
+
+    #[test]
+    fn cache_is_stale_branches() {
+        use std::time::SystemTime;
+        let refresh = Duration::from_secs(600);
+        let path = temp_path("cache-is-stale.json");
+        fs::write(&path, "{}").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        let now = SystemTime::now();
+
+        file.set_modified(now - Duration::from_secs(30)).unwrap();
+        assert!(!cache_is_stale(&path, refresh));
+        assert!(cache_is_stale(&path, Duration::ZERO));
+        file.set_modified(now - refresh).unwrap();
+        assert!(cache_is_stale(&path, refresh));
+        file.set_modified(now + Duration::from_secs(86_400))
+            .unwrap();
+        assert!(!cache_is_stale(&path, refresh));
+
+        // mtime can't be read through a path whose parent is a file
+        assert!(cache_is_stale(&path.join("cache.json"), refresh));
+        fs::remove_file(&path).unwrap();
+        assert!(cache_is_stale(&path, refresh));
+    }
+
+    #[test]
+    fn ensure_config_without_auto_create_errors() {
+        let path = temp_path("no-init/config.toml");
+        let error = ensure_config(&path, false, None).unwrap_err().to_string();
+        assert!(error.contains("--init"), "{error}");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn ensure_config_creates_then_loads() {
+        let path = temp_path("auto-init/config.toml");
+        let config = ensure_config(&path, true, None).unwrap();
+        assert!(path.exists());
+        assert_eq!(config.refresh_secs, 600);
+
+        // An existing config is loaded as-is, even without auto_create
+        fs::write(&path, "refresh_secs = 42\n").unwrap();
+        assert_eq!(ensure_config(&path, false, None).unwrap().refresh_secs, 42);
+        fs::remove_dir_all(path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn load_config_layered_overlays_user_values() {
+        let base = temp_path("layered-base.toml");
+        let user = temp_path("layered-user.toml");
+        fs::write(
+            &base,
+            "refresh_secs = 600\ntimeout_secs = 9\n[providers]\nclaude = true\n",
+        )
+        .unwrap();
+        fs::write(&user, "refresh_secs = 60\n[providers]\ncodex = true\n").unwrap();
+
+        let config = load_config_layered(&[base.clone(), user.clone()]).unwrap();
+        fs::remove_file(&base).ok();
+        fs::remove_file(&user).ok();
+
+        assert_eq!(config.refresh_secs, 60);
+        assert_eq!(config.timeout_secs, 9);
+        assert_eq!(config.providers.claude, Some(true));
+        assert_eq!(config.providers.codex, Some(true));
+    }
+
+    #[test]
+    fn load_config_layered_needs_a_path() {
+        assert!(load_config_layered(&[]).is_err());
+    }
+
+    #[test]
+    fn env_overrides_replace_file_values() {
+        let env = std::collections::HashMap::from([
+            ("TOKENGAUGE_REFRESH_SECS", "30"),
+            ("TOKENGAUGE_TIMEOUT_SECS", " 12 "),
+            ("TOKENGAUGE_CODEXBAR_BIN", "/opt/codexbar"),
+            ("TOKENGAUGE_CACHE_FILE", "/run/user/1000/tokengauge.json"),
+            ("TOKENGAUGE_WINDOW", "Weekly"),
+        ]);
+        let mut config = TokenGaugeConfig::default();
+        apply_env_overrides(&mut config, |name| env.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.refresh_secs, 30);
+        assert_eq!(config.timeout_secs, 12);
+        assert_eq!(config.codexbar_bin, "/opt/codexbar");
+        assert_eq!(
+            config.cache_file,
+            PathBuf::from("/run/user/1000/tokengauge.json")
+        );
+        assert_eq!(config.waybar.window, WaybarWindow::Weekly);
+    }
+
+    #[test]
+    fn env_overrides_reject_bad_values() {
+        let mut config = TokenGaugeConfig::default();
+        let error = apply_env_overrides(&mut config, |name| {
+            (name == "TOKENGAUGE_REFRESH_SECS").then(|| "soon".to_string())
+        })
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("TOKENGAUGE_REFRESH_SECS"),
+            "{error}"
+        );
+
+        let error = apply_env_overrides(&mut config, |name| {
+            (name == "TOKENGAUGE_WINDOW").then(|| "monthly".to_string())
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("daily or weekly"), "{error}");
+        // Nothing set leaves the config alone
+        apply_env_overrides(&mut config, |_| None).unwrap();
+        assert_eq!(config.refresh_secs, 600);
+    }
+
+    #[test]
+    fn load_config_from_reader_parses_toml() {
+        let toml =
+            "refresh_secs = 45\ncodexbar_bin = \"/opt/codexbar\"\n[providers]\nclaude = true\n";
+        let config = load_config_from_reader(toml.as_bytes(), Path::new("stdin"), None).unwrap();
+        assert_eq!(config.refresh_secs, 45);
+        assert_eq!(config.codexbar_bin, "/opt/codexbar");
+        assert_eq!(config.providers.claude, Some(true));
+
+        let error =
+            load_config_from_reader("refresh_secs = [".as_bytes(), Path::new("stdin"), None)
+                .unwrap_err()
+                .to_string();
+        assert_eq!(error, "failed to parse config at stdin");
+    }
+
+    const PROFILES_TOML: &str = r#"
+refresh_secs = 600
+[providers]
+claude = true
+codex = true
+
+[profiles.work]
+refresh_secs = 120
+[profiles.work.providers]
+codex = false
+
+[profiles.personal.waybar]
+window = "weekly"
+"#;
+
+    #[test]
+    fn profile_overlays_base_config() {
+        let load = |profile| {
+            load_config_from_reader(PROFILES_TOML.as_bytes(), Path::new("stdin"), profile)
+        };
+        let base = load(None).unwrap();
+        assert_eq!(base.refresh_secs, 600);
+        assert_eq!(base.providers.codex, Some(true));
+        assert_eq!(base.profile, None);
+
+        let work = load(Some("work")).unwrap();
+        assert_eq!(work.refresh_secs, 120);
+        assert_eq!(work.providers.claude, Some(true));
+        assert_eq!(work.providers.codex, Some(false));
+        assert_eq!(work.profile.as_deref(), Some("work"));
+
+        let personal = load(Some("personal")).unwrap();
+        assert_eq!(personal.refresh_secs, 600);
+        assert_eq!(personal.waybar.window, WaybarWindow::Weekly);
+    }
+
+    #[test]
+    fn profiles_resolve_to_distinct_cache_files() {
+        let config = TokenGaugeConfig {
+            cache_file: PathBuf::from("/tmp/usage.json"),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_cache_file(&config, None),
+            PathBuf::from("/tmp/usage.json")
+        );
+        assert_eq!(
+            resolve_cache_file(&config, Some("work")),
+            PathBuf::from("/tmp/usage-work.json")
+        );
+        assert_eq!(
+            resolve_cache_file(&config, Some("personal")),
+            PathBuf::from("/tmp/usage-personal.json")
+        );
+        // Profile names cannot escape the cache directory
+        assert_eq!(
+            resolve_cache_file(&config, Some("../x")),
+            PathBuf::from("/tmp/usage-___x.json")
+        );
+    }
+
+    #[test]
+    fn profile_cache_file_applies_unless_profile_sets_one() {
+        let toml = "cache_file = \"/tmp/usage.json\"\n\
+                    [profiles.work]\nrefresh_secs = 60\n\
+                    [profiles.lab]\ncache_file = \"/tmp/lab.json\"\n";
+        let load = |profile| {
+            load_config_from_reader(toml.as_bytes(), Path::new("stdin"), profile)
+                .unwrap()
+                .cache_file
+        };
+        assert_eq!(load(None), PathBuf::from("/tmp/usage.json"));
+        assert_eq!(load(Some("work")), PathBuf::from("/tmp/usage-work.json"));
+        assert_eq!(load(Some("lab")), PathBuf::from("/tmp/lab.json"));
+    }
+
+    #[test]
+    fn unknown_profile_errors() {
+        let error =
+            load_config_from_reader(PROFILES_TOML.as_bytes(), Path::new("stdin"), Some("home"))
+                .unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains(r#"unknown profile "home""#), "{message}");
+        assert!(message.contains("available: personal, work"), "{message}");
+
+        let error = load_config_from_reader(
+            "refresh_secs = 5\n".as_bytes(),
+            Path::new("stdin"),
+            Some("work"),
+        )
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("no [profiles.<name>] sections"));
+    }
+
+    #[test]
+    fn load_config_keeps_zero_refresh() {
+        let path = temp_path("zero-refresh.toml");
+        fs::write(&path, "refresh_secs = 0\n").unwrap();
+        let config = load_config(Some(path.clone())).unwrap();
+        assert_eq!(config.refresh_secs, 0);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn jitter_is_deterministic_and_zero_is_noop() {
+        assert_eq!(jitter(60, 42), jitter(60, 42));
+        assert_eq!(jitter(0, 42), Duration::ZERO);
+
+        let config = TokenGaugeConfig::default();
+        assert_eq!(config.refresh_threshold(), Duration::from_secs(600));
+    }
+
+    // ------------------------------------------------------------------------
+    // age_state tests
+    // ------------------------------------------------------------------------
+
+    fn minutes_ago(minutes: i64) -> String {
+        (Utc::now() - chrono::Duration::minutes(minutes)).to_rfc3339()
+    }
+
+    #[test]
+    fn age_state_bands() {
+        let refresh = Duration::from_secs(600);
+        let max_age = Duration::from_secs(3600);
+        let now = Utc.with_ymd_and_hms(2026, 1, 20, 12, 0, 0).unwrap();
+        let state = |updated: &str| age_state(Some(updated), now, refresh, max_age);
+
+        assert_eq!(state("2026-01-20T11:59:00Z"), AgeState::Fresh);
+        assert_eq!(state("2026-01-20T11:50:00Z"), AgeState::Stale);
+        assert_eq!(state("2026-01-20T11:00:01Z"), AgeState::Stale);
+        assert_eq!(state("2026-01-20T11:00:00Z"), AgeState::Expired);
+        // Without a max age, old data is only ever stale
+        assert_eq!(
+            age_state(Some("2025-01-20T11:00:00Z"), now, refresh, Duration::ZERO),
+            AgeState::Stale
+        );
+    }
+
+    #[test]
+    fn age_state_unknown_is_fresh() {
+        let refresh = Duration::from_secs(600);
+        let max_age = Duration::from_secs(3600);
+        let now = Utc::now();
+        assert_eq!(age_state(None, now, refresh, max_age), AgeState::Fresh);
+        assert_eq!(
+            age_state(Some("not a timestamp"), now, refresh, max_age),
+            AgeState::Fresh
+        );
+    }
+
+    #[test]
+    fn expire_old_usage_blanks_expired_windows() {
+        let window = UsageWindow {
+            used_percent: Some(42.0),
+            reset_description: None,
+            resets_at: None,
+            window_minutes: Some(300),
+        };
+        let payload = |updated_at: String| ProviderPayload {
+            provider: "claude".to_string(),
+            version: None,
+            source: None,
+            usage: Some(UsageSnapshot {
+                primary: Some(window.clone()),
+                secondary: None,
+                updated_at: Some(updated_at),
+            }),
+            credits: None,
+            error: None,
+        };
+        let mut payloads = vec![payload(minutes_ago(30)), payload(minutes_ago(120))];
+
+        // Expiry is off by default
+        expire_old_usage(&mut payloads, &TokenGaugeConfig::default());
+        assert!(payloads[1].usage.as_ref().unwrap().primary.is_some());
+
+        let config = TokenGaugeConfig {
+            max_age_secs: 3600,
+            ..Default::default()
+        };
+        expire_old_usage(&mut payloads, &config);
+
+        let rows = payload_to_rows(payloads);
+        assert_eq!(rows[0].session_used, Some(42)); // stale, still shown
+        assert_eq!(rows[1].session_used, None); // expired
+    }
+
+    // ------------------------------------------------------------------------
+    // provider_label tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn provider_label_known_providers() {
+        assert_eq!(provider_label("claude"), "Claude");
+        assert_eq!(provider_label("codex"), "Codex");
+        assert_eq!(provider_label("zai"), "z.ai");
+        assert_eq!(provider_label("kimik2"), "Kimi K2");
+    }
+
+    #[test]
+    fn provider_label_unknown_returns_input() {
+        assert_eq!(provider_label("unknown_provider"), "unknown_provider");
+    }
+
+    // ------------------------------------------------------------------------
+    // get_provider_info tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn get_provider_info_oauth_provider() {
+        let info = get_provider_info("claude").unwrap();
+        assert_eq!(info.name, "claude");
+        assert_eq!(info.provider_type, ProviderType::OAuth);
+        assert!(info.env_var.is_none());
+    }
+
+    #[test]
+    fn get_provider_info_api_provider() {
+        let info = get_provider_info("zai").unwrap();
+        assert_eq!(info.name, "zai");
+        assert_eq!(info.provider_type, ProviderType::Api);
+        assert_eq!(info.env_var, Some("ZAI_API_TOKEN"));
+    }
+
+    #[test]
+    fn get_provider_info_unknown() {
+        assert!(get_provider_info("nonexistent").is_none());
+    }
+
+    // ------------------------------------------------------------------------
+    // ProvidersConfig tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn providers_config_enabled_oauth_only() {
+        let config = ProvidersConfig {
+            codex: Some(true),
+            claude: Some(true),
+            ..Default::default()
+        };
+        let enabled = config.enabled_providers();
+        assert_eq!(enabled.len(), 2);
+        assert!(enabled.iter().any(|p| p.name == "codex"));
+        assert!(enabled.iter().any(|p| p.name == "claude"));
+    }
+
+    #[test]
+    fn providers_config_enabled_with_api_provider() {
+        let config = ProvidersConfig {
+            claude: Some(true),
+            zai: Some(ApiProviderConfig {
+                api_key: "test-key".to_string(),
+            }),
+            ..Default::default()
+        };
+        let enabled = config.enabled_providers();
+        assert_eq!(enabled.len(), 2);
+
+        let zai = enabled.iter().find(|p| p.name == "zai").unwrap();
+        assert_eq!(zai.api_key, Some("test-key".to_string()));
+        assert_eq!(zai.env_var, Some("ZAI_API_TOKEN"));
+    }
+
+    #[test]
+    fn providers_config_env_vars_match_registry() {
+        let api = || {
+            Some(ApiProviderConfig {
+                api_key: "key".to_string(),
+            })
+        };
+        let config = ProvidersConfig {
+            codex: Some(true),
+            claude: Some(true),
+            zai: api(),
+            kimik2: api(),
+            copilot: api(),
+            minimax: api(),
+            kimi: api(),
+            source: BTreeMap::new(),
+        };
+        let enabled = config.enabled_providers();
+        assert_eq!(enabled.len(), PROVIDERS.len());
+
+        for provider in &enabled {
+            let info = get_provider_info(&provider.name).unwrap();
+            assert_eq!(provider.env_var, info.env_var, "{}", provider.name);
+            assert_eq!(provider.provider_type, info.provider_type);
+            if info.provider_type == ProviderType::Api {
+                assert!(provider.env_var.is_some());
+                assert_eq!(provider.api_key.as_deref(), Some("key"));
+            } else {
+                assert!(provider.api_key.is_none());
+            }
+        }
+        let names: Vec<_> = enabled.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "codex", "claude", "zai", "kimik2", "copilot", "minimax", "kimi"
+            ]
+        );
+    }
+
+    #[test]
+    fn providers_config_validate_clean() {
+        let config = ProvidersConfig {
+            claude: Some(true),
+            kimi: Some(ApiProviderConfig {
+                api_key: "a".to_string(),
+            }),
+            kimik2: Some(ApiProviderConfig {
+                api_key: "b".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn providers_config_validate_empty_api_key() {
+        let config = ProvidersConfig {
+            zai: Some(ApiProviderConfig {
+                api_key: "  ".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            [ConfigWarning::EmptyApiKey {
+                provider: "zai".to_string()
+            }]
+        );
+        assert_eq!(
+            config.validate()[0].to_string(),
+            "[providers.zai] has an empty api_key"
+        );
+    }
+
+    #[test]
+    fn providers_config_validate_shared_api_key() {
+        let config = ProvidersConfig {
+            kimi: Some(ApiProviderConfig {
+                api_key: "same".to_string(),
+            }),
+            kimik2: Some(ApiProviderConfig {
+                api_key: "same".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate(),
+            [ConfigWarning::SharedApiKey {
+                providers: vec!["kimik2".to_string(), "kimi".to_string()]
+            }]
+        );
+    }
+
+    #[test]
+    fn providers_config_disabled_oauth() {
+        let config = ProvidersConfig {
+            codex: Some(false),
+            claude: Some(true),
+            ..Default::default()
+        };
+        let enabled = config.enabled_providers();
+        assert_eq!(enabled.len(), 1);
+        assert_eq!(enabled[0].name, "claude");
+    }
+
+    #[test]
+    fn providers_config_none_means_disabled() {
+        let config = ProvidersConfig::default();
+        let enabled = config.enabled_providers();
+        assert!(enabled.is_empty());
+    }
+
+    #[test]
+    fn providers_config_is_enabled() {
+        let config = ProvidersConfig {
+            codex: Some(true),
+            claude: Some(false),
+            zai: Some(ApiProviderConfig {
+                api_key: "key".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(config.is_enabled("codex"));
+        assert!(!config.is_enabled("claude"));
+        assert!(config.is_enabled("zai"));
+        assert!(!config.is_enabled("kimik2"));
+        assert!(!config.is_enabled("unknown"));
+    }
+
+    // ------------------------------------------------------------------------
+    // ProviderPayload tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn provider_payload_has_error_true() {
+        let payload = ProviderPayload {
+            provider: "test".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: Some(ProviderError {
+                message: Some("error".to_string()),
+                code: None,
+                kind: None,
+            }),
+        };
+        assert!(payload.has_error());
+    }
+
+    #[test]
+    fn provider_payload_has_error_false() {
+        let payload = ProviderPayload {
+            provider: "test".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        assert!(!payload.has_error());
+    }
+
+    // ------------------------------------------------------------------------
+    // FetchResult tests
+    // ------------------------------------------------------------------------
+
+    fn named_payload(provider: &str) -> ProviderPayload {
+        ProviderPayload {
+            provider: provider.to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn fetch_result_merges_one_entry_per_provider() {
+        let result = FetchResult {
+            payloads: vec![
+                named_payload("claude"),
+                named_payload("codex"),
+                named_payload("claude"),
+            ],
+            errors: vec![
+                ProviderFetchError::new("zai".to_string(), "timeout after 2s"),
+                ProviderFetchError::new("codex".to_string(), "partial failure"),
+            ],
+        };
+        let results = result.results();
+        let names: Vec<_> = results.iter().map(|r| r.provider.as_str()).collect();
+        assert_eq!(names, ["claude", "codex", "zai"]);
+
+        assert!(results[0].payload.is_some() && results[0].error.is_none());
+        assert!(results[1].payload.is_some() && results[1].error.is_some());
+        assert!(results[2].payload.is_none() && results[2].error.is_some());
+    }
+
+    #[test]
+    fn fetch_result_counts_and_iterators() {
+        let mut cursor = named_payload("cursor");
+        cursor.error = Some(ProviderError {
+            message: Some("not signed in".to_string()),
+            code: None,
+            kind: None,
+        });
+        let result = FetchResult {
+            payloads: vec![named_payload("claude"), named_payload("codex"), cursor],
+            errors: vec![
+                ProviderFetchError::new("zai".to_string(), "timeout after 2s"),
+                ProviderFetchError::new("codex".to_string(), "partial failure"),
+            ],
+        };
+        assert_eq!(result.successful_count(), 2);
+        assert_eq!(result.error_count(), 2);
+        let ok: Vec<_> = result.iter_ok().map(|p| p.provider.as_str()).collect();
+        assert_eq!(ok, ["claude", "codex"]);
+        let err: Vec<_> = result.iter_err().map(|e| e.provider.as_str()).collect();
+        assert_eq!(err, ["zai", "codex"]);
+        assert_eq!(
+            result.provider_names(),
+            ["claude", "codex", "cursor", "zai"]
+        );
+        assert_eq!(
+            result.iter_ok().count() + result.iter_err().count(),
+            result.successful_count() + result.error_count()
+        );
+    }
+
+    #[test]
+    fn fetch_result_results_empty() {
+        let result = FetchResult {
+            payloads: Vec::new(),
+            errors: Vec::new(),
+        };
+        assert!(result.results().is_empty());
+        assert!(result.provider_names().is_empty());
+        assert_eq!(result.successful_count() + result.error_count(), 0);
+    }
+
+    // ------------------------------------------------------------------------
+    // CachedData tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn merge_error_times_starts_new_errors_now() {
+        let mut errors = vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+        merge_error_times(&mut errors, &[], 1_000);
+        assert_eq!(errors[0].first_seen_at, Some(1_000));
+        assert_eq!(errors[0].last_seen_at, Some(1_000));
+        assert_eq!(errors[0].failing_for(1_030), None);
+    }
+
+    #[test]
+    fn merge_error_times_keeps_first_seen_of_continuing_error() {
+        let mut previous = vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+        merge_error_times(&mut previous, &[], 1_000);
+
+        let mut errors = vec![
+            ProviderFetchError::new("codex".to_string(), "timeout again"),
+            ProviderFetchError::new("claude".to_string(), "timeout"),
+        ];
+        merge_error_times(&mut errors, &previous, 1_000 + 7_200);
+        assert_eq!(errors[0].first_seen_at, Some(1_000));
+        assert_eq!(errors[0].last_seen_at, Some(8_200));
+        assert_eq!(errors[0].failing_for(8_200).as_deref(), Some("2h"));
+        // Same kind on another provider is a different error
+        assert_eq!(errors[1].first_seen_at, Some(8_200));
+
+        // A different kind for the same provider starts over
+        let mut errors = vec![ProviderFetchError::new(
+            "codex".to_string(),
+            "OAuth token expired",
+        )];
+        merge_error_times(&mut errors, &previous, 9_000);
+        assert_eq!(errors[0].first_seen_at, Some(9_000));
+    }
+
+    #[test]
+    fn merge_error_times_restarts_resolved_then_recurring_error() {
+        let mut errors = vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+        merge_error_times(&mut errors, &[], 1_000);
+        // A clean refresh caches no errors
+        let mut resolved: Vec<ProviderFetchError> = Vec::new();
+        merge_error_times(&mut resolved, &errors, 2_000);
+
+        let mut recurring = vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+        merge_error_times(&mut recurring, &resolved, 3_000);
+        assert_eq!(recurring[0].first_seen_at, Some(3_000));
+    }
+
+    #[test]
+    fn failing_for_formats_duration() {
+        let mut error = ProviderFetchError::new("codex".to_string(), "timeout");
+        assert_eq!(error.failing_for(1_000), None);
+        error.first_seen_at = Some(0);
+        assert_eq!(error.failing_for(59), None);
+        assert_eq!(error.failing_for(15 * 60).as_deref(), Some("15m"));
+        assert_eq!(error.failing_for(150 * 60).as_deref(), Some("2h 30m"));
+        assert_eq!(error.failing_for(86_400).as_deref(), Some("1d"));
+        assert_eq!(
+            error.failing_for(86_400 + 4 * 3_600).as_deref(),
+            Some("1d 4h")
+        );
+    }
+
+    #[test]
+    fn cached_data_full_format() {
+        let payload = ProviderPayload {
+            provider: "claude".to_string(),
+            version: Some("2.0".to_string()),
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let error = ProviderFetchError {
+            provider: "codex".to_string(),
+            message: "timeout".to_string(),
+            raw: "raw error".to_string(),
+            kind: FetchErrorKind::Other,
+            first_seen_at: None,
+            last_seen_at: None,
+        };
+        let cached = CachedData::Full {
+            payloads: vec![payload.clone()],
+            errors: vec![error.clone()],
+            breakers: Breakers::new(),
+        };
+
+        assert_eq!(cached.payloads().len(), 1);
+        assert_eq!(cached.errors().len(), 1);
+
+        let (payloads, errors) = cached.into_parts();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn cached_data_legacy_format() {
+        let payload = ProviderPayload {
+            provider: "claude".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let cached = CachedData::Legacy(vec![payload]);
+
+        assert_eq!(cached.payloads().len(), 1);
+        assert_eq!(cached.errors().len(), 0); // legacy has no errors
+
+        let (payloads, errors) = cached.into_parts();
+        assert_eq!(payloads.len(), 1);
+        assert!(errors.is_empty());
+    }
+
+    // ------------------------------------------------------------------------
+    // CodexbarConfig tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn codexbar_default_args_match_legacy_command() {
+        let args = CodexbarConfig::default().render_args("claude", "oauth");
+        assert_eq!(
+            args,
+            [
+                "usage",
+                "--provider",
+                "claude",
+                "--source",
+                "oauth",
+                "--format",
+                "json",
+                "--json-only"
+            ]
+        );
+    }
+
+    #[test]
+    fn codexbar_custom_template_substitutes_placeholders() {
+        let codexbar = CodexbarConfig {
+            args_template: vec![
+                "query".to_string(),
+                "--for={provider}".to_string(),
+                "{source}".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert!(codexbar.validate().is_ok());
+        assert_eq!(
+            codexbar.render_args("zai", "api"),
+            ["query", "--for=zai", "api"]
+        );
+    }
+
+    #[test]
+    fn codexbar_template_requires_provider_placeholder() {
+        let codexbar = CodexbarConfig {
+            args_template: vec!["usage".to_string(), "--format".to_string()],
+            ..Default::default()
+        };
+        assert!(codexbar.validate().is_err());
+    }
+
+    #[test]
+    fn waybar_separator_must_not_be_empty() {
+        assert!(WaybarConfig::default().validate().is_ok());
+        let waybar = WaybarConfig {
+            separator: String::new(),
+            ..Default::default()
+        };
+        assert!(waybar.validate().is_err());
+
+        let path = temp_path("empty-separator.toml");
+        fs::write(&path, "[waybar]\nseparator = \"\"\n").unwrap();
+        let error = format!("{:#}", load_config(Some(path.clone())).unwrap_err());
+        assert!(error.contains("waybar.separator"), "{error}");
+        fs::remove_file(&path).ok();
+    }
+
+    // ------------------------------------------------------------------------
+    // fetch_single_provider tests
+    // ------------------------------------------------------------------------
+
+    fn oauth_provider(name: &str) -> EnabledProvider {
+        EnabledProvider {
+            name: name.to_string(),
+            provider_type: ProviderType::OAuth,
+            api_key: None,
+            env_var: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn fetch_single_provider_backfills_missing_provider() {
+        let spec = FakeCodexbar {
+            json: Some(r#"{"source":"oauth","usage":{"primary":{"usedPercent":5}}}"#.to_string()),
+            ..Default::default()
+        };
+        let payloads = with_fake_codexbar(&spec, |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                ..Default::default()
+            };
+            fetch_single_provider(&config, &oauth_provider("codex"), Duration::from_secs(5))
+                .unwrap()
+        });
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].provider, "codex");
+    }
+
+    #[test]
+    fn fetch_reports_truncated_output_without_failing() {
+        let spec = FakeCodexbar {
+            json: Some(r#"[{"provider":"claude","version":"2.1.12"},{"provider":"cl"#.to_string()),
+            ..Default::default()
+        };
+        let mut breakers = Breakers::new();
+        let result = with_fake_codexbar(&spec, |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                providers: ProvidersConfig {
+                    claude: Some(true),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            fetch_with_breakers(&config, &mut breakers, &BatchSupport::new(), false)
+        });
+
+        assert_eq!(result.payloads.len(), 1);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].kind, FetchErrorKind::Truncated);
+        assert!(result.errors[0].message.contains("recovered 1 payload"));
+        assert!(breakers.is_empty());
+    }
+
+    #[test]
+    fn fetch_single_provider_parses_json_from_stderr() {
+        let spec = FakeCodexbar {
+            stderr: Some(r#"{"provider":"claude","version":"2.1.12"}"#.to_string()),
+            ..Default::default()
+        };
+        let payloads = with_fake_codexbar(&spec, |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                ..Default::default()
+            };
+            fetch_single_provider(&config, &oauth_provider("claude"), Duration::from_secs(5))
+                .unwrap()
+        });
+        assert_eq!(payloads[0].provider, "claude");
+        assert_eq!(payloads[0].version.as_deref(), Some("2.1.12"));
+    }
+
+    #[test]
+    fn fetch_single_provider_non_json_stderr_is_error() {
+        let spec = FakeCodexbar {
+            stderr: Some("boom".to_string()),
+            exit_code: 1,
+            ..Default::default()
+        };
+        let error = with_fake_codexbar(&spec, |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                ..Default::default()
+            };
+            fetch_single_provider(&config, &oauth_provider("claude"), Duration::from_secs(5))
+                .unwrap_err()
+        });
+        assert!(error.to_string().contains("boom"));
+    }
+
+    #[test]
+    fn describe_output_text_binary_and_empty() {
+        assert_eq!(describe_output(b"  oops\n").as_deref(), Some("oops"));
+        assert_eq!(describe_output(b" \n"), None);
+        assert_eq!(
+            describe_output(&[0xff, 0xfe, 0x00, b'a']).as_deref(),
+            Some("codexbar produced non-text output (4 bytes)")
+        );
+        // Valid UTF-8 but full of control bytes is still binary
+        assert_eq!(
+            describe_output(b"\x00\x01\x02").as_deref(),
+            Some("codexbar produced non-text output (3 bytes)")
+        );
+    }
+
+    #[test]
+    fn clean_error_message_keeps_non_text_summary() {
+        let raw = "codexbar failed (exit status: 1) - codexbar produced non-text output (7 bytes)";
+        let error = ProviderFetchError::new("claude".to_string(), raw);
+        assert_eq!(error.message, "codexbar produced non-text output (7 bytes)");
+    }
+
+    #[test]
+    fn fetch_single_provider_reports_non_text_output() {
+        let spec = scripted(r"printf '\377\376\000junk' >&2; exit 1");
+        let error = with_fake_codexbar(&spec, |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                ..Default::default()
+            };
+            fetch_single_provider(&config, &oauth_provider("claude"), Duration::from_secs(5))
+                .unwrap_err()
+                .to_string()
+        });
+        assert!(
+            error.contains("codexbar produced non-text output (7 bytes)"),
+            "unexpected error: {error}"
+        );
+    }
+
+    #[test]
+    fn fetch_single_provider_kills_on_timeout() {
+        with_fake_codexbar(&scripted("exec sleep 5"), |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                ..Default::default()
+            };
+
+            let started = Instant::now();
+            let error = fetch_single_provider(
+                &config,
+                &oauth_provider("claude"),
+                Duration::from_millis(200),
+            )
+            .unwrap_err();
+            assert!(error.to_string().contains("timeout"));
+            assert!(started.elapsed() < Duration::from_secs(2));
+        });
+    }
+
+    #[test]
+    fn fetch_all_providers_returns_partial_results_at_deadline() {
+        let spec = scripted(
+            r#"case "$*" in
+  *claude*) echo '{"provider":"claude"}' ;;
+  *) exec sleep 5 ;;
+esac"#,
+        );
+        let started = Instant::now();
+        let result = with_fake_codexbar(&spec, |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                timeout_secs: 30,
+                ..Default::default()
+            };
+            fetch_all_providers_with_deadline(&config, Duration::from_millis(500))
+        });
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(result.payloads.len(), 1);
+        assert_eq!(result.payloads[0].provider, "claude");
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].provider, "codex");
+        assert_eq!(result.errors[0].message, "Request timed out");
+    }
+
+    #[test]
+    fn split_batch_payloads_groups_multi_provider_array() {
+        let providers = [oauth_provider("codex"), oauth_provider("claude")];
+        let payloads = parse_payload(serde_json::json!([
+            {"provider": "claude", "usage": {"primary": {"usedPercent": 19}}},
+            {"provider": "claude", "account": "work"},
+            {"provider": "codex"},
+        ]))
+        .unwrap();
+        let grouped = split_batch_payloads(payloads, &providers).unwrap();
+        assert_eq!(grouped[0].as_ref().unwrap().len(), 1);
+        assert_eq!(grouped[1].as_ref().unwrap().len(), 2);
+
+        // Nothing for codex leaves it to a per-provider fetch
+        let payloads = vec![named_payload("claude")];
+        let grouped = split_batch_payloads(payloads, &providers).unwrap();
+        assert!(grouped[0].is_none());
+
+        // A payload without an owner makes the response unusable
+        let payloads = vec![named_payload("")];
+        assert!(split_batch_payloads(payloads, &providers).is_none());
+    }
+
+    /// A codexbar that logs its arguments to `calls` next to it and runs
+    /// `script` (a `case "$*"` body) to answer.
+    fn logging_codexbar(script: &str) -> FakeCodexbar {
+        scripted(&format!(
+            "echo \"$*\" >> \"$(dirname \"$0\")/calls\"\ncase \"$*\" in\n{script}\nesac"
+        ))
+    }
+
+    /// Everything `bin` (a `logging_codexbar`) was called with.
+    fn call_log(bin: &Path) -> String {
+        fs::read_to_string(bin.with_file_name("calls")).unwrap_or_default()
+    }
+
+    /// Calls of `bin` that fetched usage, as opposed to probing.
+    fn usage_calls(bin: &Path) -> Vec<String> {
+        call_log(bin)
+            .lines()
+            .filter(|line| line.contains("--provider"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn batch_config(bin: &Path) -> TokenGaugeConfig {
+        let mut config = TokenGaugeConfig {
+            codexbar_bin: bin.display().to_string(),
+            ..Default::default()
+        };
+        config.codexbar.batch = true;
+        config
+    }
+
+    fn fetch_batched(config: &TokenGaugeConfig, support: &BatchSupport) -> FetchResult {
+        let enabled = config.providers.enabled_providers();
+        let deadline = fetch_deadline(config, enabled.len());
+        fetch_providers(config, enabled, deadline, support)
+    }
+
+    #[test]
+    fn parse_version_finds_first_version_number() {
+        assert_eq!(parse_version("CodexBar 0.18.0"), Some((0, 18, 0)));
+        assert_eq!(parse_version("codexbar v1.2"), Some((1, 2, 0)));
+        assert_eq!(parse_version("codexbar 0.19.1-beta"), Some((0, 19, 1)));
+        assert_eq!(parse_version("codexbar"), None);
+    }
+
+    #[test]
+    fn batch_fetch_uses_one_call_for_all_providers() {
+        let spec = logging_codexbar(
+            r#"  --version) echo 'CodexBar 0.18.0' ;;
+  *codex,claude*) echo '[{"provider":"codex"},{"provider":"claude"}]' ;;
+  *) exit 1 ;;"#,
+        );
+        with_fake_codexbar(&spec, |bin| {
+            let config = batch_config(bin);
+            let support = BatchSupport::new();
+
+            let result = fetch_batched(&config, &support);
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+            let names: Vec<_> = result
+                .payloads
+                .iter()
+                .map(|p| p.provider.as_str())
+                .collect();
+            assert_eq!(names, ["codex", "claude"]);
+            let usage = usage_calls(bin);
+            assert_eq!(usage.len(), 1, "{usage:?}");
+            assert!(usage[0].contains("--source oauth"), "{usage:?}");
+
+            // The probe ran once and is remembered
+            fetch_batched(&config, &support);
+            let log = call_log(bin);
+            assert_eq!(log.matches("--version").count(), 1, "{log}");
+        });
+    }
+
+    #[test]
+    fn batch_fetch_skips_codexbar_too_old_to_batch() {
+        let spec = logging_codexbar(
+            r#"  --version) echo 'CodexBar 0.17.4' ;;
+  *claude*) echo '{"provider":"claude"}' ;;
+  *) echo '{"provider":"codex"}' ;;"#,
+        );
+        with_fake_codexbar(&spec, |bin| {
+            let result = fetch_batched(&batch_config(bin), &BatchSupport::new());
+            assert_eq!(result.payloads.len(), 2);
+            let usage = usage_calls(bin);
+            assert!(usage.iter().all(|line| !line.contains(',')), "{usage:?}");
+        });
+    }
+
+    #[test]
+    fn batch_fetch_falls_back_when_rejected() {
+        let spec = logging_codexbar(
+            r#"  *,*) echo "unknown provider" >&2; exit 2 ;;
+  *claude*) echo '{"provider":"claude"}' ;;
+  *) echo '{"provider":"codex"}' ;;"#,
+        );
+        with_fake_codexbar(&spec, |bin| {
+            let config = batch_config(bin);
+            let support = BatchSupport::new();
+
+            let result = fetch_batched(&config, &support);
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+            assert_eq!(result.payloads.len(), 2);
+            assert_eq!(usage_calls(bin).len(), 3);
+
+            // The rejection is remembered, so the next refresh skips the batch
+            fetch_batched(&config, &support);
+            assert_eq!(usage_calls(bin).len(), 5);
+        });
+    }
+
+    #[test]
+    fn batch_fetch_timeout_is_not_remembered_and_keeps_fallback_budget() {
+        let spec = logging_codexbar(
+            r#"  *,*) exec sleep 5 ;;
+  *claude*) sleep 0.5; echo '{"provider":"claude"}' ;;
+  *) sleep 0.5; echo '{"provider":"codex"}' ;;"#,
+        );
+        with_fake_codexbar(&spec, |bin| {
+            let mut config = batch_config(bin);
+            config.timeout_secs = 1;
+            let support = BatchSupport::new();
+
+            // The batch uses its whole timeout, yet the fallback still has time
+            let result = fetch_batched(&config, &support);
+            assert!(result.errors.is_empty(), "{:?}", result.errors);
+            assert_eq!(result.payloads.len(), 2);
+
+            // A timeout says nothing about support, so the batch is tried again
+            assert!(support.supported(&config));
+        });
+    }
+
+    #[test]
+    fn sequential_fetch_never_overlaps_children() {
+        let spec = scripted(
+            r#"dir="$(dirname "$0")"
+mkdir "$dir/lock" 2>/dev/null || echo overlap >> "$dir/overlaps"
+sleep 0.3
+rmdir "$dir/lock" 2>/dev/null
+case "$*" in
+  *claude*) echo '{"provider":"claude"}' ;;
+  *) echo '{"provider":"codex"}' ;;
+esac"#,
+        );
+        with_fake_codexbar(&spec, |bin| {
+            let lock = bin.with_file_name("lock");
+            let overlaps = bin.with_file_name("overlaps");
+            let mut config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                timeout_secs: 1,
+                ..Default::default()
+            };
+            config.providers.zai = Some(ApiProviderConfig {
+                api_key: "key".to_string(),
+            });
+
+            // Parallel children overlap, which the lock detects
+            let result = fetch_all_providers(&config);
+            assert_eq!(result.payloads.len(), 3, "{:?}", result.errors);
+            assert!(overlaps.exists());
+
+            fs::remove_file(&overlaps).ok();
+            fs::remove_dir(&lock).ok();
+            config.fetch.mode = FetchMode::Sequential;
+            let started = Instant::now();
+            let result = fetch_all_providers(&config);
+            assert!(started.elapsed() >= Duration::from_millis(900));
+            // Three rounds fit even though together they outlast one timeout
+            assert_eq!(result.payloads.len(), 3, "{:?}", result.errors);
+            assert!(!overlaps.exists(), "two codexbar children ran at once");
+        });
+    }
+
+    // ------------------------------------------------------------------------
+    // NetworkConfig tests
+    // ------------------------------------------------------------------------
+
+    fn command_env(command: &Command, name: &str) -> Option<String> {
+        command
+            .get_envs()
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| value.map(|v| v.to_string_lossy().to_string()))
+    }
+
+    #[test]
+    fn network_env_applied_when_configured() {
+        let network = NetworkConfig {
+            https_proxy: Some("http://proxy:3128".to_string()),
+            http_proxy: None,
+            no_proxy: Some("localhost".to_string()),
+        };
+        let mut command = Command::new("codexbar");
+        apply_network_env(&mut command, &network);
+
+        assert_eq!(
+            command_env(&command, "HTTPS_PROXY").as_deref(),
+            Some("http://proxy:3128")
+        );
+        assert_eq!(
+            command_env(&command, "NO_PROXY").as_deref(),
+            Some("localhost")
+        );
+        assert!(command_env(&command, "HTTP_PROXY").is_none());
+    }
+
+    #[test]
+    fn network_env_default_inherits() {
+        let mut command = Command::new("codexbar");
+        apply_network_env(&mut command, &NetworkConfig::default());
+        assert_eq!(command.get_envs().count(), 0);
+    }
+
+    // ------------------------------------------------------------------------
+    // Error message cleaning tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn provider_fetch_error_timeout() {
+        let error = ProviderFetchError::new("codex".to_string(), "timeout after 2s");
+        assert_eq!(error.message, "Request timed out");
+        assert_eq!(error.raw, "timeout after 2s");
+    }
+
+    #[test]
+    fn provider_fetch_error_api_401() {
+        let raw = r#"codexbar failed (exit status: 1) - {"error":"Unauthorized"}"#;
+        let error = ProviderFetchError::new("kimik2".to_string(), raw);
+        assert!(error.message.contains("Unauthorized"));
+    }
+
+    #[test]
+    fn oauth_expiry_messages_are_classified() {
+        let expired = [
+            r#"codexbar failed (exit status: 1) - {"error":"invalid_grant","error_description":"Refresh token expired"}"#,
+            "codexbar failed - OAuth token has expired. Please log in again.",
+            r#"Claude API returned 401: {"error":"Unauthorized"}"#,
+            "codexbar failed - not logged in; run `codex login`",
+            "authentication failed: invalid_token",
+        ];
+        for raw in expired {
+            let error = ProviderFetchError::new("claude".to_string(), raw);
+            assert_eq!(error.kind, FetchErrorKind::OAuthExpired, "{raw}");
+            assert!(error.needs_reauth());
+        }
+
+        let other = ProviderFetchError::new("codex".to_string(), "timeout after 2s");
+        assert_eq!(other.kind, FetchErrorKind::Other);
+        // Codes and words only count on their own
+        for raw in [
+            "request failed after 4012ms",
+            "HTTP 5401 from proxy",
+            "failed to write refresh token cache",
+            "unauthorizedly slow",
+        ] {
+            let error = ProviderFetchError::new("claude".to_string(), raw);
+            assert_eq!(error.kind, FetchErrorKind::Other, "{raw}");
+        }
+        // A 401 from an API-key provider is a wrong key, not an expired login
+        let api = ProviderFetchError::new(
+            "kimik2".to_string(),
+            r#"Kimi K2 API returned 401: {"error":"Unauthorized"}"#,
+        );
+        assert_eq!(api.kind, FetchErrorKind::Other);
+    }
+
+    #[test]
+    fn fetch_error_kind_defaults_for_old_caches() {
+        let json = r#"{"provider":"claude","message":"m","raw":"r"}"#;
+        let error: ProviderFetchError = serde_json::from_str(json).unwrap();
+        assert_eq!(error.kind, FetchErrorKind::Other);
+        let value = serde_json::to_value(ProviderFetchError::new(
+            "claude".to_string(),
+            "token expired",
+        ))
+        .unwrap();
+        assert_eq!(value["kind"], "oauth_expired");
+    }
+
+    #[test]
+    fn provider_fetch_error_no_fetch_strategy() {
+        let raw = "codexbar failed - No available fetch strategy for provider";
+        let error = ProviderFetchError::new("test".to_string(), raw);
+        assert_eq!(error.message, "No available fetch strategy");
+    }
+
+    #[test]
+    fn provider_fetch_error_short_message_unchanged() {
+        let error = ProviderFetchError::new("test".to_string(), "Short error");
+        assert_eq!(error.message, "Short error");
+    }
+
+    #[test]
+    fn provider_fetch_error_long_message_truncated() {
+        let long_msg = "a".repeat(100);
+        let error = ProviderFetchError::new("test".to_string(), &long_msg);
+        assert!(error.message.len() <= 60);
+        assert!(error.message.ends_with("..."));
+    }
+
+    #[test]
+    fn provider_fetch_error_caps_raw_message() {
+        let raw = "x".repeat(100 * 1024);
+        let error = ProviderFetchError::new("test".to_string(), &raw);
+        assert!(error.raw.len() <= DEFAULT_MAX_RAW_ERROR_BYTES + 64);
+        assert!(
+            error
+                .raw
+                .starts_with(&"x".repeat(DEFAULT_MAX_RAW_ERROR_BYTES))
+        );
+        assert!(error.raw.ends_with(&format!(
+            "[truncated {} bytes]",
+            raw.len() - DEFAULT_MAX_RAW_ERROR_BYTES
+        )));
+        assert!(error.message.len() <= 60);
+
+        let short = ProviderFetchError::with_raw_limit("test".to_string(), "tiny", 16);
+        assert_eq!(short.raw, "tiny");
+    }
+
+    #[test]
+    fn provider_fetch_error_truncates_on_char_boundary() {
+        // "é" is two bytes, so a 5-byte cap falls mid-character
+        let error = ProviderFetchError::with_raw_limit("test".to_string(), "ééééé", 5);
+        assert_eq!(error.raw, "éé… [truncated 6 bytes]");
+
+        // Byte 57 falls inside a three-byte char of the message
+        let message = format!("a{}", "認証".repeat(20));
+        let error = ProviderFetchError::new("test".to_string(), &message);
+        assert_eq!(error.message, format!("a{}...", "認証".repeat(9)));
+        assert!(error.message.len() <= 60);
+    }
+
+    // ------------------------------------------------------------------------
+    // JSON parsing tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn usage_window_accepts_snake_and_camel_case() {
+        let camel: UsageWindow = serde_json::from_str(
+            r#"{"usedPercent":19,"resetDescription":"in 2h","resetsAt":"2026-01-20T12:59:00Z","windowMinutes":300}"#,
+        )
+        .unwrap();
+        let snake: UsageWindow = serde_json::from_str(
+            r#"{"used_percent":19,"reset_description":"in 2h","resets_at":"2026-01-20T12:59:00Z","window_minutes":300}"#,
+        )
+        .unwrap();
+        assert_eq!(camel, snake);
+        assert_eq!(snake.used_percent, Some(19.0));
+        assert_eq!(snake.window_minutes, Some(300));
+
+        let payloads = parse_payload_bytes(
+            br#"{"provider":"claude","usage":{"primary":{"used_percent":19},"updated_at":"2026-01-20T07:37:16Z"}}"#,
+        )
+        .unwrap();
+        let usage = payloads[0].usage.as_ref().unwrap();
+        assert_eq!(usage.updated_at.as_deref(), Some("2026-01-20T07:37:16Z"));
+
+        // Serialization stays camelCase
+        let json = serde_json::to_string(&snake).unwrap();
+        assert!(json.contains(r#""usedPercent":19.0"#), "{json}");
+        assert!(!json.contains("used_percent"), "{json}");
+    }
+
+    #[test]
+    fn parse_payload_single_object() {
+        let json = r#"{"provider":"claude","version":"2.1.12","source":"oauth"}"#;
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        let payloads = parse_payload(value).unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].provider, "claude");
+    }
+
+    #[test]
+    fn parse_payload_array() {
+        let json = r#"[{"provider":"claude"},{"provider":"codex"}]"#;
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        let payloads = parse_payload(value).unwrap();
+        assert_eq!(payloads.len(), 2);
+    }
+
+    #[test]
+    fn remap_fields_renames_nested_keys() {
+        let fields = BTreeMap::from([
+            ("percent".to_string(), "usedPercent".to_string()),
+            ("window".to_string(), "windowMinutes".to_string()),
+        ]);
+        let mut value: serde_json::Value =
+            serde_json::from_str(r#"{"percent":42.5,"window":300,"resetsAt":null}"#).unwrap();
+        remap_fields(&mut value, &fields);
+        let window: UsageWindow = serde_json::from_value(value).unwrap();
+        assert_eq!(window.used_percent, Some(42.5));
+        assert_eq!(window.window_minutes, Some(300));
+    }
+
+    #[test]
+    fn parse_provider_output_applies_field_mapping() {
+        use std::os::unix::process::ExitStatusExt;
+        let codexbar = CodexbarConfig {
+            fields: BTreeMap::from([("percent".to_string(), "usedPercent".to_string())]),
+            ..Default::default()
+        };
+        let output = Output {
+            status: std::process::ExitStatus::from_raw(0),
+            stdout: br#"[{"provider":"claude","usage":{"primary":{"percent":19}}},{"provider":"codex"}]"#
+                .to_vec(),
+            stderr: Vec::new(),
+        };
+        let payloads = parse_provider_output(&output, &codexbar).unwrap();
+        let primary = payloads[0]
+            .usage
+            .as_ref()
+            .unwrap()
+            .primary
+            .as_ref()
+            .unwrap();
+        assert_eq!(primary.used_percent, Some(19.0));
+
+        // Without the mapping the unknown key is ignored
+        let payloads = parse_provider_output(&output, &CodexbarConfig::default()).unwrap();
+        let primary = payloads[0]
+            .usage
+            .as_ref()
+            .unwrap()
+            .primary
+            .as_ref()
+            .unwrap();
+        assert_eq!(primary.used_percent, None);
+    }
+
+    #[test]
+    fn parse_payload_bytes_valid() {
+        let json = br#"{"provider":"claude","version":"2.1.12"}"#;
+        let payloads = parse_payload_bytes(json).unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].version, Some("2.1.12".to_string()));
+    }
 
     #[test]
-    fn format_window_with_resets_at() {
-        // Use a time 2 hours and 30 minutes in the future
-        let future = Utc::now() + chrono::Duration::hours(2) + chrono::Duration::minutes(30);
-        let window = UsageWindow {
-            used_percent: Some(42),
-            reset_description: Some("Jan 20 at 12:59PM".to_string()),
-            resets_at: Some(future.to_rfc3339()),
-            window_minutes: Some(300),
+    fn parse_payload_bytes_ndjson() {
+        let ndjson = b"{\"provider\":\"claude\"}\n\n{\"provider\":\"codex\"}\n";
+        let payloads = parse_payload_bytes(ndjson).unwrap();
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].provider, "claude");
+        assert_eq!(payloads[1].provider, "codex");
+    }
+
+    #[test]
+    fn parse_payload_bytes_single_array_and_ndjson_agree() {
+        let single = parse_payload_bytes(br#"{"provider":"claude"}"#).unwrap();
+        let array =
+            parse_payload_bytes(br#"[{"provider":"claude"},{"provider":"codex"}]"#).unwrap();
+        let ndjson =
+            parse_payload_bytes(b"{\"provider\":\"claude\"}\r\n{\"provider\":\"codex\"}").unwrap();
+
+        assert_eq!(single.len(), 1);
+        assert_eq!(array.len(), 2);
+        assert_eq!(ndjson.len(), 2);
+        assert_eq!(array[1].provider, ndjson[1].provider);
+    }
+
+    #[test]
+    fn parse_payload_bytes_ndjson_with_bad_line_fails() {
+        let ndjson = b"{\"provider\":\"claude\"}\nnot json\n";
+        assert!(parse_payload_bytes(ndjson).is_err());
+    }
+
+    #[test]
+    fn parse_payload_bytes_recovers_truncated_output() {
+        let full = r#"[{"provider":"claude","version":"2.1.12"},{"provider":"codex","version":"0.5 \"beta\" ]}"},{"provider":"zai"}]"#;
+        let providers_after = |cut: &str| {
+            let end = full.find(cut).unwrap() + cut.len();
+            parse_payload_bytes(&full.as_bytes()[..end])
+                .map(|payloads| {
+                    payloads
+                        .into_iter()
+                        .map(|payload| payload.provider)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
         };
-        let (used, minutes, reset) = format_window(Some(window));
-        assert_eq!(used, Some(42));
-        assert_eq!(minutes, Some(300));
-        // Allow for slight timing variations (29-30m)
+        // Cut after a complete element, after its comma, inside a key
+        assert_eq!(providers_after(r#""2.1.12"}"#), ["claude"]);
+        assert_eq!(providers_after(r#""2.1.12"},"#), ["claude"]);
+        assert_eq!(providers_after(r#"},{"prov"#), ["claude"]);
+        // Brackets inside strings and escaped quotes don't confuse it
+        assert_eq!(providers_after(r#"beta\" ]}"#), ["claude"]);
+        assert_eq!(providers_after(r#"beta\""#), ["claude"]);
+        assert_eq!(providers_after(r#""zai"}"#), ["claude", "codex", "zai"]);
+        // Nothing complete yet
+        assert!(parse_payload_bytes(br#"[{"provider":"cla"#).is_err());
+        assert!(parse_payload_bytes(br#"{"provider":"claude","usage":{"primary":{}}"#).is_err());
+
+        // Trailing partial value after a complete object
+        let payloads =
+            parse_payload_bytes(b"{\"provider\":\"claude\"}\n{\"provider\":\"co").unwrap();
+        assert_eq!(payloads.len(), 1);
+
+        // Cut inside a multi-byte character
+        let text = r#"[{"provider":"claude","version":"é"},{"provider":"codex","version":"é"#;
+        let bytes = &text.as_bytes()[..text.len() - 1];
+        assert_eq!(parse_payload_bytes(bytes).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn parse_payload_bytes_invalid_json() {
+        let json = b"not valid json";
+        let result = parse_payload_bytes(json);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_payload_with_full_usage() {
+        let json = r#"{
+            "provider": "claude",
+            "version": "2.1.12",
+            "source": "oauth",
+            "usage": {
+                "primary": {
+                    "usedPercent": 19,
+                    "resetDescription": "Jan 20 at 12:59PM",
+                    "resetsAt": "2026-01-20T12:59:00Z",
+                    "windowMinutes": 300
+                },
+                "secondary": {
+                    "usedPercent": 12,
+                    "resetDescription": "Jan 26 at 8:59AM",
+                    "resetsAt": "2026-01-26T08:59:00Z",
+                    "windowMinutes": 10080
+                },
+                "updatedAt": "2026-01-20T07:37:16Z"
+            },
+            "credits": null,
+            "error": null
+        }"#;
+        let payloads = parse_payload_bytes(json.as_bytes()).unwrap();
+        assert_eq!(payloads.len(), 1);
+
+        let payload = &payloads[0];
+        assert_eq!(payload.provider, "claude");
+        assert!(!payload.has_error());
+
+        let usage = payload.usage.as_ref().unwrap();
+        let primary = usage.primary.as_ref().unwrap();
+        assert_eq!(primary.used_percent, Some(19.0));
+        assert_eq!(primary.window_minutes, Some(300));
+    }
+
+    #[test]
+    fn credits_accept_integer_float_string_and_null() {
+        let remaining = |json: &str| {
+            serde_json::from_str::<Credits>(json)
+                .map(|credits| credits.remaining)
+                .map_err(|error| error.to_string())
+        };
+        assert_eq!(remaining(r#"{"remaining": 1234}"#), Ok(Some(1234.0)));
+        assert_eq!(remaining(r#"{"remaining": 12.5}"#), Ok(Some(12.5)));
+        assert_eq!(remaining(r#"{"remaining": " 1234 "}"#), Ok(Some(1234.0)));
+        assert_eq!(remaining(r#"{"remaining": "0.75"}"#), Ok(Some(0.75)));
+        assert_eq!(remaining(r#"{"remaining": null}"#), Ok(None));
+        assert_eq!(remaining("{}"), Ok(None));
+        assert!(remaining(r#"{"remaining": "lots"}"#).is_err());
+    }
+
+    #[test]
+    fn credits_parse_total_and_used() {
+        let credits: Credits =
+            serde_json::from_str(r#"{"remaining": 25, "total": 100, "used": 75}"#).unwrap();
+        assert_eq!(credits.total, Some(100.0));
+        assert_eq!(credits.used, Some(75.0));
+        assert_eq!(credits.used_percent(), Some(75.0));
+
+        // Remaining is derived from total and used when missing
+        let credits: Credits = serde_json::from_str(r#"{"total": 50, "used": "10"}"#).unwrap();
+        assert_eq!(credits.remaining_amount(), Some(40.0));
+        assert_eq!(credits.used_percent(), Some(20.0));
+
+        // No percentage without a positive limit
+        let credits: Credits = serde_json::from_str(r#"{"remaining": 5, "total": 0}"#).unwrap();
+        assert_eq!(credits.used_percent(), None);
+        let credits: Credits = serde_json::from_str(r#"{"remaining": 5}"#).unwrap();
+        assert_eq!(credits.used_percent(), None);
+    }
+
+    #[test]
+    fn payload_with_string_credits_is_kept() {
+        let json = r#"{"provider": "claude", "credits": {"remaining": "42"}}"#;
+        let payload: ProviderPayload = serde_json::from_str(json).unwrap();
+        let rows = payload_to_rows(vec![payload]);
+        assert_eq!(rows[0].credits, "42.00");
+    }
+
+    // ------------------------------------------------------------------------
+    // build_command_preview tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn build_codexbar_command_sets_program_args_and_env() {
+        let config = TokenGaugeConfig {
+            codexbar_bin: "/usr/bin/codexbar".to_string(),
+            network: NetworkConfig {
+                https_proxy: Some("http://proxy:3128".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = EnabledProvider {
+            name: "zai".to_string(),
+            provider_type: ProviderType::Api,
+            api_key: Some("sk-secret".to_string()),
+            env_var: Some("ZAI_API_TOKEN"),
+            source: None,
+        };
+        let command = build_codexbar_command(&config, &provider);
+        assert_eq!(command.get_program(), "/usr/bin/codexbar");
+        let args: Vec<_> = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(args, config.codexbar.render_args("zai", "api"));
+        assert!(args.contains(&"zai".to_string()));
+        let envs: BTreeMap<_, _> = command
+            .get_envs()
+            .map(|(name, value)| {
+                (
+                    name.to_string_lossy().into_owned(),
+                    value.map(|value| value.to_string_lossy().into_owned()),
+                )
+            })
+            .collect();
+        assert_eq!(envs["ZAI_API_TOKEN"].as_deref(), Some("sk-secret"));
+        assert_eq!(envs["HTTPS_PROXY"].as_deref(), Some("http://proxy:3128"));
+    }
+
+    #[test]
+    fn command_preview_redacts_api_key() {
+        let config = TokenGaugeConfig {
+            codexbar_bin: "/opt/codex bar/codexbar".to_string(),
+            ..Default::default()
+        };
+        let provider = EnabledProvider {
+            name: "zai".to_string(),
+            provider_type: ProviderType::Api,
+            api_key: Some("sk-secret".to_string()),
+            env_var: Some("ZAI_API_TOKEN"),
+            source: None,
+        };
+        let preview = build_command_preview(&config, &provider);
+        assert!(!preview.contains("sk-secret"), "{preview}");
+        assert!(preview.starts_with("ZAI_API_TOKEN=<redacted> '/opt/codex bar/codexbar' "));
+        assert!(preview.contains("--provider zai"), "{preview}");
+        assert!(preview.contains("--source api"), "{preview}");
+    }
+
+    #[test]
+    fn command_preview_for_oauth_has_no_env() {
+        let preview =
+            build_command_preview(&TokenGaugeConfig::default(), &oauth_provider("claude"));
+        assert!(preview.starts_with("codexbar "), "{preview}");
+        assert!(preview.contains("--provider claude"), "{preview}");
+        assert!(preview.contains("--source oauth"), "{preview}");
+        assert!(!preview.contains("<redacted>"));
+    }
+
+    #[test]
+    fn source_override_reaches_command_args() {
+        let toml = "[providers]\nclaude = true\n[providers.source]\nclaude = \"api\"\n";
+        let config = load_config_from_reader(toml.as_bytes(), Path::new("stdin"), None).unwrap();
+        let providers = config.providers.enabled_providers();
+        assert_eq!(providers[0].source, Some(ProviderSource::Api));
+        let preview = build_command_preview(&config, &providers[0]);
+        assert!(preview.contains("--source api"), "{preview}");
+        // Providers looked up outside the enabled list keep the override too
+        let claude = get_provider_info("claude").unwrap();
+        assert_eq!(
+            config.providers.configured(claude).source,
+            Some(ProviderSource::Api)
+        );
+
+        let mut provider = oauth_provider("claude");
+        provider.source = Some(ProviderSource::Auto);
+        let preview = build_command_preview(&config, &provider);
+        assert!(preview.contains("--source auto"), "{preview}");
+    }
+
+    #[test]
+    fn invalid_source_override_is_rejected() {
+        let load = |toml: &str| {
+            load_config_from_reader(toml.as_bytes(), Path::new("stdin"), None)
+                .map(|_| ())
+                .unwrap_err()
+        };
+        let error = load("[providers.source]\nclaude = \"web\"\n");
+        assert_eq!(error.to_string(), "failed to parse config at stdin");
         assert!(
-            reset.starts_with("in 2h 2") || reset.starts_with("in 2h 30"),
-            "unexpected reset: {}",
-            reset
+            format!("{error:#}").contains("unknown variant"),
+            "{error:#}"
+        );
+
+        let error = load("[providers.source]\nclaud = \"api\"\n");
+        assert!(
+            format!("{error:#}").contains("unknown provider \"claud\""),
+            "{error:#}"
+        );
+    }
+
+    // ------------------------------------------------------------------------
+    // version_report tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn version_report_with_missing_codexbar() {
+        let config = TokenGaugeConfig {
+            codexbar_bin: temp_path("missing-codexbar").display().to_string(),
+            ..Default::default()
+        };
+        let report = version_report("1.2.3", Path::new("/tmp/config.toml"), &config);
+        assert_eq!(report["tokengauge"], "1.2.3");
+        assert!(report["codexbar"].is_null());
+        assert_eq!(report["config"], "/tmp/config.toml");
+    }
+
+    #[test]
+    fn version_report_reads_codexbar_version() {
+        let report = with_fake_codexbar(&scripted("echo 'CodexBar 0.18.0'"), |bin| {
+            let config = TokenGaugeConfig {
+                codexbar_bin: bin.display().to_string(),
+                ..Default::default()
+            };
+            version_report("1.2.3", Path::new("/tmp/config.toml"), &config)
+        });
+        assert_eq!(report["codexbar"], "CodexBar 0.18.0");
+    }
+
+    // ------------------------------------------------------------------------
+    // payload_to_rows tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn total_credits_sums_api_providers_only() {
+        let with_credits = |name: &str, remaining: Option<f64>| ProviderRow {
+            credits_remaining: remaining,
+            ..test_row(name, "08:00")
+        };
+        let rows = [
+            with_credits("zai", Some(100.25)),
+            with_credits("kimi", Some(28.15)),
+            // OAuth credits (e.g. Codex) aren't prepaid API credits
+            with_credits("codex", Some(50.0)),
+            with_credits("minimax", None),
+        ];
+        let total = total_credits(&rows).unwrap();
+        assert!((total - 128.40).abs() < 1e-9, "{total}");
+
+        assert_eq!(total_credits(&rows[2..]), None);
+        assert_eq!(total_credits(&[with_credits("zai", Some(0.0))]), None);
+        assert_eq!(total_credits(&[]), None);
+    }
+
+    fn minutes_window(used: f64, minutes: Option<u32>) -> UsageWindow {
+        UsageWindow {
+            used_percent: Some(used),
+            reset_description: None,
+            resets_at: None,
+            window_minutes: minutes,
+        }
+    }
+
+    fn windows_payload(primary: UsageWindow, secondary: UsageWindow) -> ProviderPayload {
+        let mut payload = named_payload("claude");
+        payload.usage = Some(UsageSnapshot {
+            primary: Some(primary),
+            secondary: Some(secondary),
+            updated_at: None,
+        });
+        payload
+    }
+
+    #[test]
+    fn payload_to_rows_orders_reversed_windows_by_length() {
+        let payload = windows_payload(
+            minutes_window(12.0, Some(10080)),
+            minutes_window(19.0, Some(300)),
+        );
+        let row = payload_to_rows(vec![payload]).remove(0);
+        assert_eq!(row.session_used, Some(19));
+        assert_eq!(row.session_window_minutes, Some(300));
+        assert_eq!(row.weekly_used, Some(12));
+        assert_eq!(row.weekly_window_minutes, Some(10080));
+    }
+
+    #[test]
+    fn payload_to_rows_keeps_order_without_minutes() {
+        let payload = windows_payload(
+            minutes_window(12.0, Some(10080)),
+            minutes_window(19.0, None),
+        );
+        let row = payload_to_rows(vec![payload]).remove(0);
+        assert_eq!(row.session_used, Some(12));
+        assert_eq!(row.weekly_used, Some(19));
+
+        let usage = windows_payload(
+            minutes_window(12.0, Some(43200)),
+            minutes_window(19.0, Some(10080)),
+        )
+        .usage
+        .unwrap();
+        assert_eq!(usage.session_window().unwrap().window_minutes, Some(10080));
+        assert_eq!(usage.weekly_window().unwrap().window_minutes, Some(43200));
+    }
+
+    #[test]
+    fn payload_to_rows_filters_errors() {
+        let good = ProviderPayload {
+            provider: "claude".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let bad = ProviderPayload {
+            provider: "codex".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: Some(ProviderError {
+                message: Some("error".to_string()),
+                code: None,
+                kind: None,
+            }),
+        };
+        let rows = payload_to_rows(vec![good, bad]);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].provider, "Claude");
+    }
+
+    #[test]
+    fn payload_to_rows_formats_credits() {
+        let payload = ProviderPayload {
+            provider: "zai".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: Some(Credits {
+                remaining: Some(42.567),
+                total: None,
+                used: None,
+            }),
+            error: None,
+        };
+        let rows = payload_to_rows(vec![payload]);
+        assert_eq!(rows[0].credits, "42.57"); // 2 decimal places
+        assert_eq!(rows[0].credits_used_percent, None);
+    }
+
+    #[test]
+    fn payload_to_rows_computes_credits_used_percent() {
+        let json = r#"{"provider": "zai", "credits": {"remaining": 42, "total": "100"}}"#;
+        let payload: ProviderPayload = serde_json::from_str(json).unwrap();
+        let row = payload_to_rows(vec![payload]).remove(0);
+        assert_eq!(row.credits, "42.00/100.00");
+        assert_eq!(row.credits_used_percent, Some(58.0));
+    }
+
+    #[test]
+    fn payload_to_rows_formats_source() {
+        // Both version and source
+        let payload1 = ProviderPayload {
+            provider: "claude".to_string(),
+            version: Some("2.1.12".to_string()),
+            source: Some("oauth".to_string()),
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let rows = payload_to_rows(vec![payload1]);
+        assert_eq!(rows[0].version.as_deref(), Some("2.1.12"));
+        assert_eq!(rows[0].source_kind.as_deref(), Some("oauth"));
+        assert_eq!(rows[0].source_text(), "2.1.12 (oauth)");
+
+        // Only version
+        let payload2 = ProviderPayload {
+            provider: "claude".to_string(),
+            version: Some("2.1.12".to_string()),
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let rows = payload_to_rows(vec![payload2]);
+        assert_eq!(rows[0].version.as_deref(), Some("2.1.12"));
+        assert_eq!(rows[0].source_kind, None);
+        assert_eq!(rows[0].source_text(), "2.1.12");
+
+        // Only source
+        let payload3 = ProviderPayload {
+            provider: "claude".to_string(),
+            version: None,
+            source: Some("oauth".to_string()),
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let rows = payload_to_rows(vec![payload3]);
+        assert_eq!(rows[0].version, None);
+        assert_eq!(rows[0].source_kind.as_deref(), Some("oauth"));
+        assert_eq!(rows[0].source_text(), "oauth");
+
+        // Neither
+        let payload4 = ProviderPayload {
+            provider: "claude".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let rows = payload_to_rows(vec![payload4]);
+        assert_eq!(rows[0].version, None);
+        assert_eq!(rows[0].source_kind, None);
+        assert_eq!(rows[0].source_text(), "—");
+    }
+
+    // ------------------------------------------------------------------------
+    // version_changes tests
+    // ------------------------------------------------------------------------
+
+    fn versioned(provider: &str, version: Option<&str>) -> ProviderPayload {
+        ProviderPayload {
+            provider: provider.to_string(),
+            version: version.map(str::to_string),
+            source: Some("oauth".to_string()),
+            usage: None,
+            credits: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn version_changes_detects_upgrades() {
+        let old = vec![
+            versioned("claude", Some("2.1.12")),
+            versioned("codex", Some("0.40.0")),
+        ];
+        let new = vec![
+            versioned("claude", Some("2.2.0")),
+            versioned("codex", Some("0.40.0")),
+        ];
+        assert_eq!(
+            version_changes(&old, &new),
+            vec![(
+                "claude".to_string(),
+                "2.1.12".to_string(),
+                "2.2.0".to_string()
+            )]
         );
     }
 
     #[test]
-    fn format_window_falls_back_to_description() {
-        // When resets_at is missing, fall back to description
-        let window = UsageWindow {
-            used_percent: Some(42),
-            reset_description: Some("Jan 20 at 12:59PM".to_string()),
-            resets_at: None,
-            window_minutes: Some(300),
-        };
-        let (used, minutes, reset) = format_window(Some(window));
-        assert_eq!(used, Some(42));
-        assert_eq!(minutes, Some(300));
-        assert_eq!(reset, "Jan 20 at 12:59PM");
+    fn version_changes_ignores_unchanged_and_unknown() {
+        let old = vec![
+            versioned("claude", Some("2.1.12")),
+            versioned("codex", None),
+        ];
+        let new = vec![
+            versioned("claude", Some("2.1.12")),
+            versioned("codex", Some("0.41.0")),
+            versioned("zai", Some("1.0.0")),
+        ];
+        assert!(version_changes(&old, &new).is_empty());
+        assert!(version_changes(&[], &new).is_empty());
+    }
+
+    // ------------------------------------------------------------------------
+    // aggregate_accounts tests
+    // ------------------------------------------------------------------------
+
+    fn account_row(name: &str, session: Option<f64>, credits: Option<f64>) -> ProviderRow {
+        ProviderRow {
+            provider: provider_label(name).to_string(),
+            name: name.to_string(),
+            session_used: session.map(|used| used.round() as u8),
+            session_percent: session,
+            session_window_minutes: Some(300),
+            session_reset: "in 1h".to_string(),
+            session_reset_at: None,
+            weekly_used: Some(20),
+            weekly_percent: Some(20.0),
+            weekly_window_minutes: Some(10080),
+            weekly_reset: "Mon".to_string(),
+            weekly_reset_at: None,
+            credits: format_credits(credits, None),
+            credits_remaining: credits,
+            credits_total: None,
+            credits_used_percent: None,
+            version: None,
+            source_kind: None,
+            updated: "08:00".to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
+        }
     }
 
     #[test]
-    fn format_window_clamps_over_100() {
-        let window = UsageWindow {
-            used_percent: Some(150),
-            reset_description: None,
-            resets_at: None,
-            window_minutes: None,
-        };
-        let (used, _, _) = format_window(Some(window));
-        assert_eq!(used, Some(100)); // clamped to 100
+    fn mark_from_cache_reaches_folded_accounts() {
+        let rows = vec![
+            account_row("claude", Some(30.0), None),
+            account_row("claude", Some(70.0), None),
+        ];
+        let mut aggregated = aggregate_accounts(rows, AccountAggregation::Max);
+        assert!(!aggregated[0].from_cache);
+
+        mark_from_cache(&mut aggregated);
+        assert!(aggregated[0].from_cache);
+        assert!(aggregated[0].accounts.iter().all(|row| row.from_cache));
     }
 
     #[test]
-    fn format_window_none() {
-        let (used, minutes, reset) = format_window(None);
-        assert_eq!(used, None);
-        assert_eq!(minutes, None);
-        assert_eq!(reset, "—");
+    fn aggregate_accounts_max_sums_credits() {
+        let rows = vec![
+            account_row("claude", Some(30.0), Some(10.5)),
+            account_row("codex", Some(5.0), None),
+            account_row("claude", Some(70.0), Some(2.25)),
+        ];
+        let aggregated = aggregate_accounts(rows, AccountAggregation::Max);
+
+        assert_eq!(aggregated.len(), 2);
+        let claude = &aggregated[0];
+        assert_eq!(claude.provider, "Claude (2 accounts)");
+        assert_eq!(claude.session_percent, Some(70.0));
+        assert_eq!(claude.session_used, Some(70));
+        assert_eq!(claude.weekly_percent, Some(20.0));
+        assert_eq!(claude.credits, "12.75");
+        assert_eq!(claude.session_reset, "in 1h");
+        assert_eq!(claude.accounts.len(), 2);
+        // Single-account providers pass through unchanged
+        assert_eq!(aggregated[1].provider, "Codex");
+        assert!(aggregated[1].accounts.is_empty());
     }
 
     #[test]
-    fn format_window_missing_both_resets_at_and_description() {
-        let window = UsageWindow {
-            used_percent: Some(50),
-            reset_description: None,
-            resets_at: None,
-            window_minutes: Some(60),
-        };
-        let (_, _, reset) = format_window(Some(window));
-        assert_eq!(reset, "—");
+    fn aggregate_accounts_avg_skips_missing_usage() {
+        let rows = vec![
+            account_row("claude", Some(30.0), Some(1.0)),
+            account_row("claude", Some(45.0), None),
+            account_row("claude", None, Some(3.0)),
+        ];
+        let aggregated = aggregate_accounts(rows, AccountAggregation::Avg);
+
+        assert_eq!(aggregated.len(), 1);
+        assert_eq!(aggregated[0].provider, "Claude (3 accounts)");
+        assert_eq!(aggregated[0].session_percent, Some(37.5));
+        assert_eq!(aggregated[0].session_used, Some(38));
+        assert_eq!(aggregated[0].credits, "4.00");
     }
 
     #[test]
-    fn format_window_minutes_only() {
-        // Use a time 45 minutes in the future
-        let future = Utc::now() + chrono::Duration::minutes(45);
-        let window = UsageWindow {
-            used_percent: Some(10),
-            reset_description: None,
-            resets_at: Some(future.to_rfc3339()),
-            window_minutes: Some(60),
-        };
-        let (_, _, reset) = format_window(Some(window));
-        // Allow for slight timing variations (44-45m)
-        assert!(
-            reset == "in 44m" || reset == "in 45m",
-            "unexpected reset: {}",
-            reset
-        );
+    fn display_rows_aggregates_only_when_enabled() {
+        let payloads = || vec![named_payload("claude"), named_payload("claude")];
+        let mut display = DisplayConfig::default();
+        assert_eq!(display_rows(payloads(), &display).len(), 2);
+        display.aggregate_accounts = true;
+        let rows = display_rows(payloads(), &display);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].provider, "Claude (2 accounts)");
     }
 
     // ------------------------------------------------------------------------
-    // format_updated tests
+    // carry_forward_stale_rows tests
     // ------------------------------------------------------------------------
 
-    #[test]
-    fn format_updated_rfc3339() {
-        // Full RFC3339 timestamp should be formatted to local time HH:MM
-        let result = format_updated(Some("2026-01-20T07:37:16Z".to_string()));
-        // We can't assert exact time due to timezone, but it should be HH:MM format
-        assert!(result.len() == 5 || result.len() <= 8); // "HH:MM" or with timezone offset
-        assert!(result.contains(':'));
+    fn test_row(name: &str, updated: &str) -> ProviderRow {
+        ProviderRow {
+            provider: provider_label(name).to_string(),
+            name: name.to_string(),
+            session_used: Some(10),
+            session_percent: Some(10.0),
+            session_window_minutes: None,
+            session_reset: "—".to_string(),
+            session_reset_at: None,
+            weekly_used: None,
+            weekly_percent: None,
+            weekly_window_minutes: None,
+            weekly_reset: "—".to_string(),
+            weekly_reset_at: None,
+            credits: "—".to_string(),
+            credits_remaining: None,
+            credits_total: None,
+            credits_used_percent: None,
+            version: None,
+            source_kind: None,
+            updated: updated.to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
+        }
     }
 
     #[test]
-    fn format_updated_iso_with_t() {
-        // ISO format with T separator, extracts time part
-        let result = format_updated(Some("2026-01-20T14:30:00Z".to_string()));
-        assert!(result.contains(':'));
+    fn carry_forward_marks_failed_provider_stale() {
+        let previous = vec![test_row("claude", "07:00"), test_row("codex", "07:00")];
+        let mut rows = vec![test_row("claude", "08:00")];
+        let errors = vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+
+        carry_forward_stale_rows(&mut rows, &previous, &errors);
+
+        assert_eq!(rows.len(), 2);
+        assert!(!rows[0].is_stale);
+        let codex = &rows[1];
+        assert_eq!(codex.provider, "Codex");
+        assert!(codex.is_stale);
+        assert_eq!(codex.stale_since.as_deref(), Some("07:00"));
     }
 
     #[test]
-    fn format_updated_none() {
-        assert_eq!(format_updated(None), "—");
+    fn carry_forward_keeps_original_stale_since() {
+        let mut old = test_row("codex", "07:00");
+        old.is_stale = true;
+        old.stale_since = Some("06:00".to_string());
+        let mut rows = Vec::new();
+        let errors = vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+
+        carry_forward_stale_rows(&mut rows, &[old], &errors);
+
+        assert_eq!(rows[0].stale_since.as_deref(), Some("06:00"));
     }
 
     #[test]
-    fn format_updated_fallback() {
-        // Unknown format returns as-is
-        let result = format_updated(Some("unknown format".to_string()));
-        assert_eq!(result, "unknown format");
+    fn carry_forward_adds_placeholder_without_history() {
+        let mut rows = Vec::new();
+        let errors = vec![
+            ProviderFetchError::new("codex".to_string(), "timeout"),
+            ProviderFetchError::new(
+                "claude".to_string(),
+                "codexbar failed - OAuth token has expired",
+            ),
+        ];
+        carry_forward_stale_rows(&mut rows, &[], &errors);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].provider, "Codex");
+        assert_eq!(rows[0].session_used, None);
+        assert_eq!(rows[0].weekly_reset, "—");
+        assert_eq!(rows[0].updated, "failed");
+        assert!(!rows[0].is_stale);
+        assert_eq!(rows[1].updated, "re-login");
+
+        // A second failure keeps the placeholder rather than marking it stale
+        let previous = rows;
+        let mut rows = Vec::new();
+        carry_forward_stale_rows(&mut rows, &previous, &errors[..1]);
+        assert_eq!(rows[0].updated, "failed");
+        assert!(!rows[0].is_stale);
     }
 
     // ------------------------------------------------------------------------
-    // provider_label tests
+    // ProviderRow serialization tests
     // ------------------------------------------------------------------------
 
     #[test]
-    fn provider_label_known_providers() {
-        assert_eq!(provider_label("claude"), "Claude");
-        assert_eq!(provider_label("codex"), "Codex");
-        assert_eq!(provider_label("zai"), "z.ai");
-        assert_eq!(provider_label("kimik2"), "Kimi K2");
+    fn provider_row_to_json_full() {
+        let row = ProviderRow {
+            provider: "Claude".to_string(),
+            name: "claude".to_string(),
+            session_used: Some(19),
+            session_percent: Some(19.0),
+            session_window_minutes: Some(300),
+            session_reset: "in 2h 30m".to_string(),
+            session_reset_at: None,
+            weekly_used: Some(12),
+            weekly_percent: Some(12.0),
+            weekly_window_minutes: Some(10080),
+            weekly_reset: "Jan 26 at 8:59AM".to_string(),
+            weekly_reset_at: None,
+            credits: "42.57".to_string(),
+            credits_remaining: Some(42.57),
+            credits_total: None,
+            credits_used_percent: None,
+            version: Some("2.1.12".to_string()),
+            source_kind: Some("oauth".to_string()),
+            updated: "07:37".to_string(),
+            updated_age_secs: None,
+            is_stale: true,
+            stale_since: Some("07:00".to_string()),
+            from_cache: false,
+            accounts: Vec::new(),
+        };
+        let json = row.to_json();
+        assert_eq!(json["provider"], "Claude");
+        assert_eq!(json["fromCache"], false);
+        assert_eq!(json["sessionUsed"], 19);
+        assert_eq!(json["sessionWindowMinutes"], 300);
+        assert_eq!(json["sessionReset"], "in 2h 30m");
+        assert_eq!(json["weeklyUsed"], 12);
+        assert_eq!(json["weeklyWindowMinutes"], 10080);
+        assert_eq!(json["weeklyReset"], "Jan 26 at 8:59AM");
+        assert_eq!(json["credits"], "42.57");
+        assert_eq!(json["source"], "2.1.12 (oauth)");
+        assert_eq!(json["version"], "2.1.12");
+        assert_eq!(json["sourceKind"], "oauth");
+        assert_eq!(json["updated"], "07:37");
+        assert_eq!(json["isStale"], true);
+        assert_eq!(json["staleSince"], "07:00");
     }
 
     #[test]
-    fn provider_label_unknown_returns_input() {
-        assert_eq!(provider_label("unknown_provider"), "unknown_provider");
+    fn provider_row_to_json_empty() {
+        let payload = ProviderPayload {
+            provider: "codex".to_string(),
+            version: None,
+            source: None,
+            usage: None,
+            credits: None,
+            error: None,
+        };
+        let row = payload_to_rows(vec![payload]).remove(0);
+        let json = row.to_json();
+        assert_eq!(json["provider"], "Codex");
+        assert!(json["sessionUsed"].is_null());
+        assert!(json["weeklyUsed"].is_null());
+        assert!(json["sessionWindowMinutes"].is_null());
+        assert_eq!(json["sessionReset"], "—");
+        assert_eq!(json["credits"], "—");
+        assert_eq!(json["isStale"], false);
+        assert!(json["staleSince"].is_null());
     }
 
     // ------------------------------------------------------------------------
-    // get_provider_info tests
+    // CircuitBreaker tests
     // ------------------------------------------------------------------------
 
-    #[test]
-    fn get_provider_info_oauth_provider() {
-        let info = get_provider_info("claude").unwrap();
-        assert_eq!(info.name, "claude");
-        assert_eq!(info.provider_type, ProviderType::OAuth);
-        assert!(info.env_var.is_none());
+    /// Breaker settings with pausing switched on after three failures.
+    fn enabled_breaker() -> BreakerConfig {
+        BreakerConfig {
+            failure_threshold: 3,
+            ..Default::default()
+        }
     }
 
     #[test]
-    fn get_provider_info_api_provider() {
-        let info = get_provider_info("zai").unwrap();
-        assert_eq!(info.name, "zai");
-        assert_eq!(info.provider_type, ProviderType::Api);
-        assert_eq!(info.env_var, Some("ZAI_API_TOKEN"));
+    fn breaker_stays_closed_below_threshold() {
+        let config = enabled_breaker();
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker::default();
+        assert_eq!(breaker.state(now, &config), BreakerState::Closed);
+
+        breaker.record_failure(now, &config);
+        breaker.record_failure(now, &config);
+        assert_eq!(breaker.state(now, &config), BreakerState::Closed);
+        assert!(breaker.open_until.is_none());
     }
 
     #[test]
-    fn get_provider_info_unknown() {
-        assert!(get_provider_info("nonexistent").is_none());
-    }
+    fn breaker_opens_then_half_opens_after_cooldown() {
+        let config = enabled_breaker();
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker::default();
+        for _ in 0..3 {
+            breaker.record_failure(now, &config);
+        }
+        assert_eq!(breaker.state(now, &config), BreakerState::Open);
+        assert_eq!(breaker.open_until, Some(now.timestamp() + 600));
 
-    // ------------------------------------------------------------------------
-    // ProvidersConfig tests
-    // ------------------------------------------------------------------------
+        let later = now + chrono::Duration::seconds(601);
+        assert_eq!(breaker.state(later, &config), BreakerState::HalfOpen);
+    }
 
     #[test]
-    fn providers_config_enabled_oauth_only() {
-        let config = ProvidersConfig {
-            codex: Some(true),
-            claude: Some(true),
+    fn breaker_half_open_failure_doubles_cooldown() {
+        let config = enabled_breaker();
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker {
+            consecutive_failures: 3,
+            open_until: Some(now.timestamp() - 1),
             ..Default::default()
         };
-        let enabled = config.enabled_providers();
-        assert_eq!(enabled.len(), 2);
-        assert!(enabled.iter().any(|p| p.name == "codex"));
-        assert!(enabled.iter().any(|p| p.name == "claude"));
+        assert_eq!(breaker.state(now, &config), BreakerState::HalfOpen);
+
+        breaker.record_failure(now, &config);
+        assert_eq!(breaker.state(now, &config), BreakerState::Open);
+        assert_eq!(breaker.open_until, Some(now.timestamp() + 1200));
+
+        // Cooldown is capped
+        for _ in 0..20 {
+            breaker.record_failure(now, &config);
+        }
+        assert_eq!(
+            breaker.open_until,
+            Some(now.timestamp() + config.max_cooldown_secs as i64)
+        );
     }
 
     #[test]
-    fn providers_config_enabled_with_api_provider() {
-        let config = ProvidersConfig {
-            claude: Some(true),
-            zai: Some(ApiProviderConfig {
-                api_key: "test-key".to_string(),
-            }),
+    fn breaker_success_closes() {
+        let config = enabled_breaker();
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker {
+            consecutive_failures: 5,
+            open_until: Some(now.timestamp() - 1),
             ..Default::default()
         };
-        let enabled = config.enabled_providers();
-        assert_eq!(enabled.len(), 2);
+        breaker.record_success();
+        assert_eq!(breaker, CircuitBreaker::default());
+        assert_eq!(breaker.state(now, &config), BreakerState::Closed);
+    }
 
-        let zai = enabled.iter().find(|p| p.name == "zai").unwrap();
-        assert_eq!(zai.api_key, Some("test-key".to_string()));
-        assert_eq!(zai.env_var, Some("ZAI_API_TOKEN"));
+    #[test]
+    fn breaker_disabled_with_zero_threshold() {
+        let config = BreakerConfig {
+            failure_threshold: 0,
+            ..Default::default()
+        };
+        let now = Utc::now();
+        let mut breaker = CircuitBreaker::default();
+        for _ in 0..10 {
+            breaker.record_failure(now, &config);
+        }
+        assert_eq!(breaker.state(now, &config), BreakerState::Closed);
     }
 
     #[test]
-    fn providers_config_disabled_oauth() {
-        let config = ProvidersConfig {
-            codex: Some(false),
-            claude: Some(true),
+    fn fetch_with_breakers_skips_open_providers() {
+        let config = TokenGaugeConfig {
+            codexbar_bin: "/nonexistent/codexbar".to_string(),
+            breaker: enabled_breaker(),
             ..Default::default()
         };
-        let enabled = config.enabled_providers();
-        assert_eq!(enabled.len(), 1);
-        assert_eq!(enabled[0].name, "claude");
+        let open = CircuitBreaker {
+            consecutive_failures: 4,
+            open_until: Some(Utc::now().timestamp() + 600),
+            ..Default::default()
+        };
+        let mut breakers = Breakers::from([("claude".to_string(), open.clone())]);
+
+        let result = fetch_with_breakers(&config, &mut breakers, &BatchSupport::new(), false);
+
+        let claude = result
+            .errors
+            .iter()
+            .find(|e| e.provider == "claude")
+            .unwrap();
+        assert_eq!(
+            claude.message,
+            "paused after 4 failures - re-auth and refresh"
+        );
+        // Paused provider is left untouched, codex failed to spawn once
+        assert_eq!(breakers["claude"], open);
+        assert_eq!(breakers["codex"].consecutive_failures, 1);
     }
 
     #[test]
-    fn providers_config_none_means_disabled() {
-        let config = ProvidersConfig::default();
-        let enabled = config.enabled_providers();
-        assert!(enabled.is_empty());
+    fn fetch_with_breakers_force_retries_open_providers() {
+        let config = TokenGaugeConfig {
+            codexbar_bin: "/nonexistent/codexbar".to_string(),
+            breaker: enabled_breaker(),
+            ..Default::default()
+        };
+        let open = CircuitBreaker {
+            consecutive_failures: 4,
+            open_until: Some(Utc::now().timestamp() + 600),
+            ..Default::default()
+        };
+        let mut breakers = Breakers::from([("claude".to_string(), open)]);
+
+        let result = fetch_with_breakers(&config, &mut breakers, &BatchSupport::new(), true);
+
+        assert!(
+            result
+                .errors
+                .iter()
+                .all(|e| !e.message.starts_with("paused"))
+        );
+        assert_eq!(breakers["claude"].consecutive_failures, 5);
     }
 
     #[test]
-    fn providers_config_is_enabled() {
-        let config = ProvidersConfig {
-            codex: Some(true),
-            claude: Some(false),
-            zai: Some(ApiProviderConfig {
-                api_key: "key".to_string(),
-            }),
+    fn should_auto_disable_only_unsupported_in_disable_mode() {
+        use FetchErrorKind::*;
+        assert!(should_auto_disable(Unsupported, UnsupportedPolicy::Disable));
+        assert!(!should_auto_disable(Unsupported, UnsupportedPolicy::Retry));
+        assert!(!should_auto_disable(Other, UnsupportedPolicy::Disable));
+        assert!(!should_auto_disable(
+            OAuthExpired,
+            UnsupportedPolicy::Disable
+        ));
+        assert_eq!(
+            ProviderFetchError::new("zai".into(), "Error: No available fetch strategy for zai")
+                .kind,
+            Unsupported
+        );
+    }
+
+    /// A codexbar that counts its runs next to it and reports no fetch
+    /// strategy.
+    fn unsupported_codexbar() -> FakeCodexbar {
+        scripted(
+            r#"echo run >> "$(dirname "$0")/runs"
+echo 'Error: No available fetch strategy' >&2
+exit 1"#,
+        )
+    }
+
+    /// Config with only kimi (an API provider) enabled, backed by `bin`, an
+    /// `unsupported_codexbar`.
+    fn unsupported_config(bin: &Path, policy: UnsupportedPolicy) -> TokenGaugeConfig {
+        let mut config = TokenGaugeConfig {
+            codexbar_bin: bin.display().to_string(),
+            providers: ProvidersConfig {
+                kimi: Some(ApiProviderConfig {
+                    api_key: "key".to_string(),
+                }),
+                ..Default::default()
+            },
             ..Default::default()
         };
-        assert!(config.is_enabled("codex"));
-        assert!(!config.is_enabled("claude"));
-        assert!(config.is_enabled("zai"));
-        assert!(!config.is_enabled("kimik2"));
-        assert!(!config.is_enabled("unknown"));
+        config.breaker.unsupported = policy;
+        config
     }
 
-    // ------------------------------------------------------------------------
-    // ProviderPayload tests
-    // ------------------------------------------------------------------------
+    fn run_count(bin: &Path) -> usize {
+        fs::read_to_string(bin.with_file_name("runs")).map_or(0, |text| text.lines().count())
+    }
 
     #[test]
-    fn provider_payload_has_error_true() {
-        let payload = ProviderPayload {
-            provider: "test".to_string(),
-            version: None,
-            source: None,
-            usage: None,
-            credits: None,
-            error: Some(ProviderError {
-                message: Some("error".to_string()),
-                code: None,
-                kind: None,
-            }),
-        };
-        assert!(payload.has_error());
+    fn unsupported_provider_keeps_retrying_by_default() {
+        with_fake_codexbar(&unsupported_codexbar(), |bin| {
+            let config = unsupported_config(bin, UnsupportedPolicy::Retry);
+            let mut breakers = Breakers::new();
+            for _ in 0..2 {
+                let result =
+                    fetch_with_breakers(&config, &mut breakers, &BatchSupport::new(), false);
+                assert_eq!(result.errors.len(), 1);
+                assert_eq!(result.errors[0].kind, FetchErrorKind::Unsupported);
+            }
+            assert_eq!(run_count(bin), 2);
+        });
     }
 
     #[test]
-    fn provider_payload_has_error_false() {
-        let payload = ProviderPayload {
-            provider: "test".to_string(),
-            version: None,
-            source: None,
-            usage: None,
-            credits: None,
-            error: None,
-        };
-        assert!(!payload.has_error());
+    fn unsupported_provider_disabled_until_forced() {
+        with_fake_codexbar(&unsupported_codexbar(), |bin| {
+            let config = unsupported_config(bin, UnsupportedPolicy::Disable);
+            let mut breakers = Breakers::new();
+            let fetch = |breakers: &mut Breakers, force| {
+                fetch_with_breakers(&config, breakers, &BatchSupport::new(), force)
+            };
+
+            let first = fetch(&mut breakers, false);
+            assert_eq!(
+                first.errors[0].message,
+                "not supported by this codexbar - skipped until a manual refresh"
+            );
+            assert!(breakers["kimi"].disabled);
+            // Later refreshes don't run it or repeat the error
+            let second = fetch(&mut breakers, false);
+            assert!(second.errors.is_empty());
+            assert_eq!(run_count(bin), 1);
+
+            // A manual refresh tries again, and success switches it back on
+            let forced = fetch(&mut breakers, true);
+            assert_eq!(forced.errors.len(), 1);
+            assert_eq!(run_count(bin), 2);
+            breakers.get_mut("kimi").unwrap().record_success();
+            assert!(!breakers["kimi"].disabled);
+        });
     }
 
     // ------------------------------------------------------------------------
-    // CachedData tests
+    // Cache file tests
     // ------------------------------------------------------------------------
 
-    #[test]
-    fn cached_data_full_format() {
-        let payload = ProviderPayload {
-            provider: "claude".to_string(),
-            version: Some("2.0".to_string()),
-            source: None,
-            usage: None,
-            credits: None,
-            error: None,
-        };
-        let error = ProviderFetchError {
-            provider: "codex".to_string(),
-            message: "timeout".to_string(),
-            raw: "raw error".to_string(),
-        };
-        let cached = CachedData::Full {
-            payloads: vec![payload.clone()],
-            errors: vec![error.clone()],
-        };
-
-        assert_eq!(cached.payloads().len(), 1);
-        assert_eq!(cached.errors().len(), 1);
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tokengauge-{}-{}", std::process::id(), name))
+    }
 
-        let (payloads, errors) = cached.into_parts();
-        assert_eq!(payloads.len(), 1);
-        assert_eq!(errors.len(), 1);
+    #[test]
+    fn volatile_path_classification() {
+        let mounts = "/dev/sda1 / ext4 rw 0 0\n\
+                      tmpfs /home/someone/scratch tmpfs rw 0 0\n\
+                      /dev/sda2 /home ext4 rw 0 0\n";
+        let volatile = |path: &str| is_volatile_with_mounts(Path::new(path), mounts);
+        assert!(volatile("/tmp/tokengauge-usage.json"));
+        assert!(volatile("/dev/shm/usage.json"));
+        // /var/tmp survives reboots
+        assert!(!volatile("/var/tmp/tokengauge-usage.json"));
+        assert!(!volatile("/tmpfoo/usage.json"));
+        assert!(!volatile("/home/someone/.cache/tokengauge/usage.json"));
+        // The deepest mount wins
+        assert!(volatile("/home/someone/scratch/usage.json"));
+
+        let config = TokenGaugeConfig::default();
+        let warning = volatile_cache_warning(&config).unwrap();
+        assert!(warning.contains("/tmp/tokengauge-usage.json"), "{warning}");
+        assert!(warning.contains("tokengauge/usage.json\""), "{warning}");
     }
 
     #[test]
-    fn cached_data_legacy_format() {
+    fn write_cache_pretty_round_trips() {
+        let path = temp_path("pretty-cache.json");
         let payload = ProviderPayload {
             provider: "claude".to_string(),
-            version: None,
-            source: None,
+            version: Some("2.1.12".to_string()),
+            source: Some("oauth".to_string()),
             usage: None,
             credits: None,
             error: None,
         };
-        let cached = CachedData::Legacy(vec![payload]);
-
-        assert_eq!(cached.payloads().len(), 1);
-        assert_eq!(cached.errors().len(), 0); // legacy has no errors
+        let error = ProviderFetchError::new("codex".to_string(), "timeout after 2s");
 
-        let (payloads, errors) = cached.into_parts();
-        assert_eq!(payloads.len(), 1);
-        assert!(errors.is_empty());
+        write_cache_full(
+            &path,
+            &[payload],
+            &[error],
+            &Breakers::new(),
+            &CacheConfig {
+                pretty: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("\n  "), "expected indented JSON");
+
+        let cached = read_cache_full(&path).unwrap();
+        assert_eq!(cached.payloads()[0].provider, "claude");
+        assert_eq!(cached.errors()[0].message, "Request timed out");
+        fs::remove_file(&path).ok();
     }
 
-    // ------------------------------------------------------------------------
-    // Error message cleaning tests
-    // ------------------------------------------------------------------------
-
     #[test]
-    fn provider_fetch_error_timeout() {
-        let error = ProviderFetchError::new("codex".to_string(), "timeout after 2s");
-        assert_eq!(error.message, "Request timed out");
-        assert_eq!(error.raw, "timeout after 2s");
+    fn read_cache_or_recover_quarantines_truncated_cache() {
+        let path = temp_path("truncated-cache.json");
+        let quarantine = temp_path("truncated-cache.json.corrupt");
+        fs::write(&path, r#"{"payloads":[{"provider":"claude","vers"#).unwrap();
+
+        let recovered = read_cache_or_recover(&path).unwrap_err();
+        assert_eq!(recovered.moved_to, quarantine);
+        assert!(!path.exists());
+        assert!(quarantine.exists());
+        fs::remove_file(&quarantine).ok();
     }
 
     #[test]
-    fn provider_fetch_error_api_401() {
-        let raw = r#"codexbar failed (exit status: 1) - {"error":"Unauthorized"}"#;
-        let error = ProviderFetchError::new("kimik2".to_string(), raw);
-        assert!(error.message.contains("Unauthorized"));
+    fn read_cache_or_recover_quarantines_non_utf8_cache() {
+        let path = temp_path("binary-cache.json");
+        let quarantine = temp_path("binary-cache.json.corrupt");
+        fs::write(&path, [0xff, 0xfe, 0x00, 0x7b]).unwrap();
+
+        let recovered = read_cache_or_recover(&path).unwrap_err();
+        assert_eq!(recovered.moved_to, quarantine);
+        assert!(!path.exists());
+        assert!(quarantine.exists());
+        fs::remove_file(&quarantine).ok();
     }
 
     #[test]
-    fn provider_fetch_error_no_fetch_strategy() {
-        let raw = "codexbar failed - No available fetch strategy for provider";
-        let error = ProviderFetchError::new("test".to_string(), raw);
-        assert_eq!(error.message, "No available fetch strategy");
+    fn read_cache_or_recover_valid_and_missing() {
+        let path = temp_path("valid-cache.json");
+        assert!(read_cache_or_recover(&path).unwrap().is_none());
+
+        write_cache_full(&path, &[], &[], &Breakers::new(), &CacheConfig::default()).unwrap();
+        assert!(read_cache_or_recover(&path).unwrap().is_some());
+        assert!(path.exists());
+        fs::remove_file(&path).ok();
     }
 
     #[test]
-    fn provider_fetch_error_short_message_unchanged() {
-        let error = ProviderFetchError::new("test".to_string(), "Short error");
-        assert_eq!(error.message, "Short error");
+    fn cache_write_due_debounces_quick_refreshes() {
+        let mut config = TokenGaugeConfig {
+            cache_file: temp_path("debounce-cache.json"),
+            refresh_secs: 600,
+            cache: CacheConfig {
+                min_write_interval_secs: 60,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut writes = 0;
+        for _ in 0..2 {
+            if config.cache_write_due(false) {
+                write_cache_full(
+                    &config.cache_file,
+                    &[],
+                    &[],
+                    &Breakers::new(),
+                    &config.cache,
+                )
+                .unwrap();
+                writes += 1;
+            }
+        }
+        assert_eq!(writes, 1);
+        // Forced refreshes always write
+        assert!(config.cache_write_due(true));
+
+        // The interval never outlasts refresh_secs, so a stale cache is rewritten
+        config.refresh_secs = 0;
+        assert!(config.cache_write_due(false));
+        config.refresh_secs = 600;
+        config.cache.min_write_interval_secs = 0;
+        assert!(config.cache_write_due(false));
+        fs::remove_file(&config.cache_file).ok();
     }
 
     #[test]
-    fn provider_fetch_error_long_message_truncated() {
-        let long_msg = "a".repeat(100);
-        let error = ProviderFetchError::new("test".to_string(), &long_msg);
-        assert!(error.message.len() <= 60);
-        assert!(error.message.ends_with("..."));
+    fn write_cache_compact_by_default() {
+        let path = temp_path("compact-cache.json");
+        write_cache_full(&path, &[], &[], &Breakers::new(), &CacheConfig::default()).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains('\n'));
+        assert!(read_cache_full(&path).is_ok());
+        fs::remove_file(&path).ok();
     }
 
     // ------------------------------------------------------------------------
-    // JSON parsing tests
+    // History tests
     // ------------------------------------------------------------------------
 
+    fn sample(timestamp: i64, provider: &str, session: Option<f64>) -> HistorySample {
+        HistorySample {
+            timestamp,
+            provider: provider.to_string(),
+            session,
+            weekly: None,
+        }
+    }
+
     #[test]
-    fn parse_payload_single_object() {
-        let json = r#"{"provider":"claude","version":"2.1.12","source":"oauth"}"#;
-        let value: serde_json::Value = serde_json::from_str(json).unwrap();
-        let payloads = parse_payload(value).unwrap();
-        assert_eq!(payloads.len(), 1);
-        assert_eq!(payloads[0].provider, "claude");
+    fn history_samples_skip_errors_and_clamp() {
+        let mut claude = named_payload("claude");
+        claude.usage = Some(UsageSnapshot {
+            primary: Some(UsageWindow {
+                used_percent: Some(140.0),
+                reset_description: None,
+                resets_at: None,
+                window_minutes: None,
+            }),
+            secondary: None,
+            updated_at: None,
+        });
+        let mut codex = named_payload("codex");
+        codex.error = Some(ProviderError {
+            message: Some("expired".to_string()),
+            code: None,
+            kind: None,
+        });
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let samples = history_samples(&[claude, codex], now);
+        assert_eq!(samples, vec![sample(1_700_000_000, "claude", Some(100.0))]);
     }
 
     #[test]
-    fn parse_payload_array() {
-        let json = r#"[{"provider":"claude"},{"provider":"codex"}]"#;
-        let value: serde_json::Value = serde_json::from_str(json).unwrap();
-        let payloads = parse_payload(value).unwrap();
-        assert_eq!(payloads.len(), 2);
+    fn append_and_read_history_round_trip() {
+        let path = temp_path("history-round-trip.jsonl");
+        fs::remove_file(&path).ok();
+        assert!(read_history(&path).unwrap().is_empty());
+
+        let history = HistoryConfig::default();
+        let now = DateTime::from_timestamp(3, 0).unwrap();
+        append_history(&path, &[sample(1, "claude", Some(10.0))], &history, now).unwrap();
+        append_history(
+            &path,
+            &[sample(2, "claude", None), sample(2, "codex", Some(5.5))],
+            &history,
+            now,
+        )
+        .unwrap();
+        // A torn final line from an interrupted write is skipped
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"timestamp\":3,").unwrap();
+
+        let history = read_history(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            history,
+            vec![
+                sample(1, "claude", Some(10.0)),
+                sample(2, "claude", None),
+                sample(2, "codex", Some(5.5)),
+            ]
+        );
     }
 
     #[test]
-    fn parse_payload_bytes_valid() {
-        let json = br#"{"provider":"claude","version":"2.1.12"}"#;
-        let payloads = parse_payload_bytes(json).unwrap();
-        assert_eq!(payloads.len(), 1);
-        assert_eq!(payloads[0].version, Some("2.1.12".to_string()));
+    fn trim_history_drops_samples_past_max_age() {
+        const DAY: i64 = 24 * 3600;
+        let now = DateTime::from_timestamp(100 * DAY, 0).unwrap();
+        let mut samples = vec![
+            sample(80 * DAY, "claude", Some(1.0)),
+            sample(90 * DAY, "claude", Some(2.0)),
+            sample(99 * DAY, "claude", Some(3.0)),
+        ];
+        let history = HistoryConfig {
+            max_age_days: 10,
+            max_points: 0,
+            ..Default::default()
+        };
+        trim_history(&mut samples, &history, now);
+        assert_eq!(
+            samples,
+            vec![
+                sample(90 * DAY, "claude", Some(2.0)),
+                sample(99 * DAY, "claude", Some(3.0)),
+            ]
+        );
+
+        let mut samples = vec![sample(0, "claude", Some(1.0))];
+        let keep_all = HistoryConfig {
+            max_age_days: 0,
+            max_points: 0,
+            ..Default::default()
+        };
+        trim_history(&mut samples, &keep_all, now);
+        assert_eq!(samples.len(), 1);
     }
 
     #[test]
-    fn parse_payload_bytes_invalid_json() {
-        let json = b"not valid json";
-        let result = parse_payload_bytes(json);
-        assert!(result.is_err());
+    fn trim_history_keeps_newest_max_points() {
+        let now = DateTime::from_timestamp(10, 0).unwrap();
+        let mut samples: Vec<_> = (1..=5).map(|t| sample(t, "claude", None)).collect();
+        let history = HistoryConfig {
+            max_age_days: 0,
+            max_points: 2,
+            ..Default::default()
+        };
+        trim_history(&mut samples, &history, now);
+        assert_eq!(
+            samples,
+            vec![sample(4, "claude", None), sample(5, "claude", None)]
+        );
     }
 
     #[test]
-    fn parse_payload_with_full_usage() {
-        let json = r#"{
-            "provider": "claude",
-            "version": "2.1.12",
-            "source": "oauth",
-            "usage": {
-                "primary": {
-                    "usedPercent": 19,
-                    "resetDescription": "Jan 20 at 12:59PM",
-                    "resetsAt": "2026-01-20T12:59:00Z",
-                    "windowMinutes": 300
-                },
-                "secondary": {
-                    "usedPercent": 12,
-                    "resetDescription": "Jan 26 at 8:59AM",
-                    "resetsAt": "2026-01-26T08:59:00Z",
-                    "windowMinutes": 10080
-                },
-                "updatedAt": "2026-01-20T07:37:16Z"
-            },
-            "credits": null,
-            "error": null
-        }"#;
-        let payloads = parse_payload_bytes(json.as_bytes()).unwrap();
-        assert_eq!(payloads.len(), 1);
+    fn append_history_rewrites_once_over_max_points() {
+        let path = temp_path("history-trim.jsonl");
+        fs::remove_file(&path).ok();
+        let history = HistoryConfig {
+            max_age_days: 0,
+            max_points: 4,
+            ..Default::default()
+        };
+        let now = DateTime::from_timestamp(100, 0).unwrap();
+        let mut lengths = Vec::new();
+        for timestamp in 10..20 {
+            append_history(&path, &[sample(timestamp, "claude", None)], &history, now).unwrap();
+            lengths.push(read_history(&path).unwrap().len());
+        }
+        let kept = read_history(&path).unwrap();
+        fs::remove_file(&path).ok();
 
-        let payload = &payloads[0];
-        assert_eq!(payload.provider, "claude");
-        assert!(!payload.has_error());
+        // Grows to a quarter over the limit (5 of 4) before each rewrite
+        assert_eq!(lengths, vec![1, 2, 3, 4, 5, 4, 5, 4, 5, 4]);
+        assert_eq!(kept.first().map(|sample| sample.timestamp), Some(16));
+    }
 
-        let usage = payload.usage.as_ref().unwrap();
-        let primary = usage.primary.as_ref().unwrap();
-        assert_eq!(primary.used_percent, Some(19));
-        assert_eq!(primary.window_minutes, Some(300));
+    #[test]
+    fn append_history_rewrites_once_oldest_sample_expires() {
+        const DAY: i64 = 24 * 3600;
+        let path = temp_path("history-age.jsonl");
+        fs::remove_file(&path).ok();
+        let history = HistoryConfig {
+            max_age_days: 1,
+            max_points: 0,
+            ..Default::default()
+        };
+        let start = DateTime::from_timestamp(0, 0).unwrap();
+        append_history(&path, &[sample(0, "claude", Some(1.0))], &history, start).unwrap();
+
+        // Within the grace day the expired sample stays
+        let soon = DateTime::from_timestamp(DAY + DAY / 2, 0).unwrap();
+        append_history(
+            &path,
+            &[sample(soon.timestamp(), "claude", None)],
+            &history,
+            soon,
+        )
+        .unwrap();
+        assert_eq!(read_history(&path).unwrap().len(), 2);
+
+        let later = DateTime::from_timestamp(3 * DAY, 0).unwrap();
+        append_history(
+            &path,
+            &[sample(later.timestamp(), "claude", None)],
+            &history,
+            later,
+        )
+        .unwrap();
+        let kept = read_history(&path).unwrap();
+        fs::remove_file(&path).ok();
+        assert_eq!(kept, vec![sample(3 * DAY, "claude", None)]);
+    }
+
+    #[test]
+    fn record_history_does_nothing_when_disabled() {
+        let path = temp_path("history-disabled.jsonl");
+        fs::remove_file(&path).ok();
+        let mut config = TokenGaugeConfig::default();
+        config.history.file = path.clone();
+        record_history(&config, &[named_payload("claude")]).unwrap();
+        assert!(!path.exists());
     }
 
     // ------------------------------------------------------------------------
-    // payload_to_rows tests
+    // WaybarConfig tests
     // ------------------------------------------------------------------------
 
     #[test]
-    fn payload_to_rows_filters_errors() {
-        let good = ProviderPayload {
-            provider: "claude".to_string(),
-            version: None,
-            source: None,
-            usage: None,
-            credits: None,
-            error: None,
-        };
-        let bad = ProviderPayload {
-            provider: "codex".to_string(),
-            version: None,
-            source: None,
-            usage: None,
-            credits: None,
-            error: Some(ProviderError {
-                message: Some("error".to_string()),
-                code: None,
-                kind: None,
-            }),
-        };
-        let rows = payload_to_rows(vec![good, bad]);
-        assert_eq!(rows.len(), 1);
-        assert_eq!(rows[0].provider, "Claude");
+    fn waybar_config_default() {
+        let config = WaybarConfig::default();
+        assert_eq!(config.window, WaybarWindow::Daily);
     }
 
     #[test]
-    fn payload_to_rows_formats_credits() {
-        let payload = ProviderPayload {
-            provider: "zai".to_string(),
-            version: None,
-            source: None,
-            usage: None,
-            credits: Some(Credits {
-                remaining: Some(42.567),
-            }),
-            error: None,
-        };
-        let rows = payload_to_rows(vec![payload]);
-        assert_eq!(rows[0].credits, "42.57"); // 2 decimal places
+    fn tui_config_default() {
+        let config = TuiConfig::default();
+        assert!(config.highlight_critical);
     }
 
     #[test]
-    fn payload_to_rows_formats_source() {
-        // Both version and source
-        let payload1 = ProviderPayload {
-            provider: "claude".to_string(),
-            version: Some("2.1.12".to_string()),
-            source: Some("oauth".to_string()),
-            usage: None,
-            credits: None,
-            error: None,
-        };
-        let rows = payload_to_rows(vec![payload1]);
-        assert_eq!(rows[0].source, "2.1.12 (oauth)");
+    fn apply_overrides_without_overrides_is_noop() {
+        let config = TokenGaugeConfig::default();
+        let applied = apply_overrides(config.clone(), &CliOverrides::default());
+        assert_eq!(
+            toml::to_string(&applied).unwrap(),
+            toml::to_string(&config).unwrap()
+        );
+    }
 
-        // Only version
-        let payload2 = ProviderPayload {
-            provider: "claude".to_string(),
-            version: Some("2.1.12".to_string()),
-            source: None,
-            usage: None,
-            credits: None,
-            error: None,
-        };
-        let rows = payload_to_rows(vec![payload2]);
-        assert_eq!(rows[0].source, "2.1.12");
+    #[test]
+    fn apply_overrides_each_field_wins() {
+        let base = TokenGaugeConfig::default();
+        let with = |overrides: CliOverrides| apply_overrides(base.clone(), &overrides);
 
-        // Only source
-        let payload3 = ProviderPayload {
-            provider: "claude".to_string(),
-            version: None,
-            source: Some("oauth".to_string()),
-            usage: None,
-            credits: None,
-            error: None,
-        };
-        let rows = payload_to_rows(vec![payload3]);
-        assert_eq!(rows[0].source, "oauth");
+        let config = with(CliOverrides {
+            refresh_secs: Some(30),
+            ..Default::default()
+        });
+        assert_eq!(config.refresh_secs, 30);
+        assert_eq!(config.timeout_secs, base.timeout_secs);
 
-        // Neither
-        let payload4 = ProviderPayload {
-            provider: "claude".to_string(),
-            version: None,
-            source: None,
-            usage: None,
-            credits: None,
-            error: None,
+        let config = with(CliOverrides {
+            timeout_secs: Some(5),
+            ..Default::default()
+        });
+        assert_eq!(config.timeout_secs, 5);
+        assert_eq!(config.refresh_secs, base.refresh_secs);
+
+        let config = with(CliOverrides {
+            window: Some(WaybarWindow::Weekly),
+            ..Default::default()
+        });
+        assert_eq!(config.waybar.window, WaybarWindow::Weekly);
+        assert_eq!("weekly".parse(), Ok(WaybarWindow::Weekly));
+        assert_eq!("Daily".parse(), Ok(WaybarWindow::Daily));
+        assert!("monthly".parse::<WaybarWindow>().is_err());
+    }
+
+    #[test]
+    fn providers_from_names_uses_registry_and_env() {
+        let env = |name: &str| (name == "ZAI_API_TOKEN").then(|| "sk-zai".to_string());
+        let names = |list: &[&str]| list.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+
+        let providers = providers_from_names(&names(&["claude", "zai", "claude"]), env).unwrap();
+        assert_eq!(providers.len(), 2);
+        assert_eq!(providers[0].provider_type, ProviderType::OAuth);
+        assert_eq!(providers[0].api_key, None);
+        assert_eq!(providers[1].api_key.as_deref(), Some("sk-zai"));
+        assert_eq!(providers[1].env_var, Some("ZAI_API_TOKEN"));
+
+        let config = apply_overrides(
+            TokenGaugeConfig::default(),
+            &CliOverrides {
+                enable: Some(providers),
+                ..Default::default()
+            },
+        );
+        let enabled: Vec<_> = config
+            .providers
+            .enabled_providers()
+            .into_iter()
+            .map(|provider| provider.name)
+            .collect();
+        assert_eq!(enabled, ["claude", "zai"]);
+        assert_eq!(config.providers.codex, Some(false));
+        assert_eq!(
+            config.cache_file,
+            PathBuf::from("/tmp/tokengauge-usage-claude+zai.json")
+        );
+    }
+
+    #[test]
+    fn providers_from_names_rejects_unknown_and_keyless() {
+        let names = |list: &[&str]| list.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let error = providers_from_names(&names(&["claude", "gemini"]), |_| None).unwrap_err();
+        assert!(
+            error.to_string().starts_with("unknown provider: gemini"),
+            "{error}"
+        );
+
+        let error = providers_from_names(&names(&["minimax"]), |_| None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "minimax is an API provider: set MINIMAX_API_TOKEN to fetch it"
+        );
+    }
+
+    #[test]
+    fn apply_overrides_providers_narrow_enabled_set() {
+        let mut base = TokenGaugeConfig::default();
+        base.providers.zai = Some(ApiProviderConfig {
+            api_key: "key".to_string(),
+        });
+        let names = |config: &TokenGaugeConfig| {
+            config
+                .providers
+                .enabled_providers()
+                .into_iter()
+                .map(|provider| provider.name)
+                .collect::<Vec<_>>()
         };
-        let rows = payload_to_rows(vec![payload4]);
-        assert_eq!(rows[0].source, "—");
+        assert_eq!(names(&base), ["codex", "claude", "zai"]);
+
+        let only = |providers: &[&str]| {
+            apply_overrides(
+                base.clone(),
+                &CliOverrides {
+                    providers: providers.iter().map(|name| name.to_string()).collect(),
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(names(&only(&["claude", "zai"])), ["claude", "zai"]);
+        // Listing a provider doesn't enable one the config leaves off
+        assert_eq!(names(&only(&["codex", "kimi"])), ["codex"]);
     }
 
-    // ------------------------------------------------------------------------
-    // WaybarConfig tests
-    // ------------------------------------------------------------------------
+    #[test]
+    fn tui_columns_parse_and_reject_unknown() {
+        let config: TokenGaugeConfig =
+            toml::from_str("[tui]\ncolumns = [\"provider\", \"weekly_reset\"]").unwrap();
+        assert_eq!(
+            config.tui.columns,
+            vec![TuiColumn::Provider, TuiColumn::WeeklyReset]
+        );
+        assert_eq!(TuiConfig::default().columns, TuiColumn::ALL.to_vec());
+
+        let error = toml::from_str::<TokenGaugeConfig>("[tui]\ncolumns = [\"bogus\"]")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown variant `bogus`"), "{error}");
+    }
 
     #[test]
-    fn waybar_config_default() {
-        let config = WaybarConfig::default();
-        assert_eq!(config.window, WaybarWindow::Daily);
+    fn provider_short_label_precedence() {
+        let mut config = TokenGaugeConfig::default();
+        // Registry short label
+        assert_eq!(provider_short_label("claude", &config), "CLD");
+        // Unknown provider falls back to its name
+        assert_eq!(provider_short_label("mystery", &config), "mystery");
+
+        // Config wins over the registry, and works for unknown providers
+        config
+            .short_labels
+            .insert("claude".to_string(), "C".to_string());
+        config
+            .short_labels
+            .insert("mystery".to_string(), "M".to_string());
+        assert_eq!(provider_short_label("claude", &config), "C");
+        assert_eq!(provider_short_label("mystery", &config), "M");
+        assert_eq!(provider_short_label("codex", &config), "CDX");
     }
 
     #[test]
@@ -16,8 +16,8 @@
 use std::process::Command;
 use std::time::Duration;
 use tokengauge_core::{
-    EnabledProvider, ProviderType, fetch_all_providers, fetch_single_provider, load_config,
-    parse_payload_bytes, payload_to_rows,
+    EnabledProvider, ProviderType, TokenGaugeConfig, fetch_all_providers, fetch_single_provider,
+    load_config, parse_payload_bytes, payload_to_rows,
 };
 
 /// Check if codexbar is available in PATH
@@ -43,9 +43,14 @@ fn test_fetch_claude_oauth() {
         provider_type: ProviderType::OAuth,
         api_key: None,
         env_var: None,
+        source: None,
     };
 
-    let result = fetch_single_provider("codexbar", &provider, Duration::from_secs(10));
+    let result = fetch_single_provider(
+        &TokenGaugeConfig::default(),
+        &provider,
+        Duration::from_secs(10),
+    );
 
     match result {
         Ok(payloads) => {
@@ -67,10 +72,13 @@ fn test_fetch_claude_oauth() {
 
                 // Verify usage structure
                 if let Some(primary) = &usage.primary {
-                    println!("  Primary: {}% used", primary.used_percent.unwrap_or(0));
+                    println!("  Primary: {}% used", primary.used_percent.unwrap_or(0.0));
                 }
                 if let Some(secondary) = &usage.secondary {
-                    println!("  Secondary: {}% used", secondary.used_percent.unwrap_or(0));
+                    println!(
+                        "  Secondary: {}% used",
+                        secondary.used_percent.unwrap_or(0.0)
+                    );
                 }
             }
         }
@@ -98,9 +106,14 @@ fn test_fetch_codex_oauth() {
         provider_type: ProviderType::OAuth,
         api_key: None,
         env_var: None,
+        source: None,
     };
 
-    let result = fetch_single_provider("codexbar", &provider, Duration::from_secs(10));
+    let result = fetch_single_provider(
+        &TokenGaugeConfig::default(),
+        &provider,
+        Duration::from_secs(10),
+    );
 
     match result {
         Ok(payloads) => {
@@ -202,7 +215,7 @@ fn test_fetch_all_providers_integration() {
         Ok(c) => c,
         Err(e) => {
             eprintln!("No config found ({}), using default", e);
-            tokengauge_core::TokenGaugeConfig::default()
+            TokenGaugeConfig::default()
         }
     };
 
@@ -272,7 +285,7 @@ fn test_read_existing_cache() {
         if let Some(usage) = &payload.usage
             && let Some(primary) = &usage.primary
         {
-            println!("    Primary: {}% used", primary.used_percent.unwrap_or(0));
+            println!("    Primary: {}% used", primary.used_percent.unwrap_or(0.0));
         }
     }
 
@@ -0,0 +1,140 @@
+//! Fetch tests against a scripted fake codexbar.
+//!
+//! Unlike `codexbar_integration`, these need no real credentials and run by
+//! default.
+
+mod support;
+
+use std::time::Duration;
+use support::{FakeCodexbar, usage_json, with_fake_codexbar};
+use tokengauge_core::{
+    EnabledProvider, ProviderType, ProvidersConfig, TokenGaugeConfig, fetch_all_providers,
+    fetch_single_provider, fetch_single_provider_raw, parse_provider_output, payload_to_rows,
+};
+
+fn config_for(bin: &std::path::Path) -> TokenGaugeConfig {
+    TokenGaugeConfig {
+        codexbar_bin: bin.display().to_string(),
+        timeout_secs: 5,
+        ..Default::default()
+    }
+}
+
+fn claude() -> EnabledProvider {
+    EnabledProvider {
+        name: "claude".to_string(),
+        provider_type: ProviderType::OAuth,
+        api_key: None,
+        env_var: None,
+        source: None,
+    }
+}
+
+#[test]
+fn fake_codexbar_success() {
+    let spec = FakeCodexbar {
+        json: Some(usage_json("claude", 42, 7)),
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let payloads =
+            fetch_single_provider(&config_for(bin), &claude(), Duration::from_secs(5)).unwrap();
+        let rows = payload_to_rows(payloads);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].session_used, Some(42));
+        assert_eq!(rows[0].weekly_used, Some(7));
+    });
+}
+
+#[test]
+fn fake_codexbar_per_provider_responses() {
+    let spec = FakeCodexbar {
+        responses: vec![("claude".to_string(), usage_json("claude", 10, 20))],
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let config = TokenGaugeConfig {
+            providers: ProvidersConfig {
+                claude: Some(true),
+                codex: Some(true),
+                ..Default::default()
+            },
+            ..config_for(bin)
+        };
+        let result = fetch_all_providers(&config);
+        assert_eq!(result.payloads.len(), 1);
+        assert_eq!(result.payloads[0].provider, "claude");
+        // codex has no scripted response, so its empty output fails to parse
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].provider, "codex");
+    });
+}
+
+#[test]
+fn fake_codexbar_failure_reports_stderr() {
+    let spec = FakeCodexbar {
+        stderr: Some("No available fetch strategy".to_string()),
+        exit_code: 2,
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let error = fetch_single_provider(&config_for(bin), &claude(), Duration::from_secs(5))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("codexbar failed"),
+            "unexpected error: {error}"
+        );
+        assert!(error.contains("No available fetch strategy"));
+    });
+}
+
+#[test]
+fn fake_codexbar_timeout() {
+    let spec = FakeCodexbar {
+        json: Some(usage_json("claude", 1, 1)),
+        sleep_secs: Some(5.0),
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let error = fetch_single_provider(&config_for(bin), &claude(), Duration::from_millis(200))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("timeout"), "unexpected error: {error}");
+    });
+}
+
+#[test]
+fn fake_codexbar_partial_json() {
+    let spec = FakeCodexbar {
+        json: Some(r#"{"provider":"claude","usage":{"primary":"#.to_string()),
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let result = fetch_single_provider(&config_for(bin), &claude(), Duration::from_secs(5));
+        assert!(result.is_err());
+    });
+}
+
+#[test]
+fn fake_codexbar_raw_output_is_returned_verbatim() {
+    let spec = FakeCodexbar {
+        json: Some(usage_json("claude", 3, 4)),
+        stderr: Some("debug: fetching claude".to_string()),
+        exit_code: 0,
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let output =
+            fetch_single_provider_raw(&config_for(bin), &claude(), Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, usage_json("claude", 3, 4).into_bytes());
+        assert_eq!(output.stderr, b"debug: fetching claude");
+        assert_eq!(
+            parse_provider_output(&output, &config_for(bin).codexbar)
+                .unwrap()
+                .len(),
+            1
+        );
+    });
+}
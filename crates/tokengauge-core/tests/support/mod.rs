@@ -0,0 +1,140 @@
+//! Shared test fixtures.
+//!
+//! `with_fake_codexbar` writes a small shell script that stands in for the
+//! real codexbar CLI. Its behavior is driven by `FAKE_CODEXBAR_*` environment
+//! variables, which the fixture bakes into a per-test wrapper so tests can run
+//! in parallel without touching the test process environment. Tests that need
+//! more (counting runs, answering by argument) give their own `script`.
+//!
+//! Shared via `#[path]` by the integration tests and the unit tests of every
+//! crate, each of which uses a different subset.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Generic fake codexbar. Reads its behavior from the environment:
+///
+/// - `FAKE_CODEXBAR_JSON`: stdout for every provider
+/// - `FAKE_CODEXBAR_JSON_<PROVIDER>`: stdout for one provider (overrides the above)
+/// - `FAKE_CODEXBAR_STDERR`: written to stderr
+/// - `FAKE_CODEXBAR_SLEEP`: seconds to sleep before answering
+/// - `FAKE_CODEXBAR_EXIT`: exit code (default 0)
+const FAKE_CODEXBAR_SCRIPT: &str = r#"
+provider=""
+while [ $# -gt 0 ]; do
+    if [ "$1" = "--provider" ]; then
+        provider="$2"
+        shift
+    fi
+    shift
+done
+
+if [ -n "$FAKE_CODEXBAR_SLEEP" ]; then
+    sleep "$FAKE_CODEXBAR_SLEEP"
+fi
+
+var="FAKE_CODEXBAR_JSON_$(printf '%s' "$provider" | tr 'a-z-' 'A-Z_')"
+eval "json=\${$var:-\$FAKE_CODEXBAR_JSON}"
+
+if [ -n "$FAKE_CODEXBAR_STDERR" ]; then
+    printf '%s' "$FAKE_CODEXBAR_STDERR" >&2
+fi
+printf '%s' "$json"
+exit "${FAKE_CODEXBAR_EXIT:-0}"
+"#;
+
+/// Behavior of a fake codexbar binary.
+#[derive(Debug, Clone, Default)]
+pub struct FakeCodexbar {
+    /// JSON printed for any provider without a specific response
+    pub json: Option<String>,
+    /// Per-provider JSON responses, keyed by provider name
+    pub responses: Vec<(String, String)>,
+    /// Text written to stderr
+    pub stderr: Option<String>,
+    /// Process exit code
+    pub exit_code: i32,
+    /// Delay before answering, in seconds
+    pub sleep_secs: Option<f64>,
+    /// Shell body to run instead of the generic fake. `"$(dirname "$0")"`
+    /// is the fixture directory, for files the test reads back
+    pub script: Option<String>,
+}
+
+/// A fake codexbar that runs `script`.
+pub fn scripted(script: &str) -> FakeCodexbar {
+    FakeCodexbar {
+        script: Some(script.to_string()),
+        ..Default::default()
+    }
+}
+
+impl FakeCodexbar {
+    fn env_vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        if let Some(json) = &self.json {
+            vars.push(("FAKE_CODEXBAR_JSON".to_string(), json.clone()));
+        }
+        for (provider, json) in &self.responses {
+            let name = provider.to_uppercase().replace('-', "_");
+            vars.push((format!("FAKE_CODEXBAR_JSON_{name}"), json.clone()));
+        }
+        if let Some(stderr) = &self.stderr {
+            vars.push(("FAKE_CODEXBAR_STDERR".to_string(), stderr.clone()));
+        }
+        if let Some(secs) = self.sleep_secs {
+            vars.push(("FAKE_CODEXBAR_SLEEP".to_string(), secs.to_string()));
+        }
+        vars.push(("FAKE_CODEXBAR_EXIT".to_string(), self.exit_code.to_string()));
+        vars
+    }
+}
+
+/// Removes the fixture directory even if the test body panics.
+struct FixtureDir(PathBuf);
+
+impl Drop for FixtureDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write a fake codexbar described by `spec` and run `f` with its path.
+pub fn with_fake_codexbar<T>(spec: &FakeCodexbar, f: impl FnOnce(&Path) -> T) -> T {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "tokengauge-fake-codexbar-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).expect("create fixture dir");
+    let dir = FixtureDir(dir);
+
+    let mut script = String::from("#!/bin/sh\n");
+    for (key, value) in spec.env_vars() {
+        script.push_str(&format!("export {key}={}\n", shell_quote(&value)));
+    }
+    script.push_str(spec.script.as_deref().unwrap_or(FAKE_CODEXBAR_SCRIPT));
+    script.push('\n');
+
+    let bin = dir.0.join("codexbar");
+    fs::write(&bin, script).expect("write fake codexbar");
+    fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).expect("chmod fake codexbar");
+
+    f(&bin)
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// A minimal successful codexbar payload for `provider`.
+pub fn usage_json(provider: &str, session_used: u8, weekly_used: u8) -> String {
+    format!(
+        r#"{{"provider":"{provider}","source":"oauth","usage":{{"primary":{{"usedPercent":{session_used},"windowMinutes":300}},"secondary":{{"usedPercent":{weekly_used},"windowMinutes":10080}},"updatedAt":"2026-01-20T07:37:16Z"}}}}"#
+    )
+}
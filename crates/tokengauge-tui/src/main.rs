@@ -1,73 +1,318 @@
-use std::fs;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::thread;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant};
 
 use anyhow::{Result, anyhow};
-use clap::Parser;
+use chrono::{Local, TimeZone};
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
 use crossterm::ExecutableCommand;
 use crossterm::event::{self, Event, KeyCode, KeyEvent};
 use crossterm::terminal::{
     EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
 };
-use ratatui::layout::{Constraint, Layout};
+use ratatui::backend::Backend;
+use ratatui::layout::{Constraint, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
-use ratatui::text::{Line, Span};
-use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table};
+use ratatui::symbols;
+use ratatui::text::{Line, Span, Text};
+use ratatui::widgets::{
+    Axis, Block, Borders, Cell, Chart, Dataset, GraphType, Paragraph, Row, Table,
+};
 use ratatui::{Terminal, backend::CrosstermBackend};
 use tokengauge_core::{
-    FetchResult, ProviderFetchError, ProviderRow, fetch_all_providers, load_config,
-    payload_to_rows, read_cache_full, write_cache_full, write_default_config,
+    AgeState, BatchSupport, CliOverrides, DisplayConfig, FetchResult, HistorySample, PROVIDERS,
+    ProviderFetchError, ProviderRow, STDIN_CONFIG, Severity, SpinnerStyle, TokenGaugeConfig,
+    TuiColumn, TuiConfig, WaybarWindow, apply_overrides, carry_forward_stale_rows, classify_age,
+    display_rows, edit_config, ensure_config, expire_old_usage, fetch_with_breakers,
+    is_broken_pipe, load_config_with_profile, mark_from_cache, merge_error_times,
+    providers_from_names, read_cache_full, read_cache_or_recover, read_history, record_history,
+    resolve_config_path, unix_now, version_changes, version_report, volatile_cache_warning,
+    write_cache_full, write_json,
 };
 
 const BAR_WIDTH: usize = 10;
+/// Width of the small bar drawn beside credits when the limit is known
+const CREDITS_BAR_WIDTH: usize = 5;
+/// Lower bound on the automatic refresh interval, in seconds
+const MIN_AUTO_REFRESH_SECS: u64 = 5;
+/// Input poll interval while the user is active or a refresh is running
+const ACTIVE_POLL: Duration = Duration::from_millis(120);
+/// Input poll interval once the user has been idle for `tui.idle_after_secs`
+const IDLE_POLL: Duration = Duration::from_secs(1);
+/// Share of the screen height the errors pane may take, in percent
+const ERRORS_MAX_HEIGHT_PERCENT: u16 = 40;
+const BRAILLE_FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const ASCII_FRAMES: [&str; 4] = ["|", "/", "-", "\\"];
+/// Line colors for providers in the graph view, assigned in order
+const GRAPH_COLORS: [Color; 6] = [
+    Color::LightCyan,
+    Color::LightMagenta,
+    Color::LightGreen,
+    Color::LightYellow,
+    Color::LightBlue,
+    Color::LightRed,
+];
 
 #[derive(Parser, Debug)]
 #[command(version, about = "TokenGauge TUI")]
 struct Args {
+    /// Path to the config file
     #[arg(long, env = "TOKENGAUGE_CONFIG")]
     config: Option<PathBuf>,
+    /// Config profile to overlay, from a [profiles.<name>] section
+    #[arg(long, env = "TOKENGAUGE_PROFILE")]
+    profile: Option<String>,
+    /// Override `refresh_secs` from the config
+    #[arg(long, value_name = "SECS")]
+    refresh_secs: Option<u64>,
+    /// Override `timeout_secs` from the config
+    #[arg(long, value_name = "SECS")]
+    timeout_secs: Option<u64>,
+    /// Override `waybar.window`: daily or weekly
+    #[arg(long)]
+    window: Option<WaybarWindow>,
+    /// Only show this provider (repeatable), out of those the config enables
+    #[arg(long = "provider", value_name = "NAME", value_parser = provider_names())]
+    providers: Vec<String>,
+    /// Fetch these providers (comma-separated) instead of the config's;
+    /// API providers read their key from their env var
+    #[arg(
+        long = "providers",
+        value_name = "NAMES",
+        value_delimiter = ',',
+        conflicts_with = "providers"
+    )]
+    enable_providers: Vec<String>,
+    /// Render a single borderless line of usage bars (e.g. for a tmux pane)
+    #[arg(long)]
+    compact: bool,
+    /// Write the default config if none exists, then exit
+    #[arg(long, conflicts_with = "no_init")]
+    init: bool,
+    /// Fail instead of writing a default config when none exists
+    #[arg(long)]
+    no_init: bool,
+    /// Print TokenGauge and codexbar versions and the config path as JSON
+    #[arg(long)]
+    version_json: bool,
+    /// Print the config file path and exit
+    #[arg(long, conflicts_with = "edit_config")]
+    open_config: bool,
+    /// Open the config in $VISUAL or $EDITOR (writing the default first if
+    /// missing); prints the path when neither is set
+    #[arg(long)]
+    edit_config: bool,
+    /// Print shell completions to stdout and exit
+    #[arg(long, value_enum, value_name = "SHELL", hide = true)]
+    generate_completions: Option<Shell>,
+    /// Print a roff man page to stdout and exit
+    #[arg(long, hide = true)]
+    generate_man: bool,
+}
+
+impl Args {
+    fn overrides(&self) -> Result<CliOverrides> {
+        let enable = (!self.enable_providers.is_empty())
+            .then(|| providers_from_names(&self.enable_providers, |name| std::env::var(name).ok()))
+            .transpose()?;
+        Ok(CliOverrides {
+            enable,
+            refresh_secs: self.refresh_secs,
+            timeout_secs: self.timeout_secs,
+            window: self.window.clone(),
+            providers: self.providers.clone(),
+        })
+    }
+}
+
+fn provider_names() -> clap::builder::PossibleValuesParser {
+    clap::builder::PossibleValuesParser::new(PROVIDERS.iter().map(|provider| provider.name))
+}
+
+/// How the UI is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisplayMode {
+    /// Framed dashboard with table, errors and footer
+    Full,
+    /// Single line of per-provider bars without borders
+    Compact,
+}
+
+/// What the usage area of the full layout shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    /// Table of current usage per provider
+    Table,
+    /// Line chart of session usage over the recorded history
+    Graph,
 }
 
 #[derive(Debug)]
 struct AppState {
+    config_path: PathBuf,
+    /// Providers enabled in the config as of the last refresh
+    enabled_providers: Option<usize>,
     rows: Vec<ProviderRow>,
     errors: Vec<ProviderFetchError>,
     cache_file: PathBuf,
+    tui: TuiConfig,
+    mode: DisplayMode,
+    view: View,
+    window: WaybarWindow,
+    display: DisplayConfig,
+    /// Recorded samples for the graph view, empty unless history is enabled
+    history: Vec<HistorySample>,
     last_refresh: Instant,
+    /// How long the most recent refresh took to complete
+    last_refresh_took: Duration,
     last_error: Option<String>,
     status_message: Option<String>,
+    /// Whether the rows on screen came from the cache rather than a live
+    /// fetch; `None` until the first refresh completes
+    from_cache: Option<bool>,
     spinner_index: usize,
+    /// When `spinner_index` last moved, so frames follow wall time rather
+    /// than how often the event loop happens to run
+    spinner_last_advance: Instant,
+    /// Index of the first error shown when the errors pane overflows
+    error_scroll: usize,
+    /// `refresh_secs` and `max_age_secs`, for coloring the Updated column
+    refresh: Duration,
+    max_age: Duration,
 }
 
 impl AppState {
-    fn new(cache_file: PathBuf) -> Self {
+    fn new(
+        config_path: PathBuf,
+        cache_file: PathBuf,
+        tui: TuiConfig,
+        mode: DisplayMode,
+        window: WaybarWindow,
+        display: DisplayConfig,
+    ) -> Self {
         Self {
+            config_path,
+            enabled_providers: None,
             rows: Vec::new(),
             errors: Vec::new(),
             cache_file,
+            tui,
+            mode,
+            view: View::Table,
+            window,
+            display,
+            history: Vec::new(),
             last_refresh: Instant::now(),
+            last_refresh_took: Duration::ZERO,
             last_error: None,
             status_message: None,
+            from_cache: None,
             spinner_index: 0,
+            spinner_last_advance: Instant::now(),
+            error_scroll: 0,
+            refresh: Duration::from_secs(TokenGaugeConfig::default().refresh_secs),
+            max_age: Duration::from_secs(TokenGaugeConfig::default().max_age_secs),
         }
     }
+
+    /// Step the spinner by however many frames of `tui.spinner_interval_ms`
+    /// have passed since it last moved.
+    fn advance_spinner(&mut self, now: Instant) {
+        let frame = Duration::from_millis(self.tui.spinner_interval_ms.max(1));
+        let elapsed = now.saturating_duration_since(self.spinner_last_advance);
+        let steps = (elapsed.as_millis() / frame.as_millis()) as u32;
+        if steps > 0 {
+            self.spinner_index = self.spinner_index.wrapping_add(steps as usize);
+            self.spinner_last_advance += frame * steps;
+        }
+    }
+
+    /// Move the errors pane by `delta` lines; the draw clamps it further to
+    /// what fits on screen.
+    fn scroll_errors(&mut self, delta: isize) {
+        let last = self.errors.len().saturating_sub(1);
+        self.error_scroll = self.error_scroll.saturating_add_signed(delta).min(last);
+    }
 }
 
 /// Result of a refresh operation.
 struct RefreshResult {
     rows: Vec<ProviderRow>,
     errors: Vec<ProviderFetchError>,
+    /// One-off messages for the footer, such as provider upgrades
+    notices: Vec<String>,
+    /// True when the cache was fresh enough that codexbar was not run
+    from_cache: bool,
+    history: Vec<HistorySample>,
+    /// Number of providers enabled in the reloaded config
+    enabled_providers: usize,
+}
+
+/// Write the man page, generated from the clap definitions, to `out`.
+fn write_man_page(out: &mut dyn io::Write) -> io::Result<()> {
+    clap_mangen::Man::new(Args::command()).render(out)
+}
+
+/// Write completions for `shell` to `out`.
+fn write_completions(shell: Shell, out: &mut dyn io::Write) {
+    clap_complete::generate(shell, &mut Args::command(), env!("CARGO_BIN_NAME"), out);
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    if let Some(shell) = args.generate_completions {
+        write_completions(shell, &mut io::stdout());
+        return Ok(());
+    }
+    if args.generate_man {
+        write_man_page(&mut io::stdout())?;
+        return Ok(());
+    }
+    let config_path = resolve_config_path(args.config.clone());
+    if args.open_config {
+        println!("{}", config_path.display());
+        return Ok(());
+    }
+    if args.edit_config {
+        if !edit_config(&config_path)? {
+            println!("{}", config_path.display());
+        }
+        return Ok(());
+    }
+    if args.version_json {
+        // Report even when the config is missing or broken
+        let config = load_config_with_profile(Some(config_path.clone()), args.profile.as_deref())
+            .unwrap_or_default();
+        let report = version_report(env!("CARGO_PKG_VERSION"), &config_path, &config);
+        return match write_json(&mut io::stdout(), &report) {
+            Err(error) if is_broken_pipe(&error) => Ok(()),
+            result => result,
+        };
+    }
+    if config_path == Path::new(STDIN_CONFIG) {
+        // stdin belongs to the terminal, and every refresh reloads the config
+        return Err(anyhow!("tokengauge-tui cannot read its config from stdin"));
+    }
+    if args.init {
+        ensure_config(&config_path, true, args.profile.as_deref())?;
+        println!("config: {}", config_path.display());
+        return Ok(());
+    }
+    let config = apply_overrides(
+        ensure_config(&config_path, !args.no_init, args.profile.as_deref())?,
+        &args.overrides()?,
+    );
     let stdout = io::stdout();
     if !crossterm::tty::IsTty::is_tty(&stdout) {
         return Err(anyhow!("tokengauge-tui must run in a TTY"));
     }
+    // Printed before the alternate screen, so it's still there on exit
+    if let Some(warning) = volatile_cache_warning(&config) {
+        eprintln!("tokengauge: warning: {warning}");
+    }
 
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -75,7 +320,7 @@ fn main() -> Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let result = run_app(&mut terminal, &args);
+    let result = run_app(&mut terminal, &args, config);
 
     disable_raw_mode()?;
     terminal.backend_mut().execute(LeaveAlternateScreen)?;
@@ -84,34 +329,45 @@ fn main() -> Result<()> {
     result
 }
 
-fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, args: &Args) -> Result<()> {
-    // Load config to get cache file path
-    let config_path = args
-        .config
-        .clone()
-        .unwrap_or_else(tokengauge_core::default_config_path);
-    let cache_file = if config_path.exists() {
-        load_config(Some(config_path))
-            .map(|c| c.cache_file)
-            .unwrap_or_else(|_| PathBuf::from("/tmp/tokengauge-usage.json"))
+fn run_app(
+    terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
+    args: &Args,
+    config: TokenGaugeConfig,
+) -> Result<()> {
+    let refresh_interval = auto_refresh_interval(&config);
+    let mode = if args.compact {
+        DisplayMode::Compact
     } else {
-        PathBuf::from("/tmp/tokengauge-usage.json")
+        DisplayMode::Full
     };
-
-    let mut state = AppState::new(cache_file);
-    let mut pending_refresh = Some(spawn_refresh(args, false));
+    let mut state = AppState::new(
+        args.config
+            .clone()
+            .unwrap_or_else(tokengauge_core::default_config_path),
+        config.cache_file,
+        config.tui,
+        mode,
+        config.waybar.window,
+        config.display,
+    );
+    state.refresh = Duration::from_secs(config.refresh_secs);
+    state.max_age = Duration::from_secs(config.max_age_secs);
+    // Shared by every refresh, so codexbar's batch support is probed once
+    let batch = BatchSupport::new();
+    let mut pending_refresh = Some(spawn_refresh(args, &batch, false));
+    let mut refresh_started = Instant::now();
     let mut last_cache_poll = Instant::now();
+    let mut last_input = Instant::now();
 
     loop {
         if let Some(receiver) = pending_refresh.as_ref() {
             match receiver.try_recv() {
                 Ok(result) => {
                     apply_refresh_result(&mut state, result);
+                    state.last_refresh_took = refresh_started.elapsed();
                     pending_refresh = None;
                 }
-                Err(TryRecvError::Empty) => {
-                    state.spinner_index = state.spinner_index.wrapping_add(1);
-                }
+                Err(TryRecvError::Empty) => state.advance_spinner(Instant::now()),
                 Err(TryRecvError::Disconnected) => {
                     state.last_error = Some("refresh thread disconnected".to_string());
                     state.status_message = None;
@@ -122,11 +378,14 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, args: &Args) -
 
         if pending_refresh.is_none() && last_cache_poll.elapsed() >= Duration::from_secs(60) {
             last_cache_poll = Instant::now();
-            if let Ok(config) = load_config(args.config.clone())
+            if let Ok(config) = reload_config(args)
                 && let Ok(cached) = read_cache_full(&config.cache_file)
             {
-                let (payloads, errors) = cached.into_parts();
-                state.rows = payload_to_rows(payloads);
+                let (mut payloads, errors) = cached.into_parts();
+                expire_old_usage(&mut payloads, &config);
+                let mut rows = display_rows(payloads, &config.display);
+                carry_forward_stale_rows(&mut rows, &state.rows, &errors);
+                state.rows = rows;
                 state.errors = errors;
                 state.last_error = None;
             }
@@ -134,52 +393,162 @@ fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, args: &Args) -
 
         terminal.draw(|frame| draw_ui(frame, &state, pending_refresh.is_some()))?;
 
-        if event::poll(Duration::from_millis(120))?
-            && let Event::Key(key) = event::read()?
-        {
-            if should_exit(key) {
-                break;
-            }
-            if matches!(key.code, KeyCode::Char('r')) && pending_refresh.is_none() {
-                state.status_message = Some("Refreshing…".to_string());
-                pending_refresh = Some(spawn_refresh(args, true));
+        let until_refresh = refresh_interval
+            .max(state.last_refresh_took)
+            .saturating_sub(state.last_refresh.elapsed());
+        let timeout = poll_interval(
+            last_input.elapsed(),
+            Duration::from_secs(state.tui.idle_after_secs),
+            pending_refresh.is_some(),
+            until_refresh,
+        );
+        if event::poll(timeout)? {
+            last_input = Instant::now();
+            match event::read()? {
+                Event::Key(key) => {
+                    if should_exit(key) {
+                        break;
+                    }
+                    if matches!(key.code, KeyCode::Char('r')) && pending_refresh.is_none() {
+                        state.status_message = Some("Refreshing…".to_string());
+                        pending_refresh = Some(spawn_refresh(args, &batch, true));
+                        refresh_started = Instant::now();
+                    }
+                    match key.code {
+                        KeyCode::PageDown => state.scroll_errors(1),
+                        KeyCode::PageUp => state.scroll_errors(-1),
+                        _ => {}
+                    }
+                    if matches!(key.code, KeyCode::Char('g')) {
+                        state.view = match state.view {
+                            View::Table => View::Graph,
+                            View::Graph => View::Table,
+                        };
+                    }
+                }
+                Event::Resize(width, height) => {
+                    handle_resize(terminal, width, height, &state, pending_refresh.is_some())?;
+                }
+                _ => {}
             }
         }
 
         if pending_refresh.is_none()
-            && let Ok(config) = load_config(args.config.clone())
-            && state.last_refresh.elapsed() >= Duration::from_secs(config.refresh_secs)
+            && let Ok(config) = reload_config(args)
+            && should_auto_refresh(
+                pending_refresh.is_some(),
+                state.last_refresh.elapsed(),
+                state.last_refresh_took,
+                auto_refresh_interval(&config),
+            )
         {
-            pending_refresh = Some(spawn_refresh(args, false));
+            pending_refresh = Some(spawn_refresh(args, &batch, false));
+            refresh_started = Instant::now();
         }
     }
 
     Ok(())
 }
 
+/// Interval between automatic refreshes. Floored so that `refresh_secs = 0`
+/// (always fetch) does not turn the event loop into a hot loop.
+fn auto_refresh_interval(config: &TokenGaugeConfig) -> Duration {
+    Duration::from_secs(config.refresh_secs.max(MIN_AUTO_REFRESH_SECS))
+}
+
+/// How long to wait for input. Slows down after `idle_after` without input
+/// (zero disables), but stays fast while a refresh animates and never sleeps
+/// past the next automatic refresh.
+fn poll_interval(
+    idle_for: Duration,
+    idle_after: Duration,
+    pending: bool,
+    until_refresh: Duration,
+) -> Duration {
+    let idle = !pending && !idle_after.is_zero() && idle_for >= idle_after;
+    let interval = if idle { IDLE_POLL } else { ACTIVE_POLL };
+    interval.min(until_refresh.max(ACTIVE_POLL))
+}
+
+/// Whether to start an automatic refresh now. Never overlaps a pending
+/// refresh, and a refresh that took longer than the interval pushes the next
+/// one back by its own duration so slow fetches are not run back to back.
+fn should_auto_refresh(
+    pending: bool,
+    since_last: Duration,
+    last_took: Duration,
+    interval: Duration,
+) -> bool {
+    !pending && since_last >= interval.max(last_took)
+}
+
+/// Snap the viewport to the new terminal size and redraw straight away
+/// instead of waiting for the next poll tick.
+fn handle_resize<B: Backend>(
+    terminal: &mut Terminal<B>,
+    width: u16,
+    height: u16,
+    state: &AppState,
+    is_refreshing: bool,
+) -> Result<()> {
+    terminal.resize(Rect::new(0, 0, width, height))?;
+    terminal.draw(|frame| draw_ui(frame, state, is_refreshing))?;
+    Ok(())
+}
+
 fn apply_refresh_result(state: &mut AppState, result: Result<RefreshResult>) {
     match result {
         Ok(refresh) => {
-            state.rows = refresh.rows;
+            let mut rows = refresh.rows;
+            carry_forward_stale_rows(&mut rows, &state.rows, &refresh.errors);
+            state.rows = rows;
             state.errors = refresh.errors;
             state.last_error = None;
+            state.from_cache = Some(refresh.from_cache);
+            state.history = refresh.history;
+            state.enabled_providers = Some(refresh.enabled_providers);
+            // Show notices until the next refresh replaces them
+            state.status_message =
+                (!refresh.notices.is_empty()).then(|| refresh.notices.join("; "));
         }
         Err(error) => {
             state.rows.clear();
             state.errors.clear();
             state.last_error = Some(error.to_string());
+            state.status_message = None;
+            state.from_cache = None;
         }
     }
     state.last_refresh = Instant::now();
-    state.status_message = None;
 }
 
-fn spawn_refresh(args: &Args, force: bool) -> Receiver<Result<RefreshResult>> {
+/// Load the config again, as every refresh does, with the CLI overrides.
+fn reload_config(args: &Args) -> Result<TokenGaugeConfig> {
+    let config = load_config_with_profile(args.config.clone(), args.profile.as_deref())?;
+    Ok(apply_overrides(config, &args.overrides()?))
+}
+
+fn spawn_refresh(
+    args: &Args,
+    batch: &BatchSupport,
+    force: bool,
+) -> Receiver<Result<RefreshResult>> {
     let config_override = args.config.clone();
+    let profile = args.profile.clone();
+    let overrides = args.overrides();
+    let batch = batch.clone();
     let (sender, receiver) = mpsc::channel();
 
     thread::spawn(move || {
-        let result = fetch_rows_with_config(config_override, force);
+        let result = overrides.and_then(|overrides| {
+            fetch_rows_with_config(
+                config_override,
+                profile.as_deref(),
+                &overrides,
+                &batch,
+                force,
+            )
+        });
         let _ = sender.send(result);
     });
 
@@ -190,58 +559,114 @@ fn should_exit(key: KeyEvent) -> bool {
     matches!(key.code, KeyCode::Esc | KeyCode::Char('q'))
 }
 
-fn fetch_rows_with_config(config_override: Option<PathBuf>, force: bool) -> Result<RefreshResult> {
-    let config_path = config_override.unwrap_or_else(tokengauge_core::default_config_path);
-    if !config_path.exists() {
-        write_default_config(&config_path)?;
-    }
+fn fetch_rows_with_config(
+    config_override: Option<PathBuf>,
+    profile: Option<&str>,
+    overrides: &CliOverrides,
+    batch: &BatchSupport,
+    force: bool,
+) -> Result<RefreshResult> {
+    // Reload on every refresh so config edits apply without a restart
+    let config = apply_overrides(
+        load_config_with_profile(config_override, profile)?,
+        overrides,
+    );
 
-    let config = load_config(Some(config_path))?;
+    let mut notices: Vec<String> = config.cache_clock_skew_warning().into_iter().collect();
 
     // Try to read from cache first
-    let cached = read_cache_full(&config.cache_file).ok();
+    let cached = read_cache_or_recover(&config.cache_file).unwrap_or_else(|recovered| {
+        notices.push(recovered.to_string());
+        None
+    });
 
     // Determine if we need to refresh
-    let stale = match fs::metadata(&config.cache_file) {
-        Ok(metadata) => metadata
-            .modified()
-            .ok()
-            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
-            .map(|age| age >= Duration::from_secs(config.refresh_secs))
-            .unwrap_or(true),
-        Err(_) => true,
-    };
+    let stale = config.cache_file_is_stale();
 
-    let (payloads, errors) = match cached {
-        Some(cached) if !force && !stale => cached.into_parts(),
-        _ => {
-            let FetchResult { payloads, errors } = fetch_all_providers(&config);
-            // Cache both payloads and errors
-            write_cache_full(&config.cache_file, &payloads, &errors).ok();
+    let from_cache = !force && !stale && cached.is_some();
+    let (mut payloads, errors) = match cached {
+        Some(cached) if from_cache => cached.into_parts(),
+        cached => {
+            let (previous, previous_errors, mut breakers) = cached
+                .map(|cached| {
+                    (
+                        cached.payloads().to_vec(),
+                        cached.errors().to_vec(),
+                        cached.breakers(),
+                    )
+                })
+                .unwrap_or_default();
+            let FetchResult {
+                payloads,
+                mut errors,
+            } = fetch_with_breakers(&config, &mut breakers, batch, force);
+            merge_error_times(&mut errors, &previous_errors, unix_now());
+            notices.extend(
+                version_changes(&previous, &payloads)
+                    .into_iter()
+                    .map(|(provider, _, version)| format!("{provider} updated to {version}")),
+            );
+            // Cache payloads, errors and breaker state
+            if config.cache_write_due(force) {
+                write_cache_full(
+                    &config.cache_file,
+                    &payloads,
+                    &errors,
+                    &breakers,
+                    &config.cache,
+                )
+                .ok();
+            }
+            if let Err(error) = record_history(&config, &payloads) {
+                notices.push(format!("history not recorded: {error}"));
+            }
             (payloads, errors)
         }
     };
 
-    let rows = payload_to_rows(payloads);
-    Ok(RefreshResult { rows, errors })
+    expire_old_usage(&mut payloads, &config);
+    let mut rows = display_rows(payloads, &config.display);
+    if from_cache {
+        mark_from_cache(&mut rows);
+    }
+    let enabled = config.providers.enabled_providers();
+    let history = if config.history.enabled {
+        let mut history = read_history(&config.history.file).unwrap_or_default();
+        history.retain(|sample| {
+            enabled
+                .iter()
+                .any(|provider| provider.name == sample.provider)
+        });
+        history
+    } else {
+        Vec::new()
+    };
+    Ok(RefreshResult {
+        rows,
+        errors,
+        notices,
+        from_cache,
+        history,
+        enabled_providers: enabled.len(),
+    })
 }
 
-fn percent_color(percent_left: u8) -> Color {
-    match percent_left {
-        70..=100 => Color::Green,
-        40..=69 => Color::Yellow,
-        20..=39 => Color::LightRed,
-        _ => Color::Red,
+fn severity_color(severity: Severity) -> Color {
+    match severity {
+        Severity::Ok => Color::Green,
+        Severity::Warning => Color::Yellow,
+        Severity::High => Color::LightRed,
+        Severity::Critical => Color::Red,
     }
 }
 
-fn bar_line(percent_used: Option<u8>) -> Line<'static> {
+fn bar_line(percent_used: Option<u8>, display: &DisplayConfig) -> Line<'static> {
     match percent_used {
-        Some(percent) => {
-            let percent = percent.min(100);
+        Some(used) => {
+            let percent = display.show.value(used);
             let filled = (percent as usize * BAR_WIDTH).div_ceil(100);
             let empty = BAR_WIDTH.saturating_sub(filled);
-            let color = percent_color(100 - percent);
+            let color = severity_color(display.severity(used));
             let filled_bar = "█".repeat(filled);
             let empty_bar = "░".repeat(empty);
             Line::from(vec![
@@ -257,14 +682,230 @@ fn bar_line(percent_used: Option<u8>) -> Line<'static> {
     }
 }
 
+/// Session and weekly bars on two lines of one cell, each tagged with
+/// the window it belongs to.
+fn stacked_bar_lines(row: &ProviderRow, display: &DisplayConfig) -> Text<'static> {
+    [("S ", row.session_used), ("W ", row.weekly_used)]
+        .into_iter()
+        .map(|(label, used)| {
+            let mut line = bar_line(used, display);
+            line.spans
+                .insert(0, Span::styled(label, Style::default().fg(Color::Gray)));
+            line
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Credits text, preceded by a small usage bar when the limit is known.
+fn credits_line(row: &ProviderRow, display: &DisplayConfig) -> Line<'static> {
+    let text_style = Style::default().fg(Color::LightGreen);
+    let Some(used) = row.credits_used_percent else {
+        return Line::from(Span::styled(row.credits.clone(), text_style));
+    };
+    let used = used.round() as u8;
+    let filled = (usize::from(used) * CREDITS_BAR_WIDTH).div_ceil(100);
+    let color = severity_color(display.severity(used));
+    Line::from(vec![
+        Span::styled("█".repeat(filled), Style::default().fg(color)),
+        Span::styled(
+            "░".repeat(CREDITS_BAR_WIDTH - filled),
+            Style::default().fg(Color::DarkGray),
+        ),
+        Span::styled(format!(" {}", row.credits), text_style),
+    ])
+}
+
+/// Returns true if either window is in the critical severity band.
+fn is_critical(row: &ProviderRow, display: &DisplayConfig) -> bool {
+    [row.session_used, row.weekly_used]
+        .into_iter()
+        .flatten()
+        .any(|used| display.severity(used) == Severity::Critical)
+}
+
+fn provider_style(row: &ProviderRow, tui: &TuiConfig, display: &DisplayConfig) -> Style {
+    let style = Style::default().add_modifier(Modifier::BOLD);
+    if tui.highlight_critical && is_critical(row, display) {
+        style.add_modifier(Modifier::REVERSED)
+    } else {
+        style
+    }
+}
+
+/// Stale rows are dimmed so carried-forward numbers read as old.
+fn row_style(row: &ProviderRow) -> Style {
+    if row.is_stale {
+        Style::default().add_modifier(Modifier::DIM)
+    } else {
+        Style::default()
+    }
+}
+
+fn updated_text(row: &ProviderRow) -> String {
+    match (&row.stale_since, row.is_stale) {
+        (Some(since), true) => format!("{since} stale"),
+        (None, true) => format!("{} stale", row.updated),
+        _ => row.updated.clone(),
+    }
+}
+
+/// Updated column color: green within one refresh interval, yellow when
+/// older, red once past `max_age_secs`; gray when the age is unknown.
+fn updated_color(row: &ProviderRow, refresh: Duration, max_age: Duration) -> Color {
+    match row.updated_age_secs {
+        Some(age) => match classify_age(Duration::from_secs(age), refresh, max_age) {
+            AgeState::Fresh => Color::Green,
+            AgeState::Stale => Color::Yellow,
+            AgeState::Expired => Color::Red,
+        },
+        None => Color::DarkGray,
+    }
+}
+
+/// Whether the last fetch for `row`'s provider failed, so the row is showing
+/// carried-forward data (or none at all).
+fn row_failed(row: &ProviderRow, errors: &[ProviderFetchError]) -> bool {
+    errors.iter().any(|error| error.provider == row.name)
+}
+
+/// Header text and width of a table column.
+fn column_header(column: TuiColumn) -> (&'static str, u16) {
+    match column {
+        TuiColumn::Status => (" ", 1),
+        TuiColumn::Provider => ("Provider", 12),
+        TuiColumn::Session => ("Session Used", 18),
+        TuiColumn::SessionReset => ("Session Reset", 20),
+        TuiColumn::Weekly => ("Weekly Used", 18),
+        TuiColumn::WeeklyReset => ("Weekly Reset", 20),
+        TuiColumn::Credits => ("Credits", 20),
+        TuiColumn::Source => ("Source", 18),
+        TuiColumn::Updated => ("Updated", 8),
+    }
+}
+
+/// Table columns to draw, without the weekly ones when `display.show_weekly`
+/// is off.
+fn table_columns(state: &AppState) -> Vec<TuiColumn> {
+    state
+        .tui
+        .visible_columns()
+        .into_iter()
+        .filter(|&column| {
+            state.display.show_weekly
+                || !matches!(column, TuiColumn::Weekly | TuiColumn::WeeklyReset)
+        })
+        .collect()
+}
+
+/// Whether the Session cell also carries the weekly bar.
+fn stacks_windows(state: &AppState) -> bool {
+    state.display.show_weekly && state.tui.stacks_windows()
+}
+
+fn column_cell(column: TuiColumn, row: &ProviderRow, state: &AppState) -> Cell<'static> {
+    let styled =
+        |text: String, color: Color| Cell::from(Span::styled(text, Style::default().fg(color)));
+    match column {
+        TuiColumn::Status if row_failed(row, &state.errors) => styled("✗".to_string(), Color::Red),
+        TuiColumn::Status => styled("✓".to_string(), Color::Green),
+        TuiColumn::Provider => Cell::from(Span::styled(
+            row.provider.clone(),
+            provider_style(row, &state.tui, &state.display),
+        )),
+        TuiColumn::Session if stacks_windows(state) => {
+            Cell::from(stacked_bar_lines(row, &state.display))
+        }
+        TuiColumn::Session => Cell::from(bar_line(row.session_used, &state.display)),
+        TuiColumn::SessionReset => styled(row.session_reset.clone(), Color::Gray),
+        TuiColumn::Weekly => Cell::from(bar_line(row.weekly_used, &state.display)),
+        TuiColumn::WeeklyReset => styled(row.weekly_reset.clone(), Color::Gray),
+        TuiColumn::Credits => Cell::from(credits_line(row, &state.display)),
+        TuiColumn::Source => styled(row.source_text(), Color::LightBlue),
+        TuiColumn::Updated => styled(
+            updated_text(row),
+            updated_color(row, state.refresh, state.max_age),
+        ),
+    }
+}
+
+fn error_line(err: &ProviderFetchError) -> Line<'static> {
+    let mut spans = vec![
+        Span::styled(
+            format!("{}: ", err.provider),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ),
+        if err.needs_reauth() {
+            Span::styled(
+                "re-login required (log in again in the app)",
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD),
+            )
+        } else {
+            Span::styled(
+                truncate_string(&err.message, 60),
+                Style::default().fg(Color::LightRed),
+            )
+        },
+    ];
+    if let Some(since) = err.failing_for(unix_now()) {
+        spans.push(Span::styled(
+            format!(" (failing for {since})"),
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    Line::from(spans)
+}
+
+/// Lines for an errors pane with room for `height` lines: as many errors as
+/// fit from `state.error_scroll`, then the hint about where full details
+/// live, prefixed with "(+N more)" when some errors are scrolled out of view.
+fn error_lines(state: &AppState, height: u16) -> Vec<Line<'static>> {
+    let total = state.errors.len();
+    let visible = if total < height as usize {
+        total
+    } else {
+        (height as usize).saturating_sub(1).max(1)
+    };
+    let first = state.error_scroll.min(total - visible);
+    let mut lines: Vec<Line> = state.errors[first..first + visible]
+        .iter()
+        .map(error_line)
+        .collect();
+
+    let hidden = total - visible;
+    let mut hint = Vec::new();
+    if hidden > 0 {
+        hint.push(Span::styled(
+            format!("(+{hidden} more, PgUp/PgDn to scroll) "),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    hint.push(Span::styled(
+        format!("Full details: {}", state.cache_file.display()),
+        Style::default().fg(Color::DarkGray),
+    ));
+    lines.push(Line::from(hint));
+    lines
+}
+
 fn draw_ui(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
+    if state.mode == DisplayMode::Compact {
+        draw_compact(frame, state, is_refreshing);
+        return;
+    }
+
     let size = frame.area();
 
     // Calculate layout based on whether we have errors
     let has_errors = !state.errors.is_empty();
     let error_height = if has_errors {
-        // 1 line per error + 1 for hint + 2 for borders, max 8 lines
-        (state.errors.len() as u16 + 1 + 2).min(8)
+        // 1 line per error + 1 for hint + 2 for borders, up to a share of
+        // the screen (but always room for one error and the hint)
+        let wanted = state.errors.len().saturating_add(3).min(u16::MAX as usize) as u16;
+        let cap = (size.height * ERRORS_MAX_HEIGHT_PERCENT / 100).max(4);
+        wanted.min(cap)
     } else {
         0
     };
@@ -286,17 +927,10 @@ fn draw_ui(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
         .split(size)
     };
 
-    let spinner_frames = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
-    let spinner = spinner_frames[state.spinner_index % spinner_frames.len()];
-    let header_label = if is_refreshing {
-        "Refreshing"
-    } else {
-        "TokenGauge Usage"
-    };
-    let header_text = if is_refreshing {
-        format!("{} {}", spinner, header_label)
-    } else {
-        header_label.to_string()
+    let header_text = match (is_refreshing, spinner_frame(state)) {
+        (true, Some(spinner)) => format!("{spinner} Refreshing"),
+        (true, None) => "Refreshing".to_string(),
+        (false, _) => "TokenGauge Usage".to_string(),
     };
 
     let header = Paragraph::new(header_text)
@@ -308,116 +942,63 @@ fn draw_ui(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
         .block(Block::default().borders(Borders::ALL).title("TokenGauge"));
     frame.render_widget(header, layout[0]);
 
-    if state.rows.is_empty() && state.errors.is_empty() {
-        let message = state
-            .status_message
-            .as_deref()
-            .or(state.last_error.as_deref())
-            .unwrap_or("No providers returned");
+    if state.view == View::Graph {
+        draw_graph(frame, state, layout[1]);
+    } else if state.rows.is_empty() && state.errors.is_empty() {
+        let (message, color) = empty_message(state);
         let empty = Paragraph::new(message)
-            .style(Style::default().fg(Color::Red))
+            .style(Style::default().fg(color))
             .block(Block::default().borders(Borders::ALL).title("Usage"));
         frame.render_widget(empty, layout[1]);
     } else {
+        let columns = table_columns(state);
+        let stacked = stacks_windows(state);
+        let row_height = if stacked { 2 } else { 1 };
         let table_rows = state.rows.iter().flat_map(|row| {
-            let primary = Row::new(vec![
-                Cell::from(Span::styled(
-                    row.provider.clone(),
-                    Style::default().add_modifier(Modifier::BOLD),
-                )),
-                Cell::from(bar_line(row.session_used)),
-                Cell::from(Span::styled(
-                    row.session_reset.clone(),
-                    Style::default().fg(Color::Gray),
-                )),
-                Cell::from(bar_line(row.weekly_used)),
-                Cell::from(Span::styled(
-                    row.weekly_reset.clone(),
-                    Style::default().fg(Color::Gray),
-                )),
-                Cell::from(Span::styled(
-                    row.credits.clone(),
-                    Style::default().fg(Color::LightGreen),
-                )),
-                Cell::from(Span::styled(
-                    row.source.clone(),
-                    Style::default().fg(Color::LightBlue),
-                )),
-                Cell::from(Span::styled(
-                    row.updated.clone(),
-                    Style::default().fg(Color::DarkGray),
-                )),
-            ]);
-            let spacer = Row::new(vec![Cell::from(" "); 8]);
+            let cells = columns
+                .iter()
+                .map(|&column| column_cell(column, row, state));
+            let primary = Row::new(cells).height(row_height).style(row_style(row));
+            let spacer = Row::new(vec![Cell::from(" "); columns.len()]);
             [primary, spacer]
         });
 
-        let table = Table::new(
-            table_rows,
-            [
-                Constraint::Length(12),
-                Constraint::Length(18),
-                Constraint::Length(20),
-                Constraint::Length(18),
-                Constraint::Length(20),
-                Constraint::Length(10),
-                Constraint::Length(18),
-                Constraint::Min(8),
-            ],
-        )
-        .header(
-            Row::new([
-                Cell::from("Provider"),
-                Cell::from("Session Used"),
-                Cell::from("Session Reset"),
-                Cell::from("Weekly Used"),
-                Cell::from("Weekly Reset"),
-                Cell::from("Credits"),
-                Cell::from("Source"),
-                Cell::from("Updated"),
-            ])
-            .style(
-                Style::default()
-                    .fg(Color::Cyan)
-                    .add_modifier(Modifier::BOLD),
-            ),
-        )
-        .block(Block::default().borders(Borders::ALL).title("Usage"));
+        let last = columns.len().saturating_sub(1);
+        let widths = columns.iter().enumerate().map(|(index, &column)| {
+            let (_, width) = column_header(column);
+            // The last column soaks up any remaining space
+            if index == last {
+                Constraint::Min(width)
+            } else {
+                Constraint::Length(width)
+            }
+        });
+        let table = Table::new(table_rows, widths)
+            .header(
+                Row::new(columns.iter().map(|&column| match column {
+                    TuiColumn::Session if stacked => Cell::from("Session/Weekly"),
+                    _ => Cell::from(column_header(column).0),
+                }))
+                .style(
+                    Style::default()
+                        .fg(Color::Cyan)
+                        .add_modifier(Modifier::BOLD),
+                ),
+            )
+            .block(Block::default().borders(Borders::ALL).title("Usage"));
 
         frame.render_widget(table, layout[1]);
     }
 
     // Render errors section if there are errors
     if has_errors {
-        let mut error_lines: Vec<Line> = state
-            .errors
-            .iter()
-            .map(|err| {
-                Line::from(vec![
-                    Span::styled(
-                        format!("{}: ", err.provider),
-                        Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
-                    ),
-                    Span::styled(
-                        truncate_string(&err.message, 60),
-                        Style::default().fg(Color::LightRed),
-                    ),
-                ])
-            })
-            .collect();
-
-        // Add hint about where to find full error details
-        error_lines.push(Line::from(Span::styled(
-            format!("Full details: {}", state.cache_file.display()),
-            Style::default().fg(Color::DarkGray),
-        )));
-
-        let errors_widget = Paragraph::new(error_lines).block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title("Errors")
-                .border_style(Style::default().fg(Color::Red)),
-        );
+        let errors_widget = Paragraph::new(error_lines(state, error_height.saturating_sub(2)))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Errors")
+                    .border_style(Style::default().fg(Color::Red)),
+            );
         frame.render_widget(errors_widget, layout[2]);
     }
 
@@ -429,7 +1010,7 @@ fn draw_ui(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
         Color::DarkGray
     };
 
-    let footer_line = Line::from(vec![
+    let mut footer_spans = vec![
         Span::styled(
             "r",
             Style::default()
@@ -438,6 +1019,20 @@ fn draw_ui(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
         ),
         Span::styled(" refresh", Style::default().fg(Color::Gray)),
         Span::styled(" | ", Style::default().fg(Color::DarkGray)),
+        Span::styled(
+            "g",
+            Style::default()
+                .fg(Color::LightCyan)
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::styled(
+            match state.view {
+                View::Table => " graph",
+                View::Graph => " table",
+            },
+            Style::default().fg(Color::Gray),
+        ),
+        Span::styled(" | ", Style::default().fg(Color::DarkGray)),
         Span::styled(
             "q/esc",
             Style::default()
@@ -452,16 +1047,990 @@ fn draw_ui(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
                 .fg(status_color)
                 .add_modifier(Modifier::BOLD),
         ),
-    ]);
+    ];
+    if let Some(from_cache) = state.from_cache {
+        footer_spans.push(Span::styled(" | ", Style::default().fg(Color::DarkGray)));
+        footer_spans.push(Span::styled(
+            if from_cache { "cached" } else { "live" },
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    let footer_line = Line::from(footer_spans);
 
     let footer = Paragraph::new(footer_line).block(Block::default().borders(Borders::ALL));
     frame.render_widget(footer, layout[footer_index]);
 }
 
+/// Session usage points per provider, as (unix seconds, percent) pairs in
+/// recorded order. Providers keep the order they first appear in; samples
+/// without a session value are skipped.
+fn history_datasets(history: &[HistorySample]) -> Vec<(String, Vec<(f64, f64)>)> {
+    let mut datasets: Vec<(String, Vec<(f64, f64)>)> = Vec::new();
+    for sample in history {
+        let Some(session) = sample.session else {
+            continue;
+        };
+        let point = (sample.timestamp as f64, session);
+        match datasets
+            .iter_mut()
+            .find(|(provider, _)| *provider == sample.provider)
+        {
+            Some((_, points)) => points.push(point),
+            None => datasets.push((sample.provider.clone(), vec![point])),
+        }
+    }
+    datasets
+}
+
+/// Local HH:MM for a unix timestamp, used for the graph's time axis.
+fn time_label(timestamp: f64) -> String {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|time| time.format("%H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Line chart of each provider's session usage over the recorded history.
+fn draw_graph(frame: &mut ratatui::Frame, state: &AppState, area: Rect) {
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title("Session history");
+    let datasets = history_datasets(&state.history);
+    if datasets.is_empty() {
+        let placeholder = Paragraph::new("No history yet (enable [history] in the config)")
+            .style(Style::default().fg(Color::DarkGray))
+            .block(block);
+        frame.render_widget(placeholder, area);
+        return;
+    }
+
+    let times = datasets
+        .iter()
+        .flat_map(|(_, points)| points.iter().map(|&(time, _)| time));
+    let start = times.clone().fold(f64::INFINITY, f64::min);
+    // Keep the axis non-empty when every sample shares one timestamp
+    let end = times.fold(f64::NEG_INFINITY, f64::max).max(start + 60.0);
+
+    let lines = datasets
+        .iter()
+        .enumerate()
+        .map(|(index, (provider, points))| {
+            Dataset::default()
+                .name(provider.clone())
+                .marker(symbols::Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(GRAPH_COLORS[index % GRAPH_COLORS.len()]))
+                .data(points)
+        })
+        .collect();
+    let chart = Chart::new(lines)
+        .block(block)
+        .x_axis(
+            Axis::default()
+                .style(Style::default().fg(Color::DarkGray))
+                .bounds([start, end])
+                .labels([time_label(start), time_label(end)]),
+        )
+        .y_axis(
+            Axis::default()
+                .style(Style::default().fg(Color::DarkGray))
+                .bounds([0.0, 100.0])
+                .labels(["0%", "50%", "100%"]),
+        );
+    frame.render_widget(chart, area);
+}
+
+fn spinner_frames(style: SpinnerStyle) -> &'static [&'static str] {
+    match style {
+        SpinnerStyle::Braille => &BRAILLE_FRAMES,
+        SpinnerStyle::Ascii => &ASCII_FRAMES,
+        SpinnerStyle::None => &[],
+    }
+}
+
+/// Current spinner frame, or `None` when animation is disabled.
+fn spinner_frame(state: &AppState) -> Option<&'static str> {
+    let frames = spinner_frames(state.tui.spinner);
+    (!frames.is_empty()).then(|| frames[state.spinner_index % frames.len()])
+}
+
+/// What to show instead of the table when there are no rows: the current
+/// status or error, a hint to enable a provider when none are, or a plain
+/// "nothing came back".
+fn empty_message(state: &AppState) -> (String, Color) {
+    if let Some(message) = state.status_message.as_ref().or(state.last_error.as_ref()) {
+        return (message.clone(), Color::Red);
+    }
+    if state.enabled_providers == Some(0) {
+        let message = format!(
+            "No providers enabled — edit {} to add one",
+            state.config_path.display()
+        );
+        return (message, Color::Yellow);
+    }
+    ("No providers returned".to_string(), Color::Red)
+}
+
+/// Render every provider on a single line, like the waybar text.
+fn draw_compact(frame: &mut ratatui::Frame, state: &AppState, is_refreshing: bool) {
+    let mut spans: Vec<Span> = Vec::new();
+
+    if is_refreshing {
+        let indicator = spinner_frame(state).unwrap_or("refreshing");
+        spans.push(Span::styled(
+            format!("{indicator} "),
+            Style::default().fg(Color::LightCyan),
+        ));
+    }
+
+    if state.rows.is_empty() && state.errors.is_empty() {
+        let (message, color) = empty_message(state);
+        spans.push(Span::styled(message, Style::default().fg(color)));
+    }
+
+    for (index, row) in state.rows.iter().enumerate() {
+        if index > 0 {
+            spans.push(Span::raw("  "));
+        }
+        let used = match state.window {
+            WaybarWindow::Daily => row.session_used,
+            WaybarWindow::Weekly => row.weekly_used,
+        };
+        spans.push(Span::styled(
+            format!("{} ", row.provider),
+            provider_style(row, &state.tui, &state.display),
+        ));
+        spans.extend(bar_line(used, &state.display).spans);
+    }
+
+    if !state.errors.is_empty() {
+        spans.push(Span::styled(
+            format!("  {} err", state.errors.len()),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ));
+    }
+
+    frame.render_widget(Paragraph::new(Line::from(spans)), frame.area());
+}
+
+/// `s` cut to at most `max_len` chars, ending in "…" when shortened.
 fn truncate_string(s: &str, max_len: usize) -> String {
-    if s.len() <= max_len {
+    if s.chars().count() <= max_len {
         s.to_string()
     } else {
-        format!("{}…", &s[..max_len - 1])
+        let kept: String = s.chars().take(max_len.saturating_sub(1)).collect();
+        format!("{kept}…")
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+#[path = "../../tokengauge-core/tests/support/mod.rs"]
+mod test_support;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{FakeCodexbar, usage_json, with_fake_codexbar};
+    use tokengauge_core::PercentDisplay;
+
+    fn row(session_used: Option<u8>, weekly_used: Option<u8>) -> ProviderRow {
+        ProviderRow {
+            provider: "Claude".to_string(),
+            name: "claude".to_string(),
+            session_used,
+            session_percent: session_used.map(f64::from),
+            session_window_minutes: Some(300),
+            session_reset: "—".to_string(),
+            session_reset_at: None,
+            weekly_used,
+            weekly_percent: weekly_used.map(f64::from),
+            weekly_window_minutes: Some(10080),
+            weekly_reset: "—".to_string(),
+            weekly_reset_at: None,
+            credits: "—".to_string(),
+            credits_remaining: None,
+            credits_total: None,
+            credits_used_percent: None,
+            version: None,
+            source_kind: None,
+            updated: "—".to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
+        }
+    }
+
+    fn render(state: &AppState, width: u16, height: u16) -> String {
+        let backend = ratatui::backend::TestBackend::new(width, height);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|frame| draw_ui(frame, state, false)).unwrap();
+        terminal
+            .backend()
+            .buffer()
+            .content
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect()
+    }
+
+    fn compact_state(rows: Vec<ProviderRow>) -> AppState {
+        let mut state = AppState::new(
+            PathBuf::from("/tmp/tokengauge/config.toml"),
+            PathBuf::from("/tmp/tokengauge-usage.json"),
+            TuiConfig::default(),
+            DisplayMode::Compact,
+            WaybarWindow::Daily,
+            DisplayConfig::default(),
+        );
+        state.rows = rows;
+        state
+    }
+
+    fn line_text(line: &Line) -> String {
+        line.spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect()
+    }
+
+    // ------------------------------------------------------------------------
+    // auto refresh tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn poll_interval_slows_down_when_idle() {
+        let idle_after = Duration::from_secs(30);
+        let far = Duration::from_secs(600);
+        assert_eq!(
+            poll_interval(Duration::from_secs(5), idle_after, false, far),
+            ACTIVE_POLL
+        );
+        assert_eq!(
+            poll_interval(Duration::from_secs(30), idle_after, false, far),
+            IDLE_POLL
+        );
+        // A running refresh keeps the spinner smooth
+        assert_eq!(
+            poll_interval(Duration::from_secs(60), idle_after, true, far),
+            ACTIVE_POLL
+        );
+        // Zero disables idle mode
+        assert_eq!(
+            poll_interval(Duration::from_secs(60), Duration::ZERO, false, far),
+            ACTIVE_POLL
+        );
+    }
+
+    #[test]
+    fn poll_interval_never_sleeps_past_auto_refresh() {
+        let idle_for = Duration::from_secs(60);
+        let idle_after = Duration::from_secs(30);
+        assert_eq!(
+            poll_interval(idle_for, idle_after, false, Duration::from_millis(400)),
+            Duration::from_millis(400)
+        );
+        // Overdue refreshes still wait one active tick rather than spinning
+        assert_eq!(
+            poll_interval(idle_for, idle_after, false, Duration::ZERO),
+            ACTIVE_POLL
+        );
+    }
+
+    #[test]
+    fn auto_refresh_interval_has_floor() {
+        let mut config = TokenGaugeConfig {
+            refresh_secs: 1,
+            ..Default::default()
+        };
+        assert_eq!(auto_refresh_interval(&config), Duration::from_secs(5));
+        config.refresh_secs = 0;
+        assert_eq!(auto_refresh_interval(&config), Duration::from_secs(5));
+        config.refresh_secs = 600;
+        assert_eq!(auto_refresh_interval(&config), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn should_auto_refresh_predicate() {
+        let secs = Duration::from_secs;
+        // Due once the interval has passed
+        assert!(!should_auto_refresh(false, secs(4), secs(1), secs(5)));
+        assert!(should_auto_refresh(false, secs(5), secs(1), secs(5)));
+        // Never while a refresh is pending
+        assert!(!should_auto_refresh(true, secs(60), secs(1), secs(5)));
+        // A slow refresh pushes the next one back by its own duration
+        assert!(!should_auto_refresh(false, secs(6), secs(20), secs(5)));
+        assert!(should_auto_refresh(false, secs(20), secs(20), secs(5)));
+    }
+
+    // ------------------------------------------------------------------------
+    // bar_line tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn bar_line_used_and_remaining() {
+        let remaining_display = DisplayConfig {
+            show: PercentDisplay::Remaining,
+            ..Default::default()
+        };
+        let used = bar_line(Some(90), &DisplayConfig::default());
+        assert_eq!(line_text(&used), "█████████░  90%");
+        let remaining = bar_line(Some(90), &remaining_display);
+        assert_eq!(line_text(&remaining), "█░░░░░░░░░  10%");
+
+        // Nearly exhausted is red either way
+        assert_eq!(used.spans[0].style.fg, Some(Color::Red));
+        assert_eq!(remaining.spans[0].style.fg, Some(Color::Red));
+        let fresh = bar_line(Some(10), &remaining_display);
+        assert_eq!(line_text(&fresh), "█████████░  90%");
+        assert_eq!(fresh.spans[0].style.fg, Some(Color::Green));
+    }
+
+    #[test]
+    fn credits_line_adds_bar_when_limit_known() {
+        let display = DisplayConfig::default();
+        let mut credits = row(None, None);
+        credits.credits = "42.00".to_string();
+        assert_eq!(line_text(&credits_line(&credits, &display)), "42.00");
+
+        credits.credits = "42.00/100.00".to_string();
+        credits.credits_used_percent = Some(58.0);
+        let line = credits_line(&credits, &display);
+        assert_eq!(line_text(&line), "███░░ 42.00/100.00");
+        assert_eq!(line.spans[0].style.fg, Some(Color::Yellow));
+    }
+
+    // ------------------------------------------------------------------------
+    // refresh result tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn apply_refresh_result_shows_notices_once() {
+        let mut state = compact_state(Vec::new());
+        state.status_message = Some("Refreshing…".to_string());
+        apply_refresh_result(
+            &mut state,
+            Ok(RefreshResult {
+                rows: vec![row(Some(1), Some(2))],
+                errors: Vec::new(),
+                notices: vec!["claude updated to 2.2.0".to_string()],
+                from_cache: false,
+                history: Vec::new(),
+                enabled_providers: 2,
+            }),
+        );
+        assert_eq!(
+            state.status_message.as_deref(),
+            Some("claude updated to 2.2.0")
+        );
+
+        apply_refresh_result(
+            &mut state,
+            Ok(RefreshResult {
+                rows: vec![row(Some(1), Some(2))],
+                errors: Vec::new(),
+                notices: Vec::new(),
+                from_cache: false,
+                history: Vec::new(),
+                enabled_providers: 2,
+            }),
+        );
+        assert_eq!(state.status_message, None);
+    }
+
+    #[test]
+    fn fetch_rows_reports_live_then_cached() {
+        let spec = FakeCodexbar {
+            json: Some(usage_json("claude", 5, 1)),
+            ..Default::default()
+        };
+        let (live, cached, forced) = with_fake_codexbar(&spec, |bin| {
+            let config_path = bin.with_file_name("config.toml");
+            std::fs::write(
+                &config_path,
+                format!(
+                    "codexbar_bin = {:?}\ncache_file = {:?}\nrefresh_secs = 600\n[providers]\nclaude = true\n",
+                    bin.display().to_string(),
+                    bin.with_file_name("cache.json").display().to_string(),
+                ),
+            )
+            .unwrap();
+            let fetch = |force| {
+                fetch_rows_with_config(
+                    Some(config_path.clone()),
+                    None,
+                    &CliOverrides::default(),
+                    &BatchSupport::new(),
+                    force,
+                )
+                .unwrap()
+            };
+            (fetch(false), fetch(false), fetch(true))
+        });
+
+        assert!(!live.from_cache);
+        assert_eq!(live.rows.len(), 1);
+        assert!(!live.rows[0].from_cache);
+        assert!(cached.from_cache);
+        assert_eq!(cached.rows[0].session_used, Some(5));
+        assert!(cached.rows[0].from_cache);
+        assert!(!forced.from_cache);
+        assert!(!forced.rows[0].from_cache);
+    }
+
+    // ------------------------------------------------------------------------
+    // graph view tests
+    // ------------------------------------------------------------------------
+
+    fn sample(timestamp: i64, provider: &str, session: Option<f64>) -> HistorySample {
+        HistorySample {
+            timestamp,
+            provider: provider.to_string(),
+            session,
+            weekly: Some(50.0),
+        }
+    }
+
+    #[test]
+    fn history_datasets_group_session_points_by_provider() {
+        let history = vec![
+            sample(100, "claude", Some(10.0)),
+            sample(100, "codex", Some(40.0)),
+            sample(200, "claude", None),
+            sample(300, "claude", Some(25.5)),
+        ];
+        assert_eq!(
+            history_datasets(&history),
+            vec![
+                ("claude".to_string(), vec![(100.0, 10.0), (300.0, 25.5)]),
+                ("codex".to_string(), vec![(100.0, 40.0)]),
+            ]
+        );
+        assert!(history_datasets(&[sample(100, "claude", None)]).is_empty());
+    }
+
+    #[test]
+    fn graph_view_shows_placeholder_without_history() {
+        let mut state = compact_state(vec![row(Some(1), Some(2))]);
+        state.mode = DisplayMode::Full;
+        state.view = View::Graph;
+        let screen = render(&state, 80, 14);
+        assert!(screen.contains("Session history"));
+        assert!(screen.contains("No history yet"));
+
+        state.history = vec![
+            sample(0, "claude", Some(10.0)),
+            sample(600, "claude", Some(90.0)),
+        ];
+        let screen = render(&state, 80, 24);
+        assert!(!screen.contains("No history yet"));
+        assert!(screen.contains("claude"));
+        assert!(screen.contains("100%"));
+    }
+
+    #[test]
+    fn footer_shows_data_source() {
+        let mut state = compact_state(vec![row(Some(1), Some(2))]);
+        state.mode = DisplayMode::Full;
+        assert!(!render(&state, 80, 12).contains("cached"));
+        state.from_cache = Some(true);
+        assert!(render(&state, 80, 12).contains("cached"));
+        state.from_cache = Some(false);
+        assert!(render(&state, 80, 12).contains("live"));
+    }
+
+    // ------------------------------------------------------------------------
+    // spinner tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn spinner_frames_per_style() {
+        assert_eq!(spinner_frames(SpinnerStyle::Braille)[0], "⠋");
+        assert_eq!(spinner_frames(SpinnerStyle::Ascii), ["|", "/", "-", "\\"]);
+        assert!(spinner_frames(SpinnerStyle::None).is_empty());
+
+        let mut state = compact_state(Vec::new());
+        state.spinner_index = 5;
+        state.tui.spinner = SpinnerStyle::Ascii;
+        assert_eq!(spinner_frame(&state), Some("/"));
+        state.tui.spinner = SpinnerStyle::None;
+        assert_eq!(spinner_frame(&state), None);
+    }
+
+    #[test]
+    fn spinner_advances_with_wall_time() {
+        let mut state = compact_state(Vec::new());
+        state.tui.spinner_interval_ms = 100;
+        let start = state.spinner_last_advance;
+
+        // Polls faster than the frame rate don't move it
+        state.advance_spinner(start + Duration::from_millis(40));
+        state.advance_spinner(start + Duration::from_millis(90));
+        assert_eq!(state.spinner_index, 0);
+        state.advance_spinner(start + Duration::from_millis(120));
+        assert_eq!(state.spinner_index, 1);
+        // The leftover 20ms counts towards the next frame
+        state.advance_spinner(start + Duration::from_millis(200));
+        assert_eq!(state.spinner_index, 2);
+        // A slow poll catches up on every missed frame
+        state.advance_spinner(start + Duration::from_millis(550));
+        assert_eq!(state.spinner_index, 5);
+    }
+
+    #[test]
+    fn no_spinner_shows_static_label() {
+        let mut state = compact_state(Vec::new());
+        state.tui.spinner = SpinnerStyle::None;
+        let backend = ratatui::backend::TestBackend::new(60, 1);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal.draw(|frame| draw_ui(frame, &state, true)).unwrap();
+        let line: String = terminal
+            .backend()
+            .buffer()
+            .content
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(line.starts_with("refreshing "), "unexpected line: {line}");
+    }
+
+    // ------------------------------------------------------------------------
+    // column tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn restricted_columns_omit_the_others() {
+        let mut state = compact_state(vec![row(Some(19), Some(12))]);
+        state.mode = DisplayMode::Full;
+        state.tui.columns = vec![TuiColumn::Provider, TuiColumn::Weekly, TuiColumn::Credits];
+
+        let screen = render(&state, 120, 12);
+        assert!(screen.contains("Provider"));
+        assert!(screen.contains("Weekly Used"));
+        assert!(screen.contains("Credits"));
+        for hidden in [
+            "Session Used",
+            "Session Reset",
+            "Weekly Reset",
+            "Source",
+            "Updated",
+        ] {
+            assert!(!screen.contains(hidden), "{hidden} should be hidden");
+        }
+        assert!(screen.contains(" 12%"));
+        assert!(!screen.contains(" 19%"));
+        assert!(!screen.contains("✓"));
+
+        state.tui.columns = TuiColumn::ALL.to_vec();
+        let screen = render(&state, 160, 12);
+        assert!(screen.contains("Session Reset"));
+        assert!(screen.contains("Updated"));
+    }
+
+    #[test]
+    fn show_weekly_false_drops_weekly_columns() {
+        let mut state = compact_state(vec![row(Some(19), Some(12))]);
+        state.mode = DisplayMode::Full;
+        let screen = render(&state, 160, 12);
+        assert!(screen.contains("Weekly Used"));
+        assert!(screen.contains("Weekly Reset"));
+
+        state.display.show_weekly = false;
+        let screen = render(&state, 160, 12);
+        assert!(screen.contains("Session Used"));
+        assert!(!screen.contains("Weekly Used"));
+        assert!(!screen.contains("Weekly Reset"));
+        assert!(!screen.contains(" 12%"));
+
+        // Stacking has no weekly bar to carry
+        state.tui.stacked_windows = true;
+        let screen = render(&state, 160, 12);
+        assert!(screen.contains("Session Used"));
+        assert!(!screen.contains("Session/Weekly"));
+    }
+
+    #[test]
+    fn stacked_windows_share_one_two_line_cell() {
+        let mut state = compact_state(vec![row(Some(19), Some(12))]);
+        state.mode = DisplayMode::Full;
+        let screen_lines = |screen: String| {
+            let chars: Vec<char> = screen.chars().collect();
+            chars
+                .chunks(160)
+                .map(|line| line.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        };
+        let side_by_side = render(&state, 160, 12);
+        assert!(side_by_side.contains("Weekly Used"));
+        let lines = screen_lines(side_by_side);
+        let line = lines.iter().find(|line| line.contains(" 19%")).unwrap();
+        assert!(line.contains(" 12%"));
+
+        state.tui.stacked_windows = true;
+        let screen = render(&state, 160, 12);
+        assert!(screen.contains("Session/Weekly"));
+        assert!(!screen.contains("Weekly Used"));
+        let lines = screen_lines(screen);
+        let session = lines.iter().position(|line| line.contains("S ")).unwrap();
+        assert!(lines[session].contains(" 19%"));
+        assert!(!lines[session].contains(" 12%"));
+        assert!(lines[session + 1].contains("W "));
+        assert!(lines[session + 1].contains(" 12%"));
+    }
+
+    #[test]
+    fn show_source_false_hides_source_column() {
+        let mut state = compact_state(vec![row(Some(19), Some(12))]);
+        state.mode = DisplayMode::Full;
+        assert!(render(&state, 160, 12).contains("Source"));
+
+        state.tui.show_source = false;
+        let screen = render(&state, 160, 12);
+        assert!(!screen.contains("Source"));
+        assert!(screen.contains("Credits"));
+        assert!(screen.contains("Updated"));
+    }
+
+    #[test]
+    fn status_column_marks_failed_providers() {
+        let mut codex = row(Some(30), Some(40));
+        codex.provider = "Codex".to_string();
+        codex.name = "codex".to_string();
+        let mut state = compact_state(vec![row(Some(19), Some(12)), codex]);
+        state.mode = DisplayMode::Full;
+        state.errors.push(ProviderFetchError::new(
+            "codex".to_string(),
+            "timeout after 2s",
+        ));
+
+        let screen = render(&state, 160, 16);
+        assert!(screen.contains("│✓ Claude"));
+        assert!(screen.contains("│✗ Codex"));
+    }
+
+    #[test]
+    fn failing_provider_keeps_its_table_row() {
+        let refresh = |rows: Vec<ProviderRow>, errors: Vec<ProviderFetchError>| {
+            Ok(RefreshResult {
+                rows,
+                errors,
+                notices: Vec::new(),
+                from_cache: false,
+                history: Vec::new(),
+                enabled_providers: 2,
+            })
+        };
+        let mut codex = row(Some(30), Some(40));
+        codex.provider = "Codex".to_string();
+        codex.name = "codex".to_string();
+        let timeout = || vec![ProviderFetchError::new("codex".to_string(), "timeout")];
+
+        // Failing from the start still gives the provider a row
+        let mut state = compact_state(Vec::new());
+        apply_refresh_result(&mut state, refresh(vec![row(Some(1), Some(2))], timeout()));
+        assert_eq!(state.rows.len(), 2);
+        assert_eq!(state.rows[1].updated, "failed");
+
+        apply_refresh_result(
+            &mut state,
+            refresh(vec![row(Some(1), Some(2)), codex], Vec::new()),
+        );
+        assert_eq!(state.rows.len(), 2);
+
+        apply_refresh_result(&mut state, refresh(vec![row(Some(1), Some(2))], timeout()));
+        assert_eq!(state.rows.len(), 2);
+        assert!(state.rows[1].is_stale);
+        assert_eq!(state.rows[1].weekly_used, Some(40));
+    }
+
+    // ------------------------------------------------------------------------
+    // stale row tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn updated_color_follows_data_age() {
+        let refresh = Duration::from_secs(600);
+        let max_age = Duration::from_secs(3600);
+        let aged = |age: Option<u64>| ProviderRow {
+            updated_age_secs: age,
+            ..row(Some(19), Some(12))
+        };
+        assert_eq!(
+            updated_color(&aged(None), refresh, max_age),
+            Color::DarkGray
+        );
+        assert_eq!(
+            updated_color(&aged(Some(0)), refresh, max_age),
+            Color::Green
+        );
+        assert_eq!(
+            updated_color(&aged(Some(599)), refresh, max_age),
+            Color::Green
+        );
+        assert_eq!(
+            updated_color(&aged(Some(600)), refresh, max_age),
+            Color::Yellow
+        );
+        assert_eq!(
+            updated_color(&aged(Some(3599)), refresh, max_age),
+            Color::Yellow
+        );
+        assert_eq!(
+            updated_color(&aged(Some(3600)), refresh, max_age),
+            Color::Red
+        );
+    }
+
+    #[test]
+    fn stale_rows_render_dimmed() {
+        let mut stale = row(Some(42), Some(5));
+        stale.provider = "Codex".to_string();
+        stale.name = "codex".to_string();
+        stale.is_stale = true;
+        stale.stale_since = Some("07:00".to_string());
+        let mut state = compact_state(vec![row(Some(19), Some(12)), stale]);
+        state.mode = DisplayMode::Full;
+
+        let backend = ratatui::backend::TestBackend::new(160, 12);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal
+            .draw(|frame| draw_ui(frame, &state, false))
+            .unwrap();
+        let buffer = terminal.backend().buffer();
+
+        let find_row = |needle: &str| {
+            (0..buffer.area.height)
+                .find(|&y| {
+                    let line: String = (0..buffer.area.width)
+                        .map(|x| buffer[(x, y)].symbol())
+                        .collect();
+                    line.contains(needle)
+                })
+                .unwrap()
+        };
+
+        // Table cells start after the left border
+        let claude_y = find_row("Claude");
+        let codex_y = find_row("Codex");
+        assert!(!buffer[(1, claude_y)].modifier.contains(Modifier::DIM));
+        assert!(buffer[(1, codex_y)].modifier.contains(Modifier::DIM));
+
+        let codex_line: String = (0..buffer.area.width)
+            .map(|x| buffer[(x, codex_y)].symbol())
+            .collect();
+        assert!(codex_line.contains("07:00 stale"));
+    }
+
+    // ------------------------------------------------------------------------
+    // truncate tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn truncate_string_counts_chars_not_bytes() {
+        assert_eq!(truncate_string("short", 60), "short");
+        let message = format!("a{}", "認証".repeat(40));
+        let truncated = truncate_string(&message, 60);
+        assert_eq!(truncated.chars().count(), 60);
+        assert!(truncated.starts_with("a認証"));
+        assert!(truncated.ends_with('…'));
+    }
+
+    // ------------------------------------------------------------------------
+    // resize tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn handle_resize_redraws_at_new_size() {
+        let state = compact_state(vec![row(Some(19), Some(12))]);
+        let backend = ratatui::backend::TestBackend::new(40, 1);
+        let mut terminal = Terminal::new(backend).unwrap();
+        terminal
+            .draw(|frame| draw_ui(frame, &state, false))
+            .unwrap();
+
+        terminal.backend_mut().resize(100, 3);
+        handle_resize(&mut terminal, 100, 3, &state, false).unwrap();
+
+        let area = terminal.get_frame().area();
+        assert_eq!(area, Rect::new(0, 0, 100, 3));
+        assert_eq!(terminal.backend().buffer().area, Rect::new(0, 0, 100, 3));
+        let first_line: String = terminal.backend().buffer().content[..100]
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(first_line.starts_with("Claude "));
+    }
+
+    // ------------------------------------------------------------------------
+    // compact mode tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn compact_renders_single_line() {
+        let mut codex = row(Some(42), Some(5));
+        codex.provider = "Codex".to_string();
+        codex.name = "codex".to_string();
+        let state = compact_state(vec![row(Some(19), Some(12)), codex]);
+
+        let line = render(&state, 80, 1);
+        assert!(line.starts_with("Claude "), "unexpected line: {line}");
+        assert!(line.contains(" 19%"));
+        assert!(line.contains("Codex "));
+        assert!(line.contains(" 42%"));
+        // No borders or table headers in compact mode
+        assert!(!line.contains('│'));
+        assert!(!line.contains("Provider"));
+    }
+
+    #[test]
+    fn compact_shows_error_count_and_empty_message() {
+        let mut state = compact_state(Vec::new());
+        assert!(render(&state, 60, 1).starts_with("No providers returned"));
+
+        state.errors.push(ProviderFetchError::new(
+            "codex".to_string(),
+            "timeout after 2s",
+        ));
+        assert!(render(&state, 60, 1).contains("1 err"));
+    }
+
+    #[test]
+    fn empty_state_tells_none_enabled_from_nothing_returned() {
+        let mut state = compact_state(Vec::new());
+        state.mode = DisplayMode::Full;
+        state.enabled_providers = Some(2);
+        let screen = render(&state, 100, 12);
+        assert!(screen.contains("No providers returned"));
+
+        state.enabled_providers = Some(0);
+        let screen = render(&state, 100, 12);
+        assert!(
+            screen.contains("No providers enabled — edit /tmp/tokengauge/config.toml to add one")
+        );
+        assert!(!screen.contains("No providers returned"));
+    }
+
+    #[test]
+    fn expired_login_renders_relogin_hint() {
+        let mut state = compact_state(vec![row(Some(1), Some(2))]);
+        state.mode = DisplayMode::Full;
+        state.errors.push(ProviderFetchError::new(
+            "claude".to_string(),
+            "codexbar failed - OAuth token has expired",
+        ));
+        let screen = render(&state, 100, 16);
+        assert!(screen.contains("claude: re-login required"));
+        assert!(!screen.contains("OAuth token has expired"));
+    }
+
+    #[test]
+    fn errors_pane_caps_height_and_shows_hidden_count() {
+        let mut state = compact_state(vec![row(Some(1), Some(2))]);
+        state.mode = DisplayMode::Full;
+        for index in 0..20 {
+            state.errors.push(ProviderFetchError::new(
+                format!("provider{index}"),
+                "timeout after 2s",
+            ));
+        }
+
+        // 40% of 30 rows is 12: 10 inner lines, 9 errors plus the hint
+        let screen = render(&state, 120, 30);
+        assert!(screen.contains("provider8: Request timed out"));
+        assert!(!screen.contains("provider9: Request timed out"));
+        assert!(screen.contains("(+11 more"));
+        assert!(screen.contains("Full details: /tmp/tokengauge-usage.json"));
+
+        // Scrolling stops once the last error is on screen
+        for _ in 0..50 {
+            state.scroll_errors(1);
+        }
+        let screen = render(&state, 120, 30);
+        assert!(!screen.contains("provider10: Request timed out"));
+        assert!(screen.contains("provider11: Request timed out"));
+        assert!(screen.contains("provider19: Request timed out"));
+        assert!(screen.contains("(+11 more"));
+    }
+
+    #[test]
+    fn errors_pane_shows_everything_when_it_fits() {
+        let mut state = compact_state(vec![row(Some(1), Some(2))]);
+        state.mode = DisplayMode::Full;
+        state.errors.push(ProviderFetchError::new(
+            "codex".to_string(),
+            "timeout after 2s",
+        ));
+        let screen = render(&state, 120, 30);
+        assert!(screen.contains("codex: Request timed out"));
+        assert!(!screen.contains("more, PgUp/PgDn"));
+    }
+
+    // ------------------------------------------------------------------------
+    // provider_style tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn provider_style_reversed_when_critical() {
+        let tui = TuiConfig::default();
+        let display = DisplayConfig::default();
+        let style = provider_style(&row(Some(95), Some(10)), &tui, &display);
+        assert!(style.add_modifier.contains(Modifier::REVERSED));
+        assert!(style.add_modifier.contains(Modifier::BOLD));
+
+        // Follows the shared severity thresholds
+        let style = provider_style(&row(Some(10), Some(81)), &tui, &display);
+        assert!(style.add_modifier.contains(Modifier::REVERSED));
+        let display = DisplayConfig {
+            critical_above: 95,
+            ..Default::default()
+        };
+        let style = provider_style(&row(Some(95), Some(10)), &tui, &display);
+        assert!(!style.add_modifier.contains(Modifier::REVERSED));
+    }
+
+    #[test]
+    fn provider_style_normal_below_threshold() {
+        let tui = TuiConfig::default();
+        let style = provider_style(&row(Some(80), None), &tui, &DisplayConfig::default());
+        assert!(!style.add_modifier.contains(Modifier::REVERSED));
+        assert!(style.add_modifier.contains(Modifier::BOLD));
+    }
+
+    #[test]
+    fn provider_style_disabled() {
+        let tui = TuiConfig {
+            highlight_critical: false,
+            ..Default::default()
+        };
+        let style = provider_style(&row(Some(100), Some(100)), &tui, &DisplayConfig::default());
+        assert!(!style.add_modifier.contains(Modifier::REVERSED));
+    }
+
+    // ------------------------------------------------------------------------
+    // completion tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn bash_completions_mention_config() {
+        let mut out = Vec::new();
+        write_completions(Shell::Bash, &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(!script.is_empty());
+        assert!(script.contains("--config"));
+    }
+
+    #[test]
+    fn man_page_mentions_program_and_config() {
+        let mut out = Vec::new();
+        write_man_page(&mut out).unwrap();
+        let roff = String::from_utf8(out).unwrap();
+        assert!(roff.contains(env!("CARGO_PKG_NAME")));
+        assert!(roff.contains("Path to the config file"));
     }
 }
@@ -1,37 +1,313 @@
-use std::path::PathBuf;
-use std::time::{Duration, SystemTime};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Result, anyhow};
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap_complete::Shell;
+use owo_colors::OwoColorize;
 use serde::Serialize;
 use tokengauge_core::{
-    FetchResult, ProviderPayload, ProviderRow, TokenGaugeConfig, WaybarWindow, ensure_cache_dir,
-    fetch_all_providers, load_config, payload_to_rows, read_cache, write_cache_full,
-    write_default_config,
+    BatchSupport, Breakers, CachedData, CliOverrides, CodexbarConfig, DisplayConfig, FetchResult,
+    PROVIDERS, PercentDisplay, ProviderFetchError, ProviderPayload, ProviderRow, Severity,
+    TokenGaugeConfig, WaybarConfig, WaybarStyle, WaybarWindow, apply_overrides,
+    build_command_preview, display_rows, edit_config, ensure_cache_dir, ensure_config,
+    expire_old_usage, fetch_single_provider_raw, fetch_with_breakers, get_provider_info,
+    is_broken_pipe, load_config_with_profile, mark_from_cache, merge_error_times,
+    parse_provider_output, prometheus_metrics, provider_label, provider_short_label,
+    providers_from_names, read_cache_full, read_cache_or_recover, record_history,
+    resolve_config_path, total_credits, unix_now, version_changes, version_report,
+    volatile_cache_warning, window_label, write_cache_full, write_json,
 };
 
 #[derive(Parser, Debug)]
 #[command(version, about = "Waybar module for TokenGauge")]
 struct Args {
+    /// Path to the config file, or "-" to read it from stdin
     #[arg(long, env = "TOKENGAUGE_CONFIG")]
     config: Option<PathBuf>,
+    /// Config profile to overlay, from a [profiles.<name>] section
+    #[arg(long, env = "TOKENGAUGE_PROFILE")]
+    profile: Option<String>,
+    /// Override `refresh_secs` from the config
+    #[arg(long, value_name = "SECS")]
+    refresh_secs: Option<u64>,
+    /// Override `timeout_secs` from the config
+    #[arg(long, value_name = "SECS")]
+    timeout_secs: Option<u64>,
+    /// Override `waybar.window`: daily or weekly
+    #[arg(long)]
+    window: Option<WaybarWindow>,
+    /// Only show this provider (repeatable), out of those the config enables
+    #[arg(long = "provider", value_name = "NAME", value_parser = provider_names())]
+    providers: Vec<String>,
+    /// Fetch these providers (comma-separated) instead of the config's;
+    /// API providers read their key from their env var
+    #[arg(
+        long = "providers",
+        value_name = "NAMES",
+        value_delimiter = ',',
+        conflicts_with = "providers"
+    )]
+    enable_providers: Vec<String>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Waybar)]
+    format: OutputFormat,
+    /// Run one provider and print codexbar's raw output and the parsed
+    /// payload, without touching the cache
+    #[arg(long, value_name = "PROVIDER")]
+    debug_provider: Option<String>,
+    /// Print the codexbar command for each enabled provider, with secrets
+    /// redacted, without running anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Print one plain sentence per provider, for screen readers and
+    /// text-to-speech
+    #[arg(long)]
+    announce: bool,
+    /// Health check for monitoring: exit 0 if every provider is OK, 1 if any
+    /// failed, 2 if any usage is critical. Prints a summary to stderr
+    #[arg(long)]
+    check: bool,
+    /// Keep running and print a new line every SECS seconds, for waybar
+    /// modules without an `interval`. Waybar output only; use --interval to
+    /// log rows over time
+    #[arg(long, value_name = "SECS", conflicts_with_all = ["announce", "format"])]
+    watch: Option<u64>,
+    /// Log usage every SECS seconds as one timestamped JSON line per cycle,
+    /// until interrupted
+    #[arg(long, value_name = "SECS", conflicts_with_all = ["watch", "check"])]
+    interval: Option<u64>,
+    /// Show whatever is in the cache, even if stale, without ever running
+    /// codexbar or writing anything. Fails only if there is no cache
+    #[arg(long, conflicts_with_all = ["check", "interval"])]
+    cache_only: bool,
+    /// Write the output to this file or named pipe instead of stdout,
+    /// replacing it on every render
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["check", "interval"])]
+    output: Option<PathBuf>,
+    /// Used percentage above which --check reports critical (defaults to
+    /// display.critical_above)
+    #[arg(long, value_name = "PERCENT", requires = "check")]
+    critical_percent: Option<u8>,
+    /// Write the default config if none exists, then exit
+    #[arg(long, conflicts_with = "no_init")]
+    init: bool,
+    /// Fail instead of writing a default config when none exists
+    #[arg(long)]
+    no_init: bool,
+    /// Print TokenGauge and codexbar versions and the config path as JSON
+    #[arg(long)]
+    version_json: bool,
+    /// Print the config file path and exit
+    #[arg(long, conflicts_with = "edit_config")]
+    open_config: bool,
+    /// Open the config in $VISUAL or $EDITOR (writing the default first if
+    /// missing); prints the path when neither is set
+    #[arg(long)]
+    edit_config: bool,
+    /// Print shell completions to stdout and exit
+    #[arg(long, value_enum, value_name = "SHELL", hide = true)]
+    generate_completions: Option<Shell>,
+    /// Print a roff man page to stdout and exit
+    #[arg(long, hide = true)]
+    generate_man: bool,
+}
+
+impl Args {
+    fn overrides(&self) -> Result<CliOverrides> {
+        let enable = (!self.enable_providers.is_empty())
+            .then(|| providers_from_names(&self.enable_providers, |name| std::env::var(name).ok()))
+            .transpose()?;
+        Ok(CliOverrides {
+            enable,
+            refresh_secs: self.refresh_secs,
+            timeout_secs: self.timeout_secs,
+            window: self.window.clone(),
+            providers: self.providers.clone(),
+        })
+    }
+}
+
+fn provider_names() -> clap::builder::PossibleValuesParser {
+    clap::builder::PossibleValuesParser::new(PROVIDERS.iter().map(|provider| provider.name))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Waybar custom module JSON (text, tooltip, class)
+    Waybar,
+    /// JSON array of provider rows for custom bars and scripts
+    RowsJson,
+    /// One line per provider, colored when printing to a terminal
+    Plain,
+    /// Prometheus text format, e.g. for node_exporter's textfile collector
+    Prometheus,
 }
 
 #[derive(Debug, Serialize)]
 struct WaybarOutput {
     text: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     tooltip: String,
+    /// Space-separated CSS classes, sent to waybar as an array when there
+    /// are several
+    #[serde(
+        skip_serializing_if = "String::is_empty",
+        serialize_with = "serialize_class"
+    )]
     class: String,
+    /// Text for the other window, shown by waybar's `format-alt`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    alt: String,
+    /// Used percentage for waybar's native bar, when only one is shown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percentage: Option<u8>,
+}
+
+impl WaybarOutput {
+    /// Add `class` to the output's classes, keeping the ones already set.
+    fn add_class(&mut self, class: &str) {
+        if !self.class.is_empty() {
+            self.class.push(' ');
+        }
+        self.class.push_str(class);
+    }
+}
+
+fn serialize_class<S: serde::Serializer>(class: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    let classes: Vec<_> = class.split_whitespace().collect();
+    match classes.as_slice() {
+        [single] => serializer.serialize_str(single),
+        _ => classes.serialize(serializer),
+    }
+}
+
+/// Output for when no providers have data, saying whether none are enabled
+/// or the enabled ones all failed. Empty text makes waybar hide the module.
+fn empty_output(
+    config: &WaybarConfig,
+    enabled: usize,
+    errors: &[ProviderFetchError],
+) -> WaybarOutput {
+    if config.hide_when_empty {
+        return WaybarOutput {
+            text: String::new(),
+            tooltip: String::new(),
+            class: String::new(),
+            alt: String::new(),
+            percentage: None,
+        };
+    }
+    let tooltip = if enabled == 0 {
+        "TokenGauge: no providers enabled — add one under [providers] in the config".to_string()
+    } else if errors.is_empty() {
+        "TokenGauge: no providers".to_string()
+    } else {
+        let failed: Vec<_> = errors
+            .iter()
+            .map(|error| format!("{}: {}", provider_label(&error.provider), error.message))
+            .collect();
+        format!("TokenGauge: all providers failed\n{}", failed.join("\n"))
+    };
+    WaybarOutput {
+        text: config.empty_text.clone(),
+        tooltip: tooltip_text(&tooltip, config.tooltip_markup),
+        class: "tokengauge-empty".into(),
+        alt: String::new(),
+        percentage: None,
+    }
 }
 
-fn format_bar(label: &str, value: Option<u8>) -> String {
-    let (bars, percent) = match value {
+/// Flag providers whose OAuth login expired with the `tokengauge-reauth`
+/// class, alongside the severity class, and a tooltip line per provider,
+/// since only logging in again helps.
+fn mark_reauth(output: &mut WaybarOutput, errors: &[ProviderFetchError], markup: bool) {
+    let expired: Vec<_> = errors.iter().filter(|error| error.needs_reauth()).collect();
+    if expired.is_empty() {
+        return;
+    }
+    output.add_class("tokengauge-reauth");
+    for error in expired {
+        if !output.tooltip.is_empty() {
+            output.tooltip.push('\n');
+        }
+        let line = format!("{}: re-login required", provider_label(&error.provider));
+        output.tooltip.push_str(&tooltip_text(&line, markup));
+    }
+}
+
+fn format_bar(label: &str, used: Option<u8>, show: PercentDisplay) -> String {
+    let (bars, percent) = match used.map(|used| show.value(used)) {
         Some(percent) => (bar_blocks(percent), format!("{percent}%")),
         None => ("—".to_string(), "—".to_string()),
     };
     format!("{label} {bars} {percent}")
 }
 
+/// Both windows on one line, each as a single block, e.g. `Claude S▅72% W▂31%`.
+fn format_both(
+    label: &str,
+    session: Option<u8>,
+    weekly: Option<u8>,
+    show: PercentDisplay,
+) -> String {
+    let window = |prefix: &str, used: Option<u8>| match used.map(|used| show.value(used)) {
+        Some(percent) => {
+            let block = bar_blocks(percent).chars().last().unwrap_or('▁');
+            format!("{prefix}{block}{percent}%")
+        }
+        None => format!("{prefix}—"),
+    };
+    format!("{label} {} {}", window("S", session), window("W", weekly))
+}
+
+/// Text for the "bars" and "numbers" styles. `windows` holds the selected
+/// window, or session and weekly when `show_both` is set: `Claude ▁▂▃` or
+/// `Claude S▅ W▂` for bars, `Claude 42%` or `Claude 19/12` for numbers.
+fn format_compact(
+    label: &str,
+    windows: &[Option<u8>],
+    style: WaybarStyle,
+    show: PercentDisplay,
+) -> String {
+    let values: Vec<Option<u8>> = windows
+        .iter()
+        .map(|used| used.map(|used| show.value(used)))
+        .collect();
+    let dash = || "—".to_string();
+    let text = match (style, values.as_slice()) {
+        (WaybarStyle::Numbers, [value]) => value.map_or_else(dash, |percent| format!("{percent}%")),
+        (WaybarStyle::Numbers, _) => values
+            .iter()
+            .map(|value| value.map_or_else(dash, |percent| percent.to_string()))
+            .collect::<Vec<_>>()
+            .join("/"),
+        (_, [value]) => value.map_or_else(dash, bar_blocks),
+        (_, _) => ["S", "W"]
+            .iter()
+            .zip(&values)
+            .map(|(prefix, value)| {
+                let block = value.map_or_else(dash, |percent| {
+                    bar_blocks(percent)
+                        .chars()
+                        .last()
+                        .unwrap_or('▁')
+                        .to_string()
+                });
+                format!("{prefix}{block}")
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    format!("{label} {text}")
+}
+
 fn bar_blocks(percent: u8) -> String {
     match percent.min(100) {
         0..=20 => "▁".to_string(),
@@ -42,114 +318,823 @@ fn bar_blocks(percent: u8) -> String {
     }
 }
 
+/// Write the man page, generated from the clap definitions, to `out`.
+fn write_man_page(out: &mut dyn io::Write) -> io::Result<()> {
+    clap_mangen::Man::new(Args::command()).render(out)
+}
+
+/// Write completions for `shell` to `out`.
+fn write_completions(shell: Shell, out: &mut dyn io::Write) {
+    clap_complete::generate(shell, &mut Args::command(), env!("CARGO_BIN_NAME"), out);
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
-    let config_path = args
-        .config
-        .unwrap_or_else(tokengauge_core::default_config_path);
-    if !config_path.exists() {
-        write_default_config(&config_path)?;
+    if let Some(shell) = args.generate_completions {
+        write_completions(shell, &mut io::stdout());
+        return Ok(());
+    }
+    if args.generate_man {
+        write_man_page(&mut io::stdout())?;
+        return Ok(());
+    }
+    let config_path = resolve_config_path(args.config.clone());
+    if args.open_config {
+        println!("{}", config_path.display());
+        return Ok(());
+    }
+    if args.edit_config {
+        if !edit_config(&config_path)? {
+            println!("{}", config_path.display());
+        }
+        return Ok(());
+    }
+    if args.version_json {
+        // Report even when the config is missing or broken
+        let config = load_config_with_profile(Some(config_path.clone()), args.profile.as_deref())
+            .unwrap_or_default();
+        let report = version_report(env!("CARGO_PKG_VERSION"), &config_path, &config);
+        return emit(args.output.as_deref(), &report);
+    }
+    if args.init {
+        ensure_config(&config_path, true, args.profile.as_deref())?;
+        println!("config: {}", config_path.display());
+        return Ok(());
+    }
+
+    let config = apply_overrides(
+        ensure_config(&config_path, !args.no_init, args.profile.as_deref())?,
+        &args.overrides()?,
+    );
+    // stdout belongs to waybar, so warnings go to stderr
+    for warning in config.providers.validate() {
+        eprintln!("tokengauge: warning: {warning}");
+    }
+    // Once per long-running process, not on every waybar tick
+    let long_running = args.watch.is_some() || args.interval.is_some();
+    if long_running && let Some(warning) = volatile_cache_warning(&config) {
+        eprintln!("tokengauge: warning: {warning}");
+    }
+
+    if args.dry_run {
+        print!("{}", format_dry_run(&config));
+        return Ok(());
+    }
+
+    if let Some(name) = &args.debug_provider {
+        return debug_provider(&config, name);
     }
 
-    let config = load_config(Some(config_path))?;
     ensure_cache_dir(&config.cache_file)?;
 
-    let payloads = match maybe_refresh(&config) {
-        Ok(payloads) => payloads,
+    if args.check {
+        let (code, summary) = match run_check(&config, args.critical_percent) {
+            Ok(status) => status,
+            Err(error) => (CHECK_ERROR, format!("ERROR: {error}")),
+        };
+        eprintln!("{summary}");
+        std::process::exit(code);
+    }
+
+    if let Some(secs) = args.interval {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler_stop = Arc::clone(&stop);
+        ctrlc::set_handler(move || handler_stop.store(true, Ordering::SeqCst))?;
+        log_usage(
+            &config,
+            Duration::from_secs(secs.max(1)),
+            &stop,
+            None,
+            &mut io::stdout(),
+        )?;
+        return Ok(());
+    }
+
+    if args.announce {
+        let (mut payloads, _) = load_payloads(&config, args.cache_only)?;
+        expire_old_usage(&mut payloads, &config);
+        let sentences: String = display_rows(payloads, &config.display)
+            .iter()
+            .map(|row| format!("{}\n", announce_sentence(row, &config.display)))
+            .collect();
+        return emit_text(args.output.as_deref(), &sentences);
+    }
+
+    if args.format == OutputFormat::Plain {
+        let (mut payloads, _) = load_payloads(&config, args.cache_only)?;
+        expire_old_usage(&mut payloads, &config);
+        let errors = cached_errors(&config);
+        let rows = display_rows(payloads, &config.display);
+        // Color codes are only for a terminal, never for --output
+        let color = args.output.is_none() && use_color();
+        return emit_text(
+            args.output.as_deref(),
+            &render_plain(&rows, &errors, &config.display, color),
+        );
+    }
+
+    if args.format == OutputFormat::Prometheus {
+        let (mut payloads, _) = load_payloads(&config, args.cache_only)?;
+        expire_old_usage(&mut payloads, &config);
+        let rows = display_rows(payloads, &config.display);
+        return emit_text(
+            args.output.as_deref(),
+            &prometheus_metrics(&rows, unix_now()),
+        );
+    }
+
+    if args.format == OutputFormat::RowsJson {
+        let (mut payloads, from_cache) = load_payloads(&config, args.cache_only)?;
+        expire_old_usage(&mut payloads, &config);
+        let mut rows = display_rows(payloads, &config.display);
+        if from_cache {
+            mark_from_cache(&mut rows);
+        }
+        if args.cache_only && config.cache_file_is_stale() {
+            for row in &mut rows {
+                row.is_stale = true;
+                row.stale_since = Some(row.updated.clone());
+            }
+        }
+        let values: Vec<_> = rows.iter().map(|row| row.to_json()).collect();
+        return emit(args.output.as_deref(), &values);
+    }
+
+    if let Some(secs) = args.watch {
+        return watch(
+            &config,
+            Duration::from_secs(secs.max(1)),
+            args.cache_only,
+            args.output.as_deref(),
+        );
+    }
+
+    emit(
+        args.output.as_deref(),
+        &module_output(&config, args.cache_only),
+    )
+}
+
+/// Write one JSON render to stdout, or to `path` for `--output`; see
+/// [`emit_with`].
+fn emit<T: Serialize + ?Sized>(path: Option<&Path>, value: &T) -> Result<()> {
+    emit_with(path, |out| write_json(out, value))
+}
+
+/// Write text output (`--announce`, `--format plain`/`prometheus`) the same
+/// way as [`emit`].
+fn emit_text(path: Option<&Path>, text: &str) -> Result<()> {
+    emit_with(path, |out| {
+        out.write_all(text.as_bytes())?;
+        out.flush()?;
+        Ok(())
+    })
+}
+
+/// Run `write` against stdout, or against `path` for `--output`. A file is
+/// replaced each time; a named pipe blocks until something reads it. A
+/// reader that goes away mid-write only costs this render.
+fn emit_with(path: Option<&Path>, write: impl Fn(&mut dyn Write) -> Result<()>) -> Result<()> {
+    let result = match path {
+        None => write(&mut io::stdout()),
+        Some(path) => fs::File::create(path)
+            .map_err(|error| anyhow!("failed to open {}: {error}", path.display()))
+            .and_then(|mut file| write(&mut file)),
+    };
+    match result {
+        Err(error) if is_broken_pipe(&error) => {
+            eprintln!("tokengauge: output reader went away, skipping this update");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// The waybar JSON for the current usage, refreshing if the cache is stale
+/// (unless `cache_only`). Failures become an error output rather than an
+/// `Err`, so waybar always gets a line to show.
+fn module_output(config: &TokenGaugeConfig, cache_only: bool) -> WaybarOutput {
+    let (mut payloads, from_cache) = match load_payloads(config, cache_only) {
+        Ok(refreshed) => refreshed,
         Err(error) => {
-            let output = WaybarOutput {
+            return WaybarOutput {
                 text: "⟂".into(),
-                tooltip: format!("TokenGauge: {error}"),
+                tooltip: tooltip_text(
+                    &format!("TokenGauge: {error}"),
+                    config.waybar.tooltip_markup,
+                ),
                 class: "tokengauge-error".into(),
+                alt: String::new(),
+                percentage: None,
             };
-            println!("{}", serde_json::to_string(&output)?);
-            return Ok(());
         }
     };
 
-    let rows = payload_to_rows(payloads);
+    expire_old_usage(&mut payloads, config);
+    let errors = cached_errors(config);
+    let mut rows = display_rows(payloads, &config.display);
+    if from_cache {
+        mark_from_cache(&mut rows);
+    }
     if rows.is_empty() {
-        let output = WaybarOutput {
-            text: "—".into(),
-            tooltip: "TokenGauge: no providers".into(),
-            class: "tokengauge-empty".into(),
-        };
-        println!("{}", serde_json::to_string(&output)?);
-        return Ok(());
+        let enabled = config.providers.enabled_providers().len();
+        let mut output = empty_output(&config.waybar, enabled, &errors);
+        mark_reauth(&mut output, &errors, config.waybar.tooltip_markup);
+        return output;
     }
 
-    let text = rows
+    let mut output = usage_output(&rows, config);
+    mark_reauth(&mut output, &errors, config.waybar.tooltip_markup);
+    // Only --cache-only ever shows a cache that is past refresh_secs
+    if from_cache && cache_only && config.cache_file_is_stale() {
+        output.tooltip.push_str("\n(stale cache)");
+    }
+    output
+}
+
+/// Print a module line every `every`, for waybar's continuous mode. Tells
+/// systemd the service is ready after the first line and keeps its watchdog
+/// fed while sleeping (both no-ops unless built with the `systemd` feature
+/// and run under a unit that asks for them).
+fn watch(
+    config: &TokenGaugeConfig,
+    every: Duration,
+    cache_only: bool,
+    output: Option<&Path>,
+) -> Result<()> {
+    let watchdog = watchdog_interval();
+    let mut ready = false;
+    loop {
+        emit(output, &module_output(config, cache_only))?;
+        if !ready {
+            notify_systemd("READY=1");
+            ready = true;
+        }
+        let next = Instant::now() + every;
+        while let Some(left) = next.checked_duration_since(Instant::now()) {
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(watchdog.map_or(left, |ping| ping.min(left)));
+            if watchdog.is_some() {
+                notify_systemd("WATCHDOG=1");
+            }
+        }
+    }
+}
+
+/// How often `log_usage` checks for Ctrl-C while waiting for the next cycle
+const STOP_POLL: Duration = Duration::from_millis(200);
+
+/// One `--interval` log line: the unix timestamp with the rows and errors
+/// of this cycle. Refresh failures are logged in the line, not fatal.
+fn usage_log_line(config: &TokenGaugeConfig, timestamp: i64) -> serde_json::Value {
+    match maybe_refresh(config) {
+        Ok((mut payloads, from_cache)) => {
+            expire_old_usage(&mut payloads, config);
+            let errors = cached_errors(config);
+            let rows: Vec<_> = display_rows(payloads, &config.display)
+                .iter()
+                .map(ProviderRow::to_json)
+                .collect();
+            serde_json::json!({
+                "timestamp": timestamp,
+                "cached": from_cache,
+                "rows": rows,
+                "errors": errors,
+            })
+        }
+        Err(error) => serde_json::json!({
+            "timestamp": timestamp,
+            "error": error.to_string(),
+        }),
+    }
+}
+
+/// Write a usage log line every `every` until `stop` is set (or after
+/// `max_cycles` lines). Each cycle runs to completion on this thread, so
+/// nothing is left running between cycles. Returns the number of lines.
+fn log_usage(
+    config: &TokenGaugeConfig,
+    every: Duration,
+    stop: &AtomicBool,
+    max_cycles: Option<usize>,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    let mut cycles = 0;
+    while !stop.load(Ordering::SeqCst) && max_cycles.is_none_or(|max| cycles < max) {
+        write_json(out, &usage_log_line(config, unix_now()))?;
+        cycles += 1;
+
+        let next = Instant::now() + every;
+        while !stop.load(Ordering::SeqCst) && max_cycles.is_none_or(|max| cycles < max) {
+            let Some(left) = next.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(left.min(STOP_POLL));
+        }
+    }
+    Ok(cycles)
+}
+
+/// Send `state` to systemd's notify socket, logging failures to stderr.
+#[cfg(feature = "systemd")]
+fn notify_systemd(state: &str) {
+    let socket = std::env::var_os("NOTIFY_SOCKET");
+    if let Err(error) = sd_notify(socket.as_deref(), state) {
+        eprintln!("tokengauge: sd_notify failed: {error}");
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+fn notify_systemd(_state: &str) {}
+
+/// Send one sd_notify datagram to `socket`, a path or an abstract name
+/// starting with "@". Returns `Ok(false)` without a socket, i.e. when not
+/// started by systemd with `Type=notify`.
+#[cfg(feature = "systemd")]
+fn sd_notify(socket: Option<&std::ffi::OsStr>, state: &str) -> io::Result<bool> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let Some(socket) = socket.filter(|socket| !socket.is_empty()) else {
+        return Ok(false);
+    };
+    let bytes = socket.as_bytes();
+    let address = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(true)
+}
+
+/// Half of systemd's `WatchdogSec`, from `WATCHDOG_USEC`, when the watchdog
+/// is enabled for this process.
+#[cfg(feature = "systemd")]
+fn watchdog_interval() -> Option<Duration> {
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.trim() != std::process::id().to_string()) {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.trim().parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+#[cfg(not(feature = "systemd"))]
+fn watchdog_interval() -> Option<Duration> {
+    None
+}
+
+/// Color only when stdout is a terminal and `NO_COLOR` is unset or empty.
+fn use_color() -> bool {
+    io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// Plain text summary: one line per provider with both windows, then any
+/// fetch errors. Bars and percentages are colored by severity when `color`
+/// is set.
+fn render_plain(
+    rows: &[ProviderRow],
+    errors: &[ProviderFetchError],
+    display: &DisplayConfig,
+    color: bool,
+) -> String {
+    let width = rows
         .iter()
+        .map(|row| row.provider.chars().count())
+        .max()
+        .unwrap_or(0);
+    let window = |used: Option<u8>| match used {
+        Some(used) => {
+            let percent = display.show.value(used);
+            let text = format!("{:<5} {percent:>3}%", bar_blocks(percent));
+            if color {
+                paint(&text, display.severity(used))
+            } else {
+                text
+            }
+        }
+        None => format!("{:<5} {:>4}", "—", "—"),
+    };
+
+    let mut out = String::new();
+    for row in rows {
+        out.push_str(&format!(
+            "{:<width$}  session {}  weekly {}\n",
+            row.provider,
+            window(row.session_used),
+            window(row.weekly_used)
+        ));
+    }
+    for error in errors {
+        let line = format!("{}: {}", error.provider, error.message);
+        if color {
+            out.push_str(&line.red().to_string());
+        } else {
+            out.push_str(&line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Color `text` to match the TUI's severity colors.
+fn paint(text: &str, severity: Severity) -> String {
+    match severity {
+        Severity::Ok => text.green().to_string(),
+        Severity::Warning => text.yellow().to_string(),
+        Severity::High => text.bright_red().to_string(),
+        Severity::Critical => text.red().bold().to_string(),
+    }
+}
+
+/// Used percentage of the window selected by `waybar.window`.
+fn window_used(row: &ProviderRow, window: &WaybarWindow) -> Option<u8> {
+    match window {
+        WaybarWindow::Daily => row.session_used,
+        WaybarWindow::Weekly => row.weekly_used,
+    }
+}
+
+/// Module text for all providers, showing `window` unless `show_both` is set.
+fn render_text(rows: &[ProviderRow], config: &TokenGaugeConfig, window: &WaybarWindow) -> String {
+    rows.iter()
         .map(|row| {
-            let used = match config.waybar.window {
-                WaybarWindow::Daily => row.session_used,
-                WaybarWindow::Weekly => row.weekly_used,
+            let label = if config.waybar.short_labels {
+                provider_short_label(&row.name, config)
+            } else {
+                &row.provider
             };
-            format_bar(&row.provider, used)
+            if config.waybar.style != WaybarStyle::Full {
+                let windows = if config.waybar.show_both {
+                    vec![row.session_used, row.weekly_used]
+                } else {
+                    vec![window_used(row, window)]
+                };
+                return format_compact(label, &windows, config.waybar.style, config.display.show);
+            }
+            if config.waybar.show_both {
+                return format_both(
+                    label,
+                    row.session_used,
+                    row.weekly_used,
+                    config.display.show,
+                );
+            }
+            format_bar(label, window_used(row, window), config.display.show)
         })
+        .chain(
+            total_credits(rows)
+                .filter(|_| config.waybar.show_total_credits)
+                .map(|total| format!("Σ {total:.2} cr")),
+        )
         .collect::<Vec<_>>()
-        .join("  ");
+        .join(&config.waybar.separator)
+}
+
+fn usage_output(rows: &[ProviderRow], config: &TokenGaugeConfig) -> WaybarOutput {
+    let text = render_text(rows, config, &config.waybar.window);
+    let alt = if config.waybar.emit_alt && !config.waybar.show_both {
+        render_text(rows, config, &config.waybar.window.opposite())
+    } else {
+        String::new()
+    };
 
     let tooltip = rows
         .iter()
-        .map(format_tooltip)
+        .map(|row| format_tooltip(row, &config.display, config.waybar.tooltip_markup))
         .collect::<Vec<_>>()
         .join("\n");
 
-    let output = WaybarOutput {
+    // Waybar can only draw one native bar, so only offer a percentage when
+    // exactly one window of one provider is on screen
+    let percentage = match rows {
+        [row] if !config.waybar.show_both => window_used(row, &config.waybar.window),
+        _ => None,
+    };
+
+    let mut output = WaybarOutput {
         text,
         tooltip,
-        class: "tokengauge".into(),
+        class: severity_class(rows, config),
+        alt,
+        percentage,
     };
+    if rows.iter().any(|row| row.from_cache) {
+        output.add_class("tokengauge-cached");
+    }
+    output
+}
 
-    println!("{}", serde_json::to_string(&output)?);
-    Ok(())
+/// "tokengauge", or "tokengauge-warning"/"-high"/"-critical" for the worst
+/// window on screen, using the same `[display]` thresholds as the TUI colors.
+fn severity_class(rows: &[ProviderRow], config: &TokenGaugeConfig) -> String {
+    let severity = rows
+        .iter()
+        .flat_map(|row| {
+            if config.waybar.show_both {
+                [row.session_used, row.weekly_used]
+            } else {
+                [window_used(row, &config.waybar.window), None]
+            }
+        })
+        .flatten()
+        .map(|used| config.display.severity(used))
+        .max()
+        .unwrap_or(Severity::Ok);
+    match severity {
+        Severity::Ok => "tokengauge".into(),
+        severity => format!("tokengauge-{}", severity.label()),
+    }
 }
 
-fn maybe_refresh(config: &TokenGaugeConfig) -> Result<Vec<ProviderPayload>> {
-    let now = SystemTime::now();
-    let stale = match std::fs::metadata(&config.cache_file) {
-        Ok(metadata) => metadata
-            .modified()
-            .ok()
-            .and_then(|modified| now.duration_since(modified).ok())
-            .map(|age| age >= Duration::from_secs(config.refresh_secs))
-            .unwrap_or(true),
-        Err(_) => true,
-    };
+/// `--check` exit codes
+const CHECK_OK: i32 = 0;
+const CHECK_ERROR: i32 = 1;
+const CHECK_CRITICAL: i32 = 2;
 
-    if stale {
-        let FetchResult { payloads, errors } = fetch_all_providers(config);
-        // Cache both payloads and errors
-        write_cache_full(&config.cache_file, &payloads, &errors)?;
-        Ok(payloads)
+/// Refresh like the module does and grade the result for `--check`.
+fn run_check(config: &TokenGaugeConfig, critical_percent: Option<u8>) -> Result<(i32, String)> {
+    let (mut payloads, _) = maybe_refresh(config)?;
+    expire_old_usage(&mut payloads, config);
+    let errors = cached_errors(config);
+    let rows = display_rows(payloads, &config.display);
+    let mut display = config.display.clone();
+    if let Some(critical) = critical_percent {
+        display.critical_above = critical;
+    }
+    Ok(check_status(&rows, &errors, &display))
+}
+
+/// Exit code and one-line summary: critical usage beats errors, which beat OK.
+fn check_status(
+    rows: &[ProviderRow],
+    errors: &[ProviderFetchError],
+    display: &DisplayConfig,
+) -> (i32, String) {
+    let critical: Vec<String> = rows
+        .iter()
+        .filter_map(|row| {
+            let used = row.session_used.max(row.weekly_used)?;
+            (display.severity(used) == Severity::Critical)
+                .then(|| format!("{} {used}% used", row.provider))
+        })
+        .collect();
+    let failed: Vec<String> = errors
+        .iter()
+        .map(|error| format!("{} ({})", error.provider, error.message))
+        .collect();
+
+    if !critical.is_empty() {
+        let mut summary = format!("CRITICAL: {}", critical.join(", "));
+        if !failed.is_empty() {
+            summary.push_str(&format!("; failed: {}", failed.join(", ")));
+        }
+        (CHECK_CRITICAL, summary)
+    } else if !failed.is_empty() {
+        (CHECK_ERROR, format!("ERROR: failed: {}", failed.join(", ")))
     } else {
-        read_cache(&config.cache_file)
+        (CHECK_OK, format!("OK: {} providers", rows.len()))
+    }
+}
+
+/// Payloads for display: `maybe_refresh`, or just the cache for
+/// `--cache-only`. The flag is true when the payloads came from the cache.
+fn load_payloads(
+    config: &TokenGaugeConfig,
+    cache_only: bool,
+) -> Result<(Vec<ProviderPayload>, bool)> {
+    if !cache_only {
+        return maybe_refresh(config);
+    }
+    if !config.cache_file.exists() {
+        return Err(anyhow!(
+            "no cache at {} (--cache-only never fetches)",
+            config.cache_file.display()
+        ));
+    }
+    // Unlike a refresh, a broken cache is reported and left where it is
+    let cached = read_cache_full(&config.cache_file).map_err(|error| {
+        anyhow!(
+            "{error:#} in {} (--cache-only never rewrites it)",
+            config.cache_file.display()
+        )
+    })?;
+    Ok((cached.into_parts().0, true))
+}
+
+/// `read_cache_or_recover`, reporting a quarantined cache on stderr.
+fn read_cache_reporting(path: &Path) -> Option<CachedData> {
+    read_cache_or_recover(path).unwrap_or_else(|recovered| {
+        eprintln!("tokengauge: {recovered}");
+        None
+    })
+}
+
+/// Errors stored alongside the cache by the last refresh.
+fn cached_errors(config: &TokenGaugeConfig) -> Vec<ProviderFetchError> {
+    read_cache_full(&config.cache_file)
+        .map(|cached| cached.errors().to_vec())
+        .unwrap_or_default()
+}
+
+/// Cached payloads if the cache is fresh, otherwise a live fetch. The flag is
+/// true when the payloads came from the cache.
+fn maybe_refresh(config: &TokenGaugeConfig) -> Result<(Vec<ProviderPayload>, bool)> {
+    if let Some(warning) = config.cache_clock_skew_warning() {
+        eprintln!("tokengauge: {warning}");
+    }
+    let stale = config.cache_file_is_stale();
+
+    let (previous, previous_errors, mut breakers) = match read_cache_reporting(&config.cache_file) {
+        Some(cached) if !stale => return Ok((cached.into_parts().0, true)),
+        Some(cached) => (
+            cached.payloads().to_vec(),
+            cached.errors().to_vec(),
+            cached.breakers(),
+        ),
+        None => (Vec::new(), Vec::new(), Breakers::new()),
+    };
+
+    // Only refreshes ever fetch, and each probes batch support at most once
+    let FetchResult {
+        payloads,
+        mut errors,
+    } = fetch_with_breakers(config, &mut breakers, &BatchSupport::new(), false);
+    merge_error_times(&mut errors, &previous_errors, unix_now());
+    for (provider, _, version) in version_changes(&previous, &payloads) {
+        eprintln!("tokengauge: {provider} updated to {version}");
+    }
+    // Cache payloads, errors and breaker state
+    if config.cache_write_due(false) {
+        write_cache_full(
+            &config.cache_file,
+            &payloads,
+            &errors,
+            &breakers,
+            &config.cache,
+        )?;
     }
+    if let Err(error) = record_history(config, &payloads) {
+        eprintln!("tokengauge: history not recorded: {error}");
+    }
+    Ok((payloads, false))
+}
+
+/// Run a single provider and dump what codexbar returned.
+fn debug_provider(config: &TokenGaugeConfig, name: &str) -> Result<()> {
+    let info = get_provider_info(name).ok_or_else(|| anyhow!("unknown provider: {name}"))?;
+    // Same provider settings as a real fetch, so the command matches
+    let provider = config.providers.configured(info);
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let output = fetch_single_provider_raw(config, &provider, timeout)?;
+    print!("{}", format_debug_output(name, &output, &config.codexbar));
+    Ok(())
+}
+
+/// One line per enabled provider: the codexbar command and its timeout.
+fn format_dry_run(config: &TokenGaugeConfig) -> String {
+    let providers = config.providers.enabled_providers();
+    if providers.is_empty() {
+        return "no providers enabled\n".to_string();
+    }
+    providers
+        .iter()
+        .map(|provider| {
+            format!(
+                "{}: {} (timeout {}s)\n",
+                provider.name,
+                build_command_preview(config, provider),
+                config.timeout_secs
+            )
+        })
+        .collect()
+}
+
+fn format_debug_output(name: &str, output: &Output, codexbar: &CodexbarConfig) -> String {
+    let parsed = match parse_provider_output(output, codexbar) {
+        Ok(payloads) => format!("{payloads:#?}"),
+        Err(error) => format!("error: {error}"),
+    };
+    format!(
+        "provider: {name}\n{}\n--- stdout ({} bytes) ---\n{}\n--- stderr ({} bytes) ---\n{}\n--- parsed ---\n{parsed}\n",
+        output.status,
+        output.stdout.len(),
+        String::from_utf8_lossy(&output.stdout),
+        output.stderr.len(),
+        String::from_utf8_lossy(&output.stderr),
+    )
 }
 
-fn format_tooltip(row: &ProviderRow) -> String {
-    let session = row
-        .session_used
-        .map(|used| format!("Session {used}% used"))
-        .unwrap_or_else(|| "Session —".into());
-    let weekly = row
-        .weekly_used
-        .map(|used| format!("Weekly {used}% used"))
-        .unwrap_or_else(|| "Weekly —".into());
+/// Describe a provider's usage in words, without glyphs, e.g.
+/// "Claude session 19 percent used, resets Jan 20 at 12:59PM; weekly 12
+/// percent used, resets Jan 26 at 8:59AM."
+fn announce_sentence(row: &ProviderRow, display: &DisplayConfig) -> String {
+    let window = |name: &str, used: Option<f64>, reset: &str| match used {
+        Some(used) => {
+            let mut text = format!(
+                "{name} {} percent {}",
+                display.format_percent(used),
+                display.show.label()
+            );
+            if reset != "—" {
+                text.push_str(&format!(", resets {reset}"));
+            }
+            text
+        }
+        None => format!("{name} unknown"),
+    };
     format!(
-        "{}: {} (resets {}) | {} (resets {})",
-        row.provider, session, row.session_reset, weekly, row.weekly_reset
+        "{} {}; {}.",
+        row.provider,
+        window("session", row.session_percent, &row.session_reset),
+        window("weekly", row.weekly_percent, &row.weekly_reset)
     )
 }
 
+/// Escape text for pango markup.
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Plain tooltip text, escaped when the tooltip is pango markup so stray
+/// `<` or `&` (e.g. in codexbar's stderr) can't break it.
+fn tooltip_text(text: &str, markup: bool) -> String {
+    if markup {
+        escape_markup(text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// One line per provider. With `markup`, text is escaped for pango and each
+/// percentage is colored by its severity.
+fn format_tooltip(row: &ProviderRow, display: &DisplayConfig, markup: bool) -> String {
+    let text = |value: &str| tooltip_text(value, markup);
+    let describe = |used: f64| {
+        let percent = format!("{}%", display.format_percent(used));
+        let percent = if markup {
+            let severity = display.severity(used.round().clamp(0.0, 100.0) as u8);
+            format!("<span color=\"{}\">{percent}</span>", severity.hex())
+        } else {
+            percent
+        };
+        format!("{percent} {}", display.show.label())
+    };
+    // Name windows by their length so a primary monthly window is not
+    // mislabelled "Session"
+    let window = |percent: Option<f64>, minutes: Option<u32>, fallback: &str| {
+        let label = window_label(minutes, fallback);
+        match percent {
+            Some(used) => format!("{label} {}", describe(used)),
+            None => format!("{label} —"),
+        }
+    };
+    let session = window(row.session_percent, row.session_window_minutes, "Session");
+    let weekly = window(row.weekly_percent, row.weekly_window_minutes, "Weekly");
+    let mut tooltip = format!(
+        "{}: {} (resets {})",
+        text(&row.provider),
+        session,
+        text(&row.session_reset)
+    );
+    if display.show_weekly {
+        tooltip.push_str(&format!(
+            " | {} (resets {})",
+            weekly,
+            text(&row.weekly_reset)
+        ));
+    }
+    // Aggregated rows list each account underneath
+    for (index, account) in row.accounts.iter().enumerate() {
+        let detail = format_tooltip(account, display, markup);
+        let detail = detail
+            .split_once(": ")
+            .map_or(detail.as_str(), |(_, rest)| rest);
+        tooltip.push_str(&format!("\n  #{}: {detail}", index + 1));
+    }
+    tooltip
+}
+
 // ============================================================================
 // Tests
 // ============================================================================
 
+#[cfg(test)]
+#[path = "../../tokengauge-core/tests/support/mod.rs"]
+mod test_support;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{FakeCodexbar, scripted, usage_json, with_fake_codexbar};
 
     // ------------------------------------------------------------------------
     // bar_blocks tests
@@ -189,7 +1174,7 @@ mod tests {
 
     #[test]
     fn format_bar_with_value() {
-        let result = format_bar("Claude", Some(42));
+        let result = format_bar("Claude", Some(42), PercentDisplay::Used);
         assert!(result.contains("Claude"));
         assert!(result.contains("42%"));
         assert!(result.contains("▁▂▃")); // 41-60% range
@@ -197,10 +1182,263 @@ mod tests {
 
     #[test]
     fn format_bar_none() {
-        let result = format_bar("Codex", None);
+        let result = format_bar("Codex", None, PercentDisplay::Used);
         assert_eq!(result, "Codex — —");
     }
 
+    #[test]
+    fn format_bar_used_and_remaining() {
+        for (used, used_text, remaining_text) in [
+            (0, "Claude ▁ 0%", "Claude ▁▂▃▅▇ 100%"),
+            (19, "Claude ▁ 19%", "Claude ▁▂▃▅▇ 81%"),
+            (50, "Claude ▁▂▃ 50%", "Claude ▁▂▃ 50%"),
+            (90, "Claude ▁▂▃▅▇ 90%", "Claude ▁ 10%"),
+            (100, "Claude ▁▂▃▅▇ 100%", "Claude ▁ 0%"),
+        ] {
+            assert_eq!(
+                format_bar("Claude", Some(used), PercentDisplay::Used),
+                used_text
+            );
+            assert_eq!(
+                format_bar("Claude", Some(used), PercentDisplay::Remaining),
+                remaining_text
+            );
+        }
+    }
+
+    #[test]
+    fn format_both_windows() {
+        assert_eq!(
+            format_both("Claude", Some(72), Some(31), PercentDisplay::Used),
+            "Claude S▅72% W▂31%"
+        );
+        assert_eq!(
+            format_both("Claude", Some(72), Some(31), PercentDisplay::Remaining),
+            "Claude S▂28% W▅69%"
+        );
+    }
+
+    #[test]
+    fn format_compact_numbers() {
+        let numbers = |windows: &[Option<u8>]| {
+            format_compact(
+                "Claude",
+                windows,
+                WaybarStyle::Numbers,
+                PercentDisplay::Used,
+            )
+        };
+        assert_eq!(numbers(&[Some(19), Some(12)]), "Claude 19/12");
+        assert_eq!(numbers(&[Some(42)]), "Claude 42%");
+        assert_eq!(numbers(&[None, Some(12)]), "Claude —/12");
+        assert_eq!(numbers(&[None]), "Claude —");
+        assert_eq!(
+            format_compact(
+                "Claude",
+                &[Some(19), Some(12)],
+                WaybarStyle::Numbers,
+                PercentDisplay::Remaining
+            ),
+            "Claude 81/88"
+        );
+    }
+
+    #[test]
+    fn format_compact_bars() {
+        let bars = |windows: &[Option<u8>]| {
+            format_compact("Claude", windows, WaybarStyle::Bars, PercentDisplay::Used)
+        };
+        assert_eq!(bars(&[Some(42)]), "Claude ▁▂▃");
+        assert_eq!(bars(&[Some(72), None]), "Claude S▅ W—");
+        assert_eq!(bars(&[None]), "Claude —");
+    }
+
+    #[test]
+    fn format_both_missing_windows() {
+        assert_eq!(
+            format_both("Codex", Some(5), None, PercentDisplay::Used),
+            "Codex S▁5% W—"
+        );
+        assert_eq!(
+            format_both("Codex", None, None, PercentDisplay::Used),
+            "Codex S— W—"
+        );
+    }
+
+    // ------------------------------------------------------------------------
+    // usage_output tests
+    // ------------------------------------------------------------------------
+
+    fn shown(show: PercentDisplay) -> DisplayConfig {
+        DisplayConfig {
+            show,
+            ..Default::default()
+        }
+    }
+
+    fn usage_row(name: &str, session_used: Option<u8>, weekly_used: Option<u8>) -> ProviderRow {
+        ProviderRow {
+            provider: provider_label(name).to_string(),
+            name: name.to_string(),
+            session_used,
+            session_percent: session_used.map(f64::from),
+            session_window_minutes: Some(300),
+            session_reset: "—".to_string(),
+            session_reset_at: None,
+            weekly_used,
+            weekly_percent: weekly_used.map(f64::from),
+            weekly_window_minutes: Some(10080),
+            weekly_reset: "—".to_string(),
+            weekly_reset_at: None,
+            credits: "—".to_string(),
+            credits_remaining: None,
+            credits_total: None,
+            credits_used_percent: None,
+            version: None,
+            source_kind: Some("oauth".to_string()),
+            updated: "—".to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn usage_output_percentage_only_for_single_provider() {
+        let mut config = TokenGaugeConfig::default();
+        let single = serde_json::to_value(usage_output(
+            &[usage_row("claude", Some(42), Some(7))],
+            &config,
+        ))
+        .unwrap();
+        assert_eq!(single["percentage"], 42);
+
+        config.waybar.window = WaybarWindow::Weekly;
+        let weekly = usage_output(&[usage_row("claude", Some(42), Some(7))], &config);
+        assert_eq!(weekly.percentage, Some(7));
+
+        let multi = serde_json::to_value(usage_output(
+            &[
+                usage_row("claude", Some(42), Some(7)),
+                usage_row("codex", Some(10), Some(3)),
+            ],
+            &config,
+        ))
+        .unwrap();
+        assert!(multi.get("percentage").is_none());
+        assert_eq!(multi["class"], "tokengauge");
+    }
+
+    #[test]
+    fn class_follows_worst_visible_window_with_custom_thresholds() {
+        let mut config = TokenGaugeConfig::default();
+        config.display.warning_above = 10;
+        config.display.high_above = 20;
+        config.display.critical_above = 50;
+        let rows = [
+            usage_row("claude", Some(42), Some(7)),
+            usage_row("codex", Some(15), Some(60)),
+        ];
+
+        config.waybar.window = WaybarWindow::Daily;
+        assert_eq!(usage_output(&rows, &config).class, "tokengauge-high");
+        config.waybar.window = WaybarWindow::Weekly;
+        assert_eq!(usage_output(&rows, &config).class, "tokengauge-critical");
+        config.display.critical_above = 70;
+        assert_eq!(usage_output(&rows, &config).class, "tokengauge-high");
+        config.waybar.show_both = true;
+        config.display.high_above = 65;
+        assert_eq!(usage_output(&rows, &config).class, "tokengauge-warning");
+        config.display.warning_above = 90;
+        assert_eq!(usage_output(&rows, &config).class, "tokengauge");
+    }
+
+    #[test]
+    fn custom_separator_only_between_providers() {
+        let rows = [
+            usage_row("claude", Some(42), Some(7)),
+            usage_row("codex", Some(10), Some(3)),
+        ];
+        let mut config = TokenGaugeConfig::default();
+        config.waybar.separator = " │ ".to_string();
+        let text = render_text(&rows, &config, &WaybarWindow::Daily);
+        assert_eq!(text, "Claude ▁▂▃ 42% │ Codex ▁ 10%");
+        assert!(!text.starts_with(' ') && !text.ends_with(' '));
+
+        let single = render_text(&rows[..1], &config, &WaybarWindow::Daily);
+        assert!(!single.contains('│'));
+    }
+
+    #[test]
+    fn short_labels_in_text_only_when_enabled() {
+        let rows = [
+            usage_row("claude", Some(42), Some(7)),
+            usage_row("codex", Some(10), Some(3)),
+        ];
+        let mut config = TokenGaugeConfig::default();
+        let text = render_text(&rows, &config, &WaybarWindow::Daily);
+        assert!(text.starts_with("Claude "));
+
+        config.waybar.short_labels = true;
+        config
+            .short_labels
+            .insert("codex".to_string(), "CX".to_string());
+        let text = render_text(&rows, &config, &WaybarWindow::Daily);
+        assert_eq!(text, "CLD ▁▂▃ 42%  CX ▁ 10%");
+        // Tooltip keeps full labels
+        assert!(usage_output(&rows, &config).tooltip.starts_with("Claude: "));
+    }
+
+    #[test]
+    fn short_labels_apply_to_aggregated_rows() {
+        let mut row = usage_row("claude", Some(42), Some(7));
+        row.provider = "Claude (2 accounts)".to_string();
+        let mut config = TokenGaugeConfig::default();
+        config.waybar.short_labels = true;
+        let text = render_text(&[row], &config, &WaybarWindow::Daily);
+        assert_eq!(text, "CLD ▁▂▃ 42%");
+    }
+
+    #[test]
+    fn usage_output_alt_shows_opposite_window() {
+        let rows = [usage_row("claude", Some(42), Some(7))];
+        let mut config = TokenGaugeConfig::default();
+
+        // Off by default
+        let output = serde_json::to_value(usage_output(&rows, &config)).unwrap();
+        assert!(output.get("alt").is_none());
+
+        config.waybar.emit_alt = true;
+        let daily = usage_output(&rows, &config);
+        assert_eq!(daily.text, "Claude ▁▂▃ 42%");
+        assert_eq!(daily.alt, "Claude ▁ 7%");
+
+        config.waybar.window = WaybarWindow::Weekly;
+        let weekly = usage_output(&rows, &config);
+        assert_eq!(weekly.text, "Claude ▁ 7%");
+        assert_eq!(weekly.alt, "Claude ▁▂▃ 42%");
+    }
+
+    #[test]
+    fn total_credits_appended_when_enabled() {
+        let mut config = TokenGaugeConfig::default();
+        let mut zai = usage_row("zai", Some(10), None);
+        zai.credits_remaining = Some(128.4);
+        let rows = [usage_row("claude", Some(42), Some(7)), zai];
+        let plain = usage_output(&rows, &config).text;
+        assert!(!plain.contains('Σ'), "{plain}");
+
+        config.waybar.show_total_credits = true;
+        let text = usage_output(&rows, &config).text;
+        assert_eq!(text, format!("{plain}  Σ 128.40 cr"));
+
+        // Nothing to sum leaves the text alone
+        let oauth_only = [usage_row("claude", Some(42), Some(7))];
+        assert!(!usage_output(&oauth_only, &config).text.contains('Σ'));
+    }
+
     // ------------------------------------------------------------------------
     // format_tooltip tests
     // ------------------------------------------------------------------------
@@ -209,41 +1447,702 @@ mod tests {
     fn format_tooltip_full_data() {
         let row = ProviderRow {
             provider: "Claude".to_string(),
+            name: "claude".to_string(),
             session_used: Some(19),
+            session_percent: Some(19.0),
             session_window_minutes: Some(300),
             session_reset: "Jan 20 at 12:59PM".to_string(),
+            session_reset_at: None,
             weekly_used: Some(12),
+            weekly_percent: Some(12.0),
             weekly_window_minutes: Some(10080),
             weekly_reset: "Jan 26 at 8:59AM".to_string(),
+            weekly_reset_at: None,
             credits: "—".to_string(),
-            source: "2.1.12 (oauth)".to_string(),
+            credits_remaining: None,
+            credits_total: None,
+            credits_used_percent: None,
+            version: Some("2.1.12".to_string()),
+            source_kind: Some("oauth".to_string()),
             updated: "07:37".to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
         };
-        let tooltip = format_tooltip(&row);
+        let tooltip = format_tooltip(&row, &shown(PercentDisplay::Used), false);
         assert!(tooltip.contains("Claude"));
-        assert!(tooltip.contains("Session 19% used"));
+        assert!(tooltip.contains("5h 19% used"));
         assert!(tooltip.contains("Jan 20 at 12:59PM"));
         assert!(tooltip.contains("Weekly 12% used"));
         assert!(tooltip.contains("Jan 26 at 8:59AM"));
+
+        let tooltip = format_tooltip(&row, &shown(PercentDisplay::Remaining), false);
+        assert!(tooltip.contains("5h 81% remaining"));
+        assert!(tooltip.contains("Weekly 88% remaining"));
+    }
+
+    #[test]
+    fn format_tooltip_uses_display_precision() {
+        let mut row = usage_row("claude", Some(43), Some(7));
+        row.session_percent = Some(42.6);
+        let mut display = DisplayConfig::default();
+        assert!(format_tooltip(&row, &display, false).contains("5h 43% used"));
+        display.precision = 1;
+        let tooltip = format_tooltip(&row, &display, false);
+        assert!(tooltip.contains("5h 42.6% used"));
+        assert!(tooltip.contains("Weekly 7.0% used"));
+    }
+
+    #[test]
+    fn format_tooltip_can_hide_weekly() {
+        let row = usage_row("claude", Some(19), Some(12));
+        let display = DisplayConfig {
+            show_weekly: false,
+            ..Default::default()
+        };
+        let tooltip = format_tooltip(&row, &display, false);
+        assert!(tooltip.starts_with("Claude: 5h 19% used (resets"));
+        assert!(!tooltip.contains("Weekly"));
+        assert!(!tooltip.contains('|'));
+    }
+
+    #[test]
+    fn format_tooltip_lists_aggregated_accounts() {
+        let mut row = usage_row("claude", Some(40), None);
+        row.provider = "Claude (2 accounts)".to_string();
+        row.accounts = vec![
+            usage_row("claude", Some(40), None),
+            usage_row("claude", Some(10), None),
+        ];
+        let tooltip = format_tooltip(&row, &shown(PercentDisplay::Used), false);
+        let lines: Vec<_> = tooltip.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Claude (2 accounts): 5h 40% used"));
+        assert!(lines[1].starts_with("  #1: 5h 40% used"));
+        assert!(lines[2].starts_with("  #2: 5h 10% used"));
+    }
+
+    #[test]
+    fn format_tooltip_markup_colors_percent_by_severity() {
+        let mut row = usage_row("A&B", Some(30), Some(81));
+        row.session_reset = "<soon>".to_string();
+        let tooltip = format_tooltip(&row, &shown(PercentDisplay::Remaining), true);
+        assert!(tooltip.starts_with("A&amp;B: 5h <span color=\"#50fa7b\">70%</span> remaining"));
+        assert!(tooltip.contains("(resets &lt;soon&gt;)"));
+        // Severity follows usage even when showing what remains
+        assert!(tooltip.contains("Weekly <span color=\"#ff5555\">19%</span> remaining"));
+        assert!(!format_tooltip(&row, &shown(PercentDisplay::Used), false).contains("<span"));
     }
 
     #[test]
     fn format_tooltip_missing_data() {
         let row = ProviderRow {
             provider: "Codex".to_string(),
+            name: "codex".to_string(),
             session_used: None,
+            session_percent: None,
             session_window_minutes: None,
             session_reset: "—".to_string(),
+            session_reset_at: None,
             weekly_used: None,
+            weekly_percent: None,
             weekly_window_minutes: None,
             weekly_reset: "—".to_string(),
+            weekly_reset_at: None,
             credits: "—".to_string(),
-            source: "—".to_string(),
+            credits_remaining: None,
+            credits_total: None,
+            credits_used_percent: None,
+            version: None,
+            source_kind: None,
             updated: "—".to_string(),
+            updated_age_secs: None,
+            is_stale: false,
+            stale_since: None,
+            from_cache: false,
+            accounts: Vec::new(),
         };
-        let tooltip = format_tooltip(&row);
+        let tooltip = format_tooltip(&row, &shown(PercentDisplay::Used), false);
         assert!(tooltip.contains("Codex"));
         assert!(tooltip.contains("Session —"));
         assert!(tooltip.contains("Weekly —"));
     }
+
+    // ------------------------------------------------------------------------
+    // empty_output tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn empty_output_default_keeps_dash() {
+        let output =
+            serde_json::to_string(&empty_output(&WaybarConfig::default(), 2, &[])).unwrap();
+        assert_eq!(
+            output,
+            r#"{"text":"—","tooltip":"TokenGauge: no providers","class":"tokengauge-empty"}"#
+        );
+    }
+
+    #[test]
+    fn empty_output_custom_text() {
+        let config = WaybarConfig {
+            empty_text: "no AI".to_string(),
+            ..Default::default()
+        };
+        let output = empty_output(&config, 2, &[]);
+        assert_eq!(output.text, "no AI");
+        assert_eq!(output.class, "tokengauge-empty");
+    }
+
+    #[test]
+    fn empty_output_tells_none_enabled_from_all_failed() {
+        let config = WaybarConfig::default();
+        let none = empty_output(&config, 0, &[]);
+        assert!(none.tooltip.contains("no providers enabled"));
+
+        let errors = [ProviderFetchError::new(
+            "codex".to_string(),
+            "timeout after 2s",
+        )];
+        let failed = empty_output(&config, 1, &errors);
+        assert!(
+            failed
+                .tooltip
+                .starts_with("TokenGauge: all providers failed")
+        );
+        assert!(failed.tooltip.contains("Codex: Request timed out"));
+        assert!(!failed.tooltip.contains("enabled"));
+    }
+
+    #[test]
+    fn empty_output_escapes_errors_for_markup() {
+        let config = WaybarConfig {
+            tooltip_markup: true,
+            ..Default::default()
+        };
+        let errors = [ProviderFetchError::new(
+            "codex".to_string(),
+            "unexpected <html> & friends",
+        )];
+        let failed = empty_output(&config, 1, &errors);
+        assert!(failed.tooltip.contains("&lt;html&gt; &amp; friends"));
+        assert!(!failed.tooltip.contains("<html>"));
+    }
+
+    #[test]
+    fn empty_output_hidden() {
+        let config = WaybarConfig {
+            hide_when_empty: true,
+            empty_text: "ignored".to_string(),
+            ..Default::default()
+        };
+        let output = serde_json::to_string(&empty_output(&config, 2, &[])).unwrap();
+        assert_eq!(output, r#"{"text":""}"#);
+    }
+
+    #[test]
+    fn mark_reauth_sets_class_and_tooltip() {
+        let mut output = usage_output(
+            &[usage_row("codex", Some(10), None)],
+            &TokenGaugeConfig::default(),
+        );
+        let timeout = ProviderFetchError::new("codex".to_string(), "timeout after 2s");
+        mark_reauth(&mut output, std::slice::from_ref(&timeout), false);
+        assert_eq!(output.class, "tokengauge");
+
+        let expired = ProviderFetchError::new("claude".to_string(), "OAuth token expired");
+        mark_reauth(&mut output, &[timeout, expired], false);
+        assert_eq!(output.class, "tokengauge tokengauge-reauth");
+        assert!(output.tooltip.ends_with("\nClaude: re-login required"));
+        let json = serde_json::to_value(&output).unwrap();
+        assert_eq!(
+            json["class"],
+            serde_json::json!(["tokengauge", "tokengauge-reauth"])
+        );
+    }
+
+    // ------------------------------------------------------------------------
+    // --check tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn check_status_grades_rows_and_errors() {
+        let display = DisplayConfig::default();
+        let ok = [usage_row("claude", Some(40), Some(80))];
+        assert_eq!(
+            check_status(&ok, &[], &display),
+            (CHECK_OK, "OK: 1 providers".to_string())
+        );
+
+        let error = ProviderFetchError::new("codex".to_string(), "timeout after 2s");
+        let (code, summary) = check_status(&ok, std::slice::from_ref(&error), &display);
+        assert_eq!(code, CHECK_ERROR);
+        assert_eq!(summary, "ERROR: failed: codex (Request timed out)");
+
+        let hot = [usage_row("claude", Some(95), Some(10))];
+        let (code, summary) = check_status(&hot, &[error], &display);
+        assert_eq!(code, CHECK_CRITICAL);
+        assert_eq!(
+            summary,
+            "CRITICAL: Claude 95% used; failed: codex (Request timed out)"
+        );
+
+        let strict = DisplayConfig {
+            critical_above: 30,
+            ..Default::default()
+        };
+        assert_eq!(check_status(&ok, &[], &strict).0, CHECK_CRITICAL);
+    }
+
+    /// Config with only claude enabled, codexbar replaced by the fake `bin`
+    /// and the cache next to it.
+    fn check_config(bin: &Path) -> TokenGaugeConfig {
+        let mut config = TokenGaugeConfig {
+            codexbar_bin: bin.display().to_string(),
+            cache_file: bin.with_file_name("cache.json"),
+            ..Default::default()
+        };
+        config.providers.codex = Some(false);
+        config.providers.claude = Some(true);
+        config
+    }
+
+    #[test]
+    fn run_check_exit_codes_with_fake_codexbar() {
+        let usage = |percent: u8| FakeCodexbar {
+            json: Some(usage_json("claude", percent, 5)),
+            ..Default::default()
+        };
+        let failing = FakeCodexbar {
+            stderr: Some("boom".to_string()),
+            exit_code: 1,
+            ..Default::default()
+        };
+        for (name, spec, critical, expected) in [
+            ("ok", usage(20), None, CHECK_OK),
+            ("failed", failing, None, CHECK_ERROR),
+            ("critical", usage(95), None, CHECK_CRITICAL),
+            ("threshold", usage(20), Some(10), CHECK_CRITICAL),
+        ] {
+            let (code, summary) = with_fake_codexbar(&spec, |bin| {
+                run_check(&check_config(bin), critical).unwrap()
+            });
+            assert_eq!(code, expected, "{name}: {summary}");
+        }
+    }
+
+    #[test]
+    fn log_usage_writes_a_line_per_cycle() {
+        let spec = FakeCodexbar {
+            json: Some(usage_json("claude", 20, 5)),
+            ..Default::default()
+        };
+        with_fake_codexbar(&spec, |bin| {
+            let mut config = check_config(bin);
+            config.refresh_secs = 0;
+            let stop = AtomicBool::new(false);
+            let mut out = Vec::new();
+
+            let cycles = log_usage(&config, Duration::ZERO, &stop, Some(3), &mut out).unwrap();
+
+            let lines: Vec<serde_json::Value> = String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(cycles, 3);
+            assert_eq!(lines.len(), 3);
+            for line in &lines {
+                assert!(line["timestamp"].as_u64().unwrap() > 0);
+                assert_eq!(line["rows"][0]["provider"], "Claude");
+                assert_eq!(line["rows"][0]["sessionUsed"], 20);
+            }
+
+            // A stop requested before the first cycle writes nothing
+            stop.store(true, Ordering::SeqCst);
+            let mut out = Vec::new();
+            assert_eq!(
+                log_usage(&config, Duration::ZERO, &stop, None, &mut out).unwrap(),
+                0
+            );
+            assert!(out.is_empty());
+        });
+    }
+
+    #[test]
+    fn cache_only_never_runs_codexbar() {
+        with_fake_codexbar(&scripted(r#"touch "$(dirname "$0")/ran""#), |bin| {
+            let config = check_config(bin);
+            let sentinel = bin.with_file_name("ran");
+
+            let missing = module_output(&config, true);
+            assert_eq!(missing.class, "tokengauge-error");
+            assert!(missing.tooltip.contains("--cache-only never fetches"));
+
+            // A corrupt cache is reported, not quarantined
+            std::fs::write(&config.cache_file, "{not json").unwrap();
+            let corrupt = module_output(&config, true);
+            assert_eq!(corrupt.class, "tokengauge-error");
+            assert!(corrupt.tooltip.contains("cached JSON was invalid"));
+            assert_eq!(
+                std::fs::read_to_string(&config.cache_file).unwrap(),
+                "{not json"
+            );
+
+            let payload: ProviderPayload =
+                serde_json::from_str(&usage_json("claude", 20, 5)).unwrap();
+            write_cache_full(
+                &config.cache_file,
+                &[payload],
+                &[],
+                &Breakers::new(),
+                &config.cache,
+            )
+            .unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&config.cache_file)
+                .unwrap()
+                .set_modified(std::time::SystemTime::now() - Duration::from_secs(86_400))
+                .unwrap();
+
+            let output = module_output(&config, true);
+            assert!(output.text.contains("20%"));
+            assert!(output.tooltip.ends_with("(stale cache)"));
+            assert!(!sentinel.exists());
+
+            // Without the flag the stale cache is refetched
+            module_output(&config, false);
+            assert!(sentinel.exists());
+        });
+    }
+
+    #[test]
+    fn cached_output_gets_cached_class() {
+        let spec = FakeCodexbar {
+            json: Some(usage_json("claude", 20, 5)),
+            ..Default::default()
+        };
+        with_fake_codexbar(&spec, |bin| {
+            let config = check_config(bin);
+
+            let live = module_output(&config, false);
+            assert_eq!(live.class, "tokengauge");
+
+            let cached = module_output(&config, false);
+            assert_eq!(cached.class, "tokengauge tokengauge-cached");
+            assert_eq!(cached.tooltip, live.tooltip);
+        });
+    }
+
+    #[test]
+    fn watch_only_repeats_waybar_output() {
+        let args = Args::try_parse_from(["tokengauge-waybar", "--watch", "30"]).unwrap();
+        assert_eq!(args.watch, Some(30));
+        assert_eq!(args.format, OutputFormat::Waybar);
+        for other in [
+            &["--format", "rows-json"][..],
+            &["--format", "prometheus"],
+            &["--announce"],
+        ] {
+            let argv = [&["tokengauge-waybar", "--watch", "30"][..], other].concat();
+            assert!(Args::try_parse_from(argv).is_err(), "{other:?}");
+        }
+    }
+
+    #[test]
+    fn provider_flags_cannot_be_combined() {
+        let args =
+            Args::try_parse_from(["tokengauge-waybar", "--providers", "claude,zai"]).unwrap();
+        assert_eq!(args.enable_providers, ["claude", "zai"]);
+        assert!(
+            Args::try_parse_from([
+                "tokengauge-waybar",
+                "--providers",
+                "claude,zai",
+                "--provider",
+                "claude",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn open_config_path_honors_config_flag() {
+        let args = Args::try_parse_from(["tokengauge-waybar", "--open-config"]).unwrap();
+        assert!(args.open_config);
+        assert_eq!(
+            resolve_config_path(args.config),
+            tokengauge_core::default_config_path()
+        );
+
+        let args = Args::try_parse_from([
+            "tokengauge-waybar",
+            "--open-config",
+            "--config",
+            "/tmp/other.toml",
+        ])
+        .unwrap();
+        assert_eq!(
+            resolve_config_path(args.config),
+            PathBuf::from("/tmp/other.toml")
+        );
+        assert!(
+            Args::try_parse_from(["tokengauge-waybar", "--open-config", "--edit-config"]).is_err()
+        );
+    }
+
+    #[test]
+    fn write_json_escapes_awkward_labels() {
+        let mut row = usage_row("Cl\"au\nde\t\u{7}", Some(42), Some(7));
+        row.session_reset = "in \"2h\"\r\n".to_string();
+        let config = TokenGaugeConfig::default();
+        let mut out = Vec::new();
+        write_json(&mut out, &usage_output(&[row.clone()], &config)).unwrap();
+        write_json(&mut out, &[row.to_json()]).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let module: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert!(module["text"].as_str().unwrap().starts_with("Cl\"au\nde"));
+        let rows: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(rows[0]["provider"], "Cl\"au\nde\t\u{7}");
+    }
+
+    #[test]
+    fn emit_replaces_output_file_each_render() {
+        let path = std::env::temp_dir().join(format!(
+            "tokengauge-waybar-output-{}.json",
+            std::process::id()
+        ));
+        let config = TokenGaugeConfig::default();
+        emit(
+            Some(&path),
+            &usage_output(&[usage_row("claude", Some(42), Some(7))], &config),
+        )
+        .unwrap();
+        emit(
+            Some(&path),
+            &usage_output(&[usage_row("claude", Some(43), Some(7))], &config),
+        )
+        .unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.lines().count(), 1);
+        let output: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert!(output["text"].as_str().unwrap().contains("43%"));
+    }
+
+    #[test]
+    fn broken_pipe_is_recognised() {
+        struct Gone;
+        impl Write for Gone {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let error = write_json(&mut Gone, &serde_json::json!({"text": "x"})).unwrap_err();
+        assert!(is_broken_pipe(&error));
+        assert!(!is_broken_pipe(&anyhow!("other")));
+    }
+
+    // ------------------------------------------------------------------------
+    // sd_notify tests
+    // ------------------------------------------------------------------------
+
+    #[cfg(feature = "systemd")]
+    #[test]
+    fn sd_notify_is_noop_without_socket() {
+        assert!(!sd_notify(None, "READY=1").unwrap());
+        assert!(!sd_notify(Some(std::ffi::OsStr::new("")), "READY=1").unwrap());
+    }
+
+    #[cfg(feature = "systemd")]
+    #[test]
+    fn sd_notify_sends_state_to_socket() {
+        use std::os::unix::net::UnixDatagram;
+        let path = std::env::temp_dir().join(format!("tokengauge-notify-{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        assert!(sd_notify(Some(path.as_os_str()), "READY=1").unwrap());
+        let mut buffer = [0; 32];
+        let len = listener.recv(&mut buffer).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(&buffer[..len], b"READY=1");
+    }
+
+    // ------------------------------------------------------------------------
+    // completion tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn bash_completions_mention_config() {
+        let mut out = Vec::new();
+        write_completions(Shell::Bash, &mut out);
+        let script = String::from_utf8(out).unwrap();
+        assert!(!script.is_empty());
+        assert!(script.contains("--config"));
+    }
+
+    #[test]
+    fn man_page_mentions_program_and_config() {
+        let mut out = Vec::new();
+        write_man_page(&mut out).unwrap();
+        let roff = String::from_utf8(out).unwrap();
+        assert!(roff.contains(env!("CARGO_PKG_NAME")));
+        assert!(roff.contains("Path to the config file"));
+    }
+
+    // ------------------------------------------------------------------------
+    // debug_provider tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn debug_output_echoes_raw_streams_and_parse_result() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let output = Output {
+            status: std::process::ExitStatus::from_raw(0),
+            stdout: br#"{"provider":"claude","usage":{"primary":{"usedPercent":7}}}"#.to_vec(),
+            stderr: b"debug: token refreshed".to_vec(),
+        };
+        let text = format_debug_output("claude", &output, &CodexbarConfig::default());
+        assert!(text.contains("provider: claude"));
+        assert!(text.contains("exit status: 0"));
+        assert!(text.contains(r#"{"provider":"claude","usage""#));
+        assert!(text.contains("--- stderr (22 bytes) ---\ndebug: token refreshed"));
+        assert!(text.contains("used_percent: Some("));
+
+        let failed = Output {
+            status: std::process::ExitStatus::from_raw(1 << 8),
+            stdout: b"not json".to_vec(),
+            stderr: Vec::new(),
+        };
+        let text = format_debug_output("claude", &failed, &CodexbarConfig::default());
+        assert!(text.contains("exit status: 1"));
+        assert!(text.contains("not json"));
+        assert!(text.contains("error: codexbar failed"));
+    }
+
+    // ------------------------------------------------------------------------
+    // dry-run tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn dry_run_lists_enabled_providers_with_timeout() {
+        use tokengauge_core::{ApiProviderConfig, ProvidersConfig};
+
+        let config = TokenGaugeConfig {
+            timeout_secs: 7,
+            providers: ProvidersConfig {
+                claude: Some(true),
+                zai: Some(ApiProviderConfig {
+                    api_key: "sk-secret".to_string(),
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let text = format_dry_run(&config);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2, "{text}");
+        assert!(lines[0].starts_with("claude: codexbar "), "{text}");
+        assert!(lines[0].ends_with("(timeout 7s)"));
+        assert!(lines[1].starts_with("zai: ZAI_API_TOKEN=<redacted> codexbar "));
+        assert!(!text.contains("sk-secret"));
+    }
+
+    // ------------------------------------------------------------------------
+    // plain output tests
+    // ------------------------------------------------------------------------
+
+    fn strip_ansi(text: &str) -> String {
+        let mut out = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                // Skip "[...m"
+                for c in chars.by_ref() {
+                    if c == 'm' {
+                        break;
+                    }
+                }
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn render_plain_without_color() {
+        let rows = [
+            usage_row("claude", Some(19), Some(95)),
+            usage_row("codex", None, Some(50)),
+        ];
+        let errors = [ProviderFetchError::new(
+            "zai".to_string(),
+            "timeout after 2s",
+        )];
+        let text = render_plain(&rows, &errors, &DisplayConfig::default(), false);
+        assert!(!text.contains('\x1b'));
+        assert_eq!(
+            text,
+            "Claude  session ▁      19%  weekly ▁▂▃▅▇  95%\n\
+             Codex   session —        —  weekly ▁▂▃    50%\n\
+             zai: Request timed out\n"
+        );
+    }
+
+    #[test]
+    fn render_plain_colored_matches_plain_text() {
+        let rows = [usage_row("claude", Some(19), Some(95))];
+        let errors = [ProviderFetchError::new(
+            "zai".to_string(),
+            "timeout after 2s",
+        )];
+        let display = DisplayConfig::default();
+        let colored = render_plain(&rows, &errors, &display, true);
+        assert!(colored.contains("\x1b[32m"), "green for low usage");
+        assert!(colored.contains("\x1b[31m"), "red for critical usage");
+        assert_eq!(
+            strip_ansi(&colored),
+            render_plain(&rows, &errors, &display, false)
+        );
+    }
+
+    // ------------------------------------------------------------------------
+    // announce tests
+    // ------------------------------------------------------------------------
+
+    #[test]
+    fn announce_sentence_full_data() {
+        let mut row = usage_row("claude", Some(19), Some(12));
+        row.session_reset = "Jan 20 at 12:59PM".to_string();
+        row.weekly_reset = "Jan 26 at 8:59AM".to_string();
+        assert_eq!(
+            announce_sentence(&row, &shown(PercentDisplay::Used)),
+            "Claude session 19 percent used, resets Jan 20 at 12:59PM; weekly 12 percent used, resets Jan 26 at 8:59AM."
+        );
+        assert_eq!(
+            announce_sentence(&row, &shown(PercentDisplay::Remaining)),
+            "Claude session 81 percent remaining, resets Jan 20 at 12:59PM; weekly 88 percent remaining, resets Jan 26 at 8:59AM."
+        );
+    }
+
+    #[test]
+    fn announce_sentence_missing_data() {
+        let row = usage_row("codex", Some(5), None);
+        assert_eq!(
+            announce_sentence(&row, &shown(PercentDisplay::Used)),
+            "Codex session 5 percent used; weekly unknown."
+        );
+    }
 }
@@ -0,0 +1,136 @@
+//! Runs the waybar binary against a scripted fake codexbar.
+//!
+//! Each case gets its own temp HOME, XDG dirs, config and cache, so nothing
+//! on the machine running the tests is read or written and no credentials
+//! are needed.
+
+#[path = "../../tokengauge-core/tests/support/mod.rs"]
+mod support;
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+use support::{FakeCodexbar, usage_json, with_fake_codexbar};
+
+/// Run `tokengauge-waybar` with a config pointing at `bin` and return the
+/// JSON it printed.
+fn run_waybar(bin: &Path) -> serde_json::Value {
+    let output = run_waybar_with(bin, &[]);
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// Run `tokengauge-waybar` with `args` and a config pointing at `bin`,
+/// checking that it succeeded.
+fn run_waybar_with(bin: &Path, args: &[&str]) -> Output {
+    let home = bin.parent().unwrap().join("home");
+    fs::create_dir_all(&home).unwrap();
+    let config = home.join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "codexbar_bin = \"{}\"\ncache_file = \"{}\"\n\
+             [providers]\nclaude = true\ncodex = true\n",
+            bin.display(),
+            home.join("cache.json").display()
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_tokengauge-waybar"))
+        .arg("--config")
+        .arg(&config)
+        .args(args)
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .env("HOME", &home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_CACHE_HOME", home.join(".cache"))
+        .env("XDG_DATA_HOME", home.join(".local/share"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn waybar_shows_every_provider() {
+    let spec = FakeCodexbar {
+        responses: vec![
+            ("claude".to_string(), usage_json("claude", 42, 7)),
+            ("codex".to_string(), usage_json("codex", 10, 20)),
+        ],
+        ..Default::default()
+    };
+    let output = with_fake_codexbar(&spec, run_waybar);
+    assert_eq!(output["text"], "Codex ▁ 10%  Claude ▁▂▃ 42%");
+    let tooltip = output["tooltip"].as_str().unwrap();
+    assert!(tooltip.contains("Claude: 5h 42% used"), "{tooltip}");
+    assert!(tooltip.contains("Codex: 5h 10% used"), "{tooltip}");
+    assert_eq!(output["class"], "tokengauge-warning");
+    // Two providers on screen, so no single percentage for waybar's bar
+    assert!(output.get("percentage").is_none());
+}
+
+#[test]
+fn waybar_shows_providers_that_answered() {
+    // Codex gets no response, which fails to parse
+    let spec = FakeCodexbar {
+        responses: vec![("claude".to_string(), usage_json("claude", 42, 7))],
+        ..Default::default()
+    };
+    let output = with_fake_codexbar(&spec, run_waybar);
+    assert_eq!(output["text"], "Claude ▁▂▃ 42%");
+    let tooltip = output["tooltip"].as_str().unwrap();
+    assert!(tooltip.contains("Claude: 5h 42% used"), "{tooltip}");
+    assert!(!tooltip.contains("Codex"), "{tooltip}");
+    assert_eq!(output["percentage"], 42);
+}
+
+#[test]
+fn waybar_reports_when_every_provider_fails() {
+    let spec = FakeCodexbar {
+        stderr: Some("boom".to_string()),
+        exit_code: 1,
+        ..Default::default()
+    };
+    let output = with_fake_codexbar(&spec, run_waybar);
+    assert_eq!(output["text"], "—");
+    assert_eq!(output["class"], "tokengauge-empty");
+    let tooltip = output["tooltip"].as_str().unwrap();
+    assert!(
+        tooltip.starts_with("TokenGauge: all providers failed"),
+        "{tooltip}"
+    );
+    assert!(tooltip.contains("Claude"), "{tooltip}");
+    assert!(tooltip.contains("Codex"), "{tooltip}");
+}
+
+#[test]
+fn text_formats_write_to_output() {
+    let spec = FakeCodexbar {
+        responses: vec![("claude".to_string(), usage_json("claude", 42, 7))],
+        ..Default::default()
+    };
+    with_fake_codexbar(&spec, |bin| {
+        let path = bin.with_file_name("out.txt");
+        let target = path.to_str().unwrap();
+        for args in [
+            vec!["--announce"],
+            vec!["--format", "plain"],
+            vec!["--format", "prometheus"],
+        ] {
+            let output = run_waybar_with(bin, &[&args[..], &["--output", target]].concat());
+            assert!(output.stdout.is_empty(), "{args:?} printed to stdout");
+            let written = fs::read_to_string(&path).unwrap();
+            assert!(
+                written.to_lowercase().contains("claude"),
+                "{args:?}: {written}"
+            );
+            fs::remove_file(&path).unwrap();
+        }
+    });
+}